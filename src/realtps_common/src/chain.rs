@@ -17,6 +17,15 @@ pub enum ChainType {
     Tendermint,
 }
 
+/// Where a chain settles its transactions.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Hash, Eq, PartialEq, Ord, PartialOrd)]
+#[serde(rename_all = "lowercase")]
+pub enum ChainCategory {
+    L1,
+    /// Rollups, which settle on another chain
+    L2,
+}
+
 #[derive(Serialize, Deserialize, Debug, Copy, Clone, Hash, Eq, PartialEq)]
 #[serde(try_from = "String")]
 #[serde(rename_all = "lowercase")]
@@ -56,6 +65,11 @@ pub enum Chain {
     Solana,
     Stellar,
     Terra,
+    /// Testnets, tracked apart from the mainnets they test
+    #[serde(rename = "ethereum-sepolia")]
+    EthereumSepolia,
+    #[serde(rename = "solana-devnet")]
+    SolanaDevnet,
 }
 
 impl Chain {
@@ -104,6 +118,25 @@ impl Chain {
         ]
     }
 
+    /// Testnets, which are only worked on when chosen with `--chain` or a
+    /// profile's chains, and are left out of aggregates.
+    pub fn testnet_chains() -> Vec<Chain> {
+        vec![Chain::EthereumSepolia, Chain::SolanaDevnet]
+    }
+
+    /// The mainnet a testnet tests, or `None` for mainnets.
+    pub fn mainnet(&self) -> Option<Chain> {
+        match self {
+            Chain::EthereumSepolia => Some(Chain::Ethereum),
+            Chain::SolanaDevnet => Some(Chain::Solana),
+            _ => None,
+        }
+    }
+
+    pub fn is_testnet(&self) -> bool {
+        self.mainnet().is_some()
+    }
+
     /// Chain names showed on the website
     pub fn description(&self) -> &'static str {
         match *self {
@@ -141,6 +174,8 @@ impl Chain {
             Chain::Solana => "Solana",
             Chain::Stellar => "Stellar",
             Chain::Terra => "Terra",
+            Chain::EthereumSepolia => "Ethereum Sepolia",
+            Chain::SolanaDevnet => "Solana Devnet",
         }
     }
 
@@ -162,13 +197,14 @@ impl Chain {
             | Chain::OKEx
             | Chain::Optimism
             | Chain::Polygon
-            | Chain::Rootstock => ChainType::Ethers,
+            | Chain::Rootstock
+            | Chain::EthereumSepolia => ChainType::Ethers,
             Chain::Bitcoin => ChainType::Esplora,
             Chain::Elrond => ChainType::Elrond,
             Chain::Hedera => ChainType::Hedera,
             Chain::Near => ChainType::Near,
             Chain::Pivx => ChainType::Pivx,
-            Chain::Solana => ChainType::Solana,
+            Chain::Solana | Chain::SolanaDevnet => ChainType::Solana,
             Chain::Stellar => ChainType::Stellar,
             Chain::CosmosHub | Chain::Osmosis | Chain::SecretNetwork | Chain::Terra => {
                 ChainType::Tendermint
@@ -179,6 +215,40 @@ impl Chain {
             Chain::Algorand => ChainType::Algorand,
         }
     }
+
+    pub fn category(&self) -> ChainCategory {
+        if let Some(mainnet) = self.mainnet() {
+            return mainnet.category();
+        }
+        match self {
+            Chain::Arbitrum | Chain::Optimism => ChainCategory::L2,
+            // Sidechains and parachains have their own consensus
+            _ => ChainCategory::L1,
+        }
+    }
+
+    /// The decimals of the native token, between its smallest unit, which
+    /// blocks count in, and a whole token.
+    pub fn native_decimals(&self) -> u32 {
+        if let Some(mainnet) = self.mainnet() {
+            return mainnet.native_decimals();
+        }
+        match self {
+            Chain::Algorand
+            | Chain::CosmosHub
+            | Chain::Osmosis
+            | Chain::SecretNetwork
+            | Chain::Terra => 6,
+            Chain::Stellar => 7,
+            Chain::Bitcoin | Chain::Hedera | Chain::Pivx => 8,
+            Chain::Solana => 9,
+            Chain::Polkadot => 10,
+            Chain::Acala | Chain::Bifrost | Chain::Karura | Chain::Kusama => 12,
+            Chain::Near => 24,
+            // EVM chains, and Elrond
+            _ => 18,
+        }
+    }
 }
 
 // For serde deserializing.
@@ -222,6 +292,8 @@ impl<'a> TryFrom<&'a str> for Chain {
             "solana" => Ok(Chain::Solana),
             "stellar" => Ok(Chain::Stellar),
             "terra" => Ok(Chain::Terra),
+            "ethereum-sepolia" => Ok(Chain::EthereumSepolia),
+            "solana-devnet" => Ok(Chain::SolanaDevnet),
             chain => bail!("failed parsing chain name {}", chain),
         }
     }
@@ -1,12 +1,20 @@
-use crate::chain::Chain;
+use crate::chain::{Chain, ChainCategory};
+use crate::sketch::{self, SenderSketch};
 use anyhow::{bail, Result};
-use chrono::{DateTime, Utc};
+use async_trait::async_trait;
+use chrono::{DateTime, Datelike, NaiveDate, TimeZone, Timelike, Utc};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::fs::{self, File};
-use std::io::{BufReader, BufWriter};
+use std::collections::btree_map::{BTreeMap, Entry};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::task;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Block {
     pub chain: Chain,
     pub block_number: u64,
@@ -15,122 +23,1970 @@ pub struct Block {
     /// empty.
     pub prev_block_number: Option<u64>,
     pub timestamp: u64, // seconds since unix epoch
+    /// Transactions submitted by users. Consensus transactions, like Solana's
+    /// votes, are counted in `num_vote_txs`.
     pub num_txs: u64,
+    #[serde(default)]
+    pub num_vote_txs: Option<u64>,
+    /// How many of `num_txs` failed, for chains where the client can tell
+    #[serde(default)]
+    pub num_txs_failed: Option<u64>,
     pub hash: String,
     // FIXME this could be None, like prev_block_number
     pub parent_hash: String,
+    /// Gas used by the block's transactions, for chains that meter gas, like
+    /// the EVM chains. For Solana, the compute units of user transactions.
+    #[serde(default)]
+    pub gas_used: Option<u64>,
+    #[serde(default)]
+    pub gas_limit: Option<u64>,
+    /// Fees paid by the counted transactions, in the chain's smallest unit,
+    /// for chains whose blocks include them.
+    #[serde(default)]
+    pub fees: Option<u64>,
+    /// Native value transferred by the counted transactions, in the chain's
+    /// smallest unit, for chains whose blocks include it. A u128, as NEAR's
+    /// yoctoNEAR overflow a u64.
+    #[serde(default)]
+    pub value_transferred: Option<u128>,
+    /// ERC-20 and ERC-721 `Transfer` events, for EVM chains configured to
+    /// count them. One transaction may make many transfers.
+    #[serde(default)]
+    pub num_token_transfers: Option<u64>,
+    /// Receipts in the block's chunks, for NEAR, where a transaction's
+    /// actions are executed as receipts, often in later blocks and other
+    /// shards.
+    #[serde(default)]
+    pub num_receipts: Option<u64>,
+    /// Calls, contract creations and self-destructs made by contracts,
+    /// rather than by the transaction's sender, for EVM chains configured to
+    /// trace them.
+    #[serde(default)]
+    pub num_internal_calls: Option<u64>,
+    /// The miner, validator or other account that produced the block, for
+    /// chains where the block names it.
+    #[serde(default)]
+    pub producer: Option<String>,
+    /// The addresses that sent the counted transactions, for chains where
+    /// they come with the block.
+    #[serde(default)]
+    pub senders: Option<SenderSketch>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CalculationLog {
     pub calculating_start: DateTime<Utc>,
     pub calculating_end: DateTime<Utc>,
     pub newest_block_timestamp: DateTime<Utc>,
     pub oldest_block_timestamp: DateTime<Utc>,
+    /// The chain is disabled, so these are its last calculations, no longer
+    /// updated
+    #[serde(default)]
+    pub stale: bool,
 }
 
+/// Metrics calculated alongside TPS.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ChainStats {
+    pub windows: BTreeMap<TpsWindow, WindowStats>,
+    /// Over the shortest window, as every block in it is loaded
+    #[serde(default)]
+    pub block_times: Option<BlockTimeStats>,
+    /// Over the shortest window, for chains with gas limits
+    #[serde(default)]
+    pub max_tps: Option<MaxTpsEstimate>,
+    /// Over the shortest window
+    #[serde(default)]
+    pub txs_per_block: Option<TxsPerBlockStats>,
+    /// Over the shortest window, for chains whose blocks name their producer
+    #[serde(default)]
+    pub producers: Option<ProducerStats>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct WindowStats {
+    /// In the chain's smallest unit. None if any block has no fee count.
+    pub fee_per_tx: Option<f64>,
+    pub fees_per_second: Option<f64>,
+    /// In the chain's whole native units. None if any block has no value
+    /// count.
+    #[serde(default)]
+    pub value_per_second: Option<f64>,
+    /// `value_per_second` converted at the configured price, for chains with
+    /// one
+    #[serde(default)]
+    pub value_usd_per_second: Option<f64>,
+    /// None if any block has no token transfer count.
+    #[serde(default)]
+    pub token_transfers_per_second: Option<f64>,
+    /// None if any block has no receipt count.
+    #[serde(default)]
+    pub receipts_per_second: Option<f64>,
+    /// None if any block has no internal call count.
+    #[serde(default)]
+    pub internal_calls_per_second: Option<f64>,
+    /// The fraction of blocks without user transactions
+    #[serde(default)]
+    pub empty_block_share: Option<f64>,
+    /// TPS including consensus transactions, for chains that count them
+    #[serde(default)]
+    pub raw_tps: Option<f64>,
+    /// TPS over the window ending at the finalized block, for chains that
+    /// report one. Unlike the TPS of the latest blocks, it can't include
+    /// blocks that are later reorged away.
+    #[serde(default)]
+    pub finalized_tps: Option<f64>,
+    /// The fraction of block gas limits used. None if any block has no gas
+    /// count or limit.
+    #[serde(default)]
+    pub utilization: Option<f64>,
+    /// Blocks whose timestamps were ignored as wrong
+    #[serde(default)]
+    pub anomalous_timestamps: u64,
+    /// The fraction of the window covered by stored blocks, less than 1 if
+    /// blocks are missing, like when a chain was only recently added. The
+    /// lower it is, the less the window's TPS can be trusted.
+    #[serde(default)]
+    pub coverage: Option<f64>,
+    /// Shorter than the window for chains configured with a maximum window
+    #[serde(default)]
+    pub calculated_over: Option<TpsWindow>,
+}
+
+/// A calculation result, in a chain's TPS history.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TpsPoint {
+    /// The newest block's timestamp when calculated
+    pub timestamp: u64,
+    pub tps: BTreeMap<TpsWindow, f64>,
+}
+
+/// The summed TPS of all chains, from one calculation.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AggregateTpsPoint {
+    /// When calculated
+    pub timestamp: u64,
+    pub num_chains: u64,
+    pub tps: BTreeMap<TpsWindow, f64>,
+    pub categories: BTreeMap<ChainCategory, BTreeMap<TpsWindow, f64>>,
+}
+
+/// TPS over whole UTC days, kept after their blocks are removed.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct DailyTps {
+    pub days: BTreeMap<NaiveDate, f64>,
+    /// Estimated distinct senders, for days where every block has them
+    #[serde(default)]
+    pub active_addresses: BTreeMap<NaiveDate, u64>,
+}
+
+/// TPS over whole UTC hours, for seeing usage by time of day and day of
+/// week.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct HourlyTps {
+    /// Keyed by the hour's starting timestamp
+    pub hours: BTreeMap<u64, f64>,
+}
+
+impl HourlyTps {
+    /// The average TPS of each hour of each day of the week, Monday first,
+    /// or None for hours with no TPS.
+    pub fn heatmap(&self) -> [[Option<f64>; 24]; 7] {
+        let mut sums = [[(0.0, 0); 24]; 7];
+        for (hour_start, tps) in &self.hours {
+            let datetime = match i64::try_from(*hour_start)
+                .ok()
+                .and_then(|timestamp| Utc.timestamp_opt(timestamp, 0).single())
+            {
+                Some(datetime) => datetime,
+                None => continue,
+            };
+            let day = datetime.weekday().num_days_from_monday() as usize;
+            let hour = datetime.hour() as usize;
+            sums[day][hour].0 += tps;
+            sums[day][hour].1 += 1;
+        }
+
+        sums.map(|day| {
+            day.map(|(sum, count)| match count {
+                0 => None,
+                count => Some(sum / f64::from(count)),
+            })
+        })
+    }
+}
+
+/// How far the finalized block was behind the latest block, when last
+/// sampled during import.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FinalityLag {
+    /// The latest block's timestamp
+    pub timestamp: u64,
+    pub blocks: u64,
+    pub seconds: u64,
+    /// Missing from lags sampled before it was recorded
+    #[serde(default)]
+    pub finalized_block_number: Option<u64>,
+}
+
+/// A chain whose latest block is older than this is considered stalled, as
+/// is one whose health hasn't been checked for this long.
+pub const STALL_SECONDS: u64 = 60 * 60;
+
+/// How recently a chain produced blocks, and we imported them, when last
+/// checked during import.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChainHealth {
+    pub checked_at: u64,
+    /// The age of the newest imported block
+    pub seconds_since_import: u64,
+    /// The age of the chain's own latest block. If only
+    /// `seconds_since_import` is high, the import is falling behind.
+    pub seconds_since_latest_block: u64,
+    pub stalled: bool,
+}
+
+impl ChainHealth {
+    /// Stalled when last checked, or not checked since long before `now`.
+    pub fn is_stalled_at(&self, now: u64) -> bool {
+        self.stalled || now.saturating_sub(self.checked_at) > STALL_SECONDS
+    }
+}
+
+/// A chain's own measure of its recent TPS, sampled during import, and ours
+/// at the time, to catch counting bugs.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NativeTps {
+    pub timestamp: u64,
+    /// Including consensus transactions, like Solana's votes
+    pub native_tps: f64,
+    /// Our TPS over the last hour, including consensus transactions where we
+    /// count them
+    pub calculated_tps: Option<f64>,
+}
+
+/// How the transactions imported from a chain's second provider compare to
+/// those from the first, per timestamp index bucket.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProviderCheck {
+    pub timestamp: u64,
+    pub num_buckets: u64,
+    pub mismatches: Vec<BucketMismatch>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BucketMismatch {
+    pub bucket_start: u64,
+    pub num_txs: u64,
+    pub secondary_num_txs: u64,
+}
+
+/// How a chain's RPC endpoints have been performing, for chains with several,
+/// keyed by endpoint host.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct EndpointHealth {
+    pub endpoints: BTreeMap<String, EndpointScore>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct EndpointScore {
+    /// Moving average of successful requests' latency. None until one
+    /// succeeds.
+    pub latency_ms: Option<f64>,
+    /// Moving average of the fraction of requests that failed
+    pub error_rate: f64,
+    pub num_requests: u64,
+}
+
+/// How far a chain's import got, so an interrupted import resumes where it
+/// stopped.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ImportCheckpoint {
+    /// The highest block stored with every block below it back to the
+    /// previous import, and counted in the timestamp index.
+    pub contiguous_block_number: u64,
+    /// The blocks stored by an import that hasn't completed.
+    pub in_progress: Option<InProgressImport>,
+}
+
+/// The blocks an import stored before it was interrupted, which run without
+/// gaps from `head_block_number` down to the block after `next_block_number`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InProgressImport {
+    pub head_block_number: u64,
+    pub head_hash: String,
+    /// The block to fetch next, the parent of the oldest stored block
+    pub next_block_number: u64,
+    pub next_hash: String,
+    /// The stored blocks that are new to the chain's timestamp index
+    pub timestamp_index: TimestampIndex,
+    /// Stored blocks that replaced reorged ones, and the blocks they
+    /// replaced, to recount in the index
+    pub replaced_blocks: Vec<(Block, Block)>,
+}
+
+/// How an import catching up on a chain is getting on.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImportProgress {
+    pub updated_at: u64,
+    /// The head the import is catching up to
+    pub head_block_number: u64,
+    /// Blocks left to fetch below `head_block_number`
+    pub blocks_behind: u64,
+    /// Blocks fetched per second
+    pub blocks_per_second: f64,
+    /// Allowing for the blocks the chain makes meanwhile. `None` if the
+    /// import is falling further behind.
+    pub seconds_to_catch_up: Option<u64>,
+}
+
+/// When a chain's jobs of one kind last succeeded, and how often they have
+/// failed since, for monitoring.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct JobStatus {
+    pub last_success: Option<DateTime<Utc>>,
+    /// Failed runs since the last successful one
+    pub num_errors: u64,
+    pub last_error: Option<String>,
+}
+
+/// Whether a chain's imports are paused, set while the process runs, e.g.
+/// when a provider asks us to back off.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImportPause {
+    pub paused: bool,
+    pub reason: Option<String>,
+    pub changed: DateTime<Utc>,
+    /// Paused by the importer itself, after failing too many times in a row
+    #[serde(default)]
+    pub quarantined: bool,
+}
+
+/// Which importer instance runs a chain's jobs of one kind, for instances
+/// sharing a db. Held until `expires_at` unless renewed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChainLease {
+    pub holder: String,
+    pub expires_at: u64,
+}
+
+/// The kinds of jobs leased apart, so separate import and calculate
+/// processes can share a chain.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Hash, Eq, PartialEq, Ord, PartialOrd)]
+#[serde(rename_all = "snake_case")]
+pub enum LeaseKind {
+    /// Importing and backfilling blocks
+    Import,
+    /// Importing from the secondary provider
+    ImportSecondary,
+    Calculate,
+}
+
+impl fmt::Display for LeaseKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            LeaseKind::Import => "import",
+            LeaseKind::ImportSecondary => "import_secondary",
+            LeaseKind::Calculate => "calculate",
+        };
+        f.write_str(s)
+    }
+}
+
+/// Seconds between consecutive blocks.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BlockTimeStats {
+    pub window: TpsWindow,
+    pub mean: f64,
+    pub median: f64,
+    pub std_dev: f64,
+}
+
+/// The TPS a chain could reach if every block were full of median
+/// transactions.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MaxTpsEstimate {
+    pub window: TpsWindow,
+    pub median_gas_per_tx: f64,
+    pub max_tps: f64,
+}
+
+/// Percentiles of the number of transactions in a block.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TxsPerBlockStats {
+    pub window: TpsWindow,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub max: u64,
+}
+
+/// How concentrated block production is.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProducerStats {
+    pub window: TpsWindow,
+    pub num_producers: u64,
+    /// The fraction of blocks produced by the most prolific producer
+    pub top_share: f64,
+    /// The fewest producers that together produced over half the blocks
+    pub nakamoto_coefficient: u64,
+}
+
+/// A period to average TPS over, ending at the newest block.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Hash, Eq, PartialEq, Ord, PartialOrd)]
+#[serde(rename_all = "lowercase")]
+#[derive(clap::ArgEnum)]
+pub enum TpsWindow {
+    Hour,
+    Day,
+    Week,
+    Month,
+}
+
+impl TpsWindow {
+    pub fn all_windows() -> Vec<TpsWindow> {
+        vec![
+            TpsWindow::Hour,
+            TpsWindow::Day,
+            TpsWindow::Week,
+            TpsWindow::Month,
+        ]
+    }
+
+    pub const fn seconds(self) -> u64 {
+        match self {
+            TpsWindow::Hour => 60 * 60,
+            TpsWindow::Day => 60 * 60 * 24,
+            TpsWindow::Week => 60 * 60 * 24 * 7,
+            TpsWindow::Month => 60 * 60 * 24 * 30,
+        }
+    }
+}
+
+impl fmt::Display for TpsWindow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            TpsWindow::Hour => "1h",
+            TpsWindow::Day => "24h",
+            TpsWindow::Week => "7d",
+            TpsWindow::Month => "30d",
+        };
+        f.write_str(s)
+    }
+}
+
+/// The highest TPS seen in a single block and in a single minute.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Peaks {
+    /// TPS of one block, over the time since the previous block
+    pub block: Option<Peak>,
+    pub minute: Option<Peak>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Peak {
+    pub tps: f64,
+    /// The block's timestamp, or the start of the minute
+    pub timestamp: u64,
+}
+
+/// Peak TPS of a chain, updated with every new block.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PeakTps {
+    /// The newest block that has been counted
+    pub newest_block_number: Option<u64>,
+    /// The start and transactions so far of the newest minute, which may not
+    /// have all its blocks yet
+    pub newest_minute: Option<(u64, u64)>,
+    /// Peaks of the retained blocks, keyed by the start of their hour, so old
+    /// peaks can be dropped with the blocks
+    pub hourly: BTreeMap<u64, Peaks>,
+    pub all_time: Peaks,
+}
+
+impl Peaks {
+    pub fn update_block(&mut self, peak: Peak) {
+        update_peak(&mut self.block, peak);
+    }
+
+    pub fn update_minute(&mut self, peak: Peak) {
+        update_peak(&mut self.minute, peak);
+    }
+
+    pub fn merge(&mut self, other: &Peaks) {
+        if let Some(peak) = &other.block {
+            self.update_block(peak.clone());
+        }
+        if let Some(peak) = &other.minute {
+            self.update_minute(peak.clone());
+        }
+    }
+}
+
+fn update_peak(current: &mut Option<Peak>, peak: Peak) {
+    if current.as_ref().map(|current| current.tps) < Some(peak.tps) {
+        *current = Some(peak);
+    }
+}
+
+impl PeakTps {
+    /// The peaks over all retained blocks.
+    pub fn retained(&self) -> Peaks {
+        let mut peaks = Peaks::default();
+        for hourly_peaks in self.hourly.values() {
+            peaks.merge(hourly_peaks);
+        }
+        peaks
+    }
+}
+
+/// One of the writes applied together by `Db::store_atomically`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum DbWrite {
+    Block(Box<Block>),
+    HighestBlockNumber(u64),
+    Tps(TpsWindow, f64),
+    CalculationLog(CalculationLog),
+    TimestampIndex(TimestampIndex),
+    ImportCheckpoint(ImportCheckpoint),
+}
+
+/// The width of a `TimestampIndex` bucket.
+pub const TIMESTAMP_BUCKET_SECONDS: u64 = 60 * 60;
+
+/// Per-chain summary of stored blocks, grouped by the hour of their timestamp,
+/// so calculations can find the blocks in a time window without walking back
+/// through every block.
+///
+/// Blocks within a bucket are assumed to have contiguous block numbers, and
+/// must be inserted in ascending or descending order, as the importer fetches
+/// them. A block inside a bucket's range is then known to be counted already.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TimestampIndex {
+    /// Keyed by the bucket's starting timestamp.
+    pub buckets: BTreeMap<u64, TimestampBucket>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TimestampBucket {
+    pub first_block_number: u64,
+    pub first_timestamp: u64,
+    pub last_block_number: u64,
+    pub last_timestamp: u64,
+    pub num_txs: u64,
+    /// None for buckets from before blocks were counted.
+    #[serde(default)]
+    pub num_blocks: Option<u64>,
+    #[serde(default)]
+    pub num_empty_blocks: Option<u64>,
+    /// None if any block in the bucket has no gas count.
+    #[serde(default)]
+    pub gas_used: Option<u64>,
+    #[serde(default)]
+    pub gas_limit: Option<u64>,
+    #[serde(default)]
+    pub fees: Option<u64>,
+    #[serde(default)]
+    pub value_transferred: Option<u128>,
+    #[serde(default)]
+    pub num_token_transfers: Option<u64>,
+    #[serde(default)]
+    pub num_receipts: Option<u64>,
+    #[serde(default)]
+    pub num_internal_calls: Option<u64>,
+    #[serde(default)]
+    pub num_vote_txs: Option<u64>,
+    #[serde(default)]
+    pub num_txs_failed: Option<u64>,
+    #[serde(default)]
+    pub senders: Option<SenderSketch>,
+}
+
+impl TimestampBucket {
+    fn contains(&self, block_number: u64) -> bool {
+        (self.first_block_number..=self.last_block_number).contains(&block_number)
+    }
+
+    fn add_block(&mut self, block: &Block) {
+        self.num_txs = self.num_txs.checked_add(block.num_txs).expect("overflow");
+        self.num_blocks = add_known(self.num_blocks, Some(1));
+        self.num_empty_blocks =
+            add_known(self.num_empty_blocks, Some(u64::from(block.num_txs == 0)));
+        self.gas_used = add_known(self.gas_used, block.gas_used);
+        self.gas_limit = add_known(self.gas_limit, block.gas_limit);
+        self.fees = add_known(self.fees, block.fees);
+        self.value_transferred = add_known_value(self.value_transferred, block.value_transferred);
+        self.num_token_transfers = add_known(self.num_token_transfers, block.num_token_transfers);
+        self.num_receipts = add_known(self.num_receipts, block.num_receipts);
+        self.num_internal_calls = add_known(self.num_internal_calls, block.num_internal_calls);
+        self.num_vote_txs = add_known(self.num_vote_txs, block.num_vote_txs);
+        self.num_txs_failed = add_known(self.num_txs_failed, block.num_txs_failed);
+        self.senders = sketch::merge_known(self.senders.take(), block.senders.as_ref());
+    }
+
+    fn subtract_block(&mut self, block: &Block) {
+        let subtract = |total: Option<u64>, count: Option<u64>| {
+            total
+                .zip(count)
+                .map(|(total, count)| total.saturating_sub(count))
+        };
+        self.num_txs = self.num_txs.saturating_sub(block.num_txs);
+        self.num_blocks = subtract(self.num_blocks, Some(1));
+        self.num_empty_blocks =
+            subtract(self.num_empty_blocks, Some(u64::from(block.num_txs == 0)));
+        self.gas_used = subtract(self.gas_used, block.gas_used);
+        self.gas_limit = subtract(self.gas_limit, block.gas_limit);
+        self.fees = subtract(self.fees, block.fees);
+        self.value_transferred = self
+            .value_transferred
+            .zip(block.value_transferred)
+            .map(|(total, value)| total.saturating_sub(value));
+        self.num_token_transfers = subtract(self.num_token_transfers, block.num_token_transfers);
+        self.num_receipts = subtract(self.num_receipts, block.num_receipts);
+        self.num_internal_calls = subtract(self.num_internal_calls, block.num_internal_calls);
+        self.num_vote_txs = subtract(self.num_vote_txs, block.num_vote_txs);
+        self.num_txs_failed = subtract(self.num_txs_failed, block.num_txs_failed);
+    }
+}
+
+impl TimestampIndex {
+    pub fn insert(&mut self, block: &Block) {
+        let bucket_start = block.timestamp - block.timestamp % TIMESTAMP_BUCKET_SECONDS;
+
+        match self.buckets.entry(bucket_start) {
+            Entry::Vacant(entry) => {
+                entry.insert(TimestampBucket {
+                    first_block_number: block.block_number,
+                    first_timestamp: block.timestamp,
+                    last_block_number: block.block_number,
+                    last_timestamp: block.timestamp,
+                    num_txs: block.num_txs,
+                    num_blocks: Some(1),
+                    num_empty_blocks: Some(u64::from(block.num_txs == 0)),
+                    gas_used: block.gas_used,
+                    gas_limit: block.gas_limit,
+                    fees: block.fees,
+                    value_transferred: block.value_transferred,
+                    num_token_transfers: block.num_token_transfers,
+                    num_receipts: block.num_receipts,
+                    num_internal_calls: block.num_internal_calls,
+                    num_vote_txs: block.num_vote_txs,
+                    num_txs_failed: block.num_txs_failed,
+                    senders: block.senders.clone(),
+                });
+            }
+            Entry::Occupied(entry) => {
+                let bucket = entry.into_mut();
+                if block.block_number < bucket.first_block_number {
+                    bucket.first_block_number = block.block_number;
+                    bucket.first_timestamp = block.timestamp;
+                } else if block.block_number > bucket.last_block_number {
+                    bucket.last_block_number = block.block_number;
+                    bucket.last_timestamp = block.timestamp;
+                } else {
+                    // Already counted
+                    return;
+                }
+                bucket.add_block(block);
+            }
+        }
+    }
+
+    /// Swaps the counts of a block replaced by a reorg for those of the block
+    /// replacing it. A block can't be taken out of a sender estimate, so the
+    /// replaced block's senders stay counted.
+    pub fn replace(&mut self, old_block: &Block, new_block: &Block) {
+        let old_bucket_start = old_block.timestamp - old_block.timestamp % TIMESTAMP_BUCKET_SECONDS;
+        let old_bucket = self
+            .buckets
+            .get_mut(&old_bucket_start)
+            .filter(|bucket| bucket.contains(old_block.block_number));
+        if let Some(old_bucket) = old_bucket {
+            old_bucket.subtract_block(old_block);
+        }
+
+        let new_bucket_start = new_block.timestamp - new_block.timestamp % TIMESTAMP_BUCKET_SECONDS;
+        match self.buckets.get_mut(&new_bucket_start) {
+            Some(bucket) if bucket.contains(new_block.block_number) => bucket.add_block(new_block),
+            _ => self.insert(new_block),
+        }
+    }
+
+    /// Adds the buckets of an index of other blocks. Buckets whose block
+    /// ranges overlap are assumed to be counted already.
+    pub fn merge(&mut self, other: TimestampIndex) {
+        for (bucket_start, other_bucket) in other.buckets {
+            match self.buckets.entry(bucket_start) {
+                Entry::Vacant(entry) => {
+                    entry.insert(other_bucket);
+                }
+                Entry::Occupied(entry) => {
+                    let bucket = entry.into_mut();
+                    if other_bucket.first_block_number > bucket.last_block_number {
+                        bucket.last_block_number = other_bucket.last_block_number;
+                        bucket.last_timestamp = other_bucket.last_timestamp;
+                    } else if other_bucket.last_block_number < bucket.first_block_number {
+                        bucket.first_block_number = other_bucket.first_block_number;
+                        bucket.first_timestamp = other_bucket.first_timestamp;
+                    } else {
+                        continue;
+                    }
+                    bucket.num_txs = bucket
+                        .num_txs
+                        .checked_add(other_bucket.num_txs)
+                        .expect("overflow");
+                    bucket.num_blocks = add_known(bucket.num_blocks, other_bucket.num_blocks);
+                    bucket.num_empty_blocks =
+                        add_known(bucket.num_empty_blocks, other_bucket.num_empty_blocks);
+                    bucket.gas_used = add_known(bucket.gas_used, other_bucket.gas_used);
+                    bucket.gas_limit = add_known(bucket.gas_limit, other_bucket.gas_limit);
+                    bucket.fees = add_known(bucket.fees, other_bucket.fees);
+                    bucket.value_transferred =
+                        add_known_value(bucket.value_transferred, other_bucket.value_transferred);
+                    bucket.num_token_transfers =
+                        add_known(bucket.num_token_transfers, other_bucket.num_token_transfers);
+                    bucket.num_receipts = add_known(bucket.num_receipts, other_bucket.num_receipts);
+                    bucket.num_internal_calls =
+                        add_known(bucket.num_internal_calls, other_bucket.num_internal_calls);
+                    bucket.num_vote_txs = add_known(bucket.num_vote_txs, other_bucket.num_vote_txs);
+                    bucket.num_txs_failed =
+                        add_known(bucket.num_txs_failed, other_bucket.num_txs_failed);
+                    bucket.senders =
+                        sketch::merge_known(bucket.senders.take(), other_bucket.senders.as_ref());
+                }
+            }
+        }
+    }
+
+    /// The bucket containing `timestamp`, or the closest one before it.
+    pub fn bucket_at(&self, timestamp: u64) -> Option<(u64, &TimestampBucket)> {
+        self.buckets
+            .range(..=timestamp)
+            .next_back()
+            .map(|(start, bucket)| (*start, bucket))
+    }
+
+    /// The number of transactions in all buckets starting after `bucket_start`.
+    pub fn num_txs_after(&self, bucket_start: u64) -> u64 {
+        self.buckets
+            .range(bucket_start + 1..)
+            .map(|(_, bucket)| bucket.num_txs)
+            .sum()
+    }
+
+    /// The blocks in all buckets starting after `bucket_start`, if every one
+    /// of them has a block count.
+    pub fn num_blocks_after(&self, bucket_start: u64) -> Option<u64> {
+        self.buckets
+            .range(bucket_start + 1..)
+            .map(|(_, bucket)| bucket.num_blocks)
+            .sum()
+    }
+
+    /// The blocks without transactions in all buckets starting after
+    /// `bucket_start`, if every one of them has a block count.
+    pub fn num_empty_blocks_after(&self, bucket_start: u64) -> Option<u64> {
+        self.buckets
+            .range(bucket_start + 1..)
+            .map(|(_, bucket)| bucket.num_empty_blocks)
+            .sum()
+    }
+
+    /// The gas used in all buckets starting after `bucket_start`, if every
+    /// one of them has a gas count.
+    pub fn gas_used_after(&self, bucket_start: u64) -> Option<u64> {
+        self.buckets
+            .range(bucket_start + 1..)
+            .map(|(_, bucket)| bucket.gas_used)
+            .sum()
+    }
+
+    /// The gas limits of all buckets starting after `bucket_start`, if every
+    /// one of them has a gas limit.
+    pub fn gas_limit_after(&self, bucket_start: u64) -> Option<u64> {
+        self.buckets
+            .range(bucket_start + 1..)
+            .map(|(_, bucket)| bucket.gas_limit)
+            .sum()
+    }
+
+    /// The fees in all buckets starting after `bucket_start`, if every one of
+    /// them has a fee count.
+    pub fn fees_after(&self, bucket_start: u64) -> Option<u64> {
+        self.buckets
+            .range(bucket_start + 1..)
+            .map(|(_, bucket)| bucket.fees)
+            .sum()
+    }
+
+    /// The value transferred in all buckets starting after `bucket_start`, if
+    /// every one of them has a value count.
+    pub fn value_transferred_after(&self, bucket_start: u64) -> Option<u128> {
+        self.buckets
+            .range(bucket_start + 1..)
+            .map(|(_, bucket)| bucket.value_transferred)
+            .sum()
+    }
+
+    /// The token transfers in all buckets starting after `bucket_start`, if
+    /// every one of them has a token transfer count.
+    pub fn num_token_transfers_after(&self, bucket_start: u64) -> Option<u64> {
+        self.buckets
+            .range(bucket_start + 1..)
+            .map(|(_, bucket)| bucket.num_token_transfers)
+            .sum()
+    }
+
+    /// The receipts in all buckets starting after `bucket_start`, if every one
+    /// of them has a receipt count.
+    pub fn num_receipts_after(&self, bucket_start: u64) -> Option<u64> {
+        self.buckets
+            .range(bucket_start + 1..)
+            .map(|(_, bucket)| bucket.num_receipts)
+            .sum()
+    }
+
+    /// The internal calls in all buckets starting after `bucket_start`, if
+    /// every one of them has an internal call count.
+    pub fn num_internal_calls_after(&self, bucket_start: u64) -> Option<u64> {
+        self.buckets
+            .range(bucket_start + 1..)
+            .map(|(_, bucket)| bucket.num_internal_calls)
+            .sum()
+    }
+
+    /// The vote transactions in all buckets starting after `bucket_start`, if
+    /// every one of them has a vote count.
+    pub fn num_vote_txs_after(&self, bucket_start: u64) -> Option<u64> {
+        self.buckets
+            .range(bucket_start + 1..)
+            .map(|(_, bucket)| bucket.num_vote_txs)
+            .sum()
+    }
+
+    /// The failed transactions in all buckets starting after `bucket_start`,
+    /// if every one of them has a failure count.
+    pub fn num_txs_failed_after(&self, bucket_start: u64) -> Option<u64> {
+        self.buckets
+            .range(bucket_start + 1..)
+            .map(|(_, bucket)| bucket.num_txs_failed)
+            .sum()
+    }
+
+    /// Drops buckets that only contain blocks older than `timestamp`.
+    pub fn remove_before(&mut self, timestamp: u64) {
+        self.buckets
+            .retain(|_, bucket| bucket.last_timestamp >= timestamp);
+    }
+}
+
+/// Sums counts that not every block has, like gas, which are unknown if
+/// either is.
+pub fn add_known(a: Option<u64>, b: Option<u64>) -> Option<u64> {
+    a.zip(b).map(|(a, b)| a.checked_add(b).expect("overflow"))
+}
+
+/// `add_known` for value transferred.
+pub fn add_known_value(a: Option<u128>, b: Option<u128>) -> Option<u128> {
+    a.zip(b).map(|(a, b)| a.checked_add(b).expect("overflow"))
+}
+
+#[async_trait]
 pub trait Db: Send + Sync + 'static {
-    fn store_block(&self, block: Block) -> Result<()>;
-    fn load_block(&self, chain: Chain, block_number: u64) -> Result<Option<Block>>;
+    async fn store_block(&self, block: Block) -> Result<()>;
+    /// Stores many blocks of one chain, which backends may do more
+    /// efficiently than one at a time.
+    async fn store_blocks(&self, chain: Chain, blocks: Vec<Block>) -> Result<()> {
+        for block in blocks {
+            assert_eq!(block.chain, chain);
+            self.store_block(block).await?;
+        }
+        Ok(())
+    }
+    async fn load_block(&self, chain: Chain, block_number: u64) -> Result<Option<Block>>;
+
+    async fn store_highest_block_number(&self, chain: Chain, block_number: u64) -> Result<()>;
+    async fn load_highest_block_number(&self, chain: Chain) -> Result<Option<u64>>;
 
-    fn store_highest_block_number(&self, chain: Chain, block_number: u64) -> Result<()>;
-    fn load_highest_block_number(&self, chain: Chain) -> Result<Option<u64>>;
+    /// Removing a block that isn't stored succeeds, since another instance
+    /// sharing the db may have removed it first.
+    async fn remove_block(&self, chain: Chain, block: u64) -> Result<()>;
 
-    fn store_tps(&self, chain: Chain, tps: f64) -> Result<()>;
-    fn load_tps(&self, chain: Chain) -> Result<Option<f64>>;
+    /// All stored block numbers for a chain, in ascending order.
+    ///
+    /// This touches every stored block, so isn't for routine use.
+    async fn load_block_numbers(&self, chain: Chain) -> Result<Vec<u64>>;
+
+    /// Moves a block out of the way without deleting it, for inspecting bad
+    /// data. The block may be unreadable.
+    async fn quarantine_block(&self, chain: Chain, block: u64) -> Result<()> {
+        let _ = (chain, block);
+        bail!("quarantining blocks is not supported by this db")
+    }
 
-    fn remove_block(&self, chain: Chain, block: u64) -> Result<()>;
+    /// Stores a record for a chain, as JSON, under `key`. Each backend
+    /// implements this and `load_record_json`, and everything stored whole
+    /// per chain is built on them through `DbRecords`.
+    async fn store_record_json(&self, chain: Chain, key: &str, json: Vec<u8>) -> Result<()>;
+    async fn load_record_json(&self, chain: Chain, key: &str) -> Result<Option<Vec<u8>>>;
+
+    async fn store_tps(&self, chain: Chain, window: TpsWindow, tps: f64) -> Result<()> {
+        self.store_record(chain, &tps_file(window), &tps).await
+    }
+    async fn load_tps(&self, chain: Chain, window: TpsWindow) -> Result<Option<f64>> {
+        self.load_record(chain, &tps_file(window)).await
+    }
 
-    fn store_calculation_log(&self, chain: Chain, log: &CalculationLog) -> Result<()>;
+    async fn store_gas_per_second(&self, chain: Chain, window: TpsWindow, gas: f64) -> Result<()> {
+        self.store_record(chain, &gas_per_second_file(window), &gas)
+            .await
+    }
+    async fn load_gas_per_second(&self, chain: Chain, window: TpsWindow) -> Result<Option<f64>> {
+        self.load_record(chain, &gas_per_second_file(window)).await
+    }
+
+    async fn store_calculation_log(&self, chain: Chain, log: &CalculationLog) -> Result<()> {
+        self.store_record(chain, CALCULATION_LOG, log).await
+    }
+    async fn load_calculation_log(&self, chain: Chain) -> Result<Option<CalculationLog>> {
+        self.load_record(chain, CALCULATION_LOG).await
+    }
+
+    async fn store_timestamp_index(&self, chain: Chain, index: &TimestampIndex) -> Result<()> {
+        self.store_record(chain, TIMESTAMP_INDEX, index).await
+    }
+    async fn load_timestamp_index(&self, chain: Chain) -> Result<Option<TimestampIndex>> {
+        self.load_record(chain, TIMESTAMP_INDEX).await
+    }
+
+    async fn store_peak_tps(&self, chain: Chain, peak_tps: &PeakTps) -> Result<()> {
+        self.store_record(chain, PEAK_TPS, peak_tps).await
+    }
+    async fn load_peak_tps(&self, chain: Chain) -> Result<Option<PeakTps>> {
+        self.load_record(chain, PEAK_TPS).await
+    }
+
+    async fn store_chain_stats(&self, chain: Chain, stats: &ChainStats) -> Result<()> {
+        self.store_record(chain, CHAIN_STATS, stats).await
+    }
+    async fn load_chain_stats(&self, chain: Chain) -> Result<Option<ChainStats>> {
+        self.load_record(chain, CHAIN_STATS).await
+    }
 
-    fn load_calculation_log(&self, chain: Chain) -> Result<Option<CalculationLog>>;
+    async fn store_daily_tps(&self, chain: Chain, daily_tps: &DailyTps) -> Result<()> {
+        self.store_record(chain, DAILY_TPS, daily_tps).await
+    }
+    async fn load_daily_tps(&self, chain: Chain) -> Result<Option<DailyTps>> {
+        self.load_record(chain, DAILY_TPS).await
+    }
+
+    async fn store_hourly_tps(&self, chain: Chain, hourly_tps: &HourlyTps) -> Result<()> {
+        self.store_record(chain, HOURLY_TPS, hourly_tps).await
+    }
+    async fn load_hourly_tps(&self, chain: Chain) -> Result<Option<HourlyTps>> {
+        self.load_record(chain, HOURLY_TPS).await
+    }
+
+    async fn store_finality_lag(&self, chain: Chain, finality_lag: &FinalityLag) -> Result<()> {
+        self.store_record(chain, FINALITY_LAG, finality_lag).await
+    }
+    async fn load_finality_lag(&self, chain: Chain) -> Result<Option<FinalityLag>> {
+        self.load_record(chain, FINALITY_LAG).await
+    }
+
+    async fn store_chain_health(&self, chain: Chain, chain_health: &ChainHealth) -> Result<()> {
+        self.store_record(chain, CHAIN_HEALTH, chain_health).await
+    }
+    async fn load_chain_health(&self, chain: Chain) -> Result<Option<ChainHealth>> {
+        self.load_record(chain, CHAIN_HEALTH).await
+    }
+
+    async fn store_native_tps(&self, chain: Chain, native_tps: &NativeTps) -> Result<()> {
+        self.store_record(chain, NATIVE_TPS, native_tps).await
+    }
+    async fn load_native_tps(&self, chain: Chain) -> Result<Option<NativeTps>> {
+        self.load_record(chain, NATIVE_TPS).await
+    }
+
+    async fn store_provider_check(
+        &self,
+        chain: Chain,
+        provider_check: &ProviderCheck,
+    ) -> Result<()> {
+        self.store_record(chain, PROVIDER_CHECK, provider_check)
+            .await
+    }
+    async fn load_provider_check(&self, chain: Chain) -> Result<Option<ProviderCheck>> {
+        self.load_record(chain, PROVIDER_CHECK).await
+    }
+
+    async fn store_endpoint_health(
+        &self,
+        chain: Chain,
+        endpoint_health: &EndpointHealth,
+    ) -> Result<()> {
+        self.store_record(chain, ENDPOINT_HEALTH, endpoint_health)
+            .await
+    }
+    async fn load_endpoint_health(&self, chain: Chain) -> Result<Option<EndpointHealth>> {
+        self.load_record(chain, ENDPOINT_HEALTH).await
+    }
+
+    async fn store_import_checkpoint(
+        &self,
+        chain: Chain,
+        import_checkpoint: &ImportCheckpoint,
+    ) -> Result<()> {
+        self.store_record(chain, IMPORT_CHECKPOINT, import_checkpoint)
+            .await
+    }
+    async fn load_import_checkpoint(&self, chain: Chain) -> Result<Option<ImportCheckpoint>> {
+        self.load_record(chain, IMPORT_CHECKPOINT).await
+    }
+
+    async fn store_import_pause(&self, chain: Chain, import_pause: &ImportPause) -> Result<()> {
+        self.store_record(chain, IMPORT_PAUSE, import_pause).await
+    }
+    async fn load_import_pause(&self, chain: Chain) -> Result<Option<ImportPause>> {
+        self.load_record(chain, IMPORT_PAUSE).await
+    }
+
+    async fn store_import_progress(
+        &self,
+        chain: Chain,
+        import_progress: &ImportProgress,
+    ) -> Result<()> {
+        self.store_record(chain, IMPORT_PROGRESS, import_progress)
+            .await
+    }
+    async fn load_import_progress(&self, chain: Chain) -> Result<Option<ImportProgress>> {
+        self.load_record(chain, IMPORT_PROGRESS).await
+    }
+
+    async fn store_lease(&self, chain: Chain, kind: LeaseKind, lease: &ChainLease) -> Result<()> {
+        self.store_record(chain, &lease_file(kind), lease).await
+    }
+    async fn load_lease(&self, chain: Chain, kind: LeaseKind) -> Result<Option<ChainLease>> {
+        self.load_record(chain, &lease_file(kind)).await
+    }
+
+    /// Jobs are kept apart by lease kind, as they run at once.
+    async fn store_job_status(
+        &self,
+        chain: Chain,
+        kind: LeaseKind,
+        status: &JobStatus,
+    ) -> Result<()> {
+        self.store_record(chain, &job_status_file(kind), status)
+            .await
+    }
+    async fn load_job_status(&self, chain: Chain, kind: LeaseKind) -> Result<Option<JobStatus>> {
+        self.load_record(chain, &job_status_file(kind)).await
+    }
+
+    /// The bytes stored for a chain, if the db can tell cheaply enough.
+    async fn chain_size(&self, chain: Chain) -> Result<Option<u64>> {
+        let _ = chain;
+        Ok(None)
+    }
+
+    /// Removes everything stored for a chain: its blocks, TPS, indexes,
+    /// history and import state.
+    async fn remove_chain(&self, chain: Chain) -> Result<()>;
+
+    async fn append_tps_history(&self, chain: Chain, point: &TpsPoint) -> Result<()>;
+    /// The chain's TPS history, oldest first.
+    async fn load_tps_history(&self, chain: Chain) -> Result<Vec<TpsPoint>>;
+    async fn remove_tps_history_before(&self, chain: Chain, timestamp: u64) -> Result<()>;
+
+    async fn append_aggregate_tps(&self, point: &AggregateTpsPoint) -> Result<()>;
+    /// The TPS of all chains, oldest first.
+    async fn load_aggregate_tps_history(&self) -> Result<Vec<AggregateTpsPoint>>;
+    async fn remove_aggregate_tps_before(&self, timestamp: u64) -> Result<()>;
+
+    /// Applies related writes to one chain, such as new blocks and the new
+    /// highest block number, so that after a crash and `recover` either all
+    /// or none of them are visible.
+    ///
+    /// Backends without a way to do this apply the writes in order.
+    async fn store_atomically(&self, chain: Chain, writes: Vec<DbWrite>) -> Result<()> {
+        for write in writes {
+            store_write(self, chain, write).await?;
+        }
+        Ok(())
+    }
+
+    /// Completes any `store_atomically` calls that were interrupted.
+    async fn recover(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// The storage format version this implementation reads and writes.
+    fn schema_version(&self) -> u32;
+
+    /// The storage format version of the stored data, `None` if nothing has
+    /// been stored yet.
+    async fn load_schema_version(&self) -> Result<Option<u32>>;
+    async fn store_schema_version(&self, version: u32) -> Result<()>;
+
+    /// Upgrades data stored in older formats to `schema_version`.
+    async fn migrate(&self) -> Result<()> {
+        self.store_schema_version(self.schema_version()).await
+    }
+
+    /// Persists any writes the implementation has buffered.
+    ///
+    /// `JsonDb` writes through, but remote backends may batch writes.
+    async fn flush(&self) -> Result<()> {
+        Ok(())
+    }
 }
 
-pub struct JsonDb;
+/// Applies one of the writes of `store_atomically` on its own.
+pub async fn store_write<D: Db + ?Sized>(db: &D, chain: Chain, write: DbWrite) -> Result<()> {
+    match write {
+        DbWrite::Block(block) => {
+            assert_eq!(block.chain, chain);
+            db.store_block(*block).await
+        }
+        DbWrite::HighestBlockNumber(block_number) => {
+            db.store_highest_block_number(chain, block_number).await
+        }
+        DbWrite::Tps(window, tps) => db.store_tps(chain, window, tps).await,
+        DbWrite::CalculationLog(log) => db.store_calculation_log(chain, &log).await,
+        DbWrite::TimestampIndex(index) => db.store_timestamp_index(chain, &index).await,
+        DbWrite::ImportCheckpoint(checkpoint) => {
+            db.store_import_checkpoint(chain, &checkpoint).await
+        }
+    }
+}
+
+/// Typed records, stored per chain under a key, for any `Db`.
+///
+/// Kept apart from `Db`, whose methods can't be generic as it's used as
+/// `dyn Db`.
+#[async_trait]
+pub trait DbRecords {
+    async fn store_record<T>(&self, chain: Chain, key: &str, record: &T) -> Result<()>
+    where
+        T: Serialize + Sync + ?Sized;
+    async fn load_record<T>(&self, chain: Chain, key: &str) -> Result<Option<T>>
+    where
+        T: DeserializeOwned;
+}
+
+#[async_trait]
+impl<D: Db + ?Sized> DbRecords for D {
+    async fn store_record<T>(&self, chain: Chain, key: &str, record: &T) -> Result<()>
+    where
+        T: Serialize + Sync + ?Sized,
+    {
+        self.store_record_json(chain, key, serde_json::to_vec(record)?)
+            .await
+    }
+
+    async fn load_record<T>(&self, chain: Chain, key: &str) -> Result<Option<T>>
+    where
+        T: DeserializeOwned,
+    {
+        match self.load_record_json(chain, key).await? {
+            Some(json) => Ok(Some(serde_json::from_slice(&json)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// A `Db` storing one JSON file per block and per piece of metadata, under a
+/// directory, `db` by default.
+///
+/// Blocks are stored in directories of `BLOCKS_PER_SHARD` blocks, as
+/// `db/<chain>/blocks/<shard>/<number>`. Blocks from the older flat layout,
+/// `db/<chain>/blocks/<number>`, are moved into their shard the first time
+/// they are loaded.
+///
+/// Block files are zstd-compressed. Files written before compression was
+/// introduced are plain JSON, and are still read transparently.
+///
+/// `store_atomically` first writes all the changes to a journal file,
+/// `db/<chain>/meta/journal`, which `recover` replays if the changes weren't
+/// all applied.
+///
+/// File access is synchronous, so every method runs on tokio's blocking
+/// thread pool.
+pub struct JsonDb {
+    dir: Arc<str>,
+}
+
+impl JsonDb {
+    /// A db in `dir`, which is created once something is stored.
+    pub fn new(dir: &str) -> JsonDb {
+        JsonDb {
+            dir: Arc::from(dir.trim_end_matches('/')),
+        }
+    }
+
+    pub fn dir(&self) -> &str {
+        &self.dir
+    }
+}
+
+impl Default for JsonDb {
+    fn default() -> JsonDb {
+        JsonDb::new(JSON_DB_DIR)
+    }
+}
 
 pub static JSON_DB_DIR: &str = "db";
 pub static DB_DIR_BLOCKS: &str = "blocks";
 pub static DB_DIR_META: &str = "meta";
+pub static DB_DIR_QUARANTINE: &str = "quarantine";
 pub static HIGHEST_BLOCK_NUMBER: &str = "highest_block_number";
 pub static TRANSACTIONS_PER_SECOND: &str = "tps";
+pub static GAS_PER_SECOND: &str = "gas_per_second";
 pub static CALCULATION_LOG: &str = "calculation_log";
+pub static TIMESTAMP_INDEX: &str = "timestamp_index";
+pub static PEAK_TPS: &str = "peak_tps";
+pub static CHAIN_STATS: &str = "chain_stats";
+pub static TPS_HISTORY: &str = "tps_history";
+pub static DAILY_TPS: &str = "daily_tps";
+pub static HOURLY_TPS: &str = "hourly_tps";
+pub static AGGREGATE_TPS: &str = "aggregate_tps";
+pub static FINALITY_LAG: &str = "finality_lag";
+pub static CHAIN_HEALTH: &str = "chain_health";
+pub static NATIVE_TPS: &str = "native_tps";
+pub static PROVIDER_CHECK: &str = "provider_check";
+pub static ENDPOINT_HEALTH: &str = "endpoint_health";
+pub static IMPORT_CHECKPOINT: &str = "import_checkpoint";
+pub static IMPORT_PAUSE: &str = "import_pause";
+pub static IMPORT_PROGRESS: &str = "import_progress";
+pub static LEASE: &str = "lease";
+pub static JOB_STATUS: &str = "job_status";
+pub static SCHEMA_VERSION: &str = "schema_version";
+pub static JOURNAL: &str = "journal";
+
+/// Version 1 is the original flat, uncompressed layout, which had no version
+/// file. Version 2 shards and compresses blocks.
+pub const JSON_DB_SCHEMA_VERSION: u32 = 2;
+
+/// The number of blocks per block directory. Directories with millions of
+/// entries make filesystem operations and backups painfully slow.
+pub const BLOCKS_PER_SHARD: u64 = 10_000;
+
+/// Every zstd frame starts with these bytes, while JSON never does.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+const ZSTD_LEVEL: i32 = 3;
 
+#[async_trait]
 impl Db for JsonDb {
-    fn store_block(&self, block: Block) -> Result<()> {
-        write_json_db(
-            &format!("{}", block.chain),
-            DB_DIR_BLOCKS,
-            &format!("{}", block.block_number),
-            &block,
-        )
+    async fn store_block(&self, block: Block) -> Result<()> {
+        let db_dir = self.dir.clone();
+        blocking(move || {
+            let mut unsynced_dirs = UnsyncedDirs::default();
+            write_block(&db_dir, &block, &mut unsynced_dirs)?;
+            unsynced_dirs.sync()
+        })
+        .await
     }
 
-    fn load_block(&self, chain: Chain, block_number: u64) -> Result<Option<Block>> {
-        read_json_db(
-            &format!("{}", chain),
-            DB_DIR_BLOCKS,
-            &format!("{}", block_number),
-        )
+    async fn store_blocks(&self, chain: Chain, blocks: Vec<Block>) -> Result<()> {
+        let db_dir = self.dir.clone();
+        blocking(move || {
+            let mut unsynced_dirs = UnsyncedDirs::default();
+            for block in &blocks {
+                assert_eq!(block.chain, chain);
+                write_block(&db_dir, block, &mut unsynced_dirs)?;
+            }
+            unsynced_dirs.sync()
+        })
+        .await
     }
 
-    fn store_highest_block_number(&self, chain: Chain, block_number: u64) -> Result<()> {
-        write_json_db(
-            &format!("{}", chain),
-            DB_DIR_META,
-            HIGHEST_BLOCK_NUMBER,
-            &block_number,
-        )
+    async fn load_block(&self, chain: Chain, block_number: u64) -> Result<Option<Block>> {
+        let db_dir = self.dir.clone();
+        blocking(move || {
+            let chain_id = format!("{}", chain);
+            let shard_dir = block_shard_dir(block_number);
+            let file = format!("{}", block_number);
+
+            let block = read_json_db(&db_dir, &chain_id, &shard_dir, &file)?;
+            if block.is_none() && migrate_legacy_block(&db_dir, chain, block_number)? {
+                read_json_db(&db_dir, &chain_id, &shard_dir, &file)
+            } else {
+                Ok(block)
+            }
+        })
+        .await
     }
 
-    fn load_highest_block_number(&self, chain: Chain) -> Result<Option<u64>> {
-        read_json_db(&format!("{}", chain), DB_DIR_META, HIGHEST_BLOCK_NUMBER)
+    async fn store_highest_block_number(&self, chain: Chain, block_number: u64) -> Result<()> {
+        let db_dir = self.dir.clone();
+        blocking(move || {
+            write_json_db(
+                &db_dir,
+                &format!("{}", chain),
+                DB_DIR_META,
+                HIGHEST_BLOCK_NUMBER,
+                &block_number,
+                false,
+            )
+        })
+        .await
     }
 
-    fn store_tps(&self, chain: Chain, tps: f64) -> Result<()> {
-        write_json_db(
-            &format!("{}", chain),
-            DB_DIR_META,
-            TRANSACTIONS_PER_SECOND,
-            &tps,
+    async fn load_highest_block_number(&self, chain: Chain) -> Result<Option<u64>> {
+        let db_dir = self.dir.clone();
+        blocking(move || {
+            read_json_db(
+                &db_dir,
+                &format!("{}", chain),
+                DB_DIR_META,
+                HIGHEST_BLOCK_NUMBER,
+            )
+        })
+        .await
+    }
+
+    async fn remove_block(&self, chain: Chain, block: u64) -> Result<()> {
+        let db_dir = self.dir.clone();
+        blocking(move || {
+            let file_path = format!("{}/{}/{}/{}", db_dir, chain, block_shard_dir(block), block);
+            remove_file_if_exists(&file_path)?;
+            remove_legacy_block(&db_dir, chain, block)?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn load_block_numbers(&self, chain: Chain) -> Result<Vec<u64>> {
+        let db_dir = self.dir.clone();
+        blocking(move || {
+            let mut block_numbers: Vec<u64> = list_block_files(&db_dir, chain)?
+                .into_iter()
+                .map(|(block_number, _)| block_number)
+                .collect();
+            // A block may be in both layouts
+            block_numbers.dedup();
+            Ok(block_numbers)
+        })
+        .await
+    }
+
+    /// Moves the block's files to `db/<chain>/quarantine`.
+    async fn quarantine_block(&self, chain: Chain, block: u64) -> Result<()> {
+        let db_dir = self.dir.clone();
+        blocking(move || {
+            let quarantine_dir = format!("{}/{}/{}", db_dir, chain, DB_DIR_QUARANTINE);
+            fs::create_dir_all(&quarantine_dir)?;
+
+            let sharded_path = format!("{}/{}/{}/{}", db_dir, chain, block_shard_dir(block), block);
+            let legacy_path = legacy_block_path(&db_dir, chain, block);
+
+            let mut moved = false;
+            for (path, suffix) in [(sharded_path, ""), (legacy_path, ".legacy")] {
+                if suffix == ".legacy" && !is_file(&path) {
+                    // The shard directory, not a block
+                    continue;
+                }
+                let quarantine_path = format!("{}/{}{}", quarantine_dir, block, suffix);
+                match fs::rename(&path, &quarantine_path) {
+                    Ok(()) => moved = true,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(e) => bail!(e),
+                }
+            }
+
+            if !moved {
+                bail!("block {} for chain {} not found", block, chain);
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn store_record_json(&self, chain: Chain, key: &str, json: Vec<u8>) -> Result<()> {
+        let db_dir = self.dir.clone();
+        let key = key.to_string();
+        blocking(move || {
+            let file_dir = format!("{}/{}/{}", db_dir, chain, DB_DIR_META);
+            let file_path = format!("{}/{}", file_dir, key);
+            write_file(&file_dir, &file_path, |mut file| Ok(file.write_all(&json)?))
+        })
+        .await
+    }
+
+    async fn load_record_json(&self, chain: Chain, key: &str) -> Result<Option<Vec<u8>>> {
+        let db_dir = self.dir.clone();
+        let key = key.to_string();
+        blocking(move || read_file(format!("{}/{}/{}/{}", db_dir, chain, DB_DIR_META, key))).await
+    }
+
+    async fn chain_size(&self, chain: Chain) -> Result<Option<u64>> {
+        let db_dir = self.dir.clone();
+        blocking(move || dir_size(Path::new(&format!("{}/{}", db_dir, chain))).map(Some)).await
+    }
+
+    async fn remove_chain(&self, chain: Chain) -> Result<()> {
+        let db_dir = self.dir.clone();
+        blocking(
+            move || match fs::remove_dir_all(format!("{}/{}", db_dir, chain)) {
+                Ok(()) => Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+                Err(e) => bail!(e),
+            },
         )
+        .await
     }
 
-    fn load_tps(&self, chain: Chain) -> Result<Option<f64>> {
-        read_json_db(&format!("{}", chain), DB_DIR_META, TRANSACTIONS_PER_SECOND)
+    /// History is stored as JSON lines, so appending doesn't rewrite it.
+    async fn append_tps_history(&self, chain: Chain, point: &TpsPoint) -> Result<()> {
+        let point = point.clone();
+        let db_dir = self.dir.clone();
+        blocking(move || {
+            let meta_dir = format!("{}/{}/{}", db_dir, chain, DB_DIR_META);
+            append_json_line(&meta_dir, &tps_history_path(&db_dir, chain), &point)
+        })
+        .await
     }
 
-    fn remove_block(&self, chain: Chain, block: u64) -> Result<()> {
-        let file_path = format!("{}/{}/{}/{}", JSON_DB_DIR, chain, DB_DIR_BLOCKS, block);
-        fs::remove_file(file_path)?;
-        Ok(())
+    async fn load_tps_history(&self, chain: Chain) -> Result<Vec<TpsPoint>> {
+        let db_dir = self.dir.clone();
+        blocking(move || read_json_lines(&tps_history_path(&db_dir, chain))).await
+    }
+
+    async fn remove_tps_history_before(&self, chain: Chain, timestamp: u64) -> Result<()> {
+        let db_dir = self.dir.clone();
+        blocking(move || {
+            let history: Vec<TpsPoint> = read_json_lines(&tps_history_path(&db_dir, chain))?;
+            if history.iter().all(|point| point.timestamp >= timestamp) {
+                return Ok(());
+            }
+
+            let history = history.iter().filter(|point| point.timestamp >= timestamp);
+            rewrite_json_lines(&tps_history_path(&db_dir, chain), history)
+        })
+        .await
+    }
+
+    async fn append_aggregate_tps(&self, point: &AggregateTpsPoint) -> Result<()> {
+        let point = point.clone();
+        let db_dir = self.dir.clone();
+        blocking(move || append_json_line(&db_dir, &aggregate_tps_path(&db_dir), &point)).await
+    }
+
+    async fn load_aggregate_tps_history(&self) -> Result<Vec<AggregateTpsPoint>> {
+        let db_dir = self.dir.clone();
+        blocking(move || read_json_lines(&aggregate_tps_path(&db_dir))).await
+    }
+
+    async fn remove_aggregate_tps_before(&self, timestamp: u64) -> Result<()> {
+        let db_dir = self.dir.clone();
+        blocking(move || {
+            let history: Vec<AggregateTpsPoint> = read_json_lines(&aggregate_tps_path(&db_dir))?;
+            if history.iter().all(|point| point.timestamp >= timestamp) {
+                return Ok(());
+            }
+
+            let history = history.iter().filter(|point| point.timestamp >= timestamp);
+            rewrite_json_lines(&aggregate_tps_path(&db_dir), history)
+        })
+        .await
+    }
+
+    async fn store_atomically(&self, chain: Chain, writes: Vec<DbWrite>) -> Result<()> {
+        let db_dir = self.dir.clone();
+        blocking(move || {
+            // Once the journal is written the changes are as good as applied
+            write_json_db(
+                &db_dir,
+                &format!("{}", chain),
+                DB_DIR_META,
+                JOURNAL,
+                &writes,
+                true,
+            )?;
+            apply_writes(&db_dir, chain, &writes)?;
+            remove_file_if_exists(&journal_path(&db_dir, chain))?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn recover(&self) -> Result<()> {
+        let db_dir = self.dir.clone();
+        blocking(move || {
+            for chain in stored_chains(&db_dir)? {
+                let writes: Option<Vec<DbWrite>> =
+                    read_json_db(&db_dir, &format!("{}", chain), DB_DIR_META, JOURNAL)?;
+                if let Some(writes) = writes {
+                    apply_writes(&db_dir, chain, &writes)?;
+                    remove_file_if_exists(&journal_path(&db_dir, chain))?;
+                }
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    fn schema_version(&self) -> u32 {
+        JSON_DB_SCHEMA_VERSION
+    }
+
+    async fn load_schema_version(&self) -> Result<Option<u32>> {
+        let db_dir = self.dir.clone();
+        blocking(move || {
+            let version = read_json_file(format!("{}/{}", db_dir, SCHEMA_VERSION))?;
+            if version.is_none() && Path::new(&*db_dir).exists() {
+                // Written before the version file existed
+                Ok(Some(1))
+            } else {
+                Ok(version)
+            }
+        })
+        .await
+    }
+
+    async fn store_schema_version(&self, version: u32) -> Result<()> {
+        let db_dir = self.dir.clone();
+        blocking(move || {
+            write_json_file(
+                &db_dir,
+                &format!("{}/{}", db_dir, SCHEMA_VERSION),
+                &version,
+                false,
+            )
+        })
+        .await
+    }
+
+    /// Rewrites every block that is still in the flat layout or uncompressed.
+    async fn migrate(&self) -> Result<()> {
+        let db_dir = self.dir.clone();
+        blocking(move || {
+            for chain in stored_chains(&db_dir)? {
+                let mut unsynced_dirs = UnsyncedDirs::default();
+                for (block_number, path) in list_block_files(&db_dir, chain)? {
+                    migrate_block_file(&db_dir, chain, block_number, &path, &mut unsynced_dirs)?;
+                }
+                unsynced_dirs.sync()?;
+            }
+            Ok(())
+        })
+        .await?;
+
+        self.store_schema_version(JSON_DB_SCHEMA_VERSION).await
+    }
+}
+
+fn apply_writes(db_dir: &str, chain: Chain, writes: &[DbWrite]) -> Result<()> {
+    let chain_id = format!("{}", chain);
+    let mut unsynced_dirs = UnsyncedDirs::default();
+    for write in writes {
+        if !matches!(write, DbWrite::Block(_)) {
+            // What's written next may refer to the blocks
+            unsynced_dirs.sync()?;
+        }
+        match write {
+            DbWrite::Block(block) => {
+                assert_eq!(block.chain, chain);
+                write_block(db_dir, block, &mut unsynced_dirs)?;
+            }
+            DbWrite::HighestBlockNumber(block_number) => {
+                write_json_db(
+                    db_dir,
+                    &chain_id,
+                    DB_DIR_META,
+                    HIGHEST_BLOCK_NUMBER,
+                    block_number,
+                    false,
+                )?;
+            }
+            DbWrite::Tps(window, tps) => {
+                write_json_db(
+                    db_dir,
+                    &chain_id,
+                    DB_DIR_META,
+                    &tps_file(*window),
+                    tps,
+                    false,
+                )?;
+            }
+            DbWrite::CalculationLog(log) => {
+                write_json_db(db_dir, &chain_id, DB_DIR_META, CALCULATION_LOG, log, false)?;
+            }
+            DbWrite::TimestampIndex(index) => {
+                write_json_db(
+                    db_dir,
+                    &chain_id,
+                    DB_DIR_META,
+                    TIMESTAMP_INDEX,
+                    index,
+                    false,
+                )?;
+            }
+            DbWrite::ImportCheckpoint(checkpoint) => {
+                write_json_db(
+                    db_dir,
+                    &chain_id,
+                    DB_DIR_META,
+                    IMPORT_CHECKPOINT,
+                    checkpoint,
+                    false,
+                )?;
+            }
+        }
+    }
+    unsynced_dirs.sync()
+}
+
+/// The metadata file for a TPS window. The weekly TPS was once the only one,
+/// so keeps its original name.
+pub fn tps_file(window: TpsWindow) -> String {
+    match window {
+        TpsWindow::Week => TRANSACTIONS_PER_SECOND.to_string(),
+        _ => format!("{}_{}", TRANSACTIONS_PER_SECOND, window),
+    }
+}
+
+pub fn gas_per_second_file(window: TpsWindow) -> String {
+    format!("{}_{}", GAS_PER_SECOND, window)
+}
+
+pub fn lease_file(kind: LeaseKind) -> String {
+    format!("{}_{}", LEASE, kind)
+}
+
+pub fn job_status_file(kind: LeaseKind) -> String {
+    format!("{}_{}", JOB_STATUS, kind)
+}
+
+fn tps_history_path(db_dir: &str, chain: Chain) -> String {
+    format!("{}/{}/{}/{}", db_dir, chain, DB_DIR_META, TPS_HISTORY)
+}
+
+fn aggregate_tps_path(db_dir: &str) -> String {
+    format!("{}/{}", db_dir, AGGREGATE_TPS)
+}
+
+/// Histories are stored as JSON lines, so appending doesn't rewrite them.
+fn append_json_line<T: Serialize>(dir: &str, path: &str, data: &T) -> Result<()> {
+    let mut line = serde_json::to_vec(data)?;
+    line.push(b'\n');
+    fs::create_dir_all(dir)?;
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(&line)?;
+    Ok(())
+}
+
+fn rewrite_json_lines<'a, T: Serialize + 'a>(
+    path: &str,
+    items: impl Iterator<Item = &'a T>,
+) -> Result<()> {
+    let temp_path = format!("{}.{}.temp", path, rand::random::<u32>());
+    let mut writer = BufWriter::new(File::create(&temp_path)?);
+    for item in items {
+        serde_json::to_writer(&mut writer, item)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+    drop(writer);
+    fs::rename(temp_path, path)?;
+    Ok(())
+}
+
+fn read_json_lines<T: DeserializeOwned>(path: &str) -> Result<Vec<T>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut items = vec![];
+    let mut lines = BufReader::new(file).lines().peekable();
+    while let Some(line) = lines.next() {
+        match serde_json::from_str(&line?) {
+            Ok(item) => items.push(item),
+            // A crash while appending can leave half a line at the end
+            Err(_) if lines.peek().is_none() => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(items)
+}
+
+fn journal_path(db_dir: &str, chain: Chain) -> String {
+    format!("{}/{}/{}/{}", db_dir, chain, DB_DIR_META, JOURNAL)
+}
+
+/// Writes a block, leaving its directory to be synced with the rest of the
+/// batch.
+fn write_block(db_dir: &str, block: &Block, unsynced_dirs: &mut UnsyncedDirs) -> Result<()> {
+    let shard_dir = create_shard_dir(db_dir, block.chain, block.block_number)?;
+    let file_path = format!("{}/{}", shard_dir, block.block_number);
+    replace_file(&shard_dir, &file_path, |file| write_json(file, block, true))?;
+    unsynced_dirs.0.insert(shard_dir);
+    // Don't let a stale copy in the flat layout be migrated later
+    remove_legacy_block(db_dir, block.chain, block.block_number)?;
+    Ok(())
+}
+
+/// The block directory, relative to the chain directory.
+fn block_shard_dir(block_number: u64) -> String {
+    let shard = block_number - block_number % BLOCKS_PER_SHARD;
+    format!("{}/{}", DB_DIR_BLOCKS, shard)
+}
+
+/// The first block of each shard has the same path as the shard, so this
+/// may be a directory.
+fn legacy_block_path(db_dir: &str, chain: Chain, block_number: u64) -> String {
+    format!("{}/{}/{}/{}", db_dir, chain, DB_DIR_BLOCKS, block_number)
+}
+
+fn remove_legacy_block(db_dir: &str, chain: Chain, block_number: u64) -> Result<bool> {
+    let legacy_path = legacy_block_path(db_dir, chain, block_number);
+    if !is_file(&legacy_path) {
+        return Ok(false);
+    }
+    remove_file_if_exists(&legacy_path)
+}
+
+/// Creates the shard directory for a block, first moving the shard's first
+/// block out of the way if it is still a file in the flat layout.
+fn create_shard_dir(db_dir: &str, chain: Chain, block_number: u64) -> Result<String> {
+    let shard_dir = format!("{}/{}/{}", db_dir, chain, block_shard_dir(block_number));
+    if is_file(&shard_dir) {
+        let shard_start = block_number - block_number % BLOCKS_PER_SHARD;
+        let temp_path = format!("{}.{}.temp", shard_dir, rand::random::<u32>());
+        match fs::rename(&shard_dir, &temp_path) {
+            Ok(()) => {
+                fs::create_dir_all(&shard_dir)?;
+                fs::rename(&temp_path, format!("{}/{}", shard_dir, shard_start))?;
+            }
+            // Moved concurrently
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => bail!(e),
+        }
     }
+    fs::create_dir_all(&shard_dir)?;
+    Ok(shard_dir)
+}
+
+/// Moves a block file from the flat layout into its shard, returning whether
+/// there was one to move.
+fn migrate_legacy_block(db_dir: &str, chain: Chain, block_number: u64) -> Result<bool> {
+    let legacy_path = legacy_block_path(db_dir, chain, block_number);
+    if !is_file(&legacy_path) {
+        return Ok(false);
+    }
+
+    let shard_dir = create_shard_dir(db_dir, chain, block_number)?;
+    let sharded_path = format!("{}/{}", shard_dir, block_number);
+    match fs::rename(&legacy_path, &sharded_path) {
+        Ok(()) => Ok(true),
+        // Migrated concurrently
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(true),
+        Err(e) => bail!(e),
+    }
+}
+
+/// The chains that have a directory in the db.
+fn stored_chains(db_dir: &str) -> Result<Vec<Chain>> {
+    let entries = match fs::read_dir(db_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => bail!(e),
+    };
+
+    let mut chains = vec![];
+    for entry in entries {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        if let Some(chain) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| Chain::try_from(name).ok())
+        {
+            chains.push(chain);
+        }
+    }
+
+    Ok(chains)
+}
+
+/// Lists the block files of a chain in both the sharded and the flat layout,
+/// ordered by block number.
+fn list_block_files(db_dir: &str, chain: Chain) -> Result<Vec<(u64, PathBuf)>> {
+    let blocks_dir = format!("{}/{}/{}", db_dir, chain, DB_DIR_BLOCKS);
+    let entries = match fs::read_dir(&blocks_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => bail!(e),
+    };
+
+    let mut files = vec![];
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            for shard_entry in fs::read_dir(entry.path())? {
+                let path = shard_entry?.path();
+                if let Some(block_number) = block_file_number(&path) {
+                    files.push((block_number, path));
+                }
+            }
+        } else if let Some(block_number) = block_file_number(&entry.path()) {
+            files.push((block_number, entry.path()));
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
 
-    fn store_calculation_log(&self, chain: Chain, log: &CalculationLog) -> Result<()> {
-        write_json_db(&format!("{}", chain), DB_DIR_META, CALCULATION_LOG, log)
+/// The total size of the files under `dir`, 0 if there is no `dir`.
+fn dir_size(dir: &Path) -> Result<u64> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => bail!(e),
+    };
+
+    let mut size = 0;
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            size += dir_size(&entry.path())?;
+        } else {
+            size += entry.metadata()?.len();
+        }
     }
+    Ok(size)
+}
 
-    fn load_calculation_log(&self, chain: Chain) -> Result<Option<CalculationLog>> {
-        read_json_db(&format!("{}", chain), DB_DIR_META, CALCULATION_LOG)
+/// Temp files and anything else that isn't named by a block number are
+/// ignored.
+fn block_file_number(path: &Path) -> Option<u64> {
+    path.file_name()?.to_str()?.parse().ok()
+}
+
+/// Rewrites a block file in the current format unless it already is,
+/// returning whether it was rewritten.
+fn migrate_block_file(
+    db_dir: &str,
+    chain: Chain,
+    block_number: u64,
+    path: &Path,
+    unsynced_dirs: &mut UnsyncedDirs,
+) -> Result<bool> {
+    let sharded_path = format!(
+        "{}/{}/{}/{}",
+        db_dir,
+        chain,
+        block_shard_dir(block_number),
+        block_number
+    );
+    if path == Path::new(&sharded_path) && is_compressed(path)? {
+        return Ok(false);
     }
+
+    // Removed since it was listed
+    let block: Block = match read_json_file(path)? {
+        Some(block) => block,
+        None => return Ok(false),
+    };
+    // This also removes the file if it was in the flat layout
+    write_block(db_dir, &block, unsynced_dirs)?;
+
+    Ok(true)
 }
 
-fn write_json_db<T>(chain: &str, sub_dir: &str, file: &str, data: &T) -> Result<()>
+fn is_compressed(path: &Path) -> Result<bool> {
+    let mut magic = [0; 4];
+    let mut file = File::open(path)?;
+    match file.read_exact(&mut magic) {
+        Ok(()) => Ok(magic == ZSTD_MAGIC),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => bail!(e),
+    }
+}
+
+fn is_file(path: &str) -> bool {
+    fs::metadata(path)
+        .map(|metadata| metadata.is_file())
+        .unwrap_or(false)
+}
+
+fn remove_file_if_exists(path: &str) -> Result<bool> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => bail!(e),
+    }
+}
+
+/// Runs synchronous file IO on the blocking thread pool.
+async fn blocking<T, F>(f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    task::spawn_blocking(f).await?
+}
+
+fn write_json_db<T>(
+    db_dir: &str,
+    chain: &str,
+    sub_dir: &str,
+    file: &str,
+    data: &T,
+    compressed: bool,
+) -> Result<()>
 where
     T: Serialize + ?Sized,
 {
-    let file_dir = format!("{}/{}/{}", JSON_DB_DIR, &chain, &sub_dir);
-    fs::create_dir_all(&file_dir)?;
+    let file_dir = format!("{}/{}/{}", db_dir, &chain, &sub_dir);
+    let file_path = format!("{}/{}/{}/{}", db_dir, &chain, &sub_dir, &file);
+    write_json_file(&file_dir, &file_path, data, compressed)
+}
+
+fn write_json_file<T>(file_dir: &str, file_path: &str, data: &T, compressed: bool) -> Result<()>
+where
+    T: Serialize + ?Sized,
+{
+    write_file(file_dir, file_path, |file| {
+        write_json(file, data, compressed)
+    })
+}
+
+/// Writes to a temp file and renames it over `file_path`, syncing both so the
+/// file is either entirely old or entirely new after a crash. The journal
+/// relies on this.
+fn write_file<F>(file_dir: &str, file_path: &str, write: F) -> Result<()>
+where
+    F: FnOnce(&File) -> Result<()>,
+{
+    replace_file(file_dir, file_path, write)?;
+    // Persists the rename
+    sync_dir(file_dir)
+}
+
+/// `write_file` without syncing the directory, so a batch of files can sync
+/// each directory once instead of once per file.
+fn replace_file<F>(file_dir: &str, file_path: &str, write: F) -> Result<()>
+where
+    F: FnOnce(&File) -> Result<()>,
+{
+    fs::create_dir_all(file_dir)?;
 
-    let file_path = format!("{}/{}/{}/{}", JSON_DB_DIR, &chain, &sub_dir, &file);
     let temp_file_path = format!("{}.{}.temp", &file_path, rand::random::<u32>());
 
     let file = File::create(&temp_file_path)?;
-    let mut writer = BufWriter::new(file);
 
-    match serde_json::to_writer(&mut writer, &data) {
+    match write(&file).and_then(|()| Ok(file.sync_all()?)) {
         Err(e) => {
             fs::remove_file(temp_file_path)?;
-            bail!(e)
+            Err(e)
         }
         Ok(()) => {
             fs::rename(temp_file_path, file_path)?;
@@ -139,12 +1995,66 @@ where
     }
 }
 
-fn read_json_db<T>(chain: &str, sub_dir: &str, file: &str) -> Result<Option<T>>
+fn sync_dir(dir: &str) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// Directories with renamed files that haven't been synced, so the renames
+/// could be lost in a crash.
+#[derive(Default)]
+struct UnsyncedDirs(BTreeSet<String>);
+
+impl UnsyncedDirs {
+    fn sync(&mut self) -> Result<()> {
+        for dir in std::mem::take(&mut self.0) {
+            sync_dir(&dir)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_json<T>(file: &File, data: &T, compressed: bool) -> Result<()>
+where
+    T: Serialize + ?Sized,
+{
+    let mut writer = BufWriter::new(file);
+
+    if compressed {
+        let mut encoder = zstd::Encoder::new(&mut writer, ZSTD_LEVEL)?;
+        serde_json::to_writer(&mut encoder, data)?;
+        encoder.finish()?;
+    } else {
+        serde_json::to_writer(&mut writer, data)?;
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+fn read_json_db<T>(db_dir: &str, chain: &str, sub_dir: &str, file: &str) -> Result<Option<T>>
 where
     T: DeserializeOwned,
 {
-    let path = format!("{}/{}/{}/{}", JSON_DB_DIR, &chain, &sub_dir, &file);
+    let path = format!("{}/{}/{}/{}", db_dir, &chain, &sub_dir, &file);
+    read_json_file(path)
+}
 
+/// The file's contents, decompressed if need be.
+fn read_file<P: AsRef<Path>>(path: P) -> Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(bytes) if bytes.starts_with(&ZSTD_MAGIC) => Ok(Some(zstd::decode_all(&bytes[..])?)),
+        Ok(bytes) => Ok(Some(bytes)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => bail!(e),
+    }
+}
+
+fn read_json_file<T, P>(path: P) -> Result<Option<T>>
+where
+    T: DeserializeOwned,
+    P: AsRef<Path>,
+{
     let file = File::open(path);
     match file {
         Err(e) => match e.kind() {
@@ -152,9 +2062,191 @@ where
             _ => bail!(e),
         },
         Ok(file) => {
-            let reader = BufReader::new(file);
-            let data = serde_json::from_reader(reader)?;
+            let mut reader = BufReader::new(file);
+            let data = if reader.fill_buf()?.starts_with(&ZSTD_MAGIC) {
+                serde_json::from_reader(zstd::Decoder::with_buffer(reader)?)?
+            } else {
+                serde_json::from_reader(reader)?
+            };
             Ok(Some(data))
         }
     }
 }
+
+#[cfg(test)]
+mod test_json_db {
+    use super::{
+        block_shard_dir, is_compressed, journal_path, legacy_block_path, read_json_file, tps_file,
+        write_json_db, Block, Db, DbRecords, DbWrite, ImportPause, JsonDb, TpsWindow, DB_DIR_META,
+        IMPORT_PAUSE, JOURNAL,
+    };
+    use crate::chain::Chain;
+    use crate::test_helpers::make_block;
+    use anyhow::Result;
+    use std::fs;
+    use tempdir::TempDir;
+
+    fn temp_db() -> Result<(TempDir, JsonDb)> {
+        let dir = TempDir::new("json_db")?;
+        let db = JsonDb::new(dir.path().to_str().expect("path"));
+        Ok((dir, db))
+    }
+
+    fn block_path(db: &JsonDb, block: &Block) -> String {
+        format!(
+            "{}/{}/{}/{}",
+            db.dir(),
+            block.chain,
+            block_shard_dir(block.block_number),
+            block.block_number
+        )
+    }
+
+    #[tokio::test]
+    async fn compress_new_blocks() -> Result<()> {
+        let (_dir, db) = temp_db()?;
+        let block = make_block(Chain::Ethereum, 15_000_001);
+        db.store_block(block.clone()).await?;
+
+        let path = block_path(&db, &block);
+        assert!(is_compressed(path.as_ref())?);
+        assert_eq!(read_json_file::<Block, _>(&path)?, Some(block.clone()));
+        assert_eq!(
+            db.load_block(Chain::Ethereum, 15_000_001).await?,
+            Some(block)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn load_uncompressed_files() -> Result<()> {
+        let (_dir, db) = temp_db()?;
+        let block = make_block(Chain::Ethereum, 15_000_001);
+
+        // As written before compression
+        let path = block_path(&db, &block);
+        fs::create_dir_all(path.rsplit_once('/').expect("dir").0)?;
+        fs::write(&path, serde_json::to_vec(&block)?)?;
+        let meta_dir = format!("{}/{}/{}", db.dir(), Chain::Ethereum, DB_DIR_META);
+        fs::create_dir_all(&meta_dir)?;
+        fs::write(
+            format!("{}/{}", meta_dir, tps_file(TpsWindow::Week)),
+            "12.5",
+        )?;
+
+        assert!(!is_compressed(path.as_ref())?);
+        assert_eq!(
+            db.load_block(Chain::Ethereum, 15_000_001).await?,
+            Some(block)
+        );
+        assert_eq!(
+            db.load_tps(Chain::Ethereum, TpsWindow::Week).await?,
+            Some(12.5)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn migrate_flat_layout_into_shards() -> Result<()> {
+        let (_dir, db) = temp_db()?;
+        let chain = Chain::Ethereum;
+        // The first block of a shard has the same path as the shard
+        let blocks: Vec<Block> = [5, 20_000, 20_001]
+            .into_iter()
+            .map(|block_number| make_block(chain, block_number))
+            .collect();
+
+        // As written before sharding and compression
+        fs::create_dir_all(format!("{}/{}/blocks", db.dir(), chain))?;
+        for block in &blocks {
+            let path = legacy_block_path(db.dir(), chain, block.block_number);
+            fs::write(path, serde_json::to_vec(block)?)?;
+        }
+
+        db.migrate().await?;
+
+        for block in &blocks {
+            let path = block_path(&db, block);
+            assert!(is_compressed(path.as_ref())?);
+            assert_eq!(
+                db.load_block(chain, block.block_number).await?.as_ref(),
+                Some(block)
+            );
+        }
+        assert!(fs::metadata(legacy_block_path(db.dir(), chain, 5)).is_err());
+        assert!(fs::metadata(legacy_block_path(db.dir(), chain, 20_000))?.is_dir());
+        assert_eq!(db.load_block_numbers(chain).await?, vec![5, 20_000, 20_001]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn store_and_remove_the_first_block_of_a_shard() -> Result<()> {
+        let (_dir, db) = temp_db()?;
+        let chain = Chain::Ethereum;
+        db.store_block(make_block(chain, 20_001)).await?;
+        db.store_block(make_block(chain, 20_000)).await?;
+        assert_eq!(db.load_block_numbers(chain).await?, vec![20_000, 20_001]);
+
+        db.remove_block(chain, 20_000).await?;
+        assert_eq!(db.load_block(chain, 20_000).await?, None);
+        assert_eq!(db.load_block_numbers(chain).await?, vec![20_001]);
+
+        // As when another instance removed it first
+        db.remove_block(chain, 20_000).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn recover_from_an_unapplied_journal() -> Result<()> {
+        let (_dir, db) = temp_db()?;
+        let chain = Chain::Ethereum;
+        let block = make_block(chain, 100);
+        let writes = vec![
+            DbWrite::Block(Box::new(block.clone())),
+            DbWrite::HighestBlockNumber(100),
+            DbWrite::Tps(TpsWindow::Week, 15.0),
+        ];
+
+        // As if the importer stopped after writing the journal
+        write_json_db(db.dir(), "ethereum", DB_DIR_META, JOURNAL, &writes, true)?;
+        assert_eq!(db.load_block(chain, 100).await?, None);
+
+        db.recover().await?;
+        assert_eq!(db.load_block(chain, 100).await?, Some(block));
+        assert_eq!(db.load_highest_block_number(chain).await?, Some(100));
+        assert_eq!(db.load_tps(chain, TpsWindow::Week).await?, Some(15.0));
+        assert!(fs::metadata(journal_path(db.dir(), chain)).is_err());
+
+        // Nothing left to recover
+        db.recover().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn store_records_as_meta_files() -> Result<()> {
+        let (_dir, db) = temp_db()?;
+        let chain = Chain::Ethereum;
+        assert!(db.load_import_pause(chain).await?.is_none());
+
+        let pause = ImportPause {
+            paused: true,
+            reason: Some("rate limited".to_string()),
+            changed: chrono::Utc::now(),
+            quarantined: false,
+        };
+        db.store_import_pause(chain, &pause).await?;
+
+        let path = format!("{}/{}/{}/{}", db.dir(), chain, DB_DIR_META, IMPORT_PAUSE);
+        let stored: ImportPause = read_json_file(&path)?.expect("import pause");
+        assert_eq!(stored.reason, pause.reason);
+        let loaded: ImportPause = db.load_record(chain, IMPORT_PAUSE).await?.expect("record");
+        assert_eq!(loaded.changed, pause.changed);
+
+        db.store_record(chain, "custom", &[1, 2, 3]).await?;
+        assert_eq!(
+            db.load_record::<Vec<u32>>(chain, "custom").await?,
+            Some(vec![1, 2, 3])
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,54 @@
+use crate::db::{Db, JsonDb, JSON_DB_DIR};
+use crate::object_store_db::ObjectStoreDb;
+use anyhow::{bail, Result};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// Where and how data is stored, for the importer and the website to open
+/// the same db.
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct DbConfig {
+    #[serde(default)]
+    pub backend: Option<DbBackend>,
+    /// The directory to store data in, or with the `object-store` backend
+    /// and no `object_store`, to keep its objects in
+    #[serde(default)]
+    pub path: Option<String>,
+    /// Like `s3://bucket/prefix`
+    #[serde(default)]
+    pub object_store: Option<String>,
+}
+
+#[derive(clap::ArgEnum, Deserialize, Serialize, Debug, Copy, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub enum DbBackend {
+    /// A JSON file per block and per piece of metadata
+    Json,
+    /// Blocks grouped into objects, in S3, GCS or a local directory
+    ObjectStore,
+}
+
+pub fn make_db(db_config: &DbConfig) -> Result<Arc<dyn Db>> {
+    let path = db_config.path.as_deref().unwrap_or(JSON_DB_DIR);
+    match (db_config.backend, &db_config.object_store) {
+        (Some(DbBackend::Json), Some(_)) => {
+            bail!("an object store needs the object-store db backend")
+        }
+        (Some(DbBackend::Json) | None, None) => {
+            if db_config.path.is_some() {
+                info!("using db at {}", path);
+            }
+            Ok(Arc::new(JsonDb::new(path)))
+        }
+        (_, Some(url)) => {
+            info!("using object store at {}", url);
+            Ok(Arc::new(ObjectStoreDb::new(url)?))
+        }
+        (Some(DbBackend::ObjectStore), None) => {
+            let url = format!("file://{}", path);
+            info!("using object store at {}", url);
+            Ok(Arc::new(ObjectStoreDb::new(&url)?))
+        }
+    }
+}
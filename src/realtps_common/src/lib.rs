@@ -1,2 +1,12 @@
 pub mod chain;
 pub mod db;
+#[cfg(feature = "object_store")]
+pub mod db_config;
+#[cfg(feature = "testing")]
+pub mod memory_db;
+pub mod metadata;
+#[cfg(feature = "object_store")]
+pub mod object_store_db;
+pub mod sketch;
+#[cfg(test)]
+mod test_helpers;
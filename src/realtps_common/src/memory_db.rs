@@ -0,0 +1,179 @@
+use crate::chain::Chain;
+use crate::db::{AggregateTpsPoint, Block, Db, TpsPoint};
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+/// The storage format never changes, as nothing is persisted.
+pub const MEMORY_DB_SCHEMA_VERSION: u32 = 1;
+
+/// A `Db` that keeps everything in memory, for tests.
+#[derive(Default)]
+pub struct MemoryDb {
+    data: Mutex<MemoryData>,
+}
+
+#[derive(Default)]
+struct MemoryData {
+    blocks: HashMap<Chain, BTreeMap<u64, Block>>,
+    quarantined_blocks: HashMap<Chain, BTreeMap<u64, Block>>,
+    highest_block_numbers: HashMap<Chain, u64>,
+    /// JSON, by key
+    records: HashMap<(Chain, String), Vec<u8>>,
+    tps_history: HashMap<Chain, Vec<TpsPoint>>,
+    aggregate_tps: Vec<AggregateTpsPoint>,
+    schema_version: Option<u32>,
+}
+
+impl MemoryDb {
+    pub fn new() -> MemoryDb {
+        MemoryDb::default()
+    }
+
+    fn with_data<T>(&self, f: impl FnOnce(&mut MemoryData) -> T) -> T {
+        f(&mut self.data.lock().expect("lock"))
+    }
+}
+
+#[async_trait]
+impl Db for MemoryDb {
+    async fn store_block(&self, block: Block) -> Result<()> {
+        self.with_data(|data| {
+            data.blocks
+                .entry(block.chain)
+                .or_default()
+                .insert(block.block_number, block);
+        });
+        Ok(())
+    }
+
+    async fn load_block(&self, chain: Chain, block_number: u64) -> Result<Option<Block>> {
+        Ok(self.with_data(|data| {
+            data.blocks
+                .get(&chain)
+                .and_then(|blocks| blocks.get(&block_number))
+                .cloned()
+        }))
+    }
+
+    async fn store_highest_block_number(&self, chain: Chain, block_number: u64) -> Result<()> {
+        self.with_data(|data| data.highest_block_numbers.insert(chain, block_number));
+        Ok(())
+    }
+
+    async fn load_highest_block_number(&self, chain: Chain) -> Result<Option<u64>> {
+        Ok(self.with_data(|data| data.highest_block_numbers.get(&chain).copied()))
+    }
+
+    async fn remove_block(&self, chain: Chain, block: u64) -> Result<()> {
+        self.with_data(|data| {
+            if let Some(blocks) = data.blocks.get_mut(&chain) {
+                blocks.remove(&block);
+            }
+        });
+        Ok(())
+    }
+
+    async fn load_block_numbers(&self, chain: Chain) -> Result<Vec<u64>> {
+        Ok(self.with_data(|data| {
+            data.blocks
+                .get(&chain)
+                .map(|blocks| blocks.keys().copied().collect())
+                .unwrap_or_default()
+        }))
+    }
+
+    async fn quarantine_block(&self, chain: Chain, block: u64) -> Result<()> {
+        self.with_data(|data| {
+            let removed = data
+                .blocks
+                .get_mut(&chain)
+                .and_then(|blocks| blocks.remove(&block));
+            match removed {
+                Some(removed) => {
+                    data.quarantined_blocks
+                        .entry(chain)
+                        .or_default()
+                        .insert(block, removed);
+                    Ok(())
+                }
+                None => bail!("block {} for chain {} not found", block, chain),
+            }
+        })
+    }
+
+    async fn store_record_json(&self, chain: Chain, key: &str, json: Vec<u8>) -> Result<()> {
+        self.with_data(|data| data.records.insert((chain, key.to_string()), json));
+        Ok(())
+    }
+
+    async fn load_record_json(&self, chain: Chain, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.with_data(|data| data.records.get(&(chain, key.to_string())).cloned()))
+    }
+
+    async fn append_tps_history(&self, chain: Chain, point: &TpsPoint) -> Result<()> {
+        self.with_data(|data| {
+            data.tps_history
+                .entry(chain)
+                .or_default()
+                .push(point.clone())
+        });
+        Ok(())
+    }
+
+    async fn load_tps_history(&self, chain: Chain) -> Result<Vec<TpsPoint>> {
+        Ok(self.with_data(|data| data.tps_history.get(&chain).cloned().unwrap_or_default()))
+    }
+
+    async fn remove_tps_history_before(&self, chain: Chain, timestamp: u64) -> Result<()> {
+        self.with_data(|data| {
+            if let Some(history) = data.tps_history.get_mut(&chain) {
+                history.retain(|point| point.timestamp >= timestamp);
+            }
+        });
+        Ok(())
+    }
+
+    async fn append_aggregate_tps(&self, point: &AggregateTpsPoint) -> Result<()> {
+        self.with_data(|data| data.aggregate_tps.push(point.clone()));
+        Ok(())
+    }
+
+    async fn load_aggregate_tps_history(&self) -> Result<Vec<AggregateTpsPoint>> {
+        Ok(self.with_data(|data| data.aggregate_tps.clone()))
+    }
+
+    async fn remove_aggregate_tps_before(&self, timestamp: u64) -> Result<()> {
+        self.with_data(|data| {
+            data.aggregate_tps
+                .retain(|point| point.timestamp >= timestamp)
+        });
+        Ok(())
+    }
+
+    async fn remove_chain(&self, chain: Chain) -> Result<()> {
+        self.with_data(|data| {
+            data.blocks.remove(&chain);
+            data.quarantined_blocks.remove(&chain);
+            data.highest_block_numbers.remove(&chain);
+            data.records
+                .retain(|(record_chain, _), _| *record_chain != chain);
+            data.tps_history.remove(&chain);
+        });
+        Ok(())
+    }
+
+    fn schema_version(&self) -> u32 {
+        MEMORY_DB_SCHEMA_VERSION
+    }
+
+    async fn load_schema_version(&self) -> Result<Option<u32>> {
+        Ok(self.with_data(|data| data.schema_version))
+    }
+
+    async fn store_schema_version(&self, version: u32) -> Result<()> {
+        self.with_data(|data| data.schema_version = Some(version));
+        Ok(())
+    }
+}
@@ -0,0 +1,181 @@
+use crate::chain::Chain;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// How a chain is presented on the website and in exports.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChainMetadata {
+    pub name: String,
+    /// The native token's
+    pub ticker: String,
+    pub explorer_url: Option<String>,
+    pub kind: ChainKind,
+    pub website: Option<String>,
+}
+
+/// What kind of chain it is, as shown. Aggregates group by
+/// `Chain::category` instead, which counts sidechains as L1s.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Hash, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChainKind {
+    L1,
+    /// Rollups, which settle on another chain
+    L2,
+    /// Chains with their own consensus, bridged to another chain they
+    /// checkpoint to or are merge-mined with
+    Sidechain,
+}
+
+/// Config replacing some of a chain's built-in metadata, like
+/// `[metadata.polygon]` with `name = "Polygon"`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct MetadataOverride {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub ticker: Option<String>,
+    #[serde(default)]
+    pub explorer_url: Option<String>,
+    #[serde(default)]
+    pub kind: Option<ChainKind>,
+    #[serde(default)]
+    pub website: Option<String>,
+}
+
+impl MetadataOverride {
+    pub fn apply(&self, metadata: ChainMetadata) -> ChainMetadata {
+        ChainMetadata {
+            name: self.name.clone().unwrap_or(metadata.name),
+            ticker: self.ticker.clone().unwrap_or(metadata.ticker),
+            explorer_url: self.explorer_url.clone().or(metadata.explorer_url),
+            kind: self.kind.unwrap_or(metadata.kind),
+            website: self.website.clone().or(metadata.website),
+        }
+    }
+}
+
+/// A chain's metadata, with any override from `overrides` applied.
+pub fn chain_metadata(chain: Chain, overrides: &HashMap<Chain, MetadataOverride>) -> ChainMetadata {
+    let metadata = chain.metadata();
+    match overrides.get(&chain) {
+        Some(metadata_override) => metadata_override.apply(metadata),
+        None => metadata,
+    }
+}
+
+impl Chain {
+    /// The built-in metadata. Use `chain_metadata` to apply the config's
+    /// overrides.
+    pub fn metadata(&self) -> ChainMetadata {
+        let (ticker, explorer_url, website) = match *self {
+            Chain::Acala => ("ACA", "https://acala.subscan.io", "https://acala.network"),
+            Chain::Algorand => ("ALGO", "https://allo.info", "https://algorand.co"),
+            Chain::Arbitrum => ("ETH", "https://arbiscan.io", "https://arbitrum.io"),
+            Chain::Astar => ("ASTR", "https://astar.subscan.io", "https://astar.network"),
+            Chain::Avalanche => ("AVAX", "https://snowtrace.io", "https://www.avax.network"),
+            Chain::Bifrost => ("BNC", "https://bifrost.subscan.io", "https://bifrost.io"),
+            Chain::Binance => ("BNB", "https://bscscan.com", "https://www.bnbchain.org"),
+            Chain::Bitcoin => ("BTC", "https://blockstream.info", "https://bitcoin.org"),
+            Chain::Celo => ("CELO", "https://celoscan.io", "https://celo.org"),
+            Chain::CosmosHub => (
+                "ATOM",
+                "https://www.mintscan.io/cosmos",
+                "https://cosmos.network",
+            ),
+            Chain::Cronos => ("CRO", "https://cronoscan.com", "https://cronos.org"),
+            Chain::Elrond => ("EGLD", "https://explorer.elrond.com", "https://elrond.com"),
+            Chain::Ethereum => ("ETH", "https://etherscan.io", "https://ethereum.org"),
+            Chain::Fantom => ("FTM", "https://ftmscan.com", "https://fantom.foundation"),
+            Chain::Harmony => (
+                "ONE",
+                "https://explorer.harmony.one",
+                "https://www.harmony.one",
+            ),
+            Chain::Hedera => ("HBAR", "https://hashscan.io/mainnet", "https://hedera.com"),
+            Chain::Heco => ("HT", "https://hecoinfo.com", "https://www.hecochain.com"),
+            Chain::Karura => (
+                "KAR",
+                "https://karura.subscan.io",
+                "https://acala.network/karura",
+            ),
+            Chain::KuCoin => ("KCS", "https://explorer.kcc.io", "https://www.kcc.io"),
+            Chain::Kusama => ("KSM", "https://kusama.subscan.io", "https://kusama.network"),
+            Chain::Moonbeam => ("GLMR", "https://moonscan.io", "https://moonbeam.network"),
+            Chain::Moonriver => (
+                "MOVR",
+                "https://moonriver.moonscan.io",
+                "https://moonbeam.network/networks/moonriver",
+            ),
+            Chain::Near => ("NEAR", "https://explorer.near.org", "https://near.org"),
+            Chain::OKEx => (
+                "OKT",
+                "https://www.oklink.com/okc",
+                "https://www.okx.com/okc",
+            ),
+            Chain::Optimism => (
+                "ETH",
+                "https://optimistic.etherscan.io",
+                "https://www.optimism.io",
+            ),
+            Chain::Osmosis => (
+                "OSMO",
+                "https://www.mintscan.io/osmosis",
+                "https://osmosis.zone",
+            ),
+            Chain::Pivx => (
+                "PIVX",
+                "https://chainz.cryptoid.info/pivx",
+                "https://pivx.org",
+            ),
+            Chain::Polkadot => (
+                "DOT",
+                "https://polkadot.subscan.io",
+                "https://polkadot.network",
+            ),
+            Chain::Polygon => (
+                "MATIC",
+                "https://polygonscan.com",
+                "https://polygon.technology",
+            ),
+            Chain::Rootstock => ("RBTC", "https://explorer.rsk.co", "https://rootstock.io"),
+            Chain::SecretNetwork => (
+                "SCRT",
+                "https://www.mintscan.io/secret",
+                "https://scrt.network",
+            ),
+            Chain::Solana => ("SOL", "https://explorer.solana.com", "https://solana.com"),
+            Chain::Stellar => (
+                "XLM",
+                "https://stellar.expert/explorer/public",
+                "https://stellar.org",
+            ),
+            Chain::Terra => (
+                "LUNA",
+                "https://finder.terra.money",
+                "https://www.terra.money",
+            ),
+            Chain::EthereumSepolia => (
+                "ETH",
+                "https://sepolia.etherscan.io",
+                "https://ethereum.org/en/developers/docs/networks/#sepolia",
+            ),
+            Chain::SolanaDevnet => (
+                "SOL",
+                "https://explorer.solana.com/?cluster=devnet",
+                "https://solana.com/docs/core/clusters#devnet",
+            ),
+        };
+        let kind = match self.mainnet().unwrap_or(*self) {
+            Chain::Arbitrum | Chain::Optimism => ChainKind::L2,
+            Chain::Polygon | Chain::Rootstock => ChainKind::Sidechain,
+            _ => ChainKind::L1,
+        };
+        ChainMetadata {
+            name: self.description().to_string(),
+            ticker: ticker.to_string(),
+            explorer_url: Some(explorer_url.to_string()),
+            kind,
+            website: Some(website.to_string()),
+        }
+    }
+}
@@ -0,0 +1,743 @@
+use crate::chain::Chain;
+use crate::db::{
+    store_write, AggregateTpsPoint, Block, Db, DbWrite, TpsPoint, AGGREGATE_TPS, DB_DIR_BLOCKS,
+    DB_DIR_META, HIGHEST_BLOCK_NUMBER, SCHEMA_VERSION, TPS_HISTORY,
+};
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::lock::Mutex as AsyncMutex;
+use futures::stream::TryStreamExt;
+use object_store::{
+    aws::AmazonS3Builder, gcp::GoogleCloudStorageBuilder, local::LocalFileSystem, memory::InMemory,
+    path::Path, ObjectStore,
+};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+
+/// Number of consecutive block numbers stored together in one object.
+///
+/// Storing one object per block would mean millions of tiny objects and
+/// requests per chain.
+pub const BLOCKS_PER_OBJECT: u64 = 1000;
+
+/// There has only been one storage format so far.
+pub const OBJECT_STORE_DB_SCHEMA_VERSION: u32 = 1;
+
+/// Buffered blocks are written out once this many are pending.
+const MAX_PENDING_BLOCKS: usize = 10_000;
+
+/// Unwritten changes to one range object. `None` marks a removed block.
+type PendingRange = BTreeMap<u64, Option<Block>>;
+
+/// A range object read from the store, keyed by its first block number.
+type CachedRange = (u64, BTreeMap<u64, Block>);
+
+/// Held while a range object is read and rewritten.
+type RangeLock = Arc<AsyncMutex<()>>;
+
+/// A `Db` that keeps its data in S3, GCS, MinIO, or any other store supported
+/// by the `object_store` crate.
+///
+/// Blocks are grouped into objects of `BLOCKS_PER_OBJECT` blocks. Block writes
+/// are buffered in memory and written when the highest block number for the
+/// chain is stored, on `flush`, or when too many are pending, so the highest
+/// block number never points past blocks that haven't been persisted.
+pub struct ObjectStoreDb {
+    store: Box<dyn ObjectStore>,
+    prefix: String,
+    /// Changes stay pending until their range object is written, so loads
+    /// find them while it is.
+    pending: Mutex<HashMap<(Chain, u64), PendingRange>>,
+    /// Held while a range object is read and rewritten, so concurrent flushes
+    /// don't overwrite each other, and while a load reads it to cache.
+    range_locks: Mutex<HashMap<(Chain, u64), RangeLock>>,
+    /// The most recently read range object per chain, since calculations
+    /// load blocks from the same range over and over.
+    last_range: Mutex<HashMap<Chain, CachedRange>>,
+}
+
+impl ObjectStoreDb {
+    /// Opens a store from a URL like `s3://bucket/prefix`,
+    /// `gs://bucket/prefix`, `file:///path/to/dir` or `memory://`.
+    ///
+    /// S3 credentials, region and endpoint (for MinIO) are read from the
+    /// standard `AWS_*` environment variables. GCS credentials are read from
+    /// the file named by `GOOGLE_SERVICE_ACCOUNT`.
+    pub fn new(url: &str) -> Result<Self> {
+        let (scheme, location) = url
+            .split_once("://")
+            .with_context(|| format!("invalid object store url {}", url))?;
+        let (bucket, prefix) = match location.split_once('/') {
+            Some((bucket, prefix)) => (bucket, prefix.trim_matches('/')),
+            None => (location, ""),
+        };
+
+        let (store, prefix): (Box<dyn ObjectStore>, &str) = match scheme {
+            "s3" => {
+                let store = AmazonS3Builder::from_env()
+                    .with_bucket_name(bucket)
+                    .build()?;
+                (Box::new(store), prefix)
+            }
+            "gs" => {
+                let service_account = std::env::var("GOOGLE_SERVICE_ACCOUNT")
+                    .context("GOOGLE_SERVICE_ACCOUNT is not set")?;
+                let store = GoogleCloudStorageBuilder::new()
+                    .with_bucket_name(bucket)
+                    .with_service_account_path(service_account)
+                    .build()?;
+                (Box::new(store), prefix)
+            }
+            "file" => {
+                std::fs::create_dir_all(location)?;
+                (Box::new(LocalFileSystem::new_with_prefix(location)?), "")
+            }
+            "memory" => (Box::new(InMemory::new()), ""),
+            scheme => bail!("unsupported object store scheme {}", scheme),
+        };
+
+        Ok(ObjectStoreDb {
+            store,
+            prefix: prefix.to_string(),
+            pending: Mutex::new(HashMap::new()),
+            range_locks: Mutex::new(HashMap::new()),
+            last_range: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn chain_dir(&self, chain: Chain) -> Path {
+        if self.prefix.is_empty() {
+            Path::from(chain.to_string())
+        } else {
+            Path::from(format!("{}/{}", self.prefix, chain))
+        }
+    }
+
+    fn dir(&self, chain: Chain, sub_dir: &str) -> Path {
+        if self.prefix.is_empty() {
+            Path::from(format!("{}/{}", chain, sub_dir))
+        } else {
+            Path::from(format!("{}/{}/{}", self.prefix, chain, sub_dir))
+        }
+    }
+
+    fn path(&self, chain: Chain, sub_dir: &str, file: &str) -> Path {
+        self.dir(chain, sub_dir).child(file)
+    }
+
+    fn schema_version_path(&self) -> Path {
+        self.top_level_path(SCHEMA_VERSION)
+    }
+
+    /// For data that isn't any one chain's.
+    fn top_level_path(&self, file: &str) -> Path {
+        if self.prefix.is_empty() {
+            Path::from(file)
+        } else {
+            Path::from(format!("{}/{}", self.prefix, file))
+        }
+    }
+
+    fn range_path(&self, chain: Chain, range_start: u64) -> Path {
+        self.path(chain, DB_DIR_BLOCKS, &format!("{}", range_start))
+    }
+
+    async fn write_json<T>(&self, path: &Path, data: &T) -> Result<()>
+    where
+        T: Serialize + Sync + ?Sized,
+    {
+        let bytes = Bytes::from(serde_json::to_vec(data)?);
+        self.store.put(path, bytes).await?;
+        Ok(())
+    }
+
+    async fn read_json<T>(&self, path: &Path) -> Result<Option<T>>
+    where
+        T: DeserializeOwned,
+    {
+        match self.read_bytes(path).await? {
+            Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn read_bytes(&self, path: &Path) -> Result<Option<Bytes>> {
+        let bytes = match self.store.get(path).await {
+            Ok(get_result) => get_result.bytes().await,
+            Err(e) => Err(e),
+        };
+
+        match bytes {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(e) => bail!(e),
+        }
+    }
+
+    async fn read_range(&self, chain: Chain, range_start: u64) -> Result<BTreeMap<u64, Block>> {
+        let blocks: Vec<Block> = self
+            .read_json(&self.range_path(chain, range_start))
+            .await?
+            .unwrap_or_default();
+        Ok(blocks
+            .into_iter()
+            .map(|block| (block.block_number, block))
+            .collect())
+    }
+
+    fn range_lock(&self, chain: Chain, range_start: u64) -> RangeLock {
+        self.range_locks
+            .lock()
+            .expect("lock")
+            .entry((chain, range_start))
+            .or_default()
+            .clone()
+    }
+
+    fn pending_change(&self, chain: Chain, block_number: u64) -> Option<Option<Block>> {
+        self.pending
+            .lock()
+            .expect("lock")
+            .get(&(chain, range_start(block_number)))
+            .and_then(|changes| changes.get(&block_number))
+            .cloned()
+    }
+
+    /// Must hold the range's lock.
+    async fn write_range(
+        &self,
+        chain: Chain,
+        range_start: u64,
+        changes: &PendingRange,
+    ) -> Result<()> {
+        let mut blocks = self.read_range(chain, range_start).await?;
+        for (block_number, block) in changes {
+            match block {
+                Some(block) => {
+                    blocks.insert(*block_number, block.clone());
+                }
+                None => {
+                    blocks.remove(block_number);
+                }
+            }
+        }
+
+        let path = self.range_path(chain, range_start);
+        if blocks.is_empty() {
+            match self.store.delete(&path).await {
+                Ok(()) | Err(object_store::Error::NotFound { .. }) => {}
+                Err(e) => bail!(e),
+            }
+        } else {
+            self.write_json(&path, &blocks.values().collect::<Vec<_>>())
+                .await?;
+        }
+
+        self.last_range
+            .lock()
+            .expect("lock")
+            .insert(chain, (range_start, blocks));
+
+        Ok(())
+    }
+
+    async fn flush_chains(&self, chain: Option<Chain>) -> Result<()> {
+        let ranges: Vec<(Chain, u64)> = self
+            .pending
+            .lock()
+            .expect("lock")
+            .keys()
+            .filter(|(range_chain, _)| chain.is_none() || chain == Some(*range_chain))
+            .cloned()
+            .collect();
+
+        for (chain, range_start) in ranges {
+            self.flush_range(chain, range_start).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn flush_range(&self, chain: Chain, range_start: u64) -> Result<()> {
+        let range_lock = self.range_lock(chain, range_start);
+        let _range_guard = range_lock.lock().await;
+
+        // Another flush may have written them while waiting
+        let changes = match self
+            .pending
+            .lock()
+            .expect("lock")
+            .get(&(chain, range_start))
+        {
+            Some(changes) => changes.clone(),
+            None => return Ok(()),
+        };
+        self.write_range(chain, range_start, &changes).await?;
+
+        // Changes made while writing stay pending for the next flush
+        let mut pending = self.pending.lock().expect("lock");
+        if let Some(pending_changes) = pending.get_mut(&(chain, range_start)) {
+            pending_changes.retain(|block_number, block| changes.get(block_number) != Some(block));
+            if pending_changes.is_empty() {
+                pending.remove(&(chain, range_start));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn range_start(block_number: u64) -> u64 {
+    block_number - block_number % BLOCKS_PER_OBJECT
+}
+
+#[async_trait]
+impl Db for ObjectStoreDb {
+    async fn store_block(&self, block: Block) -> Result<()> {
+        let key = (block.chain, range_start(block.block_number));
+        let num_pending: usize = {
+            let mut pending = self.pending.lock().expect("lock");
+            pending
+                .entry(key)
+                .or_default()
+                .insert(block.block_number, Some(block));
+            pending.values().map(BTreeMap::len).sum()
+        };
+
+        if num_pending >= MAX_PENDING_BLOCKS {
+            self.flush().await?;
+        }
+
+        Ok(())
+    }
+
+    async fn store_blocks(&self, chain: Chain, blocks: Vec<Block>) -> Result<()> {
+        let num_pending: usize = {
+            let mut pending = self.pending.lock().expect("lock");
+            for block in blocks {
+                assert_eq!(block.chain, chain);
+                pending
+                    .entry((chain, range_start(block.block_number)))
+                    .or_default()
+                    .insert(block.block_number, Some(block));
+            }
+            pending.values().map(BTreeMap::len).sum()
+        };
+
+        if num_pending >= MAX_PENDING_BLOCKS {
+            self.flush().await?;
+        }
+
+        Ok(())
+    }
+
+    async fn load_block(&self, chain: Chain, block_number: u64) -> Result<Option<Block>> {
+        let range_start = range_start(block_number);
+
+        if let Some(change) = self.pending_change(chain, block_number) {
+            return Ok(change);
+        }
+
+        // A block missing from the cached range may have been written since
+        // by another process, like the importer for the website, so is read
+        let cached_block = match self.last_range.lock().expect("lock").get(&chain) {
+            Some((last_range_start, blocks)) if *last_range_start == range_start => {
+                blocks.get(&block_number).cloned()
+            }
+            _ => None,
+        };
+        if let Some(block) = cached_block {
+            return Ok(Some(block));
+        }
+
+        // Not while a flush rewrites the range, or this could cache it stale
+        let range_lock = self.range_lock(chain, range_start);
+        let _range_guard = range_lock.lock().await;
+        if let Some(change) = self.pending_change(chain, block_number) {
+            return Ok(change);
+        }
+        let blocks = self.read_range(chain, range_start).await?;
+        let block = blocks.get(&block_number).cloned();
+        self.last_range
+            .lock()
+            .expect("lock")
+            .insert(chain, (range_start, blocks));
+
+        Ok(block)
+    }
+
+    async fn store_highest_block_number(&self, chain: Chain, block_number: u64) -> Result<()> {
+        self.flush_chains(Some(chain)).await?;
+        self.write_json(
+            &self.path(chain, DB_DIR_META, HIGHEST_BLOCK_NUMBER),
+            &block_number,
+        )
+        .await
+    }
+
+    async fn load_highest_block_number(&self, chain: Chain) -> Result<Option<u64>> {
+        self.read_json(&self.path(chain, DB_DIR_META, HIGHEST_BLOCK_NUMBER))
+            .await
+    }
+
+    async fn remove_block(&self, chain: Chain, block: u64) -> Result<()> {
+        self.pending
+            .lock()
+            .expect("lock")
+            .entry((chain, range_start(block)))
+            .or_default()
+            .insert(block, None);
+        Ok(())
+    }
+
+    async fn load_block_numbers(&self, chain: Chain) -> Result<Vec<u64>> {
+        let blocks_dir = self.dir(chain, DB_DIR_BLOCKS);
+        let range_starts: Vec<Option<u64>> = self
+            .store
+            .list(Some(&blocks_dir))
+            .await?
+            .map_ok(|meta| meta.location.filename().and_then(|name| name.parse().ok()))
+            .try_collect()
+            .await?;
+
+        let mut block_numbers = BTreeSet::new();
+        for range_start in range_starts.into_iter().flatten() {
+            block_numbers.extend(self.read_range(chain, range_start).await?.into_keys());
+        }
+
+        let pending = self.pending.lock().expect("lock");
+        for ((range_chain, _), changes) in pending.iter() {
+            if *range_chain != chain {
+                continue;
+            }
+            for (block_number, block) in changes {
+                if block.is_some() {
+                    block_numbers.insert(*block_number);
+                } else {
+                    block_numbers.remove(block_number);
+                }
+            }
+        }
+
+        Ok(block_numbers.into_iter().collect())
+    }
+
+    /// Lists every object of the chain, which may take a while on a large
+    /// store.
+    async fn chain_size(&self, chain: Chain) -> Result<Option<u64>> {
+        let sizes: Vec<usize> = self
+            .store
+            .list(Some(&self.chain_dir(chain)))
+            .await?
+            .map_ok(|meta| meta.size)
+            .try_collect()
+            .await?;
+        Ok(Some(sizes.into_iter().map(|size| size as u64).sum()))
+    }
+
+    async fn store_record_json(&self, chain: Chain, key: &str, json: Vec<u8>) -> Result<()> {
+        self.store
+            .put(&self.path(chain, DB_DIR_META, key), Bytes::from(json))
+            .await?;
+        Ok(())
+    }
+
+    async fn load_record_json(&self, chain: Chain, key: &str) -> Result<Option<Vec<u8>>> {
+        let bytes = self.read_bytes(&self.path(chain, DB_DIR_META, key)).await?;
+        Ok(bytes.map(|bytes| bytes.to_vec()))
+    }
+
+    async fn remove_chain(&self, chain: Chain) -> Result<()> {
+        self.pending
+            .lock()
+            .expect("lock")
+            .retain(|(range_chain, _), _| *range_chain != chain);
+        self.range_locks
+            .lock()
+            .expect("lock")
+            .retain(|(range_chain, _), _| *range_chain != chain);
+        self.last_range.lock().expect("lock").remove(&chain);
+
+        let paths: Vec<Path> = self
+            .store
+            .list(Some(&self.chain_dir(chain)))
+            .await?
+            .map_ok(|meta| meta.location)
+            .try_collect()
+            .await?;
+        for path in paths {
+            self.store.delete(&path).await?;
+        }
+        Ok(())
+    }
+
+    /// Object stores can't append, so this rewrites the whole history.
+    async fn append_tps_history(&self, chain: Chain, point: &TpsPoint) -> Result<()> {
+        let mut history = self.load_tps_history(chain).await?;
+        history.push(point.clone());
+        self.write_json(&self.path(chain, DB_DIR_META, TPS_HISTORY), &history)
+            .await
+    }
+
+    async fn load_tps_history(&self, chain: Chain) -> Result<Vec<TpsPoint>> {
+        let history = self
+            .read_json(&self.path(chain, DB_DIR_META, TPS_HISTORY))
+            .await?;
+        Ok(history.unwrap_or_default())
+    }
+
+    async fn remove_tps_history_before(&self, chain: Chain, timestamp: u64) -> Result<()> {
+        let mut history = self.load_tps_history(chain).await?;
+        let len = history.len();
+        history.retain(|point| point.timestamp >= timestamp);
+        if history.len() == len {
+            return Ok(());
+        }
+        self.write_json(&self.path(chain, DB_DIR_META, TPS_HISTORY), &history)
+            .await
+    }
+
+    async fn append_aggregate_tps(&self, point: &AggregateTpsPoint) -> Result<()> {
+        let mut history = self.load_aggregate_tps_history().await?;
+        history.push(point.clone());
+        self.write_json(&self.top_level_path(AGGREGATE_TPS), &history)
+            .await
+    }
+
+    async fn load_aggregate_tps_history(&self) -> Result<Vec<AggregateTpsPoint>> {
+        let history = self.read_json(&self.top_level_path(AGGREGATE_TPS)).await?;
+        Ok(history.unwrap_or_default())
+    }
+
+    async fn remove_aggregate_tps_before(&self, timestamp: u64) -> Result<()> {
+        let mut history = self.load_aggregate_tps_history().await?;
+        let len = history.len();
+        history.retain(|point| point.timestamp >= timestamp);
+        if history.len() == len {
+            return Ok(());
+        }
+        self.write_json(&self.top_level_path(AGGREGATE_TPS), &history)
+            .await
+    }
+
+    /// Not atomic, but blocks are written before anything else is, so
+    /// nothing written can refer to blocks that weren't.
+    async fn store_atomically(&self, chain: Chain, writes: Vec<DbWrite>) -> Result<()> {
+        for write in writes {
+            if !matches!(write, DbWrite::Block(_)) {
+                self.flush_chains(Some(chain)).await?;
+            }
+            store_write(self, chain, write).await?;
+        }
+        Ok(())
+    }
+
+    fn schema_version(&self) -> u32 {
+        OBJECT_STORE_DB_SCHEMA_VERSION
+    }
+
+    async fn load_schema_version(&self) -> Result<Option<u32>> {
+        self.read_json(&self.schema_version_path()).await
+    }
+
+    async fn store_schema_version(&self, version: u32) -> Result<()> {
+        self.write_json(&self.schema_version_path(), &version).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.flush_chains(None).await
+    }
+}
+
+#[cfg(test)]
+mod test_object_store_db {
+    use super::{ObjectStoreDb, BLOCKS_PER_OBJECT, MAX_PENDING_BLOCKS};
+    use crate::chain::Chain;
+    use crate::db::{Db, DbWrite, ImportCheckpoint};
+    use crate::test_helpers::make_block;
+    use anyhow::Result;
+    use std::ops::Range;
+    use tempdir::TempDir;
+
+    fn memory_db() -> ObjectStoreDb {
+        ObjectStoreDb::new("memory://").expect("db")
+    }
+
+    async fn range_is_stored(db: &ObjectStoreDb, chain: Chain, range_start: u64) -> bool {
+        db.store
+            .head(&db.range_path(chain, range_start))
+            .await
+            .is_ok()
+    }
+
+    #[tokio::test]
+    async fn round_trip_blocks_across_ranges() -> Result<()> {
+        let db = memory_db();
+        let chain = Chain::Ethereum;
+        let blocks: Vec<_> = (BLOCKS_PER_OBJECT - 2..BLOCKS_PER_OBJECT + 2)
+            .map(|block_number| make_block(chain, block_number))
+            .collect();
+        db.store_blocks(chain, blocks.clone()).await?;
+        db.flush().await?;
+
+        assert!(range_is_stored(&db, chain, 0).await);
+        assert!(range_is_stored(&db, chain, BLOCKS_PER_OBJECT).await);
+
+        // Read back from the store, not the buffer or cache
+        let db = ObjectStoreDb {
+            store: db.store,
+            ..memory_db()
+        };
+        for block in blocks {
+            assert_eq!(db.load_block(chain, block.block_number).await?, Some(block));
+        }
+        assert_eq!(
+            db.load_block_numbers(chain).await?,
+            (BLOCKS_PER_OBJECT - 2..BLOCKS_PER_OBJECT + 2).collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn remove_blocks_on_flush() -> Result<()> {
+        let db = memory_db();
+        let chain = Chain::Solana;
+        db.store_blocks(chain, vec![make_block(chain, 1), make_block(chain, 2)])
+            .await?;
+        db.flush().await?;
+
+        db.remove_block(chain, 1).await?;
+        assert_eq!(db.load_block(chain, 1).await?, None);
+        db.flush().await?;
+        assert_eq!(db.load_block(chain, 1).await?, None);
+        assert_eq!(db.load_block_numbers(chain).await?, vec![2]);
+
+        // Removing the last block in a range removes its object
+        db.remove_block(chain, 2).await?;
+        db.flush().await?;
+        assert!(!range_is_stored(&db, chain, 0).await);
+        assert!(db.load_block_numbers(chain).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn flush_when_too_many_blocks_are_pending() -> Result<()> {
+        let db = memory_db();
+        let chain = Chain::Polygon;
+        let num_blocks = MAX_PENDING_BLOCKS as u64;
+
+        let blocks = (0..num_blocks - 1)
+            .map(|block_number| make_block(chain, block_number))
+            .collect();
+        db.store_blocks(chain, blocks).await?;
+        assert!(!range_is_stored(&db, chain, 0).await);
+
+        db.store_block(make_block(chain, num_blocks - 1)).await?;
+        assert!(db.pending.lock().expect("lock").is_empty());
+        assert!(range_is_stored(&db, chain, 0).await);
+        assert!(range_is_stored(&db, chain, num_blocks - BLOCKS_PER_OBJECT).await);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn flush_chain_before_storing_highest_block_number() -> Result<()> {
+        let db = memory_db();
+        db.store_block(make_block(Chain::Near, 5)).await?;
+        db.store_block(make_block(Chain::Celo, 5)).await?;
+
+        db.store_highest_block_number(Chain::Near, 5).await?;
+        assert!(range_is_stored(&db, Chain::Near, 0).await);
+        assert_eq!(db.load_highest_block_number(Chain::Near).await?, Some(5));
+
+        // Other chains stay buffered
+        assert!(!range_is_stored(&db, Chain::Celo, 0).await);
+        assert_eq!(db.pending.lock().expect("lock").len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn flush_blocks_before_storing_them_atomically_with_metadata() -> Result<()> {
+        let db = memory_db();
+        let chain = Chain::Ethereum;
+        let checkpoint = ImportCheckpoint {
+            contiguous_block_number: 11,
+            in_progress: None,
+        };
+        let writes = vec![
+            DbWrite::Block(Box::new(make_block(chain, 10))),
+            DbWrite::Block(Box::new(make_block(chain, 11))),
+            DbWrite::ImportCheckpoint(checkpoint),
+        ];
+        db.store_atomically(chain, writes).await?;
+
+        assert!(db.pending.lock().expect("lock").is_empty());
+        assert!(range_is_stored(&db, chain, 0).await);
+        let checkpoint = db.load_import_checkpoint(chain).await?.expect("checkpoint");
+        assert_eq!(checkpoint.contiguous_block_number, 11);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn load_blocks_written_by_another_process() -> Result<()> {
+        let dir = TempDir::new("object_store_db")?;
+        let url = format!("file://{}", dir.path().display());
+        let (writer, reader) = (ObjectStoreDb::new(&url)?, ObjectStoreDb::new(&url)?);
+        let chain = Chain::Ethereum;
+        writer.store_block(make_block(chain, 1)).await?;
+        writer.flush().await?;
+        assert_eq!(
+            reader.load_block(chain, 1).await?,
+            Some(make_block(chain, 1))
+        );
+
+        // In the range the reader has cached
+        writer.store_block(make_block(chain, 2)).await?;
+        writer.flush().await?;
+        assert_eq!(
+            reader.load_block(chain, 2).await?,
+            Some(make_block(chain, 2))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn concurrent_flushes_of_a_range_keep_every_block() -> Result<()> {
+        // The local store writes on blocking threads, so the flushes interleave
+        let dir = TempDir::new("object_store_db")?;
+        let url = format!("file://{}", dir.path().display());
+        let db = ObjectStoreDb::new(&url)?;
+        let chain = Chain::Ethereum;
+        let blocks = |numbers: Range<u64>| {
+            numbers
+                .map(|block_number| make_block(chain, block_number))
+                .collect::<Vec<_>>()
+        };
+        db.store_blocks(chain, blocks(0..10)).await?;
+
+        let (first_flush, second_flush, loaded) = tokio::join!(
+            db.flush(),
+            async {
+                db.store_blocks(chain, blocks(10..20)).await?;
+                db.flush_chains(Some(chain)).await
+            },
+            async {
+                tokio::task::yield_now().await;
+                db.load_block(chain, 0).await
+            },
+        );
+        first_flush?;
+        second_flush?;
+        assert_eq!(loaded?, Some(make_block(chain, 0)));
+        assert!(db.pending.lock().expect("lock").is_empty());
+
+        let db = ObjectStoreDb::new(&url)?;
+        assert_eq!(
+            db.load_block_numbers(chain).await?,
+            (0..20).collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+}
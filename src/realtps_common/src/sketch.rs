@@ -0,0 +1,96 @@
+use serde::{Deserialize, Serialize};
+
+/// The first bits of an address's hash pick a register.
+const INDEX_BITS: u32 = 6;
+/// For a standard error of about 13%.
+const NUM_REGISTERS: usize = 1 << INDEX_BITS;
+
+/// A HyperLogLog sketch of the addresses sending transactions.
+///
+/// Sketches can be merged, so distinct senders can be estimated per hour or
+/// day from blocks' sketches, without keeping any addresses.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SenderSketch {
+    registers: Vec<u8>,
+}
+
+impl Default for SenderSketch {
+    fn default() -> SenderSketch {
+        SenderSketch {
+            registers: vec![0; NUM_REGISTERS],
+        }
+    }
+}
+
+impl SenderSketch {
+    pub fn insert(&mut self, address: &str) {
+        let hash = hash_address(address);
+        let index = (hash >> (64 - INDEX_BITS)) as usize;
+        // The position of the first set bit after the index bits
+        let rank = ((hash << INDEX_BITS) | (1 << (INDEX_BITS - 1))).leading_zeros() + 1;
+        let rank = u8::try_from(rank).expect("u8");
+        self.registers[index] = self.registers[index].max(rank);
+    }
+
+    pub fn merge(&mut self, other: &SenderSketch) {
+        for (register, other_register) in self.registers.iter_mut().zip(&other.registers) {
+            *register = (*register).max(*other_register);
+        }
+    }
+
+    /// The bytes the registers take.
+    pub fn size(&self) -> usize {
+        self.registers.len()
+    }
+
+    /// The estimated number of distinct senders.
+    pub fn estimate(&self) -> u64 {
+        let num_registers = self.registers.len() as f64;
+        let alpha = 0.709;
+        let sum: f64 = self
+            .registers
+            .iter()
+            .map(|register| 2f64.powi(-i32::from(*register)))
+            .sum();
+        let estimate = alpha * num_registers * num_registers / sum;
+
+        // Small counts are more accurate from the number of empty registers
+        let num_zeros = self
+            .registers
+            .iter()
+            .filter(|register| **register == 0)
+            .count();
+        let estimate = if estimate <= 2.5 * num_registers && num_zeros > 0 {
+            num_registers * (num_registers / num_zeros as f64).ln()
+        } else {
+            estimate
+        };
+
+        estimate.round() as u64
+    }
+}
+
+/// Merges sketches that not every block has, which are unknown if either is.
+pub fn merge_known(a: Option<SenderSketch>, b: Option<&SenderSketch>) -> Option<SenderSketch> {
+    a.zip(b).map(|(mut a, b)| {
+        a.merge(b);
+        a
+    })
+}
+
+/// FNV-1a, which is stable across releases, unlike std's hasher, followed by
+/// a finalizer to spread it over every bit.
+fn hash_address(address: &str) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in address.bytes() {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^= hash >> 33;
+    hash
+}
@@ -0,0 +1,18 @@
+use crate::chain::Chain;
+use crate::db::Block;
+
+/// A block with a hash derived from its number, linked to the block before
+/// it, a second apart.
+pub fn make_block(chain: Chain, block_number: u64) -> Block {
+    let prev_block_number = block_number.checked_sub(1);
+    serde_json::from_value(serde_json::json!({
+        "chain": chain,
+        "block_number": block_number,
+        "prev_block_number": prev_block_number,
+        "timestamp": 1_620_000_000 + block_number,
+        "num_txs": 5,
+        "hash": format!("{}", block_number),
+        "parent_hash": prev_block_number.map(|number| format!("{}", number)).unwrap_or_default(),
+    }))
+    .expect("block")
+}
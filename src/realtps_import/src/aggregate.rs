@@ -0,0 +1,89 @@
+use anyhow::Result;
+use realtps_common::{
+    chain::Chain,
+    db::{AggregateTpsPoint, Db, TpsWindow},
+};
+use std::sync::Arc;
+
+/// Sums the published TPS of `chains`, in total and per category. Testnets
+/// are left out, so their load tests don't count towards mainnet totals.
+///
+/// Published TPS is summed, rather than one round's calculations, so a chain
+/// whose calculation failed still counts with its last TPS.
+pub async fn calculate_aggregate_tps(
+    chains: &[Chain],
+    db: &Arc<dyn Db>,
+    windows: &[TpsWindow],
+    timestamp: u64,
+) -> Result<AggregateTpsPoint> {
+    let mut point = AggregateTpsPoint {
+        timestamp,
+        ..AggregateTpsPoint::default()
+    };
+
+    for chain in chains.iter().filter(|chain| !chain.is_testnet()) {
+        let mut has_tps = false;
+        for window in windows {
+            if let Some(tps) = db.load_tps(*chain, *window).await? {
+                *point.tps.entry(*window).or_default() += tps;
+                *point
+                    .categories
+                    .entry(chain.category())
+                    .or_default()
+                    .entry(*window)
+                    .or_default() += tps;
+                has_tps = true;
+            }
+        }
+        if has_tps {
+            point.num_chains += 1;
+        }
+    }
+
+    Ok(point)
+}
+
+#[cfg(test)]
+mod test_aggregate {
+    use super::calculate_aggregate_tps;
+    use realtps_common::{
+        chain::{Chain, ChainCategory},
+        db::{Db, TpsWindow},
+        memory_db::MemoryDb,
+    };
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn sum_chains_by_category() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        db.store_tps(Chain::Ethereum, TpsWindow::Hour, 10.0).await?;
+        db.store_tps(Chain::Solana, TpsWindow::Hour, 1000.0).await?;
+        db.store_tps(Chain::Arbitrum, TpsWindow::Hour, 5.0).await?;
+        db.store_tps(Chain::Arbitrum, TpsWindow::Day, 4.0).await?;
+        db.store_tps(Chain::SolanaDevnet, TpsWindow::Hour, 500.0)
+            .await?;
+
+        let chains = [
+            Chain::Ethereum,
+            Chain::Solana,
+            Chain::Arbitrum,
+            Chain::Near,
+            Chain::SolanaDevnet,
+        ];
+        let windows = [TpsWindow::Hour, TpsWindow::Day];
+        let point = calculate_aggregate_tps(&chains, &db, &windows, 100).await?;
+
+        assert_eq!(point.timestamp, 100);
+        // Near has no TPS yet, and testnets don't count
+        assert_eq!(point.num_chains, 3);
+        assert_eq!(point.tps[&TpsWindow::Hour], 1015.0);
+        assert_eq!(point.tps[&TpsWindow::Day], 4.0);
+        assert_eq!(
+            point.categories[&ChainCategory::L1][&TpsWindow::Hour],
+            1010.0
+        );
+        assert_eq!(point.categories[&ChainCategory::L2][&TpsWindow::Hour], 5.0);
+        assert!(!point.categories[&ChainCategory::L1].contains_key(&TpsWindow::Day));
+        Ok(())
+    }
+}
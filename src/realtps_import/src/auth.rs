@@ -0,0 +1,108 @@
+use anyhow::{Context, Result};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// How to authenticate to an RPC endpoint, for providers that take API keys
+/// in headers rather than in the URL.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct EndpointAuth {
+    /// Headers to send with every request, like `x-api-key`
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Sent as `Authorization: Bearer <token>`
+    #[serde(default)]
+    pub bearer_token: Option<String>,
+    #[serde(default)]
+    pub basic_auth: Option<BasicAuth>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct BasicAuth {
+    pub username: String,
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+impl EndpointAuth {
+    /// The headers to send with every request. A bearer token or basic auth
+    /// replaces any `Authorization` header.
+    pub fn headers(&self) -> Result<HeaderMap> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            let name = HeaderName::try_from(name.as_str())
+                .with_context(|| format!("invalid header name `{}`", name))?;
+            headers.insert(name, sensitive_value(value)?);
+        }
+        if let Some(token) = &self.bearer_token {
+            headers.insert(
+                AUTHORIZATION,
+                sensitive_value(&format!("Bearer {}", token))?,
+            );
+        }
+        if let Some(basic_auth) = &self.basic_auth {
+            let credentials = format!(
+                "{}:{}",
+                basic_auth.username,
+                basic_auth.password.as_deref().unwrap_or_default()
+            );
+            headers.insert(
+                AUTHORIZATION,
+                sensitive_value(&format!("Basic {}", base64::encode(credentials)))?,
+            );
+        }
+        Ok(headers)
+    }
+}
+
+/// Kept out of debug output, as they are usually secrets.
+fn sensitive_value(value: &str) -> Result<HeaderValue> {
+    let mut value = HeaderValue::from_str(value)
+        .context("invalid header value; headers can't hold control characters")?;
+    value.set_sensitive(true);
+    Ok(value)
+}
+
+#[cfg(test)]
+mod test_auth {
+    use super::{BasicAuth, EndpointAuth};
+    use reqwest::header::AUTHORIZATION;
+
+    #[test]
+    fn build_headers() -> Result<(), anyhow::Error> {
+        let auth = EndpointAuth {
+            headers: [("x-api-key".to_string(), "abc123".to_string())].into(),
+            bearer_token: Some("token".to_string()),
+            basic_auth: None,
+        };
+        let headers = auth.headers()?;
+        assert_eq!(headers["x-api-key"], "abc123");
+        assert_eq!(headers[AUTHORIZATION], "Bearer token");
+        assert!(headers[AUTHORIZATION].is_sensitive());
+
+        let auth = EndpointAuth {
+            basic_auth: Some(BasicAuth {
+                username: "user".to_string(),
+                password: Some("pass".to_string()),
+            }),
+            ..EndpointAuth::default()
+        };
+        assert_eq!(auth.headers()?[AUTHORIZATION], "Basic dXNlcjpwYXNz");
+        Ok(())
+    }
+
+    #[test]
+    fn reject_invalid_headers() {
+        let auth = EndpointAuth {
+            headers: [("bad header".to_string(), "value".to_string())].into(),
+            ..EndpointAuth::default()
+        };
+        assert!(auth.headers().is_err());
+
+        let auth = EndpointAuth {
+            bearer_token: Some("line\nbreak".to_string()),
+            ..EndpointAuth::default()
+        };
+        assert!(auth.headers().is_err());
+    }
+}
@@ -0,0 +1,257 @@
+use crate::client::Client;
+use crate::gaps::{self, Gap};
+use crate::helpers::*;
+use crate::pace_setter::PaceSetter;
+use crate::shutdown;
+use anyhow::{bail, Result};
+use log::{debug, info};
+use realtps_common::{
+    chain::Chain,
+    db::{Block, Db, DbWrite, TimestampIndex},
+};
+use std::sync::Arc;
+
+/// The most block numbers of a range to fetch before storing them, so an
+/// interrupted range import keeps what it fetched.
+const RANGE_CHUNK_SIZE: u64 = 1000;
+
+/// Fetches the blocks missing between the oldest and newest stored blocks,
+/// returning how many were stored.
+///
+/// Gaps of blocks stamped before `min_timestamp` are left, as they would only
+/// be removed again.
+pub async fn backfill(
+    chain: Chain,
+    client: &dyn Client,
+    db: &Arc<dyn Db>,
+    min_timestamp: u64,
+) -> Result<u64> {
+    let gaps = gaps::find_gaps(chain, db).await?;
+
+    let mut num_backfilled = 0;
+    for gap in &gaps {
+        if shutdown::requested() {
+            break;
+        }
+        let blocks = fetch_gap(chain, client, gap, min_timestamp).await?;
+        if blocks.is_empty() {
+            debug!(
+                "not backfilling blocks {} to {} for chain {}, which are too old",
+                gap.start, gap.end, chain
+            );
+            continue;
+        }
+        num_backfilled += u64::try_from(blocks.len())?;
+        store_gap(chain, db, blocks).await?;
+        info!(
+            "backfilled blocks {} to {} for chain {}",
+            gap.start, gap.end, chain
+        );
+    }
+
+    Ok(num_backfilled)
+}
+
+/// Fetches the blocks from `from` to `to`, inclusive, that aren't stored yet,
+/// returning how many were stored. Unlike imports, this doesn't follow the
+/// chain's head, which is left where it is.
+pub async fn import_range(
+    chain: Chain,
+    client: &dyn Client,
+    db: &Arc<dyn Db>,
+    from: u64,
+    to: u64,
+) -> Result<u64> {
+    if from > to {
+        bail!("range starts at block {} after it ends at {}", from, to);
+    }
+
+    let block_numbers = db.load_block_numbers(chain).await?;
+    let missing = missing_runs(&block_numbers, from, to);
+
+    let mut num_imported = 0;
+    for run in missing.iter().rev() {
+        let mut end = run.end;
+        loop {
+            if shutdown::requested() {
+                return Ok(num_imported);
+            }
+            let start = end.saturating_sub(RANGE_CHUNK_SIZE - 1).max(run.start);
+            let blocks = fetch_gap(chain, client, &Gap { start, end }, 0).await?;
+            // Continue below the oldest block, as the block numbers between
+            // were skipped by the chain
+            let next_end = blocks
+                .last()
+                .and_then(|block| block.prev_block_number)
+                .filter(|prev_block_number| *prev_block_number >= run.start);
+            num_imported += u64::try_from(blocks.len())?;
+            store_gap(chain, db, blocks).await?;
+            info!("imported blocks {} to {} for chain {}", start, end, chain);
+
+            match next_end {
+                Some(next_end) => end = next_end,
+                None => break,
+            }
+        }
+    }
+
+    Ok(num_imported)
+}
+
+/// The runs of block numbers from `from` to `to` that aren't in the sorted
+/// `block_numbers`.
+fn missing_runs(block_numbers: &[u64], from: u64, to: u64) -> Vec<Gap> {
+    let mut runs = vec![];
+    let mut next = from;
+    let stored = block_numbers
+        .iter()
+        .copied()
+        .filter(|block_number| (from..=to).contains(block_number));
+    for block_number in stored {
+        if block_number > next {
+            runs.push(Gap {
+                start: next,
+                end: block_number - 1,
+            });
+        }
+        next = block_number + 1;
+    }
+    if next <= to {
+        runs.push(Gap {
+            start: next,
+            end: to,
+        });
+    }
+    runs
+}
+
+/// Fetches the blocks in a gap, newest first, following each block's previous
+/// block number so skipped block numbers aren't requested. Returns no blocks if
+/// the newest is stamped before `min_timestamp`.
+async fn fetch_gap(
+    chain: Chain,
+    client: &dyn Client,
+    gap: &Gap,
+    min_timestamp: u64,
+) -> Result<Vec<Block>> {
+    let mut pace = PaceSetter::new(chain);
+
+    let mut blocks = vec![];
+    let mut block_number = gap.end;
+    loop {
+        let block = fetch_block(chain, client, block_number).await?;
+        if blocks.is_empty() && block.timestamp < min_timestamp {
+            break;
+        }
+        let prev_block_number = block.prev_block_number;
+        blocks.push(block);
+
+        // The blocks fetched so far are still stored, narrowing the gap
+        if shutdown::requested() {
+            break;
+        }
+
+        match prev_block_number {
+            Some(prev_block_number) if prev_block_number >= gap.start => {
+                block_number = prev_block_number;
+            }
+            _ => break,
+        }
+
+        pace.wait().await;
+    }
+
+    Ok(blocks)
+}
+
+/// Stores the blocks of a gap together with the timestamp index updated with
+/// them.
+pub async fn store_gap(chain: Chain, db: &Arc<dyn Db>, blocks: Vec<Block>) -> Result<()> {
+    let mut gap_index = TimestampIndex::default();
+    for block in &blocks {
+        gap_index.insert(block);
+    }
+    let mut timestamp_index = db.load_timestamp_index(chain).await?.unwrap_or_default();
+    timestamp_index.merge(gap_index);
+
+    let mut writes: Vec<DbWrite> = blocks
+        .into_iter()
+        .map(|block| DbWrite::Block(Box::new(block)))
+        .collect();
+    writes.push(DbWrite::TimestampIndex(timestamp_index));
+
+    db.store_atomically(chain, writes).await
+}
+
+#[cfg(test)]
+mod test_backfill {
+    use super::{backfill, import_range};
+    use crate::gaps::find_gaps;
+    use crate::test_helpers::{make_blocks, MockClient, GENESIS_TIMESTAMP};
+    use realtps_common::{chain::Chain, db::Db, memory_db::MemoryDb};
+    use std::sync::Arc;
+
+    // Solana has no block pace, so tests don't wait between blocks.
+    const CHAIN: Chain = Chain::Solana;
+
+    #[tokio::test]
+    async fn backfill_missing_blocks() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let blocks = make_blocks(CHAIN, 100, 1, 5);
+        let client = MockClient::new(blocks.clone());
+        let blocks = blocks
+            .into_iter()
+            .filter(|block| !(20..30).contains(&block.block_number))
+            .filter(|block| block.block_number != 80)
+            .collect();
+        db.store_blocks(CHAIN, blocks).await?;
+
+        let num_backfilled = backfill(CHAIN, &client, &db, 0).await?;
+        assert_eq!(num_backfilled, 11);
+        assert!(find_gaps(CHAIN, &db).await?.is_empty());
+        assert_eq!(
+            db.load_block_numbers(CHAIN).await?,
+            (0..100).collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn skip_gaps_before_min_timestamp() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let blocks = make_blocks(CHAIN, 100, 1, 5);
+        let client = MockClient::new(blocks.clone());
+        let blocks = blocks
+            .into_iter()
+            .filter(|block| !(20..30).contains(&block.block_number))
+            .filter(|block| block.block_number != 80)
+            .collect();
+        db.store_blocks(CHAIN, blocks).await?;
+
+        let num_backfilled = backfill(CHAIN, &client, &db, GENESIS_TIMESTAMP + 50).await?;
+        assert_eq!(num_backfilled, 1);
+        let gaps = find_gaps(CHAIN, &db).await?;
+        assert_eq!(gaps.len(), 1);
+        assert_eq!(gaps[0].start, 20);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn import_missing_blocks_in_range() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let blocks = make_blocks(CHAIN, 100, 1, 5);
+        let client = MockClient::new(blocks.clone());
+        let blocks = blocks
+            .into_iter()
+            .filter(|block| !(20..60).contains(&block.block_number))
+            .collect();
+        db.store_blocks(CHAIN, blocks).await?;
+
+        let num_imported = import_range(CHAIN, &client, &db, 30, 70).await?;
+        assert_eq!(num_imported, 30);
+        let block_numbers = db.load_block_numbers(CHAIN).await?;
+        assert!((30..60).all(|block_number| block_numbers.contains(&block_number)));
+        assert!(!block_numbers.contains(&29));
+        Ok(())
+    }
+}
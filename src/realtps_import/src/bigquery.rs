@@ -0,0 +1,367 @@
+use crate::backfill::store_gap;
+use crate::helpers::*;
+use crate::shutdown;
+use anyhow::{anyhow, bail, Context, Result};
+use log::info;
+use realtps_common::{
+    chain::Chain,
+    db::{Block, Db},
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+static BIGQUERY_API: &str = "https://bigquery.googleapis.com/bigquery/v2";
+
+/// How many rows to fetch per page of results, well under BigQuery's limit on
+/// the size of a response.
+const PAGE_SIZE: u64 = 10_000;
+
+/// How long each request waits for the query to finish.
+const QUERY_TIMEOUT_MS: u64 = 60_000;
+
+/// A public table of a chain's blocks.
+struct Dataset {
+    table: &'static str,
+    /// EVM tables have parent hashes, gas and miners. Bitcoin's has none of
+    /// them, so parent hashes are taken from the previous row.
+    evm: bool,
+    /// A filter on the table's partitions, if they aren't by `timestamp`, so
+    /// only those in range are scanned and billed
+    partition_filter: Option<&'static str>,
+}
+
+fn dataset(chain: Chain) -> Result<Dataset> {
+    match chain {
+        Chain::Ethereum => Ok(Dataset {
+            table: "bigquery-public-data.crypto_ethereum.blocks",
+            evm: true,
+            partition_filter: None,
+        }),
+        Chain::Polygon => Ok(Dataset {
+            table: "public-data-finance.crypto_polygon.blocks",
+            evm: true,
+            partition_filter: None,
+        }),
+        Chain::Bitcoin => Ok(Dataset {
+            table: "bigquery-public-data.crypto_bitcoin.blocks",
+            evm: false,
+            partition_filter: Some(
+                "timestamp_month >= DATE_TRUNC(DATE(TIMESTAMP_SECONDS({since})), MONTH) AND ",
+            ),
+        }),
+        _ => bail!("chain {} has no BigQuery public dataset", chain),
+    }
+}
+
+/// Stores the blocks stamped from `since` to the newest in the chain's
+/// BigQuery public dataset, returning how many were stored. Blocks already
+/// stored are skipped, and the highest block number is raised to the newest
+/// seeded block, so imports carry on from there, fetching only the blocks
+/// the dataset doesn't have yet.
+///
+/// Queries are billed to `project`, and authorized with an OAuth
+/// `access_token`, like from `gcloud auth print-access-token`.
+pub async fn seed_from_bigquery(
+    chain: Chain,
+    db: &Arc<dyn Db>,
+    project: &str,
+    access_token: &str,
+    since: u64,
+) -> Result<u64> {
+    let dataset = dataset(chain)?;
+    let stored: HashSet<u64> = db.load_block_numbers(chain).await?.into_iter().collect();
+
+    info!("querying {} for chain {}", dataset.table, chain);
+    let mut pages = QueryPages::start(project, access_token, &query_sql(&dataset, since)).await?;
+
+    let mut num_stored = 0;
+    let mut newest_block_number = None;
+    while let Some(rows) = pages.next_page().await? {
+        let mut blocks = vec![];
+        for row in rows {
+            if let Some(block) = row_to_block(chain, &dataset, &row)? {
+                newest_block_number = newest_block_number.max(Some(block.block_number));
+                if !stored.contains(&block.block_number) {
+                    blocks.push(block);
+                }
+            }
+        }
+        if !blocks.is_empty() {
+            num_stored += u64::try_from(blocks.len())?;
+            store_gap(chain, db, blocks).await?;
+            info!("seeded {} blocks for chain {}", num_stored, chain);
+        }
+
+        if shutdown::requested() {
+            return Ok(num_stored);
+        }
+    }
+
+    if let Some(newest_block_number) = newest_block_number {
+        let highest_known_block_number = load_highest_known_block_number(chain, db).await?;
+        if highest_known_block_number < Some(newest_block_number) {
+            db.store_highest_block_number(chain, newest_block_number)
+                .await?;
+        }
+    }
+
+    Ok(num_stored)
+}
+
+/// Selects number, timestamp, hash, parent hash, transaction count, gas used,
+/// gas limit and miner.
+fn query_sql(dataset: &Dataset, since: u64) -> String {
+    let (parent_hash, evm_columns) = if dataset.evm {
+        ("parent_hash", "gas_used, gas_limit, miner")
+    } else {
+        (
+            "LAG(hash) OVER (ORDER BY number)",
+            "NULL AS gas_used, NULL AS gas_limit, NULL AS miner",
+        )
+    };
+    let partition_filter = dataset
+        .partition_filter
+        .unwrap_or_default()
+        .replace("{since}", &since.to_string());
+    format!(
+        "SELECT number, UNIX_SECONDS(timestamp), hash, {} AS parent_hash, transaction_count, {} \
+         FROM `{}` \
+         WHERE {}timestamp >= TIMESTAMP_SECONDS({}) \
+         ORDER BY number",
+        parent_hash, evm_columns, dataset.table, partition_filter, since
+    )
+}
+
+/// A row of results, each value a string or null, in the order selected.
+#[derive(Deserialize, Debug)]
+struct Row {
+    f: Vec<Cell>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Cell {
+    v: Option<String>,
+}
+
+/// None for a row without a parent hash, the first Bitcoin row.
+fn row_to_block(chain: Chain, dataset: &Dataset, row: &Row) -> Result<Option<Block>> {
+    let value = |index: usize| row.f.get(index).and_then(|cell| cell.v.as_deref());
+    let number = |index: usize| -> Result<Option<u64>> {
+        value(index)
+            .map(|value| value.parse().context("bad number in bigquery row"))
+            .transpose()
+    };
+    // As the clients format them
+    let hash = |index: usize| value(index).map(|hash| hash.trim_start_matches("0x").to_string());
+
+    let parent_hash = match hash(3) {
+        Some(parent_hash) => parent_hash,
+        None => return Ok(None),
+    };
+    let block_number = number(0)?.ok_or_else(|| anyhow!("bigquery row without a number"))?;
+
+    Ok(Some(Block {
+        chain,
+        block_number,
+        prev_block_number: block_number.checked_sub(1),
+        timestamp: number(1)?.ok_or_else(|| anyhow!("bigquery row without a timestamp"))?,
+        num_txs: number(4)?.unwrap_or_default(),
+        hash: hash(2).ok_or_else(|| anyhow!("bigquery row without a hash"))?,
+        parent_hash,
+        gas_used: number(5)?,
+        gas_limit: number(6)?,
+        fees: None,
+        value_transferred: None,
+        num_token_transfers: None,
+        num_receipts: None,
+        num_internal_calls: None,
+        producer: value(7).filter(|_| dataset.evm).map(String::from),
+        num_vote_txs: None,
+        num_txs_failed: None,
+        senders: None,
+    }))
+}
+
+/// The pages of a query's results, fetched as they're asked for.
+struct QueryPages<'a> {
+    http: reqwest::Client,
+    project: &'a str,
+    access_token: &'a str,
+    job_id: String,
+    location: Option<String>,
+    response: Option<QueryResponse>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct QueryResponse {
+    job_reference: JobReference,
+    #[serde(default)]
+    job_complete: bool,
+    #[serde(default)]
+    rows: Vec<Row>,
+    #[serde(default)]
+    page_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct JobReference {
+    job_id: String,
+    #[serde(default)]
+    location: Option<String>,
+}
+
+impl<'a> QueryPages<'a> {
+    async fn start(project: &'a str, access_token: &'a str, sql: &str) -> Result<QueryPages<'a>> {
+        let http = reqwest::Client::new();
+        let response = http
+            .post(format!("{}/projects/{}/queries", BIGQUERY_API, project))
+            .bearer_auth(access_token)
+            .json(&json!({
+                "query": sql,
+                "useLegacySql": false,
+                "maxResults": PAGE_SIZE,
+                "timeoutMs": QUERY_TIMEOUT_MS,
+            }))
+            .send()
+            .await?;
+        let response: QueryResponse = parse_response(response).await?;
+
+        Ok(QueryPages {
+            http,
+            project,
+            access_token,
+            job_id: response.job_reference.job_id.clone(),
+            location: response.job_reference.location.clone(),
+            response: Some(response),
+        })
+    }
+
+    /// The next page of rows, waiting for the query to finish first.
+    async fn next_page(&mut self) -> Result<Option<Vec<Row>>> {
+        loop {
+            let response = match self.response.take() {
+                Some(response) => response,
+                None => return Ok(None),
+            };
+            if !response.job_complete {
+                info!("waiting for bigquery job {}", self.job_id);
+                self.response = Some(self.get_results(None).await?);
+                continue;
+            }
+            if let Some(page_token) = &response.page_token {
+                self.response = Some(self.get_results(Some(page_token)).await?);
+            }
+            return Ok(Some(response.rows));
+        }
+    }
+
+    async fn get_results(&self, page_token: Option<&str>) -> Result<QueryResponse> {
+        let mut query = vec![
+            ("maxResults", PAGE_SIZE.to_string()),
+            ("timeoutMs", QUERY_TIMEOUT_MS.to_string()),
+        ];
+        if let Some(page_token) = page_token {
+            query.push(("pageToken", page_token.to_string()));
+        }
+        if let Some(location) = &self.location {
+            query.push(("location", location.clone()));
+        }
+        let response = self
+            .http
+            .get(format!(
+                "{}/projects/{}/queries/{}",
+                BIGQUERY_API, self.project, self.job_id
+            ))
+            .bearer_auth(self.access_token)
+            .query(&query)
+            .send()
+            .await?;
+        parse_response(response).await
+    }
+}
+
+/// BigQuery explains errors in the body.
+async fn parse_response(response: reqwest::Response) -> Result<QueryResponse> {
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        bail!("bigquery request failed with {}: {}", status, body);
+    }
+    Ok(response.json().await?)
+}
+
+#[cfg(test)]
+mod test_bigquery {
+    use super::{dataset, query_sql, row_to_block, Cell, Row};
+    use realtps_common::chain::Chain;
+
+    fn make_row(values: &[Option<&str>]) -> Row {
+        Row {
+            f: values
+                .iter()
+                .map(|value| Cell {
+                    v: value.map(String::from),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn convert_evm_rows() -> Result<(), anyhow::Error> {
+        let dataset = dataset(Chain::Ethereum)?;
+        let row = make_row(&[
+            Some("18000000"),
+            Some("1693066895"),
+            Some("0xaa"),
+            Some("0xbb"),
+            Some("94"),
+            Some("7000000"),
+            Some("30000000"),
+            Some("0x1f9090aae28b8a3dceadf281b0f12828e676c326"),
+        ]);
+        let block = row_to_block(Chain::Ethereum, &dataset, &row)?.expect("block");
+        assert_eq!(block.block_number, 18000000);
+        assert_eq!(block.prev_block_number, Some(17999999));
+        assert_eq!(block.timestamp, 1693066895);
+        assert_eq!(block.num_txs, 94);
+        assert_eq!(block.hash, "aa");
+        assert_eq!(block.parent_hash, "bb");
+        assert_eq!(block.gas_used, Some(7000000));
+        assert_eq!(block.gas_limit, Some(30000000));
+        assert_eq!(
+            block.producer.as_deref(),
+            Some("0x1f9090aae28b8a3dceadf281b0f12828e676c326")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn skip_bitcoin_row_without_parent() -> Result<(), anyhow::Error> {
+        let dataset = dataset(Chain::Bitcoin)?;
+        let first = make_row(&[
+            Some("800000"),
+            Some("1690168629"),
+            Some("00aa"),
+            None,
+            Some("3721"),
+            None,
+            None,
+            None,
+        ]);
+        assert!(row_to_block(Chain::Bitcoin, &dataset, &first)?.is_none());
+
+        let sql = query_sql(&dataset, 1690000000);
+        assert!(sql.contains("LAG(hash)"));
+        assert!(sql.contains("timestamp_month >= DATE_TRUNC(DATE(TIMESTAMP_SECONDS(1690000000))"));
+        Ok(())
+    }
+
+    #[test]
+    fn reject_chains_without_datasets() {
+        assert!(dataset(Chain::Solana).is_err());
+    }
+}
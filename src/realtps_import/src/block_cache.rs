@@ -0,0 +1,296 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use lru::LruCache;
+use realtps_common::{
+    chain::Chain,
+    db::{AggregateTpsPoint, Block, Db, DbWrite, TpsPoint},
+};
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// A `Db` that keeps recently loaded blocks in memory, since imports and
+/// calculations load the same recent blocks every cycle.
+///
+/// Everything else goes straight to the inner `Db`. Blocks are evicted from
+/// the cache whenever they are written or removed through it.
+pub struct BlockCacheDb {
+    inner: Arc<dyn Db>,
+    cache: Mutex<Cache>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct Cache {
+    blocks: LruCache<(Chain, u64), Block>,
+    /// Counts evictions, so that a block loaded while it was being
+    /// rewritten is not put back in the cache.
+    evictions: u64,
+}
+
+impl BlockCacheDb {
+    pub fn new(inner: Arc<dyn Db>, capacity: NonZeroUsize) -> BlockCacheDb {
+        BlockCacheDb {
+            inner,
+            cache: Mutex::new(Cache {
+                blocks: LruCache::new(capacity),
+                evictions: 0,
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// The number of cache hits and misses since the last call.
+    pub fn take_stats(&self) -> (u64, u64) {
+        (
+            self.hits.swap(0, Ordering::Relaxed),
+            self.misses.swap(0, Ordering::Relaxed),
+        )
+    }
+
+    fn evict(&self, chain: Chain, block_number: u64) {
+        let mut cache = self.cache.lock().expect("lock");
+        cache.blocks.pop(&(chain, block_number));
+        cache.evictions += 1;
+    }
+
+    /// The cached block, or the eviction count to pass to `cache_loaded`
+    /// once the block has been loaded from the inner `Db`.
+    fn cached(&self, chain: Chain, block_number: u64) -> Result<Block, u64> {
+        let mut cache = self.cache.lock().expect("lock");
+        match cache.blocks.get(&(chain, block_number)) {
+            Some(block) => Ok(block.clone()),
+            None => Err(cache.evictions),
+        }
+    }
+
+    /// Caches a loaded block, unless something was evicted since the load
+    /// began, in which case the block may already be stale.
+    fn cache_loaded(&self, block: &Block, evictions: u64) {
+        let mut cache = self.cache.lock().expect("lock");
+        if cache.evictions == evictions {
+            cache
+                .blocks
+                .put((block.chain, block.block_number), block.clone());
+        }
+    }
+}
+
+#[async_trait]
+impl Db for BlockCacheDb {
+    async fn store_block(&self, block: Block) -> Result<()> {
+        let (chain, block_number) = (block.chain, block.block_number);
+        self.evict(chain, block_number);
+        let result = self.inner.store_block(block).await;
+        self.evict(chain, block_number);
+        result
+    }
+
+    async fn store_blocks(&self, chain: Chain, blocks: Vec<Block>) -> Result<()> {
+        let block_numbers: Vec<u64> = blocks.iter().map(|block| block.block_number).collect();
+        for block_number in &block_numbers {
+            self.evict(chain, *block_number);
+        }
+        let result = self.inner.store_blocks(chain, blocks).await;
+        for block_number in block_numbers {
+            self.evict(chain, block_number);
+        }
+        result
+    }
+
+    async fn load_block(&self, chain: Chain, block_number: u64) -> Result<Option<Block>> {
+        let evictions = match self.cached(chain, block_number) {
+            Ok(block) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(Some(block));
+            }
+            Err(evictions) => evictions,
+        };
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        let block = self.inner.load_block(chain, block_number).await?;
+        if let Some(block) = &block {
+            self.cache_loaded(block, evictions);
+        }
+
+        Ok(block)
+    }
+
+    async fn store_highest_block_number(&self, chain: Chain, block_number: u64) -> Result<()> {
+        self.inner
+            .store_highest_block_number(chain, block_number)
+            .await
+    }
+
+    async fn load_highest_block_number(&self, chain: Chain) -> Result<Option<u64>> {
+        self.inner.load_highest_block_number(chain).await
+    }
+
+    async fn remove_block(&self, chain: Chain, block: u64) -> Result<()> {
+        self.evict(chain, block);
+        let result = self.inner.remove_block(chain, block).await;
+        self.evict(chain, block);
+        result
+    }
+
+    async fn load_block_numbers(&self, chain: Chain) -> Result<Vec<u64>> {
+        self.inner.load_block_numbers(chain).await
+    }
+
+    async fn quarantine_block(&self, chain: Chain, block: u64) -> Result<()> {
+        self.evict(chain, block);
+        let result = self.inner.quarantine_block(chain, block).await;
+        self.evict(chain, block);
+        result
+    }
+
+    async fn store_record_json(&self, chain: Chain, key: &str, json: Vec<u8>) -> Result<()> {
+        self.inner.store_record_json(chain, key, json).await
+    }
+
+    async fn load_record_json(&self, chain: Chain, key: &str) -> Result<Option<Vec<u8>>> {
+        self.inner.load_record_json(chain, key).await
+    }
+
+    async fn chain_size(&self, chain: Chain) -> Result<Option<u64>> {
+        self.inner.chain_size(chain).await
+    }
+
+    async fn remove_chain(&self, chain: Chain) -> Result<()> {
+        {
+            let mut cache = self.cache.lock().expect("lock");
+            cache.evictions += 1;
+            let keys: Vec<(Chain, u64)> = cache
+                .blocks
+                .iter()
+                .map(|(key, _)| *key)
+                .filter(|(block_chain, _)| *block_chain == chain)
+                .collect();
+            for key in keys {
+                cache.blocks.pop(&key);
+            }
+        }
+        let result = self.inner.remove_chain(chain).await;
+        self.cache.lock().expect("lock").evictions += 1;
+        result
+    }
+
+    async fn append_tps_history(&self, chain: Chain, point: &TpsPoint) -> Result<()> {
+        self.inner.append_tps_history(chain, point).await
+    }
+
+    async fn load_tps_history(&self, chain: Chain) -> Result<Vec<TpsPoint>> {
+        self.inner.load_tps_history(chain).await
+    }
+
+    async fn remove_tps_history_before(&self, chain: Chain, timestamp: u64) -> Result<()> {
+        self.inner.remove_tps_history_before(chain, timestamp).await
+    }
+
+    async fn append_aggregate_tps(&self, point: &AggregateTpsPoint) -> Result<()> {
+        self.inner.append_aggregate_tps(point).await
+    }
+
+    async fn load_aggregate_tps_history(&self) -> Result<Vec<AggregateTpsPoint>> {
+        self.inner.load_aggregate_tps_history().await
+    }
+
+    async fn remove_aggregate_tps_before(&self, timestamp: u64) -> Result<()> {
+        self.inner.remove_aggregate_tps_before(timestamp).await
+    }
+
+    async fn store_atomically(&self, chain: Chain, writes: Vec<DbWrite>) -> Result<()> {
+        let block_numbers: Vec<u64> = writes
+            .iter()
+            .filter_map(|write| match write {
+                DbWrite::Block(block) => Some(block.block_number),
+                _ => None,
+            })
+            .collect();
+        for block_number in &block_numbers {
+            self.evict(chain, *block_number);
+        }
+        let result = self.inner.store_atomically(chain, writes).await;
+        for block_number in block_numbers {
+            self.evict(chain, block_number);
+        }
+        result
+    }
+
+    async fn recover(&self) -> Result<()> {
+        self.inner.recover().await
+    }
+
+    fn schema_version(&self) -> u32 {
+        self.inner.schema_version()
+    }
+
+    async fn load_schema_version(&self) -> Result<Option<u32>> {
+        self.inner.load_schema_version().await
+    }
+
+    async fn store_schema_version(&self, version: u32) -> Result<()> {
+        self.inner.store_schema_version(version).await
+    }
+
+    async fn migrate(&self) -> Result<()> {
+        self.inner.migrate().await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.inner.flush().await
+    }
+}
+
+#[cfg(test)]
+mod test_block_cache {
+    use super::BlockCacheDb;
+    use crate::test_helpers::make_block;
+    use realtps_common::{chain::Chain, db::Db, memory_db::MemoryDb};
+    use std::num::NonZeroUsize;
+    use std::sync::Arc;
+
+    const CHAIN: Chain = Chain::Ethereum;
+
+    #[tokio::test]
+    async fn cache_loaded_blocks() -> Result<(), anyhow::Error> {
+        let inner: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let db = BlockCacheDb::new(inner, NonZeroUsize::new(10).expect("nonzero"));
+
+        db.store_block(make_block(CHAIN, 1, 1, 5)).await?;
+        db.load_block(CHAIN, 1).await?.expect("block");
+        db.load_block(CHAIN, 1).await?.expect("block");
+        assert_eq!(db.take_stats(), (1, 1));
+
+        // Writes through the cache replace cached blocks
+        let mut block = make_block(CHAIN, 1, 1, 5);
+        block.hash = "reorg".to_string();
+        db.store_block(block).await?;
+        assert_eq!(db.load_block(CHAIN, 1).await?.expect("block").hash, "reorg");
+
+        db.remove_block(CHAIN, 1).await?;
+        assert!(db.load_block(CHAIN, 1).await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dont_cache_blocks_rewritten_while_loading() -> Result<(), anyhow::Error> {
+        let inner: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let db = BlockCacheDb::new(inner, NonZeroUsize::new(10).expect("nonzero"));
+
+        let stale_block = make_block(CHAIN, 1, 1, 5);
+        db.store_block(stale_block.clone()).await?;
+
+        // A load misses, then the block is rewritten before the load finishes
+        let evictions = db.cached(CHAIN, 1).expect_err("miss");
+        let mut block = make_block(CHAIN, 1, 1, 5);
+        block.hash = "reorg".to_string();
+        db.store_block(block).await?;
+        db.cache_loaded(&stale_block, evictions);
+
+        assert_eq!(db.load_block(CHAIN, 1).await?.expect("block").hash, "reorg");
+        Ok(())
+    }
+}
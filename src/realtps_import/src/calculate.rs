@@ -1,38 +1,740 @@
 use crate::helpers::*;
-use anyhow::{anyhow, Result};
+use crate::peaks;
+use anyhow::{anyhow, bail, Result};
 use chrono::{TimeZone, Utc};
-use realtps_common::{chain::Chain, db::CalculationLog, db::Db};
+use realtps_common::{
+    chain::Chain,
+    db::{
+        add_known, add_known_value, Block, BlockTimeStats, CalculationLog, ChainStats, Db,
+        MaxTpsEstimate, PeakTps, ProducerStats, TimestampIndex, TpsWindow, TxsPerBlockStats,
+        WindowStats,
+    },
+};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::Arc;
 
+#[derive(Serialize)]
 pub struct ChainCalcs {
     pub chain: Chain,
-    pub tps: f64,
+    /// The newest block's timestamp
+    pub timestamp: u64,
+    pub tps: BTreeMap<TpsWindow, f64>,
+    /// Only for windows where every block has a gas count
+    pub gas_per_second: BTreeMap<TpsWindow, f64>,
+    pub stats: ChainStats,
+    pub peak_tps: PeakTps,
+}
+
+/// Sums over a run of blocks.
+#[derive(Clone, Copy)]
+struct BlockTotals {
+    num_txs: u64,
+    num_blocks: Option<u64>,
+    num_empty_blocks: Option<u64>,
+    gas_used: Option<u64>,
+    gas_limit: Option<u64>,
+    fees: Option<u64>,
+    value_transferred: Option<u128>,
+    num_token_transfers: Option<u64>,
+    num_receipts: Option<u64>,
+    num_internal_calls: Option<u64>,
+    num_vote_txs: Option<u64>,
+    num_txs_failed: Option<u64>,
 }
 
-pub async fn calculate_for_chain(chain: Chain, db: Arc<dyn Db>) -> Result<ChainCalcs> {
+impl BlockTotals {
+    fn zero() -> BlockTotals {
+        BlockTotals {
+            num_txs: 0,
+            num_blocks: Some(0),
+            num_empty_blocks: Some(0),
+            gas_used: Some(0),
+            gas_limit: Some(0),
+            fees: Some(0),
+            value_transferred: Some(0),
+            num_token_transfers: Some(0),
+            num_receipts: Some(0),
+            num_internal_calls: Some(0),
+            num_vote_txs: Some(0),
+            num_txs_failed: Some(0),
+        }
+    }
+
+    fn add_block(&mut self, block: &Block) {
+        self.num_txs = self.num_txs.checked_add(block.num_txs).expect("overflow");
+        self.num_blocks = add_known(self.num_blocks, Some(1));
+        self.num_empty_blocks =
+            add_known(self.num_empty_blocks, Some(u64::from(block.num_txs == 0)));
+        self.gas_used = add_known(self.gas_used, block.gas_used);
+        self.gas_limit = add_known(self.gas_limit, block.gas_limit);
+        self.fees = add_known(self.fees, block.fees);
+        self.value_transferred = add_known_value(self.value_transferred, block.value_transferred);
+        self.num_token_transfers = add_known(self.num_token_transfers, block.num_token_transfers);
+        self.num_receipts = add_known(self.num_receipts, block.num_receipts);
+        self.num_internal_calls = add_known(self.num_internal_calls, block.num_internal_calls);
+        self.num_vote_txs = add_known(self.num_vote_txs, block.num_vote_txs);
+        self.num_txs_failed = add_known(self.num_txs_failed, block.num_txs_failed);
+    }
+
+    /// The totals without the blocks summed in `other`, which must be among
+    /// them.
+    fn without(&self, other: &BlockTotals) -> BlockTotals {
+        let subtract = |total: Option<u64>, other: Option<u64>| {
+            total
+                .zip(other)
+                .map(|(total, other)| total.saturating_sub(other))
+        };
+        BlockTotals {
+            num_txs: self.num_txs.saturating_sub(other.num_txs),
+            num_blocks: subtract(self.num_blocks, other.num_blocks),
+            num_empty_blocks: subtract(self.num_empty_blocks, other.num_empty_blocks),
+            gas_used: subtract(self.gas_used, other.gas_used),
+            gas_limit: subtract(self.gas_limit, other.gas_limit),
+            fees: subtract(self.fees, other.fees),
+            value_transferred: self
+                .value_transferred
+                .zip(other.value_transferred)
+                .map(|(total, other)| total.saturating_sub(other)),
+            num_token_transfers: subtract(self.num_token_transfers, other.num_token_transfers),
+            num_receipts: subtract(self.num_receipts, other.num_receipts),
+            num_internal_calls: subtract(self.num_internal_calls, other.num_internal_calls),
+            num_vote_txs: subtract(self.num_vote_txs, other.num_vote_txs),
+            num_txs_failed: subtract(self.num_txs_failed, other.num_txs_failed),
+        }
+    }
+
+    /// The transactions counted towards TPS.
+    fn counted_txs(&self, exclude_failed_txs: bool) -> u64 {
+        match (exclude_failed_txs, self.num_txs_failed) {
+            (true, Some(num_txs_failed)) => self.num_txs.saturating_sub(num_txs_failed),
+            _ => self.num_txs,
+        }
+    }
+}
+
+/// The blocks in a window, back to the oldest block at or before its start.
+struct WindowTotals {
+    init_timestamp: u64,
+    totals: BlockTotals,
+    /// Blocks whose timestamps were ignored
+    num_anomalous: u64,
+}
+
+/// Blocks stamped further ahead of our clock have wrong timestamps.
+const MAX_CLOCK_SKEW_SECONDS: u64 = 10 * 60;
+
+/// Beyond this, the clock is more likely wrong than the blocks.
+const MAX_FUTURE_BLOCKS: u64 = 10;
+
+/// Blocks stamped this many median block times before the block preceding
+/// them have wrong timestamps.
+const ANOMALOUS_BLOCK_TIMES: f64 = 10.0;
+
+pub async fn calculate_for_chain(
+    chain: Chain,
+    db: Arc<dyn Db>,
+    windows: Vec<TpsWindow>,
+    exclude_failed_txs: bool,
+    max_window: Option<TpsWindow>,
+) -> Result<ChainCalcs> {
     let calculating_start = Utc::now();
 
     let highest_block_number = load_highest_known_block_number(chain, &db).await?;
     let highest_block_number =
         highest_block_number.ok_or_else(|| anyhow!("no data for chain {}", chain))?;
 
-    let load_block = |number| load_block(chain, &db, number);
-
-    let latest_timestamp = load_block(highest_block_number)
+    let latest_block = load_block(chain, &db, highest_block_number)
         .await?
-        .expect("first block")
-        .timestamp;
+        .expect("first block");
+    let (latest_block, num_future_blocks) = skip_future_blocks(chain, &db, latest_block).await?;
+    let latest_timestamp = latest_block.timestamp;
 
-    let seconds_per_week = 60 * 60 * 24 * 7;
-    let min_timestamp = latest_timestamp
-        .checked_sub(seconds_per_week)
-        .expect("underflow");
+    let timestamp_index = db.load_timestamp_index(chain).await?;
 
-    let mut current_block = load_block(highest_block_number)
-        .await?
-        .expect("first_block");
+    // Windows longer than `max_window` are calculated over it instead
+    let calc_window = |window: TpsWindow| match max_window {
+        Some(max_window) => window.min(max_window),
+        None => window,
+    };
+
+    let retention_seconds = windows
+        .iter()
+        .map(|window| calc_window(*window).seconds())
+        .max()
+        .unwrap_or_default();
+    let shortest_window = windows.iter().min().copied().map(calc_window);
+
+    let samples = match shortest_window {
+        Some(window) => load_block_samples(chain, &db, &latest_block, window).await?,
+        None => vec![],
+    };
+    let max_block_time = shortest_window
+        .and_then(|window| calculate_block_times(&samples, window))
+        .map(|block_times| (block_times.median.max(1.0) * ANOMALOUS_BLOCK_TIMES).ceil() as u64);
+
+    let finalized = load_finalized_block(chain, &db, &latest_block).await?;
+
+    let mut tps = BTreeMap::new();
+    let mut gas_per_second = BTreeMap::new();
+    let mut stats = ChainStats::default();
+    let mut oldest_timestamp = latest_timestamp;
+
+    for window in windows {
+        let calculated_over = calc_window(window);
+        let min_timestamp = latest_timestamp
+            .checked_sub(calculated_over.seconds())
+            .expect("underflow");
+
+        let WindowTotals {
+            init_timestamp,
+            totals,
+            num_anomalous,
+        } = find_window(
+            chain,
+            &db,
+            timestamp_index.as_ref(),
+            &latest_block,
+            min_timestamp,
+            max_block_time,
+        )
+        .await?;
+
+        if exclude_failed_txs && totals.num_txs_failed.is_none() {
+            log::warn!(
+                "not all blocks over {} for chain {} have failed transaction counts",
+                window,
+                chain
+            );
+        }
+        let num_txs = totals.counted_txs(exclude_failed_txs);
+
+        // The window ending at the finalized block starts earlier, so is
+        // found from the latest block, then the unfinalized blocks removed
+        let finalized_tps = match &finalized {
+            Some((finalized_block, unfinalized_totals)) => {
+                let min_timestamp = finalized_block
+                    .timestamp
+                    .checked_sub(calculated_over.seconds())
+                    .expect("underflow");
+                let window_totals = find_window(
+                    chain,
+                    &db,
+                    timestamp_index.as_ref(),
+                    &latest_block,
+                    min_timestamp,
+                    max_block_time,
+                )
+                .await?;
+                let num_txs = window_totals
+                    .totals
+                    .without(unfinalized_totals)
+                    .counted_txs(exclude_failed_txs);
+                Some(calculate_per_second(
+                    window_totals.init_timestamp,
+                    finalized_block.timestamp,
+                    num_txs,
+                ))
+            }
+            None => None,
+        };
+
+        tps.insert(
+            window,
+            calculate_per_second(init_timestamp, latest_timestamp, num_txs),
+        );
+        if let Some(gas_used) = totals.gas_used {
+            gas_per_second.insert(
+                window,
+                calculate_per_second(init_timestamp, latest_timestamp, gas_used),
+            );
+        }
+        stats.windows.insert(
+            window,
+            WindowStats {
+                fee_per_tx: totals
+                    .fees
+                    .filter(|_| totals.num_txs > 0)
+                    .map(|fees| fees as f64 / totals.num_txs as f64),
+                fees_per_second: totals
+                    .fees
+                    .map(|fees| calculate_per_second(init_timestamp, latest_timestamp, fees)),
+                value_per_second: totals.value_transferred.map(|value| {
+                    calculate_value_per_second(chain, init_timestamp, latest_timestamp, value)
+                }),
+                value_usd_per_second: None,
+                empty_block_share: totals
+                    .num_blocks
+                    .filter(|num_blocks| *num_blocks > 0)
+                    .zip(totals.num_empty_blocks)
+                    .map(|(num_blocks, num_empty_blocks)| {
+                        num_empty_blocks as f64 / num_blocks as f64
+                    }),
+                token_transfers_per_second: totals.num_token_transfers.map(|num_transfers| {
+                    calculate_per_second(init_timestamp, latest_timestamp, num_transfers)
+                }),
+                receipts_per_second: totals.num_receipts.map(|num_receipts| {
+                    calculate_per_second(init_timestamp, latest_timestamp, num_receipts)
+                }),
+                internal_calls_per_second: totals.num_internal_calls.map(|num_calls| {
+                    calculate_per_second(init_timestamp, latest_timestamp, num_calls)
+                }),
+                raw_tps: totals.num_vote_txs.map(|num_vote_txs| {
+                    let num_raw_txs = totals.num_txs.checked_add(num_vote_txs).expect("overflow");
+                    calculate_per_second(init_timestamp, latest_timestamp, num_raw_txs)
+                }),
+                finalized_tps,
+                utilization: totals
+                    .gas_used
+                    .zip(totals.gas_limit.filter(|gas_limit| *gas_limit > 0))
+                    .map(|(gas_used, gas_limit)| gas_used as f64 / gas_limit as f64),
+                anomalous_timestamps: num_future_blocks + num_anomalous,
+                coverage: Some(calculate_coverage(
+                    init_timestamp,
+                    latest_timestamp,
+                    calculated_over,
+                )),
+                calculated_over: Some(calculated_over),
+            },
+        );
+        if num_future_blocks + num_anomalous > 0 {
+            log::warn!(
+                "ignored {} anomalous block timestamps over {} for chain {}",
+                num_future_blocks + num_anomalous,
+                window,
+                chain
+            );
+        }
+        oldest_timestamp = oldest_timestamp.min(init_timestamp);
+    }
+
+    if let Some(window) = shortest_window {
+        stats.block_times = calculate_block_times(&samples, window);
+        stats.max_tps = calculate_max_tps(&samples, window);
+        stats.txs_per_block = calculate_txs_per_block(&samples, window);
+        stats.producers = calculate_producers(&samples, window);
+    }
+
+    let peak_tps = peaks::update_peak_tps(chain, &db, &latest_block, retention_seconds).await?;
+
+    let calculating_end = Utc::now();
+
+    let newest_block_timestamp = Utc.timestamp(i64::try_from(latest_timestamp)?, 0);
+    let oldest_block_timestamp = Utc.timestamp(i64::try_from(oldest_timestamp)?, 0);
+
+    let calculation_log = CalculationLog {
+        calculating_start,
+        calculating_end,
+        newest_block_timestamp,
+        oldest_block_timestamp,
+        stale: false,
+    };
+
+    log::debug!(
+        "done calculation for chain {}: {:#?}",
+        chain,
+        calculation_log
+    );
+
+    write_calculation_log(chain, &db, calculation_log).await?;
+
+    Ok(ChainCalcs {
+        chain,
+        timestamp: latest_timestamp,
+        tps,
+        gas_per_second,
+        stats,
+        peak_tps,
+    })
+}
+
+/// Flags a disabled chain's last calculations as stale, leaving its TPS
+/// published as it was. Recalculating clears the flag.
+pub async fn mark_stale(chain: Chain, db: &Arc<dyn Db>) -> Result<()> {
+    if let Some(mut calculation_log) = db.load_calculation_log(chain).await? {
+        if !calculation_log.stale {
+            calculation_log.stale = true;
+            db.store_calculation_log(chain, &calculation_log).await?;
+            log::info!(
+                "chain {} is disabled; leaving its last calculations published as stale",
+                chain
+            );
+        }
+    }
+    Ok(())
+}
+
+/// TPS over a stored range of blocks, rather than a window ending now.
+#[derive(Serialize, Debug)]
+pub struct RangeCalcs {
+    pub chain: Chain,
+    /// The timestamps of the blocks at or before the requested start and
+    /// end, which may be narrower than requested if blocks are missing
+    pub from: u64,
+    pub to: u64,
+    pub num_txs: u64,
+    pub tps: f64,
+    /// Only if every block has a gas count
+    pub gas_per_second: Option<f64>,
+}
+
+/// Prints each chain's TPS from `from` to `to` as JSON, for chains with
+/// blocks stored over the range.
+pub async fn print_range_tps(
+    chains: &[Chain],
+    db: &Arc<dyn Db>,
+    from: u64,
+    to: u64,
+    exclude_failed_txs: &HashSet<Chain>,
+) -> Result<()> {
+    if from >= to {
+        bail!("--from must be before --to");
+    }
+
+    for chain in chains {
+        let exclude_failed_txs = exclude_failed_txs.contains(chain);
+        match calculate_for_range(*chain, db, from, to, exclude_failed_txs).await? {
+            Some(range_calcs) => println!("{}", serde_json::to_string_pretty(&range_calcs)?),
+            None => log::warn!("no blocks from {} to {} for chain {}", from, to, chain),
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints each chain's TPS over the `seconds` before its newest block as
+/// JSON, for windows other than those published.
+pub async fn print_window_tps(
+    chains: &[Chain],
+    db: &Arc<dyn Db>,
+    seconds: u64,
+    exclude_failed_txs: &HashSet<Chain>,
+) -> Result<()> {
+    for chain in chains {
+        let latest_block = match load_highest_known_block_number(*chain, db).await? {
+            Some(block_number) => load_block(*chain, db, block_number).await?,
+            None => None,
+        };
+        let latest_timestamp = match latest_block {
+            Some(latest_block) => latest_block.timestamp,
+            None => {
+                log::warn!("no data for chain {}", chain);
+                continue;
+            }
+        };
+        let from = latest_timestamp.saturating_sub(seconds);
+        let exclude_failed_txs = exclude_failed_txs.contains(chain);
+        match calculate_for_range(*chain, db, from, latest_timestamp, exclude_failed_txs).await? {
+            Some(range_calcs) => println!("{}", serde_json::to_string_pretty(&range_calcs)?),
+            None => log::warn!("no blocks in the window for chain {}", chain),
+        }
+    }
+
+    Ok(())
+}
 
-    let mut num_txs: u64 = 0;
+/// Prints each chain's calculations over `windows` as JSON, without storing
+/// them.
+pub async fn print_chain_calcs(
+    chains: &[Chain],
+    db: &Arc<dyn Db>,
+    windows: &[TpsWindow],
+    exclude_failed_txs: &HashSet<Chain>,
+    max_tps_window: &HashMap<Chain, TpsWindow>,
+) -> Result<()> {
+    for chain in chains {
+        let calcs = calculate_for_chain(
+            *chain,
+            db.clone(),
+            windows.to_vec(),
+            exclude_failed_txs.contains(chain),
+            max_tps_window.get(chain).copied(),
+        )
+        .await?;
+        println!("{}", serde_json::to_string_pretty(&calcs)?);
+    }
+
+    Ok(())
+}
+
+/// Parses a window like `90m`, `24h` or `7d` into seconds.
+pub fn parse_window(window: &str) -> Result<u64> {
+    let unit_start = window
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(|| anyhow!("window `{}` needs a unit: s, m, h or d", window))?;
+    let (amount, unit) = window.split_at(unit_start);
+    let amount: u64 = amount
+        .parse()
+        .map_err(|_| anyhow!("window `{}` needs a number, like 24h", window))?;
+    let seconds_per_unit = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        _ => bail!(
+            "unknown unit `{}` in window `{}`: use s, m, h or d",
+            unit,
+            window
+        ),
+    };
+    match amount.checked_mul(seconds_per_unit) {
+        Some(seconds) if seconds > 0 => Ok(seconds),
+        _ => bail!("window `{}` is out of range", window),
+    }
+}
+
+/// Calculates TPS over the blocks stamped from `from` to `to`, or `None` if
+/// none are stored.
+///
+/// The blocks in the range are those since `from` without those since `to`,
+/// both found back from the latest block, so the timestamp index can be
+/// used.
+pub async fn calculate_for_range(
+    chain: Chain,
+    db: &Arc<dyn Db>,
+    from: u64,
+    to: u64,
+    exclude_failed_txs: bool,
+) -> Result<Option<RangeCalcs>> {
+    let highest_block_number = match load_highest_known_block_number(chain, db).await? {
+        Some(highest_block_number) => highest_block_number,
+        None => return Ok(None),
+    };
+    let latest_block = match load_block(chain, db, highest_block_number).await? {
+        Some(latest_block) => latest_block,
+        None => return Ok(None),
+    };
+    let (latest_block, _) = skip_future_blocks(chain, db, latest_block).await?;
+
+    let timestamp_index = db.load_timestamp_index(chain).await?;
+
+    let (to, totals_after_to) = if to >= latest_block.timestamp {
+        (latest_block.timestamp, BlockTotals::zero())
+    } else {
+        let window_totals =
+            find_window(chain, db, timestamp_index.as_ref(), &latest_block, to, None).await?;
+        (window_totals.init_timestamp, window_totals.totals)
+    };
+    let WindowTotals {
+        init_timestamp: from,
+        totals: totals_after_from,
+        ..
+    } = find_window(
+        chain,
+        db,
+        timestamp_index.as_ref(),
+        &latest_block,
+        from,
+        None,
+    )
+    .await?;
+
+    if from >= to {
+        return Ok(None);
+    }
+
+    let totals = totals_after_from.without(&totals_after_to);
+    let num_txs = totals.counted_txs(exclude_failed_txs);
+
+    Ok(Some(RangeCalcs {
+        chain,
+        from,
+        to,
+        num_txs,
+        tps: calculate_per_second(from, to, num_txs),
+        gas_per_second: totals
+            .gas_used
+            .map(|gas_used| calculate_per_second(from, to, gas_used)),
+    }))
+}
+
+/// Finds the oldest block timestamp and the block totals since
+/// `min_timestamp`, using the timestamp index if there is one.
+async fn find_window(
+    chain: Chain,
+    db: &Arc<dyn Db>,
+    timestamp_index: Option<&TimestampIndex>,
+    latest_block: &Block,
+    min_timestamp: u64,
+    max_block_time: Option<u64>,
+) -> Result<WindowTotals> {
+    let indexed_window = match timestamp_index {
+        Some(timestamp_index) => {
+            find_window_in_index(
+                chain,
+                db,
+                timestamp_index,
+                latest_block,
+                min_timestamp,
+                max_block_time,
+            )
+            .await?
+        }
+        None => None,
+    };
+    match indexed_window {
+        Some(window_totals) => Ok(window_totals),
+        None => {
+            walk_back(
+                chain,
+                db,
+                latest_block.clone(),
+                BlockTotals::zero(),
+                min_timestamp,
+                max_block_time,
+            )
+            .await
+        }
+    }
+}
+
+/// Loads the finalized block last sampled during import, and the totals of
+/// the blocks after it up to `latest_block`, or `None` for chains without a
+/// finalized block.
+async fn load_finalized_block(
+    chain: Chain,
+    db: &Arc<dyn Db>,
+    latest_block: &Block,
+) -> Result<Option<(Block, BlockTotals)>> {
+    let finalized_block_number = match db.load_finality_lag(chain).await? {
+        Some(finality_lag) => match finality_lag.finalized_block_number {
+            Some(finalized_block_number) => finalized_block_number,
+            None => return Ok(None),
+        },
+        None => return Ok(None),
+    };
+
+    let mut block = latest_block.clone();
+    let mut unfinalized_totals = BlockTotals::zero();
+    while block.block_number > finalized_block_number {
+        unfinalized_totals.add_block(&block);
+        block = match block.prev_block_number {
+            Some(prev_block_number) => match load_block(chain, db, prev_block_number).await? {
+                Some(prev_block) => prev_block,
+                None => return Ok(None),
+            },
+            None => return Ok(None),
+        };
+    }
+
+    Ok(Some((block, unfinalized_totals)))
+}
+
+/// Finds the oldest block timestamp and the block totals since
+/// `min_timestamp` using the timestamp index, if the index reaches back that
+/// far.
+///
+/// Transactions are summed per bucket, and only the blocks in the bucket
+/// containing `min_timestamp` are loaded, to find where the window starts.
+async fn find_window_in_index(
+    chain: Chain,
+    db: &Arc<dyn Db>,
+    timestamp_index: &TimestampIndex,
+    latest_block: &Block,
+    min_timestamp: u64,
+    max_block_time: Option<u64>,
+) -> Result<Option<WindowTotals>> {
+    let (bucket_start, _) = match timestamp_index.bucket_at(min_timestamp) {
+        Some(bucket) => bucket,
+        None => return Ok(None),
+    };
+
+    let next_bucket = timestamp_index.buckets.range(bucket_start + 1..).next();
+
+    let (start_block, totals_after_start) = match next_bucket {
+        Some((_, next_bucket)) => {
+            let start_block = match load_block(chain, db, next_bucket.first_block_number).await? {
+                Some(block) => block,
+                None => return Ok(None),
+            };
+            let subtract_start = |total: Option<u64>, start: Option<u64>| {
+                total
+                    .zip(start)
+                    .map(|(total, start)| total.saturating_sub(start))
+            };
+            let totals_after_start = BlockTotals {
+                num_txs: timestamp_index
+                    .num_txs_after(bucket_start)
+                    .saturating_sub(start_block.num_txs),
+                num_blocks: subtract_start(timestamp_index.num_blocks_after(bucket_start), Some(1)),
+                num_empty_blocks: subtract_start(
+                    timestamp_index.num_empty_blocks_after(bucket_start),
+                    Some(u64::from(start_block.num_txs == 0)),
+                ),
+                gas_used: subtract_start(
+                    timestamp_index.gas_used_after(bucket_start),
+                    start_block.gas_used,
+                ),
+                gas_limit: subtract_start(
+                    timestamp_index.gas_limit_after(bucket_start),
+                    start_block.gas_limit,
+                ),
+                fees: subtract_start(timestamp_index.fees_after(bucket_start), start_block.fees),
+                value_transferred: timestamp_index
+                    .value_transferred_after(bucket_start)
+                    .zip(start_block.value_transferred)
+                    .map(|(total, start)| total.saturating_sub(start)),
+                num_token_transfers: subtract_start(
+                    timestamp_index.num_token_transfers_after(bucket_start),
+                    start_block.num_token_transfers,
+                ),
+                num_receipts: subtract_start(
+                    timestamp_index.num_receipts_after(bucket_start),
+                    start_block.num_receipts,
+                ),
+                num_internal_calls: subtract_start(
+                    timestamp_index.num_internal_calls_after(bucket_start),
+                    start_block.num_internal_calls,
+                ),
+                num_vote_txs: subtract_start(
+                    timestamp_index.num_vote_txs_after(bucket_start),
+                    start_block.num_vote_txs,
+                ),
+                num_txs_failed: subtract_start(
+                    timestamp_index.num_txs_failed_after(bucket_start),
+                    start_block.num_txs_failed,
+                ),
+            };
+            (start_block, totals_after_start)
+        }
+        None => (latest_block.clone(), BlockTotals::zero()),
+    };
+
+    let window_totals = walk_back(
+        chain,
+        db,
+        start_block,
+        totals_after_start,
+        min_timestamp,
+        max_block_time,
+    )
+    .await?;
+
+    Ok(Some(window_totals))
+}
+
+/// Finds the oldest block timestamp and the block totals since
+/// `min_timestamp` by loading every block back from `start_block`.
+///
+/// `totals_after_start` are the totals of the blocks after `start_block`.
+///
+/// A block stamped more than `max_block_time` before the block preceding it
+/// doesn't end the window. Its transactions are counted, but not its
+/// timestamp.
+async fn walk_back(
+    chain: Chain,
+    db: &Arc<dyn Db>,
+    start_block: Block,
+    totals_after_start: BlockTotals,
+    min_timestamp: u64,
+    max_block_time: Option<u64>,
+) -> Result<WindowTotals> {
+    let load_block = |number| load_block(chain, db, number);
+
+    let mut current_block = start_block;
+
+    let mut totals = totals_after_start;
+    let mut num_anomalous = 0;
 
     let init_timestamp = loop {
         let prev_block_number = current_block.prev_block_number;
@@ -51,11 +753,14 @@ pub async fn calculate_for_chain(chain: Chain, db: Arc<dyn Db>) -> Result<ChainC
 
         let prev_block = prev_block.unwrap();
 
-        num_txs = num_txs
-            .checked_add(current_block.num_txs)
-            .expect("overflow");
+        totals.add_block(&current_block);
 
         if prev_block.timestamp <= min_timestamp {
+            if is_stamped_before_prev(chain, db, &prev_block, max_block_time).await? {
+                num_anomalous += 1;
+                current_block = prev_block;
+                continue;
+            }
             break prev_block.timestamp;
         }
         if prev_block.block_number == 0 {
@@ -65,44 +770,866 @@ pub async fn calculate_for_chain(chain: Chain, db: Arc<dyn Db>) -> Result<ChainC
         current_block = prev_block;
     };
 
-    let tps = calculate_tps(init_timestamp, latest_timestamp, num_txs)?;
+    Ok(WindowTotals {
+        init_timestamp,
+        totals,
+        num_anomalous,
+    })
+}
 
-    let calculating_end = Utc::now();
+async fn is_stamped_before_prev(
+    chain: Chain,
+    db: &Arc<dyn Db>,
+    block: &Block,
+    max_block_time: Option<u64>,
+) -> Result<bool> {
+    let (max_block_time, prev_block_number) = match (max_block_time, block.prev_block_number) {
+        (Some(max_block_time), Some(prev_block_number)) => (max_block_time, prev_block_number),
+        _ => return Ok(false),
+    };
+    Ok(match load_block(chain, db, prev_block_number).await? {
+        Some(prev_block) => prev_block.timestamp > block.timestamp + max_block_time,
+        None => false,
+    })
+}
 
-    let newest_block_timestamp = Utc.timestamp(i64::try_from(latest_timestamp)?, 0);
-    let oldest_block_timestamp = Utc.timestamp(i64::try_from(init_timestamp)?, 0);
+/// Steps back from head blocks stamped in the future, returning the newest
+/// block with a believable timestamp and how many were skipped.
+async fn skip_future_blocks(
+    chain: Chain,
+    db: &Arc<dyn Db>,
+    latest_block: Block,
+) -> Result<(Block, u64)> {
+    let max_timestamp = u64::try_from(Utc::now().timestamp())? + MAX_CLOCK_SKEW_SECONDS;
 
-    let calculation_log = CalculationLog {
-        calculating_start,
-        calculating_end,
-        newest_block_timestamp,
-        oldest_block_timestamp,
-    };
+    let mut block = latest_block.clone();
+    for num_skipped in 0..MAX_FUTURE_BLOCKS {
+        if block.timestamp <= max_timestamp {
+            return Ok((block, num_skipped));
+        }
+        block = match block.prev_block_number {
+            Some(prev_block_number) => match load_block(chain, db, prev_block_number).await? {
+                Some(prev_block) => prev_block,
+                None => break,
+            },
+            None => break,
+        };
+    }
 
-    log::debug!(
-        "done calculation for chain {}: {:#?}",
-        chain,
-        calculation_log
+    log::warn!(
+        "newest blocks for chain {} are in the future. is the clock right?",
+        chain
     );
+    Ok((latest_block, 0))
+}
 
-    write_calculation_log(chain, &db, calculation_log).await?;
+/// What the statistics over every block in a window need of each block.
+struct BlockSample {
+    timestamp: u64,
+    num_txs: u64,
+    gas_used: Option<u64>,
+    gas_limit: Option<u64>,
+    producer: Option<String>,
+}
+
+/// Walks back through every block in `window`, newest first.
+async fn load_block_samples(
+    chain: Chain,
+    db: &Arc<dyn Db>,
+    latest_block: &Block,
+    window: TpsWindow,
+) -> Result<Vec<BlockSample>> {
+    let min_timestamp = latest_block.timestamp.saturating_sub(window.seconds());
+
+    let sample = |block: &Block| BlockSample {
+        timestamp: block.timestamp,
+        num_txs: block.num_txs,
+        gas_used: block.gas_used,
+        gas_limit: block.gas_limit,
+        producer: block.producer.clone(),
+    };
+
+    let mut samples = vec![sample(latest_block)];
+    let mut prev_block_number = latest_block.prev_block_number;
+
+    while let Some(block_number) = prev_block_number {
+        let block = match load_block(chain, db, block_number).await? {
+            Some(block) => block,
+            None => break,
+        };
+        if block.timestamp < min_timestamp {
+            break;
+        }
+
+        samples.push(sample(&block));
+        prev_block_number = block.prev_block_number;
+    }
+
+    Ok(samples)
+}
+
+/// The time between each block and the one before it.
+fn calculate_block_times(samples: &[BlockSample], window: TpsWindow) -> Option<BlockTimeStats> {
+    let mut block_times: Vec<u64> = samples
+        .windows(2)
+        .map(|pair| pair[0].timestamp.saturating_sub(pair[1].timestamp))
+        .collect();
+
+    if block_times.is_empty() {
+        return None;
+    }
+
+    block_times.sort_unstable();
+
+    let count = block_times.len() as f64;
+    let mean = block_times.iter().sum::<u64>() as f64 / count;
+    let middle = block_times.len() / 2;
+    let median = if block_times.len().is_multiple_of(2) {
+        (block_times[middle - 1] + block_times[middle]) as f64 / 2.0
+    } else {
+        block_times[middle] as f64
+    };
+    let variance = block_times
+        .iter()
+        .map(|block_time| (*block_time as f64 - mean).powi(2))
+        .sum::<f64>()
+        / count;
+
+    Some(BlockTimeStats {
+        window,
+        mean,
+        median,
+        std_dev: variance.sqrt(),
+    })
+}
+
+/// Divides the gas limit per second by the median gas used per transaction.
+/// None unless every block has gas counts.
+fn calculate_max_tps(samples: &[BlockSample], window: TpsWindow) -> Option<MaxTpsEstimate> {
+    let (oldest, newer) = samples.split_last()?;
+
+    let mut gas_limit: u64 = 0;
+    let mut gas_per_tx = vec![];
+
+    // The oldest block is before the time the others span
+    for sample in newer {
+        let (block_gas_used, block_gas_limit) = sample.gas_used.zip(sample.gas_limit)?;
+        gas_limit = gas_limit.checked_add(block_gas_limit).expect("overflow");
+        if sample.num_txs > 0 {
+            gas_per_tx.push(block_gas_used as f64 / sample.num_txs as f64);
+        }
+    }
 
-    Ok(ChainCalcs { chain, tps })
+    if gas_per_tx.is_empty() {
+        return None;
+    }
+
+    gas_per_tx.sort_unstable_by(|a, b| a.total_cmp(b));
+
+    let middle = gas_per_tx.len() / 2;
+    let median_gas_per_tx = if gas_per_tx.len().is_multiple_of(2) {
+        (gas_per_tx[middle - 1] + gas_per_tx[middle]) / 2.0
+    } else {
+        gas_per_tx[middle]
+    };
+    if median_gas_per_tx == 0.0 {
+        return None;
+    }
+
+    let gas_limit_per_second =
+        calculate_per_second(oldest.timestamp, samples[0].timestamp, gas_limit);
+
+    Some(MaxTpsEstimate {
+        window,
+        median_gas_per_tx,
+        max_tps: gas_limit_per_second / median_gas_per_tx,
+    })
+}
+
+/// Percentiles of transactions per block, which tell sustained load from
+/// rare bursts.
+fn calculate_txs_per_block(samples: &[BlockSample], window: TpsWindow) -> Option<TxsPerBlockStats> {
+    let mut txs_per_block: Vec<u64> = samples.iter().map(|sample| sample.num_txs).collect();
+    txs_per_block.sort_unstable();
+    let max = *txs_per_block.last()?;
+
+    // Nearest rank
+    let percentile = |percent: usize| {
+        let rank = (txs_per_block.len() * percent).div_ceil(100);
+        txs_per_block[rank.saturating_sub(1)]
+    };
+
+    Some(TxsPerBlockStats {
+        window,
+        p50: percentile(50),
+        p90: percentile(90),
+        p99: percentile(99),
+        max,
+    })
 }
 
-fn calculate_tps(init_timestamp: u64, latest_timestamp: u64, num_txs: u64) -> Result<f64> {
+/// How many blocks each producer produced, if every block names its producer.
+fn calculate_producers(samples: &[BlockSample], window: TpsWindow) -> Option<ProducerStats> {
+    let mut blocks_per_producer: HashMap<&str, u64> = HashMap::new();
+    for sample in samples {
+        *blocks_per_producer
+            .entry(sample.producer.as_deref()?)
+            .or_default() += 1;
+    }
+    let mut blocks_per_producer: Vec<u64> = blocks_per_producer.into_values().collect();
+    blocks_per_producer.sort_unstable_by(|a, b| b.cmp(a));
+    let top_blocks = *blocks_per_producer.first()?;
+    let num_blocks = samples.len() as u64;
+
+    let mut nakamoto_coefficient = 0;
+    let mut blocks_produced = 0;
+    for num_producer_blocks in &blocks_per_producer {
+        if blocks_produced * 2 > num_blocks {
+            break;
+        }
+        blocks_produced += num_producer_blocks;
+        nakamoto_coefficient += 1;
+    }
+
+    Some(ProducerStats {
+        window,
+        num_producers: blocks_per_producer.len() as u64,
+        top_share: top_blocks as f64 / num_blocks as f64,
+        nakamoto_coefficient,
+    })
+}
+
+fn calculate_coverage(init_timestamp: u64, latest_timestamp: u64, window: TpsWindow) -> f64 {
+    let total_seconds = latest_timestamp.saturating_sub(init_timestamp);
+    (total_seconds as f64 / window.seconds() as f64).min(1.0)
+}
+
+/// A rate over a window, for tx counts and totals like gas and fees, which
+/// can all overflow a u32 over a month.
+fn calculate_per_second(init_timestamp: u64, latest_timestamp: u64, total: u64) -> f64 {
+    let total_seconds = latest_timestamp.saturating_sub(init_timestamp);
+    if total_seconds == 0 {
+        return 0.0;
+    }
+
+    // f64 precision is plenty for a rate
+    total as f64 / total_seconds as f64
+}
+
+/// Value transferred per second, in whole native units.
+fn calculate_value_per_second(
+    chain: Chain,
+    init_timestamp: u64,
+    latest_timestamp: u64,
+    value: u128,
+) -> f64 {
     let total_seconds = latest_timestamp.saturating_sub(init_timestamp);
-    let total_seconds_u32 =
-        u32::try_from(total_seconds).map_err(|_| anyhow!("seconds overflows u32"))?;
-    let num_txs_u32 = u32::try_from(num_txs).map_err(|_| anyhow!("num txs overflows u32"))?;
-    let total_seconds_f64 = f64::from(total_seconds_u32);
-    let num_txs_f64 = f64::from(num_txs_u32);
-    let mut tps = num_txs_f64 / total_seconds_f64;
+    if total_seconds == 0 {
+        return 0.0;
+    }
+
+    let unit = 10f64.powi(i32::try_from(chain.native_decimals()).expect("i32"));
+    value as f64 / unit / total_seconds as f64
+}
+
+/// Fills in the USD value per second of every window, at `usd_price` per
+/// whole native unit.
+pub fn convert_value_to_usd(stats: &mut ChainStats, usd_price: f64) {
+    for window_stats in stats.windows.values_mut() {
+        window_stats.value_usd_per_second = window_stats
+            .value_per_second
+            .map(|value_per_second| value_per_second * usd_price);
+    }
+}
+
+/// An exponential moving average of calculated TPS, weighting the new
+/// calculation by `smoothing`, so a calculation over a short window, after
+/// an RPC hiccup, doesn't make the published TPS jump.
+pub fn smooth_tps(published_tps: Option<f64>, tps: f64, smoothing: f64) -> f64 {
+    match published_tps {
+        Some(published_tps) => smoothing * tps + (1.0 - smoothing) * published_tps,
+        None => tps,
+    }
+}
+
+#[cfg(test)]
+mod test_calculate {
+    use super::{
+        calculate_for_chain, calculate_for_range, convert_value_to_usd, mark_stale, parse_window,
+        smooth_tps,
+    };
+    use crate::test_helpers::{make_blocks, GENESIS_TIMESTAMP};
+    use chrono::Utc;
+    use realtps_common::{
+        chain::Chain,
+        db::{Db, FinalityLag, TimestampIndex, TpsWindow},
+        memory_db::MemoryDb,
+    };
+    use std::sync::Arc;
+
+    const CHAIN: Chain = Chain::Ethereum;
+
+    /// Two weeks of blocks, a block every 10 minutes with 60 txs, for 0.1 tps.
+    async fn make_db(with_index: bool) -> Result<Arc<dyn Db>, anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let blocks = make_blocks(CHAIN, 2000, 600, 60);
+
+        if with_index {
+            let mut timestamp_index = TimestampIndex::default();
+            for block in &blocks {
+                timestamp_index.insert(block);
+            }
+            db.store_timestamp_index(CHAIN, &timestamp_index).await?;
+        }
+
+        db.store_blocks(CHAIN, blocks).await?;
+        db.store_highest_block_number(CHAIN, 1999).await?;
+        Ok(db)
+    }
+
+    #[tokio::test]
+    async fn calculate_walking_blocks() -> Result<(), anyhow::Error> {
+        let db = make_db(false).await?;
+        let calcs =
+            calculate_for_chain(CHAIN, db.clone(), TpsWindow::all_windows(), false, None).await?;
+        for window in TpsWindow::all_windows() {
+            assert!((calcs.tps[&window] - 0.1).abs() < 1e-9);
+        }
+
+        // The month window reaches back to the first block
+        let calculation_log = db.load_calculation_log(CHAIN).await?.expect("log");
+        let window =
+            calculation_log.newest_block_timestamp - calculation_log.oldest_block_timestamp;
+        assert_eq!(window.num_seconds(), 1999 * 600);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn calculate_more_txs_than_fit_a_u32() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        // Over 4 billion txs in the month window, for 5000 tps
+        db.store_blocks(CHAIN, make_blocks(CHAIN, 2000, 600, 3_000_000))
+            .await?;
+        db.store_highest_block_number(CHAIN, 1999).await?;
+
+        let calcs = calculate_for_chain(CHAIN, db, vec![TpsWindow::Month], false, None).await?;
+        assert!((calcs.tps[&TpsWindow::Month] - 5000.0).abs() < 1e-6);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn mark_disabled_chain_stale() -> Result<(), anyhow::Error> {
+        let db = make_db(false).await?;
+        calculate_for_chain(CHAIN, db.clone(), vec![TpsWindow::Week], false, None).await?;
+        db.store_tps(CHAIN, TpsWindow::Week, 0.1).await?;
+
+        mark_stale(CHAIN, &db).await?;
+        assert!(db.load_calculation_log(CHAIN).await?.expect("log").stale);
+        assert_eq!(db.load_tps(CHAIN, TpsWindow::Week).await?, Some(0.1));
+
+        // Once enabled again
+        calculate_for_chain(CHAIN, db.clone(), vec![TpsWindow::Week], false, None).await?;
+        assert!(!db.load_calculation_log(CHAIN).await?.expect("log").stale);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn calculate_with_timestamp_index() -> Result<(), anyhow::Error> {
+        let db = make_db(true).await?;
+
+        // Only the blocks around the start of the week are needed
+        for block_number in (0..991).chain(997..1999) {
+            db.remove_block(CHAIN, block_number).await?;
+        }
+
+        let calcs =
+            calculate_for_chain(CHAIN, db.clone(), vec![TpsWindow::Week], false, None).await?;
+        assert!((calcs.tps[&TpsWindow::Week] - 0.1).abs() < 1e-9);
+
+        let calculation_log = db.load_calculation_log(CHAIN).await?.expect("log");
+        let window =
+            calculation_log.newest_block_timestamp - calculation_log.oldest_block_timestamp;
+        assert_eq!(window.num_days(), 7);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ignore_block_stamped_before_prev() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let mut blocks = make_blocks(CHAIN, 2000, 600, 60);
+        // Would end the hour early
+        blocks[1996].timestamp = GENESIS_TIMESTAMP;
+        db.store_blocks(CHAIN, blocks).await?;
+        db.store_highest_block_number(CHAIN, 1999).await?;
+
+        let calcs = calculate_for_chain(CHAIN, db, vec![TpsWindow::Hour], false, None).await?;
+        assert!((calcs.tps[&TpsWindow::Hour] - 0.1).abs() < 1e-9);
+        assert_eq!(
+            calcs.stats.windows[&TpsWindow::Hour].anomalous_timestamps,
+            1
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn skip_blocks_stamped_in_future() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let mut blocks = make_blocks(CHAIN, 2000, 600, 60);
+        blocks[1999].timestamp = u64::try_from(Utc::now().timestamp())? + 60 * 60 * 24;
+        db.store_blocks(CHAIN, blocks).await?;
+        db.store_highest_block_number(CHAIN, 1999).await?;
+
+        let calcs = calculate_for_chain(CHAIN, db, vec![TpsWindow::Hour], false, None).await?;
+        assert_eq!(calcs.timestamp, GENESIS_TIMESTAMP + 1998 * 600);
+        assert!((calcs.tps[&TpsWindow::Hour] - 0.1).abs() < 1e-9);
+        assert_eq!(
+            calcs.stats.windows[&TpsWindow::Hour].anomalous_timestamps,
+            1
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn calculate_gas_per_second() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let mut blocks = make_blocks(CHAIN, 2000, 600, 60);
+        for block in &mut blocks {
+            block.gas_used = Some(600_000);
+        }
+        // Blocks before the gas count was recorded
+        for block in &mut blocks[..900] {
+            block.gas_used = None;
+        }
+
+        let mut timestamp_index = TimestampIndex::default();
+        for block in &blocks {
+            timestamp_index.insert(block);
+        }
+        db.store_timestamp_index(CHAIN, &timestamp_index).await?;
+        db.store_blocks(CHAIN, blocks).await?;
+        db.store_highest_block_number(CHAIN, 1999).await?;
+
+        let calcs = calculate_for_chain(CHAIN, db, TpsWindow::all_windows(), false, None).await?;
+        for window in [TpsWindow::Hour, TpsWindow::Day, TpsWindow::Week] {
+            assert!((calcs.gas_per_second[&window] - 1000.0).abs() < 1e-9);
+        }
+        assert!(!calcs.gas_per_second.contains_key(&TpsWindow::Month));
+        Ok(())
+    }
 
-    // Special float values will not serialize sensibly
-    if tps.is_nan() || tps.is_infinite() {
-        tps = 0.0;
+    #[tokio::test]
+    async fn calculate_utilization() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let mut blocks = make_blocks(CHAIN, 2000, 600, 60);
+        for block in &mut blocks {
+            block.gas_used = Some(300_000);
+            block.gas_limit = Some(1_000_000);
+        }
+        // Blocks before the gas limit was recorded
+        for block in &mut blocks[..900] {
+            block.gas_limit = None;
+        }
+
+        let mut timestamp_index = TimestampIndex::default();
+        for block in &blocks {
+            timestamp_index.insert(block);
+        }
+        db.store_timestamp_index(CHAIN, &timestamp_index).await?;
+        db.store_blocks(CHAIN, blocks).await?;
+        db.store_highest_block_number(CHAIN, 1999).await?;
+
+        let calcs = calculate_for_chain(CHAIN, db, TpsWindow::all_windows(), false, None).await?;
+        for window in [TpsWindow::Hour, TpsWindow::Day, TpsWindow::Week] {
+            let utilization = calcs.stats.windows[&window]
+                .utilization
+                .expect("utilization");
+            assert!((utilization - 0.3).abs() < 1e-9);
+        }
+        assert_eq!(calcs.stats.windows[&TpsWindow::Month].utilization, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn calculate_empty_block_share() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let mut blocks = make_blocks(CHAIN, 2000, 600, 60);
+        for block in blocks
+            .iter_mut()
+            .filter(|block| block.block_number % 4 == 0)
+        {
+            block.num_txs = 0;
+        }
+
+        let mut timestamp_index = TimestampIndex::default();
+        for block in &blocks {
+            timestamp_index.insert(block);
+        }
+        db.store_timestamp_index(CHAIN, &timestamp_index).await?;
+        db.store_blocks(CHAIN, blocks).await?;
+        db.store_highest_block_number(CHAIN, 1999).await?;
+
+        let windows = vec![TpsWindow::Day, TpsWindow::Week];
+        let calcs = calculate_for_chain(CHAIN, db, windows, false, None).await?;
+        for window in [TpsWindow::Day, TpsWindow::Week] {
+            let empty_block_share = calcs.stats.windows[&window]
+                .empty_block_share
+                .expect("empty block share");
+            assert!((empty_block_share - 0.25).abs() < 0.01);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn calculate_finalized_tps() -> Result<(), anyhow::Error> {
+        for with_index in [false, true] {
+            let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+            let mut blocks = make_blocks(CHAIN, 2000, 600, 60);
+            // A burst of transactions in blocks that aren't yet finalized
+            for block in blocks.iter_mut().filter(|block| block.block_number > 1989) {
+                block.num_txs = 600;
+            }
+
+            if with_index {
+                let mut timestamp_index = TimestampIndex::default();
+                for block in &blocks {
+                    timestamp_index.insert(block);
+                }
+                db.store_timestamp_index(CHAIN, &timestamp_index).await?;
+            }
+            db.store_blocks(CHAIN, blocks).await?;
+            db.store_highest_block_number(CHAIN, 1999).await?;
+
+            let calcs =
+                calculate_for_chain(CHAIN, db.clone(), vec![TpsWindow::Day], false, None).await?;
+            assert_eq!(calcs.stats.windows[&TpsWindow::Day].finalized_tps, None);
+
+            let finality_lag = FinalityLag {
+                timestamp: GENESIS_TIMESTAMP + 1999 * 600,
+                blocks: 10,
+                seconds: 6000,
+                finalized_block_number: Some(1989),
+            };
+            db.store_finality_lag(CHAIN, &finality_lag).await?;
+
+            let calcs = calculate_for_chain(CHAIN, db, vec![TpsWindow::Day], false, None).await?;
+            let finalized_tps = calcs.stats.windows[&TpsWindow::Day]
+                .finalized_tps
+                .expect("finalized tps");
+            assert!((finalized_tps - 0.1).abs() < 1e-9);
+            assert!(calcs.tps[&TpsWindow::Day] > 0.1);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn calculate_historical_range() -> Result<(), anyhow::Error> {
+        for with_index in [false, true] {
+            let db = make_db(with_index).await?;
+
+            let from = GENESIS_TIMESTAMP + 100 * 600;
+            let to = GENESIS_TIMESTAMP + 200 * 600 + 300;
+            let range_calcs = calculate_for_range(CHAIN, &db, from, to, false)
+                .await?
+                .expect("range");
+            assert_eq!(range_calcs.from, from);
+            assert_eq!(range_calcs.to, GENESIS_TIMESTAMP + 200 * 600);
+            assert_eq!(range_calcs.num_txs, 6000);
+            assert!((range_calcs.tps - 0.1).abs() < 1e-9);
+
+            // Past the latest block
+            let range_calcs = calculate_for_range(CHAIN, &db, from, u64::MAX, false)
+                .await?
+                .expect("range");
+            assert_eq!(range_calcs.to, GENESIS_TIMESTAMP + 1999 * 600);
+            assert_eq!(range_calcs.num_txs, 1899 * 60);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn calculate_fees() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let mut blocks = make_blocks(CHAIN, 100, 600, 60);
+        for block in &mut blocks {
+            block.fees = Some(6000);
+        }
+        db.store_blocks(CHAIN, blocks).await?;
+        db.store_highest_block_number(CHAIN, 99).await?;
+
+        let calcs = calculate_for_chain(CHAIN, db, vec![TpsWindow::Day], false, None).await?;
+        let stats = &calcs.stats.windows[&TpsWindow::Day];
+        assert_eq!(stats.fee_per_tx, Some(100.0));
+        assert_eq!(stats.fees_per_second, Some(10.0));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn calculate_token_transfers() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let mut blocks = make_blocks(CHAIN, 100, 600, 60);
+        for block in &mut blocks {
+            block.num_token_transfers = Some(300);
+        }
+        db.store_blocks(CHAIN, blocks).await?;
+        db.store_highest_block_number(CHAIN, 99).await?;
+
+        let calcs = calculate_for_chain(CHAIN, db, vec![TpsWindow::Day], false, None).await?;
+        let stats = &calcs.stats.windows[&TpsWindow::Day];
+        assert_eq!(stats.token_transfers_per_second, Some(0.5));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn calculate_receipts() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let mut blocks = make_blocks(CHAIN, 100, 600, 60);
+        for block in &mut blocks {
+            block.num_receipts = Some(120);
+        }
+        db.store_blocks(CHAIN, blocks).await?;
+        db.store_highest_block_number(CHAIN, 99).await?;
+
+        let calcs = calculate_for_chain(CHAIN, db, vec![TpsWindow::Day], false, None).await?;
+        let stats = &calcs.stats.windows[&TpsWindow::Day];
+        assert_eq!(stats.receipts_per_second, Some(0.2));
+        // Receipts aren't transactions
+        assert!((calcs.tps[&TpsWindow::Day] - 0.1).abs() < 1e-9);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn calculate_internal_calls() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let mut blocks = make_blocks(CHAIN, 100, 600, 60);
+        for block in &mut blocks {
+            block.num_internal_calls = Some(600);
+        }
+        db.store_blocks(CHAIN, blocks).await?;
+        db.store_highest_block_number(CHAIN, 99).await?;
+
+        let calcs = calculate_for_chain(CHAIN, db, vec![TpsWindow::Day], false, None).await?;
+        let stats = &calcs.stats.windows[&TpsWindow::Day];
+        assert_eq!(stats.internal_calls_per_second, Some(1.0));
+        Ok(())
     }
 
-    Ok(tps)
+    #[tokio::test]
+    async fn calculate_value_transferred() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let mut blocks = make_blocks(CHAIN, 100, 600, 60);
+        for block in &mut blocks {
+            // 6 whole tokens
+            block.value_transferred = Some(6 * 10u128.pow(CHAIN.native_decimals()));
+        }
+        db.store_blocks(CHAIN, blocks).await?;
+        db.store_highest_block_number(CHAIN, 99).await?;
+
+        let mut calcs = calculate_for_chain(CHAIN, db, vec![TpsWindow::Day], false, None).await?;
+        let value_per_second = calcs.stats.windows[&TpsWindow::Day]
+            .value_per_second
+            .expect("value per second");
+        assert!((value_per_second - 0.01).abs() < 1e-9);
+
+        convert_value_to_usd(&mut calcs.stats, 2.0);
+        let value_usd_per_second = calcs.stats.windows[&TpsWindow::Day]
+            .value_usd_per_second
+            .expect("usd per second");
+        assert!((value_usd_per_second - 0.02).abs() < 1e-9);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn calculate_block_times() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let mut blocks = make_blocks(CHAIN, 100, 600, 60);
+        // Alternating 500 and 700 seconds between blocks
+        for block in blocks
+            .iter_mut()
+            .filter(|block| block.block_number % 2 == 1)
+        {
+            block.timestamp -= 100;
+        }
+        db.store_blocks(CHAIN, blocks).await?;
+        db.store_highest_block_number(CHAIN, 99).await?;
+
+        let windows = vec![TpsWindow::Hour, TpsWindow::Week];
+        let calcs = calculate_for_chain(CHAIN, db, windows, false, None).await?;
+        let block_times = calcs.stats.block_times.expect("block times");
+        assert_eq!(block_times.window, TpsWindow::Hour);
+        assert_eq!(block_times.mean, 600.0);
+        assert_eq!(block_times.median, 600.0);
+        assert_eq!(block_times.std_dev, 100.0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn calculate_max_tps() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let mut blocks = make_blocks(CHAIN, 100, 600, 60);
+        for block in &mut blocks {
+            block.gas_used = Some(60 * 21_000);
+            block.gas_limit = Some(240 * 21_000);
+        }
+        // A block of expensive transactions doesn't move the median
+        blocks[99].gas_used = Some(60 * 100_000);
+        db.store_blocks(CHAIN, blocks).await?;
+        db.store_highest_block_number(CHAIN, 99).await?;
+
+        let windows = vec![TpsWindow::Hour, TpsWindow::Week];
+        let calcs = calculate_for_chain(CHAIN, db, windows, false, None).await?;
+        let max_tps = calcs.stats.max_tps.expect("max tps");
+        assert_eq!(max_tps.window, TpsWindow::Hour);
+        assert_eq!(max_tps.median_gas_per_tx, 21_000.0);
+        // Four times the observed 0.1 tps
+        assert!((max_tps.max_tps - 0.4).abs() < 1e-9);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn calculate_txs_per_block() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        // Blocks with 1 to 99 txs, then a burst of 1000
+        let mut blocks = make_blocks(CHAIN, 100, 1, 0);
+        for block in &mut blocks {
+            block.num_txs = block.block_number + 1;
+        }
+        blocks[99].num_txs = 1000;
+        db.store_blocks(CHAIN, blocks).await?;
+        db.store_highest_block_number(CHAIN, 99).await?;
+
+        let windows = vec![TpsWindow::Hour];
+        let calcs = calculate_for_chain(CHAIN, db, windows, false, None).await?;
+        let txs_per_block = calcs.stats.txs_per_block.expect("txs per block");
+        assert_eq!(txs_per_block.window, TpsWindow::Hour);
+        assert_eq!(txs_per_block.p50, 50);
+        assert_eq!(txs_per_block.p90, 90);
+        assert_eq!(txs_per_block.p99, 99);
+        assert_eq!(txs_per_block.max, 1000);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn calculate_producers() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        // Producer 0 makes 40 blocks, 1 makes 30, and 2 and 3 make 15 each
+        let mut blocks = make_blocks(CHAIN, 100, 1, 1);
+        for block in &mut blocks {
+            let producer = match block.block_number {
+                0..=39 => 0,
+                40..=69 => 1,
+                70..=84 => 2,
+                _ => 3,
+            };
+            block.producer = Some(format!("{}", producer));
+        }
+        db.store_blocks(CHAIN, blocks).await?;
+        db.store_highest_block_number(CHAIN, 99).await?;
+
+        let windows = vec![TpsWindow::Hour];
+        let calcs = calculate_for_chain(CHAIN, db, windows, false, None).await?;
+        let producers = calcs.stats.producers.expect("producers");
+        assert_eq!(producers.num_producers, 4);
+        assert!((producers.top_share - 0.4).abs() < 1e-9);
+        assert_eq!(producers.nakamoto_coefficient, 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn calculate_raw_tps() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let mut blocks = make_blocks(CHAIN, 100, 600, 60);
+        for block in &mut blocks {
+            block.num_vote_txs = Some(540);
+        }
+        db.store_blocks(CHAIN, blocks).await?;
+        db.store_highest_block_number(CHAIN, 99).await?;
+
+        let calcs = calculate_for_chain(CHAIN, db, vec![TpsWindow::Day], false, None).await?;
+        assert!((calcs.tps[&TpsWindow::Day] - 0.1).abs() < 1e-9);
+        let raw_tps = calcs.stats.windows[&TpsWindow::Day]
+            .raw_tps
+            .expect("raw tps");
+        assert!((raw_tps - 1.0).abs() < 1e-9);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn calculate_excluding_failed_txs() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let mut blocks = make_blocks(CHAIN, 100, 600, 60);
+        for block in &mut blocks {
+            block.num_txs_failed = Some(30);
+        }
+        db.store_blocks(CHAIN, blocks).await?;
+        db.store_highest_block_number(CHAIN, 99).await?;
+
+        let windows = vec![TpsWindow::Day];
+        let calcs = calculate_for_chain(CHAIN, db.clone(), windows.clone(), false, None).await?;
+        assert!((calcs.tps[&TpsWindow::Day] - 0.1).abs() < 1e-9);
+        let calcs = calculate_for_chain(CHAIN, db, windows, true, None).await?;
+        assert!((calcs.tps[&TpsWindow::Day] - 0.05).abs() < 1e-9);
+        Ok(())
+    }
+
+    #[test]
+    fn smooth_published_tps() {
+        assert_eq!(smooth_tps(None, 10.0, 0.25), 10.0);
+        assert_eq!(smooth_tps(Some(2.0), 10.0, 0.25), 4.0);
+        assert_eq!(smooth_tps(Some(2.0), 10.0, 1.0), 10.0);
+    }
+
+    #[tokio::test]
+    async fn calculate_coverage() -> Result<(), anyhow::Error> {
+        // A day and a half of blocks
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        db.store_blocks(CHAIN, make_blocks(CHAIN, 217, 600, 60))
+            .await?;
+        db.store_highest_block_number(CHAIN, 216).await?;
+
+        let windows = vec![TpsWindow::Day, TpsWindow::Week];
+        let calcs = calculate_for_chain(CHAIN, db, windows, false, None).await?;
+        assert_eq!(calcs.stats.windows[&TpsWindow::Day].coverage, Some(1.0));
+        let coverage = calcs.stats.windows[&TpsWindow::Week].coverage;
+        assert!((coverage.expect("coverage") - 1.5 / 7.0).abs() < 1e-9);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn calculate_over_max_window() -> Result<(), anyhow::Error> {
+        let db = make_db(true).await?;
+        let windows = vec![TpsWindow::Hour, TpsWindow::Week];
+        let calcs = calculate_for_chain(CHAIN, db, windows, false, Some(TpsWindow::Day)).await?;
+        for window in [TpsWindow::Hour, TpsWindow::Week] {
+            assert!((calcs.tps[&window] - 0.1).abs() < 1e-9);
+        }
+        let week_stats = &calcs.stats.windows[&TpsWindow::Week];
+        assert_eq!(week_stats.calculated_over, Some(TpsWindow::Day));
+        assert_eq!(week_stats.coverage, Some(1.0));
+        let hour_stats = &calcs.stats.windows[&TpsWindow::Hour];
+        assert_eq!(hour_stats.calculated_over, Some(TpsWindow::Hour));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn calculate_short_history() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        db.store_blocks(CHAIN, make_blocks(CHAIN, 100, 600, 60))
+            .await?;
+        db.store_highest_block_number(CHAIN, 99).await?;
+
+        let calcs = calculate_for_chain(CHAIN, db, vec![TpsWindow::Week], false, None).await?;
+        assert!((calcs.tps[&TpsWindow::Week] - 0.1).abs() < 1e-9);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_windows() {
+        assert_eq!(parse_window("90m").unwrap(), 90 * 60);
+        assert_eq!(parse_window("24h").unwrap(), TpsWindow::Day.seconds());
+        assert_eq!(parse_window("7d").unwrap(), TpsWindow::Week.seconds());
+        assert!(parse_window("24").is_err());
+        assert!(parse_window("h").is_err());
+        assert!(parse_window("2w").is_err());
+        assert!(parse_window("0s").is_err());
+    }
 }
@@ -0,0 +1,160 @@
+use anyhow::{bail, Result};
+use log::{error, info, warn};
+use realtps_common::{
+    chain::Chain,
+    db::{Block, Db},
+};
+use std::sync::Arc;
+
+/// What to do with blocks that can't be used.
+#[derive(clap::ArgEnum, Debug, Copy, Clone)]
+pub enum Fix {
+    Delete,
+    Quarantine,
+}
+
+#[derive(Default)]
+struct CheckReport {
+    num_blocks: usize,
+    /// Blocks that can't be loaded
+    corrupt: Vec<u64>,
+    /// Blocks that are stored under the wrong chain or number, so duplicate
+    /// another height
+    misplaced: Vec<u64>,
+    /// Blocks whose previous block is stored but has the wrong hash, or whose
+    /// previous block number isn't lower than their own
+    broken_links: Vec<u64>,
+    /// Blocks with an earlier timestamp than their previous block
+    non_monotonic: Vec<u64>,
+}
+
+impl CheckReport {
+    /// Blocks that should be removed from the db.
+    fn bad_blocks(&self) -> impl Iterator<Item = &u64> {
+        self.corrupt.iter().chain(self.misplaced.iter())
+    }
+
+    fn num_problems(&self) -> usize {
+        self.corrupt.len()
+            + self.misplaced.len()
+            + self.broken_links.len()
+            + self.non_monotonic.len()
+    }
+}
+
+/// Checks the stored blocks of each chain, optionally removing the ones that
+/// can't be used. Fails if any problems remain.
+pub async fn check(chains: &[Chain], db: &Arc<dyn Db>, fix: Option<Fix>) -> Result<()> {
+    let mut num_unfixed_problems = 0;
+
+    for chain in chains {
+        let chain = *chain;
+        let report = check_chain(chain, db).await?;
+
+        info!(
+            "checked {} blocks for chain {}: {} corrupt, {} misplaced, {} broken links, {} non-monotonic timestamps",
+            report.num_blocks,
+            chain,
+            report.corrupt.len(),
+            report.misplaced.len(),
+            report.broken_links.len(),
+            report.non_monotonic.len(),
+        );
+
+        for block_number in &report.corrupt {
+            error!("corrupt block {} for chain {}", block_number, chain);
+        }
+        for block_number in &report.misplaced {
+            error!("misplaced block {} for chain {}", block_number, chain);
+        }
+        for block_number in &report.broken_links {
+            warn!(
+                "block {} for chain {} doesn't link to its previous block",
+                block_number, chain
+            );
+        }
+        for block_number in &report.non_monotonic {
+            warn!(
+                "block {} for chain {} is older than its previous block",
+                block_number, chain
+            );
+        }
+
+        num_unfixed_problems += report.num_problems();
+
+        if let Some(fix) = fix {
+            for block_number in report.bad_blocks() {
+                match fix {
+                    Fix::Delete => db.remove_block(chain, *block_number).await?,
+                    Fix::Quarantine => db.quarantine_block(chain, *block_number).await?,
+                }
+                info!("{:?}: block {} for chain {}", fix, block_number, chain);
+                num_unfixed_problems -= 1;
+            }
+            db.flush().await?;
+        }
+    }
+
+    if num_unfixed_problems > 0 {
+        bail!("found {} problems", num_unfixed_problems);
+    }
+
+    Ok(())
+}
+
+async fn check_chain(chain: Chain, db: &Arc<dyn Db>) -> Result<CheckReport> {
+    let block_numbers = db.load_block_numbers(chain).await?;
+
+    let mut report = CheckReport {
+        num_blocks: block_numbers.len(),
+        ..Default::default()
+    };
+
+    let mut prev_block: Option<Block> = None;
+
+    for block_number in block_numbers {
+        let block = match db.load_block(chain, block_number).await {
+            Ok(Some(block)) => block,
+            Ok(None) => {
+                // Removed while we were checking
+                continue;
+            }
+            Err(e) => {
+                warn!(
+                    "error loading block {} for chain {}: {}",
+                    block_number, chain, e
+                );
+                report.corrupt.push(block_number);
+                continue;
+            }
+        };
+
+        if block.chain != chain || block.block_number != block_number {
+            report.misplaced.push(block_number);
+            continue;
+        }
+
+        match block.prev_block_number {
+            Some(prev_block_number) if prev_block_number >= block_number => {
+                report.broken_links.push(block_number);
+            }
+            Some(prev_block_number) => {
+                if let Some(prev_block) = &prev_block {
+                    if prev_block.block_number == prev_block_number {
+                        if prev_block.hash != block.parent_hash {
+                            report.broken_links.push(block_number);
+                        }
+                        if prev_block.timestamp > block.timestamp {
+                            report.non_monotonic.push(block_number);
+                        }
+                    }
+                }
+            }
+            None => {}
+        }
+
+        prev_block = Some(block);
+    }
+
+    Ok(report)
+}
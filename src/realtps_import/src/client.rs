@@ -1,6 +1,13 @@
 use anyhow::Result;
 use async_trait::async_trait;
-use realtps_common::db::Block;
+use futures::future;
+use realtps_common::db::{Block, EndpointHealth};
+
+/// The newest block that can't be reverted.
+pub struct FinalizedBlock {
+    pub block_number: u64,
+    pub timestamp: u64,
+}
 
 #[async_trait]
 pub trait Client: Send + Sync + 'static {
@@ -8,4 +15,33 @@ pub trait Client: Send + Sync + 'static {
     async fn get_latest_block_number(&self) -> Result<u64>;
     /// Returns `None` if the network thinks the block doesn't exist
     async fn get_block(&self, block_number: u64) -> Result<Option<Block>>;
+    /// Fetches several blocks at once, leaving out any that fail or don't
+    /// exist. Clients whose RPCs take batches of requests override it to
+    /// fetch them in fewer round trips.
+    async fn get_blocks(&self, block_numbers: &[u64]) -> Result<Vec<Block>> {
+        let blocks = future::join_all(
+            block_numbers
+                .iter()
+                .map(|block_number| self.get_block(*block_number)),
+        )
+        .await;
+        Ok(blocks
+            .into_iter()
+            .filter_map(|block| block.ok().flatten())
+            .collect())
+    }
+    /// For chains where the latest block may not be final yet. Returns `None`
+    /// if the client can't tell.
+    async fn get_finalized_block(&self) -> Result<Option<FinalizedBlock>> {
+        Ok(None)
+    }
+    /// The chain's own measure of its recent TPS, including consensus
+    /// transactions like votes, for chains that publish one.
+    async fn get_native_tps(&self) -> Result<Option<f64>> {
+        Ok(None)
+    }
+    /// How each endpoint has been performing, for clients of several.
+    fn endpoint_health(&self) -> Option<EndpointHealth> {
+        None
+    }
 }
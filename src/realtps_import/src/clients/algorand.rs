@@ -1,46 +1,78 @@
 use crate::client::Client;
 use algonaut::{algod::v2::Algod, indexer::v2::Indexer};
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use hex::ToHex;
-use realtps_common::{chain::Chain, db::Block};
+use realtps_common::{chain::Chain, db::Block, sketch::SenderSketch};
+use reqwest::header::HeaderMap;
+use std::future::Future;
+use std::time::Duration;
 
 pub struct AlgorandClient {
     algod: Algod,
     indexer: Indexer,
+    request_timeout: Duration,
 }
 
 impl AlgorandClient {
-    pub fn new(urls: &str) -> Result<Self> {
+    /// Algonaut makes its own HTTP clients, so takes the headers for both
+    /// APIs and the request timeout rather than a client.
+    pub fn new(urls: &str, headers: &HeaderMap, request_timeout: Duration) -> Result<Self> {
         let urls: Vec<&str> = urls.split(';').collect();
         let algod_url = urls.first().expect("algorand algod url");
         let indexer_url = urls.get(1).expect("algorand indexer url");
+        let headers = headers
+            .iter()
+            .map(|(name, value)| Ok((name.as_str(), value.to_str()?)))
+            .collect::<Result<Vec<_>>>()?;
         Ok(Self {
-            algod: Algod::with_headers(algod_url, vec![])?,
-            indexer: Indexer::with_headers(indexer_url, vec![])?,
+            algod: Algod::with_headers(algod_url, headers.clone())?,
+            indexer: Indexer::with_headers(indexer_url, headers)?,
+            request_timeout,
         })
     }
+
+    /// Algonaut's HTTP clients never time out, so requests are timed out
+    /// here.
+    async fn timed<T, E>(&self, request: impl Future<Output = Result<T, E>>) -> Result<T>
+    where
+        E: Into<anyhow::Error>,
+    {
+        tokio::time::timeout(self.request_timeout, request)
+            .await
+            .map_err(|_| anyhow!("timed out after {:?}", self.request_timeout))?
+            .map_err(Into::into)
+    }
 }
 
 #[async_trait]
 impl Client for AlgorandClient {
     async fn client_version(&self) -> Result<String> {
-        let versions = self.algod.versions().await?;
+        let versions = self.timed(self.algod.versions()).await?;
         Ok(versions.build.semver())
     }
 
     async fn get_latest_block_number(&self) -> Result<u64> {
-        let status = self.algod.status().await?;
+        let status = self.timed(self.algod.status()).await?;
         Ok(status.last_round)
     }
 
     async fn get_block(&self, block_number: u64) -> Result<Option<Block>> {
         let block = self
-            .algod
-            .block_with_certificate(block_number.into())
+            .timed(self.algod.block_with_certificate(block_number.into()))
             .await?;
 
-        let indexer_block = self.indexer.block(block_number.into()).await?;
+        let indexer_block = self.timed(self.indexer.block(block_number.into())).await?;
+
+        let mut senders = SenderSketch::default();
+        let mut value_transferred: u128 = 0;
+        for tx in &indexer_block.transactions {
+            senders.insert(&tx.sender);
+            if let Some(payment) = &tx.payment_transaction {
+                let close_amount = payment.close_amount.map_or(0, |amount| amount.0);
+                value_transferred += u128::from(payment.amount.0) + u128::from(close_amount);
+            }
+        }
 
         Ok(Some(Block {
             chain: Chain::Algorand,
@@ -54,6 +86,17 @@ impl Client for AlgorandClient {
             num_txs: indexer_block.transactions.len() as u64,
             hash: block.hash().encode_hex(),
             parent_hash: indexer_block.previous_block_hash.encode_hex(),
+            gas_used: None,
+            gas_limit: None,
+            fees: Some(indexer_block.transactions.iter().map(|tx| tx.fee).sum()),
+            value_transferred: Some(value_transferred),
+            num_token_transfers: None,
+            num_receipts: None,
+            num_internal_calls: None,
+            producer: None,
+            num_vote_txs: None,
+            num_txs_failed: None,
+            senders: Some(senders),
         }))
     }
 }
@@ -63,9 +106,15 @@ mod test_algorand {
     use anyhow::Result;
 
     use super::{AlgorandClient, Client};
+    use reqwest::header::HeaderMap;
+    use std::time::Duration;
 
     fn create_client() -> Result<AlgorandClient> {
-        AlgorandClient::new("https://mainnet-api.algonode.cloud;https://mainnet-idx.algonode.cloud")
+        AlgorandClient::new(
+            "https://mainnet-api.algonode.cloud;https://mainnet-idx.algonode.cloud",
+            &HeaderMap::new(),
+            Duration::from_secs(30),
+        )
     }
 
     #[tokio::test]
@@ -9,9 +9,9 @@ pub struct ElrondClient {
 }
 
 impl ElrondClient {
-    pub fn new(url: &str) -> Result<Self> {
+    pub fn new(url: &str, client: reqwest::Client) -> Result<Self> {
         Ok(ElrondClient {
-            client: reqwest::Client::new(),
+            client,
             url: url.to_string(),
         })
     }
@@ -130,6 +130,17 @@ impl Client for ElrondClient {
                     num_txs,
                     hash,
                     parent_hash,
+                    gas_used: None,
+                    gas_limit: None,
+                    fees: None,
+                    value_transferred: None,
+                    num_token_transfers: None,
+                    num_receipts: None,
+                    num_internal_calls: None,
+                    producer: None,
+                    num_vote_txs: None,
+                    num_txs_failed: None,
+                    senders: None,
                 }))
             }
         }
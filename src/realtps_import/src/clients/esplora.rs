@@ -6,6 +6,7 @@ use serde::Deserialize;
 use std::str::FromStr;
 
 pub struct EsploraClient {
+    client: reqwest::Client,
     url: String,
 }
 
@@ -20,8 +21,9 @@ struct EsploraBlock {
 }
 
 impl EsploraClient {
-    pub fn new(url: &str) -> Result<Self> {
+    pub fn new(url: &str, client: reqwest::Client) -> Result<Self> {
         Ok(EsploraClient {
+            client,
             url: url.to_string(),
         })
     }
@@ -30,11 +32,17 @@ impl EsploraClient {
 #[async_trait]
 impl Client for EsploraClient {
     async fn client_version(&self) -> Result<String> {
-        let block_hash = reqwest::get(format!("{}/{}", self.url, "blocks/tip/hash"))
+        let block_hash = self
+            .client
+            .get(format!("{}/{}", self.url, "blocks/tip/hash"))
+            .send()
             .await?
             .text()
             .await?;
-        let block: EsploraBlock = reqwest::get(format!("{}/{}/{}", self.url, "block", block_hash))
+        let block: EsploraBlock = self
+            .client
+            .get(format!("{}/{}/{}", self.url, "block", block_hash))
+            .send()
             .await?
             .json()
             .await?;
@@ -43,7 +51,10 @@ impl Client for EsploraClient {
     }
 
     async fn get_latest_block_number(&self) -> Result<u64> {
-        let block_number = reqwest::get(format!("{}/{}", self.url, "blocks/tip/height"))
+        let block_number = self
+            .client
+            .get(format!("{}/{}", self.url, "blocks/tip/height"))
+            .send()
             .await?
             .text()
             .await?;
@@ -52,22 +63,31 @@ impl Client for EsploraClient {
     }
 
     async fn get_block(&self, block_number: u64) -> Result<Option<Block>> {
-        let block_hash = reqwest::get(format!("{}/{}/{}", self.url, "block-height", block_number))
+        let block_hash = self
+            .client
+            .get(format!("{}/{}/{}", self.url, "block-height", block_number))
+            .send()
             .await?
             .text()
             .await?;
-        let block: EsploraBlock = reqwest::get(format!("{}/{}/{}", self.url, "block", block_hash))
+        let block: EsploraBlock = self
+            .client
+            .get(format!("{}/{}/{}", self.url, "block", block_hash))
+            .send()
             .await?
             .json()
             .await?;
 
-        let prev_block: EsploraBlock = reqwest::get(format!(
-            "{}/{}/{}",
-            self.url, "block", block.previousblockhash
-        ))
-        .await?
-        .json()
-        .await?;
+        let prev_block: EsploraBlock = self
+            .client
+            .get(format!(
+                "{}/{}/{}",
+                self.url, "block", block.previousblockhash
+            ))
+            .send()
+            .await?
+            .json()
+            .await?;
 
         let block = Block {
             chain: Chain::Bitcoin,
@@ -77,6 +97,17 @@ impl Client for EsploraClient {
             num_txs: u64::from(block.tx_count),
             hash: block.id,
             parent_hash: block.previousblockhash,
+            gas_used: None,
+            gas_limit: None,
+            fees: None,
+            value_transferred: None,
+            num_token_transfers: None,
+            num_receipts: None,
+            num_internal_calls: None,
+            producer: None,
+            num_vote_txs: None,
+            num_txs_failed: None,
+            senders: None,
         };
 
         Ok(Some(block))
@@ -1,21 +1,363 @@
-use crate::client::Client;
+use crate::client::{Client, FinalizedBlock};
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use ethers::prelude::*;
 use ethers::utils::hex::ToHex;
 use realtps_common::{chain::Chain, db::Block};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::json;
 
 pub struct EthersClient {
     chain: Chain,
     provider: Provider<Http>,
+    /// For batch requests, which this version of ethers can't make
+    http: reqwest::Client,
+    url: String,
+    /// Fetch receipts to count failed transactions, which costs an extra
+    /// request per block
+    count_failed_txs: bool,
+    /// Fetch the block's logs to count token transfers, which costs an extra
+    /// request per block
+    count_token_transfers: bool,
+    /// Fetch the block's header and transaction count instead of the block
+    /// with its transaction hashes
+    light: bool,
+    /// Trace the block's transactions to count internal calls, which costs
+    /// an extra, and expensive, request per block
+    trace_method: Option<TraceMethod>,
+}
+
+/// How a node traces a block's transactions. Both need the node to keep the
+/// block's state, and providers usually charge more for them.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TraceMethod {
+    /// `trace_block`, served by Erigon, Nethermind and OpenEthereum
+    Trace,
+    /// `debug_traceBlockByNumber` with the call tracer, served by Geth
+    Debug,
+}
+
+impl TraceMethod {
+    fn method(self) -> &'static str {
+        match self {
+            TraceMethod::Trace => "trace_block",
+            TraceMethod::Debug => "debug_traceBlockByNumber",
+        }
+    }
+
+    fn params(self, block_number: u64) -> serde_json::Value {
+        match self {
+            TraceMethod::Trace => json!([U64::from(block_number)]),
+            TraceMethod::Debug => json!([U64::from(block_number), { "tracer": "callTracer" }]),
+        }
+    }
+
+    /// Counts the calls, creations and self-destructs in a block's traces
+    /// that aren't the transactions themselves.
+    fn count_internal_calls(self, traces: serde_json::Value) -> Result<u64> {
+        match self {
+            TraceMethod::Trace => {
+                // Transactions, and block rewards, are at the root of the
+                // trace tree
+                let traces: Vec<FlatTrace> = serde_json::from_value(traces)?;
+                let num_calls = traces
+                    .iter()
+                    .filter(|trace| !trace.trace_address.is_empty())
+                    .count();
+                Ok(u64::try_from(num_calls)?)
+            }
+            TraceMethod::Debug => {
+                let traces: Vec<TxCallTrace> = serde_json::from_value(traces)?;
+                Ok(traces
+                    .iter()
+                    .filter_map(|trace| trace.result.as_ref())
+                    .map(CallFrame::num_subcalls)
+                    .sum())
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FlatTrace {
+    #[serde(default)]
+    trace_address: Vec<u64>,
+}
+
+#[derive(Deserialize)]
+struct TxCallTrace {
+    #[serde(default)]
+    result: Option<CallFrame>,
+}
+
+#[derive(Deserialize)]
+struct CallFrame {
+    #[serde(default)]
+    calls: Vec<CallFrame>,
+}
+
+impl CallFrame {
+    fn num_subcalls(&self) -> u64 {
+        self.calls.iter().map(|call| 1 + call.num_subcalls()).sum()
+    }
+}
+
+/// The event of both ERC-20 and ERC-721 transfers, which differ only in
+/// whether the last argument is indexed.
+const TRANSFER_EVENT: &str = "Transfer(address,address,uint256)";
+
+/// The most requests to send in one JSON-RPC batch. Many providers reject
+/// larger batches.
+const MAX_BATCH_SIZE: usize = 50;
+
+#[derive(Deserialize)]
+struct BatchResponse<T> {
+    id: usize,
+    result: Option<T>,
 }
 
 impl EthersClient {
-    pub fn new(chain: Chain, url: &str) -> Result<Self> {
-        let provider = Provider::<Http>::try_from(url)?;
+    pub fn new(
+        chain: Chain,
+        url: &str,
+        count_failed_txs: bool,
+        count_token_transfers: bool,
+        light: bool,
+        trace_method: Option<TraceMethod>,
+        http: reqwest::Client,
+    ) -> Result<Self> {
+        let provider = Provider::new(Http::new_with_client(
+            reqwest::Url::parse(url)?,
+            http.clone(),
+        ));
+
+        Ok(EthersClient {
+            chain,
+            provider,
+            http,
+            url: url.to_string(),
+            count_failed_txs,
+            count_token_transfers,
+            light,
+            trace_method,
+        })
+    }
+
+    /// Fetches a block's header and transaction count, a fraction of the
+    /// size of the block. `eth_getHeaderByNumber` isn't standard, but Geth
+    /// and Erigon serve it.
+    async fn get_light_block(&self, block_number: u64) -> Result<Option<Block>> {
+        let (header, num_txs) = tokio::try_join!(
+            self.provider
+                .request::<_, Option<ethers::prelude::Block<H256>>>(
+                    "eth_getHeaderByNumber",
+                    [U64::from(block_number)]
+                ),
+            self.provider.request::<_, Option<U64>>(
+                "eth_getBlockTransactionCountByNumber",
+                [U64::from(block_number)]
+            ),
+        )?;
+        header
+            .zip(num_txs)
+            .map(|(header, num_txs)| light_block(self.chain, header, num_txs))
+            .transpose()
+    }
+
+    async fn count_failed_txs(&self, block_number: u64) -> Result<u64> {
+        let receipts = self.provider.get_block_receipts(block_number).await?;
+        count_failed_receipts(&receipts)
+    }
+
+    async fn count_token_transfers(&self, block_number: u64) -> Result<u64> {
+        let logs = self
+            .provider
+            .get_logs(&transfer_filter(block_number))
+            .await?;
+        Ok(u64::try_from(logs.len())?)
+    }
 
-        Ok(EthersClient { chain, provider })
+    async fn count_internal_calls(
+        &self,
+        trace_method: TraceMethod,
+        block_number: u64,
+    ) -> Result<u64> {
+        let traces: serde_json::Value = self
+            .provider
+            .request(trace_method.method(), trace_method.params(block_number))
+            .await?;
+        trace_method.count_internal_calls(traces)
     }
+
+    /// Makes the same request with each of `params` in one JSON-RPC batch,
+    /// returning the results in the same order, with `None` for any that
+    /// failed or were null.
+    async fn batch_request<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: Vec<serde_json::Value>,
+    ) -> Result<Vec<Option<T>>> {
+        let num_requests = params.len();
+        let requests: Vec<_> = params
+            .into_iter()
+            .enumerate()
+            .map(|(id, params)| {
+                json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "method": method,
+                    "params": params,
+                })
+            })
+            .collect();
+
+        let responses: Vec<BatchResponse<T>> = self
+            .http
+            .post(&self.url)
+            .json(&requests)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        // Responses may come in any order
+        let mut results: Vec<Option<T>> = (0..num_requests).map(|_| None).collect();
+        for response in responses {
+            if let Some(result) = results.get_mut(response.id) {
+                *result = response.result;
+            }
+        }
+        Ok(results)
+    }
+
+    /// Fetches blocks in one batch request, and their receipts, logs and
+    /// traces in one more each if counted. Blocks missing any of them are left out.
+    async fn get_block_batch(&self, block_numbers: &[u64]) -> Result<Vec<Block>> {
+        let fetched_blocks: Vec<Option<Block>> = if self.light {
+            let params: Vec<_> = block_numbers
+                .iter()
+                .map(|block_number| json!([U64::from(*block_number)]))
+                .collect();
+            let headers: Vec<Option<ethers::prelude::Block<H256>>> = self
+                .batch_request("eth_getHeaderByNumber", params.clone())
+                .await?;
+            let tx_counts: Vec<Option<U64>> = self
+                .batch_request("eth_getBlockTransactionCountByNumber", params)
+                .await?;
+            headers
+                .into_iter()
+                .zip(tx_counts)
+                .map(|(header, num_txs)| {
+                    header
+                        .zip(num_txs)
+                        .map(|(header, num_txs)| light_block(self.chain, header, num_txs))
+                        .transpose()
+                })
+                .collect::<Result<_>>()?
+        } else {
+            let block_params = block_numbers
+                .iter()
+                .map(|block_number| json!([U64::from(*block_number), false]))
+                .collect();
+            let eth_blocks: Vec<Option<ethers::prelude::Block<H256>>> = self
+                .batch_request("eth_getBlockByNumber", block_params)
+                .await?;
+            eth_blocks
+                .into_iter()
+                .map(|eth_block| {
+                    eth_block
+                        .map(|eth_block| ethers_block_to_block(self.chain, eth_block))
+                        .transpose()
+                })
+                .collect::<Result<_>>()?
+        };
+
+        let mut receipts: Option<Vec<Option<Vec<TransactionReceipt>>>> = None;
+        if self.count_failed_txs {
+            let receipt_params = block_numbers
+                .iter()
+                .map(|block_number| json!([U64::from(*block_number)]))
+                .collect();
+            receipts = Some(
+                self.batch_request("eth_getBlockReceipts", receipt_params)
+                    .await?,
+            );
+        }
+        let mut logs: Option<Vec<Option<Vec<Log>>>> = None;
+        if self.count_token_transfers {
+            let log_params = block_numbers
+                .iter()
+                .map(|block_number| json!([transfer_filter(*block_number)]))
+                .collect();
+            logs = Some(self.batch_request("eth_getLogs", log_params).await?);
+        }
+        let mut traces: Option<Vec<Option<serde_json::Value>>> = None;
+        if let Some(trace_method) = self.trace_method {
+            let trace_params = block_numbers
+                .iter()
+                .map(|block_number| trace_method.params(*block_number))
+                .collect();
+            traces = Some(
+                self.batch_request(trace_method.method(), trace_params)
+                    .await?,
+            );
+        }
+
+        let mut blocks = vec![];
+        for (i, block) in fetched_blocks.into_iter().enumerate() {
+            let mut block = match block {
+                Some(block) => block,
+                None => continue,
+            };
+            if let Some(receipts) = &mut receipts {
+                match receipts[i].take() {
+                    Some(receipts) => {
+                        block.num_txs_failed = Some(count_failed_receipts(&receipts)?)
+                    }
+                    None => continue,
+                }
+            }
+            if let Some(logs) = &mut logs {
+                match logs[i].take() {
+                    Some(logs) => block.num_token_transfers = Some(u64::try_from(logs.len())?),
+                    None => continue,
+                }
+            }
+            if let (Some(traces), Some(trace_method)) = (&mut traces, self.trace_method) {
+                match traces[i].take() {
+                    Some(traces) => {
+                        block.num_internal_calls = Some(trace_method.count_internal_calls(traces)?)
+                    }
+                    None => continue,
+                }
+            }
+            blocks.push(block);
+        }
+        Ok(blocks)
+    }
+}
+
+/// A header has no transaction hashes to count, so they're counted apart.
+fn light_block(chain: Chain, header: ethers::prelude::Block<H256>, num_txs: U64) -> Result<Block> {
+    let mut block = ethers_block_to_block(chain, header)?;
+    block.num_txs = num_txs.as_u64();
+    Ok(block)
+}
+
+fn count_failed_receipts(receipts: &[TransactionReceipt]) -> Result<u64> {
+    // Receipts from before Byzantium have no status
+    let num_failed = receipts
+        .iter()
+        .filter(|receipt| receipt.status == Some(U64::zero()))
+        .count();
+    Ok(u64::try_from(num_failed)?)
+}
+
+fn transfer_filter(block_number: u64) -> Filter {
+    Filter::new().select(block_number).event(TRANSFER_EVENT)
 }
 
 #[async_trait]
@@ -29,16 +371,60 @@ impl Client for EthersClient {
     }
 
     async fn get_block(&self, block_number: u64) -> Result<Option<Block>> {
-        if let Some(block) = self.provider.get_block(block_number).await? {
-            // I like this `map` <3
-            ethers_block_to_block(self.chain, block).map(Some)
+        let block = if self.light {
+            self.get_light_block(block_number).await?
+        } else {
+            self.provider
+                .get_block(block_number)
+                .await?
+                .map(|block| ethers_block_to_block(self.chain, block))
+                .transpose()?
+        };
+        if let Some(mut block) = block {
+            if self.count_failed_txs {
+                block.num_txs_failed = Some(self.count_failed_txs(block_number).await?);
+            }
+            if self.count_token_transfers {
+                block.num_token_transfers = Some(self.count_token_transfers(block_number).await?);
+            }
+            if let Some(trace_method) = self.trace_method {
+                block.num_internal_calls = Some(
+                    self.count_internal_calls(trace_method, block_number)
+                        .await?,
+                );
+            }
+            Ok(Some(block))
         } else {
             Ok(None)
         }
     }
+
+    async fn get_blocks(&self, block_numbers: &[u64]) -> Result<Vec<Block>> {
+        let mut blocks = vec![];
+        for batch in block_numbers.chunks(MAX_BATCH_SIZE) {
+            blocks.extend(self.get_block_batch(batch).await?);
+        }
+        Ok(blocks)
+    }
+
+    async fn get_finalized_block(&self) -> Result<Option<FinalizedBlock>> {
+        // The "finalized" tag is newer than this version of ethers
+        let block: Option<ethers::prelude::Block<H256>> = self
+            .provider
+            .request("eth_getBlockByNumber", ("finalized", false))
+            .await?;
+        block
+            .map(|block| {
+                Ok(FinalizedBlock {
+                    block_number: block.number.expect("block number").as_u64(),
+                    timestamp: u64::try_from(block.timestamp).map_err(|e| anyhow!("{}", e))?,
+                })
+            })
+            .transpose()
+    }
 }
 
-fn ethers_block_to_block(chain: Chain, block: ethers::prelude::Block<H256>) -> Result<Block> {
+pub fn ethers_block_to_block(chain: Chain, block: ethers::prelude::Block<H256>) -> Result<Block> {
     let block_number = block.number.expect("block number").as_u64();
     Ok(Block {
         chain,
@@ -48,5 +434,48 @@ fn ethers_block_to_block(chain: Chain, block: ethers::prelude::Block<H256>) -> R
         num_txs: u64::try_from(block.transactions.len())?,
         hash: block.hash.expect("hash").encode_hex(),
         parent_hash: block.parent_hash.encode_hex(),
+        gas_used: Some(u64::try_from(block.gas_used).map_err(|e| anyhow!("{}", e))?),
+        gas_limit: Some(u64::try_from(block.gas_limit).map_err(|e| anyhow!("{}", e))?),
+        fees: None,
+        value_transferred: None,
+        num_token_transfers: None,
+        num_receipts: None,
+        num_internal_calls: None,
+        producer: block.author.map(|author| format!("{:?}", author)),
+        num_vote_txs: None,
+        num_txs_failed: None,
+        senders: None,
     })
 }
+
+#[cfg(test)]
+mod test_ethers {
+    use super::TraceMethod;
+    use serde_json::json;
+
+    #[test]
+    fn count_flat_traces() -> Result<(), anyhow::Error> {
+        let traces = json!([
+            { "type": "call", "traceAddress": [] },
+            { "type": "call", "traceAddress": [0] },
+            { "type": "create", "traceAddress": [0, 0] },
+            { "type": "call", "traceAddress": [] },
+            { "type": "reward", "traceAddress": [] },
+        ]);
+        assert_eq!(TraceMethod::Trace.count_internal_calls(traces)?, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn count_call_tracer_frames() -> Result<(), anyhow::Error> {
+        let traces = json!([
+            { "result": { "type": "CALL" } },
+            { "result": { "type": "CALL", "calls": [
+                { "type": "STATICCALL" },
+                { "type": "CALL", "calls": [{ "type": "CREATE" }] },
+            ] } },
+        ]);
+        assert_eq!(TraceMethod::Debug.count_internal_calls(traces)?, 3);
+        Ok(())
+    }
+}
@@ -0,0 +1,109 @@
+use super::ethers_block_to_block;
+use crate::client::Client;
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use ethers::prelude::{H256, U64};
+use realtps_common::{chain::Chain, db::Block};
+use serde::{de::DeserializeOwned, Deserialize};
+
+/// Fetches an EVM chain's blocks from an Etherscan-style explorer API, which
+/// proxies a few JSON-RPC methods, for when the chain's RPCs are down or
+/// rate limited.
+pub struct EtherscanClient {
+    chain: Chain,
+    http: reqwest::Client,
+    url: String,
+    api_key: Option<String>,
+}
+
+/// Explorer APIs wrap errors, like hitting the rate limit, in a status and
+/// message, and pass JSON-RPC errors through.
+#[derive(Deserialize)]
+struct ProxyResponse {
+    #[serde(default)]
+    status: Option<String>,
+    #[serde(default)]
+    message: Option<String>,
+    #[serde(default)]
+    result: serde_json::Value,
+    #[serde(default)]
+    error: Option<serde_json::Value>,
+}
+
+impl EtherscanClient {
+    pub fn new(
+        chain: Chain,
+        url: &str,
+        api_key: Option<String>,
+        http: reqwest::Client,
+    ) -> Result<Self> {
+        Ok(EtherscanClient {
+            chain,
+            http,
+            url: url.to_string(),
+            api_key,
+        })
+    }
+
+    async fn proxy<T: DeserializeOwned>(&self, action: &str, params: &[(&str, &str)]) -> Result<T> {
+        let mut query = vec![("module", "proxy"), ("action", action)];
+        query.extend_from_slice(params);
+        if let Some(api_key) = &self.api_key {
+            query.push(("apikey", api_key));
+        }
+
+        // Errors leave out the URL, which has the API key in it
+        let response: ProxyResponse = self
+            .http
+            .get(&self.url)
+            .query(&query)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| e.without_url())?
+            .json()
+            .await
+            .map_err(|e| e.without_url())?;
+
+        if let Some(error) = response.error {
+            bail!("{} failed for chain {}: {}", action, self.chain, error);
+        }
+        if response.status.as_deref() == Some("0") {
+            bail!(
+                "{} failed for chain {}: {}: {}",
+                action,
+                self.chain,
+                response.message.unwrap_or_default(),
+                response.result
+            );
+        }
+        Ok(serde_json::from_value(response.result)?)
+    }
+}
+
+#[async_trait]
+impl Client for EtherscanClient {
+    async fn client_version(&self) -> Result<String> {
+        // Explorer APIs have no version, so just check the API answers
+        self.get_latest_block_number().await?;
+        Ok("etherscan-style explorer api".to_string())
+    }
+
+    async fn get_latest_block_number(&self) -> Result<u64> {
+        let block_number: U64 = self.proxy("eth_blockNumber", &[]).await?;
+        Ok(block_number.as_u64())
+    }
+
+    async fn get_block(&self, block_number: u64) -> Result<Option<Block>> {
+        let tag = format!("{:#x}", block_number);
+        let block: Option<ethers::prelude::Block<H256>> = self
+            .proxy(
+                "eth_getBlockByNumber",
+                &[("tag", &tag), ("boolean", "false")],
+            )
+            .await?;
+        block
+            .map(|block| ethers_block_to_block(self.chain, block))
+            .transpose()
+    }
+}
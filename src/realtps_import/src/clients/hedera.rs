@@ -9,9 +9,9 @@ pub struct HederaClient {
 }
 
 impl HederaClient {
-    pub fn new(url: &str) -> Result<Self> {
+    pub fn new(url: &str, client: reqwest::Client) -> Result<Self> {
         Ok(Self {
-            client: reqwest::Client::new(),
+            client,
             url: url.to_string(),
         })
     }
@@ -79,6 +79,17 @@ impl Client for HederaClient {
             num_txs: block.count as u64,
             hash: block.hash,
             parent_hash: block.previous_hash,
+            gas_used: None,
+            gas_limit: None,
+            fees: None,
+            value_transferred: None,
+            num_token_transfers: None,
+            num_receipts: None,
+            num_internal_calls: None,
+            producer: None,
+            num_vote_txs: None,
+            num_txs_failed: None,
+            senders: None,
         }))
     }
 }
@@ -2,6 +2,7 @@ mod algorand;
 mod elrond;
 mod esplora;
 mod ethers;
+mod etherscan;
 mod hedera;
 mod near;
 mod pivx;
@@ -14,6 +15,7 @@ pub use self::algorand::*;
 pub use self::elrond::*;
 pub use self::esplora::*;
 pub use self::ethers::*;
+pub use self::etherscan::*;
 pub use self::hedera::*;
 pub use self::near::*;
 pub use self::pivx::*;
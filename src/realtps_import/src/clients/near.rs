@@ -1,24 +1,32 @@
-use crate::client::Client;
+use crate::client::{Client, FinalizedBlock};
 use anyhow::Result;
 use async_trait::async_trait;
+use log::trace;
+use near_jsonrpc_client::methods::tx::TransactionInfo;
 use near_jsonrpc_client::{methods, JsonRpcClient};
 use near_jsonrpc_primitives::types::chunks::ChunkReference;
 use near_primitives::{
-    types::{BlockId, BlockReference},
-    views::BlockView,
+    types::{BlockId, BlockReference, Finality},
+    views::{ActionView, BlockView, FinalExecutionStatus},
 };
-use realtps_common::{chain::Chain, db::Block};
+use realtps_common::{chain::Chain, db::Block, sketch::SenderSketch};
 use std::time::Duration;
 
 pub struct NearClient {
     client: JsonRpcClient,
+    /// Fetch the outcome of every transaction to count failed ones, which
+    /// costs a request per transaction
+    count_failed_txs: bool,
 }
 
 impl NearClient {
-    pub fn new(url: &str) -> Result<Self> {
-        let client = JsonRpcClient::connect(url);
+    pub fn new(url: &str, count_failed_txs: bool, http: reqwest::Client) -> Result<Self> {
+        let client = JsonRpcClient::with(http).connect(url);
 
-        Ok(NearClient { client })
+        Ok(NearClient {
+            client,
+            count_failed_txs,
+        })
     }
 }
 
@@ -44,9 +52,24 @@ impl Client for NearClient {
             })
             .await?;
 
-        // caculating total tx numbers from chunks in the block
+        // Each shard's transactions are in its chunk of the block
         let mut num_txs: usize = 0;
+        let mut num_receipts: usize = 0;
+        let mut num_txs_failed: u64 = 0;
+        let mut senders = SenderSketch::default();
+        let mut value_transferred: u128 = 0;
         for chunk_head in &block.chunks {
+            // A shard that missed the block repeats its last chunk's header,
+            // whose transactions were counted with that chunk's block
+            if chunk_head.height_included != block_number {
+                trace!(
+                    "shard {} has no chunk in near block {}",
+                    chunk_head.shard_id,
+                    block_number
+                );
+                continue;
+            }
+
             let chunk = self
                 .client
                 .call(methods::chunk::RpcChunkRequest {
@@ -58,14 +81,78 @@ impl Client for NearClient {
 
             let txs = chunk.transactions.len();
             num_txs = num_txs.checked_add(txs).expect("number of txs overflow");
+            num_receipts = num_receipts
+                .checked_add(chunk.receipts.len())
+                .expect("number of receipts overflow");
+
+            for tx in &chunk.transactions {
+                senders.insert(tx.signer_id.as_ref());
+                for action in &tx.actions {
+                    if let ActionView::Transfer { deposit } = action {
+                        value_transferred = value_transferred
+                            .checked_add(*deposit)
+                            .expect("value overflow");
+                    }
+                }
+            }
+
+            if self.count_failed_txs {
+                for tx in chunk.transactions {
+                    let outcome = self
+                        .client
+                        .call(methods::tx::RpcTransactionStatusRequest {
+                            transaction_info: TransactionInfo::TransactionId {
+                                hash: tx.hash,
+                                account_id: tx.signer_id,
+                            },
+                        })
+                        .await?;
+                    if let FinalExecutionStatus::Failure(_) = outcome.status {
+                        num_txs_failed += 1;
+                    }
+                }
+            }
         }
 
         let num_txs = u64::try_from(num_txs)?;
-        near_block_to_block(block, block_number, num_txs).map(Some)
+        let num_receipts = u64::try_from(num_receipts)?;
+        let num_txs_failed = self.count_failed_txs.then_some(num_txs_failed);
+        near_block_to_block(
+            block,
+            block_number,
+            num_txs,
+            num_receipts,
+            num_txs_failed,
+            value_transferred,
+            senders,
+        )
+        .map(Some)
+    }
+
+    async fn get_finalized_block(&self) -> Result<Option<FinalizedBlock>> {
+        let block = self
+            .client
+            .call(methods::block::RpcBlockRequest {
+                block_reference: BlockReference::Finality(Finality::Final),
+            })
+            .await?;
+
+        Ok(Some(FinalizedBlock {
+            block_number: block.header.height,
+            timestamp: Duration::from_nanos(block.header.timestamp_nanosec).as_secs(),
+        }))
     }
 }
 
-fn near_block_to_block(block: BlockView, block_number: u64, num_txs: u64) -> Result<Block> {
+fn near_block_to_block(
+    block: BlockView,
+    block_number: u64,
+    num_txs: u64,
+    num_receipts: u64,
+    num_txs_failed: Option<u64>,
+    value_transferred: u128,
+    senders: SenderSketch,
+) -> Result<Block> {
     Ok(Block {
         chain: Chain::Near,
         block_number,
@@ -74,5 +161,16 @@ fn near_block_to_block(block: BlockView, block_number: u64, num_txs: u64) -> Res
         num_txs,
         hash: block.header.hash.to_string(),
         parent_hash: block.header.prev_hash.to_string(),
+        gas_used: None,
+        gas_limit: None,
+        fees: None,
+        value_transferred: Some(value_transferred),
+        num_token_transfers: None,
+        num_receipts: Some(num_receipts),
+        num_internal_calls: None,
+        producer: Some(block.author.to_string()),
+        num_vote_txs: None,
+        num_txs_failed,
+        senders: Some(senders),
     })
 }
@@ -9,9 +9,9 @@ pub struct PivxClient {
 }
 
 impl PivxClient {
-    pub fn new(url: &str) -> Result<Self> {
+    pub fn new(url: &str, client: reqwest::Client) -> Result<Self> {
         Ok(Self {
-            client: reqwest::Client::new(),
+            client,
             url: url.to_string(),
         })
     }
@@ -76,6 +76,17 @@ impl Client for PivxClient {
             num_txs: block_info.tx.len() as u64,
             hash: block_info.hash,
             parent_hash: block_info.previousblockhash,
+            gas_used: None,
+            gas_limit: None,
+            fees: None,
+            value_transferred: None,
+            num_token_transfers: None,
+            num_receipts: None,
+            num_internal_calls: None,
+            producer: None,
+            num_vote_txs: None,
+            num_txs_failed: None,
+            senders: None,
         }))
     }
 }
@@ -91,7 +102,7 @@ mod test_pivx {
 
     #[tokio::test]
     async fn client_version() -> Result<(), anyhow::Error> {
-        let client = PivxClient::new(API_URL)?;
+        let client = PivxClient::new(API_URL, reqwest::Client::new())?;
         let ver = client.client_version().await?;
         println!("PIVX client_version: {}", ver);
         assert!(!ver.is_empty());
@@ -100,7 +111,7 @@ mod test_pivx {
 
     #[tokio::test]
     async fn get_latest_block_number() -> Result<(), anyhow::Error> {
-        let client = PivxClient::new(API_URL)?;
+        let client = PivxClient::new(API_URL, reqwest::Client::new())?;
         let latest_block_number = client.get_latest_block_number().await?;
         println!("PIVX latest_block_number: {}", latest_block_number);
         assert!(latest_block_number > 0);
@@ -109,7 +120,7 @@ mod test_pivx {
 
     #[tokio::test]
     async fn get_block() -> Result<(), anyhow::Error> {
-        let client = PivxClient::new(API_URL)?;
+        let client = PivxClient::new(API_URL, reqwest::Client::new())?;
         let latest_block_number = client.get_latest_block_number().await?;
         println!("PIVX latest_block_number: {}", latest_block_number);
         let block = client.get_block(latest_block_number).await?;
@@ -1,23 +1,146 @@
 use crate::client::Client;
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
+use futures::future;
 use log::{debug, trace};
-use realtps_common::{chain::Chain, db::Block};
-use solana_client::{rpc_client::RpcClient, rpc_config::RpcBlockConfig};
+use realtps_common::{
+    chain::Chain,
+    db::{add_known, Block},
+    sketch::SenderSketch,
+};
+use reqwest::{header::RETRY_AFTER, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use solana_client::{
+    client_error::Result as ClientResult,
+    rpc_client::{RpcClient, RpcClientConfig},
+    rpc_config::RpcBlockConfig,
+    rpc_request::{RpcError, RpcRequest, RpcResponseErrorData},
+    rpc_sender::{RpcSender, RpcTransportStats},
+};
 use solana_sdk::{commitment_config::CommitmentConfig, message::VersionedMessage};
-use solana_transaction_status::{TransactionDetails, UiConfirmedBlock, UiTransactionEncoding};
+use solana_transaction_status::{
+    EncodedTransaction, EncodedTransactionWithStatusMeta, TransactionDetails, UiAccountsList,
+    UiConfirmedBlock, UiTransactionEncoding,
+};
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::task;
 
+/// The most compute units a block may use, as of Solana 1.14.
+const MAX_BLOCK_COMPUTE_UNITS: u64 = 48_000_000;
+
+/// Performance samples are taken every minute, so this is the last hour.
+const NUM_PERFORMANCE_SAMPLES: usize = 60;
+
+/// How many times to wait out a rate limit before failing the request, as
+/// Solana's own sender does.
+const MAX_RATE_LIMITED_RETRIES: u32 = 5;
+
+/// How much of each transaction to fetch with a block.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SolanaBlockDetails {
+    /// Whole transactions, to count compute units
+    #[default]
+    Full,
+    /// Only each transaction's accounts and status, a fraction of the size.
+    /// Votes are told apart by their accounts, and compute units are left
+    /// out.
+    Accounts,
+}
+
 pub struct SolanaClient {
     client: Arc<RpcClient>,
+    block_details: SolanaBlockDetails,
 }
 
 impl SolanaClient {
-    pub fn new(url: &str) -> Result<Self> {
-        let client = Arc::new(RpcClient::new(url.to_string()));
+    pub fn new(
+        url: &str,
+        block_details: SolanaBlockDetails,
+        http: reqwest::Client,
+    ) -> Result<Self> {
+        let sender = HeaderSender {
+            http,
+            url: url.to_string(),
+            request_id: AtomicU64::new(0),
+        };
+        let client = Arc::new(RpcClient::new_sender(sender, RpcClientConfig::default()));
+
+        Ok(SolanaClient {
+            client,
+            block_details,
+        })
+    }
+}
+
+/// Sends requests with our own HTTP client, so they carry the endpoint's
+/// headers, which Solana's sender can't add.
+struct HeaderSender {
+    http: reqwest::Client,
+    url: String,
+    request_id: AtomicU64,
+}
+
+#[async_trait]
+impl RpcSender for HeaderSender {
+    async fn send(
+        &self,
+        request: RpcRequest,
+        params: serde_json::Value,
+    ) -> ClientResult<serde_json::Value> {
+        let request_id = self.request_id.fetch_add(1, Ordering::Relaxed);
+        let request_json = json!({
+            "jsonrpc": "2.0",
+            "id": request_id,
+            "method": request.to_string(),
+            "params": params,
+        });
+
+        let mut retries = 0;
+        let response = loop {
+            let response = self.http.post(&self.url).json(&request_json).send().await?;
+            if response.status() != StatusCode::TOO_MANY_REQUESTS
+                || retries == MAX_RATE_LIMITED_RETRIES
+            {
+                break response.error_for_status()?;
+            }
+            let retry_after = response
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|retry_after| retry_after.to_str().ok()?.parse().ok())
+                .filter(|secs| *secs < 120)
+                .map(Duration::from_secs)
+                .unwrap_or(Duration::from_millis(500));
+            debug!("solana rpc rate limited; retrying in {:?}", retry_after);
+            tokio::time::sleep(retry_after).await;
+            retries += 1;
+        };
+
+        let mut json: serde_json::Value = response.json().await?;
+        if json["error"].is_object() {
+            return Err(RpcError::RpcResponseError {
+                code: json["error"]["code"].as_i64().unwrap_or_default(),
+                message: json["error"]["message"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string(),
+                data: RpcResponseErrorData::Empty,
+            }
+            .into());
+        }
+        Ok(json["result"].take())
+    }
 
-        Ok(SolanaClient { client })
+    fn get_transport_stats(&self) -> RpcTransportStats {
+        RpcTransportStats::default()
+    }
+
+    fn url(&self) -> String {
+        self.url.clone()
     }
 }
 
@@ -42,9 +165,16 @@ impl Client for SolanaClient {
         // `ClientResult<EncodedConfirmedBlock>`
 
         let client = self.client.clone();
+        let (encoding, transaction_details) = match self.block_details {
+            SolanaBlockDetails::Full => (UiTransactionEncoding::Base64, TransactionDetails::Full),
+            // Accounts lists are always JSON
+            SolanaBlockDetails::Accounts => {
+                (UiTransactionEncoding::Json, TransactionDetails::Accounts)
+            }
+        };
         let config = RpcBlockConfig {
-            encoding: Some(UiTransactionEncoding::Base64),
-            transaction_details: Some(TransactionDetails::Full),
+            encoding: Some(encoding),
+            transaction_details: Some(transaction_details),
             rewards: Some(false),
             commitment: Some(CommitmentConfig::finalized()),
             max_supported_transaction_version: Some(0),
@@ -56,41 +186,102 @@ impl Client for SolanaClient {
 
         solana_block_to_block(block, block_number).map(Some)
     }
+
+    /// Skipped slots have no block, so only the slots that do are requested,
+    /// as found with one `getBlocks` request for the whole range.
+    async fn get_blocks(&self, block_numbers: &[u64]) -> Result<Vec<Block>> {
+        let (first_slot, last_slot) = match (block_numbers.iter().min(), block_numbers.iter().max())
+        {
+            (Some(first_slot), Some(last_slot)) => (*first_slot, *last_slot),
+            _ => return Ok(vec![]),
+        };
+
+        let client = self.client.clone();
+        let slots = task::spawn_blocking(move || {
+            client.get_blocks_with_commitment(
+                first_slot,
+                Some(last_slot),
+                CommitmentConfig::finalized(),
+            )
+        })
+        .await??;
+
+        let wanted_slots: HashSet<u64> = block_numbers.iter().copied().collect();
+        let blocks = future::join_all(
+            slots
+                .into_iter()
+                .filter(|slot| wanted_slots.contains(slot))
+                .map(|slot| self.get_block(slot)),
+        )
+        .await;
+        Ok(blocks
+            .into_iter()
+            .filter_map(|block| block.ok().flatten())
+            .collect())
+    }
+
+    async fn get_native_tps(&self) -> Result<Option<f64>> {
+        let client = self.client.clone();
+        let samples = task::spawn_blocking(move || {
+            client.get_recent_performance_samples(Some(NUM_PERFORMANCE_SAMPLES))
+        })
+        .await??;
+
+        let num_txs: u64 = samples.iter().map(|sample| sample.num_transactions).sum();
+        let num_seconds: u64 = samples
+            .iter()
+            .map(|sample| u64::from(sample.sample_period_secs))
+            .sum();
+        if num_seconds == 0 {
+            return Ok(None);
+        }
+
+        Ok(Some(num_txs as f64 / num_seconds as f64))
+    }
+}
+
+/// Counts of a block's transactions. Fees and failures are of the user
+/// transactions.
+struct TxCounts {
+    num_user_txs: u64,
+    num_vote_txs: Option<u64>,
+    num_user_txs_failed: Option<u64>,
+    user_fees: Option<u64>,
+    user_compute_units: Option<u64>,
+    user_senders: Option<SenderSketch>,
 }
 
 fn solana_block_to_block(block: UiConfirmedBlock, slot_number: u64) -> Result<Block> {
-    fn calc_user_txs(block: &UiConfirmedBlock) -> u64 {
+    fn calc_user_txs(block: &UiConfirmedBlock) -> TxCounts {
         let mut num_user_txs = 0;
+        let mut num_user_txs_failed = Some(0);
+        let mut user_fees = Some(0);
+        let mut user_compute_units = Some(0);
+        let mut user_senders = SenderSketch::default();
 
         if let Some(block_txs) = &block.transactions {
             for tx_status in block_txs {
-                let tx = tx_status.transaction.decode().unwrap();
                 trace!("tx_meta: {:#?}", tx_status.meta.as_ref().unwrap());
-                trace!("tx: {:#?}", tx);
-
-                let account_keys = match &tx.message {
-                    VersionedMessage::Legacy(message) => &message.account_keys,
-                    VersionedMessage::V0(message) => &message.account_keys,
-                };
-
-                let mut num_vote_instrs = 0;
-                for instr in tx.message.instructions() {
-                    let program_id_index = instr.program_id_index;
-                    let program_id = account_keys[usize::from(program_id_index)];
-
-                    if program_id == solana_sdk::vote::program::id() {
-                        num_vote_instrs += 1;
-                        trace!("found vote instruction");
-                    } else {
-                        trace!("non-vote instruction");
-                    }
-                }
-                if num_vote_instrs == tx.message.instructions().len() {
+                let (is_vote, fee_payer) = classify_tx(tx_status);
+                if is_vote {
                     trace!("it's a vote transaction");
                 } else {
                     // This doesn't look like a vote transaction
                     trace!("it's a non-vote transaction");
                     num_user_txs += 1;
+                    user_fees = add_known(user_fees, tx_status.meta.as_ref().map(|meta| meta.fee));
+                    let failed = tx_status
+                        .meta
+                        .as_ref()
+                        .map(|meta| u64::from(meta.err.is_some()));
+                    num_user_txs_failed = add_known(num_user_txs_failed, failed);
+                    // Not reported by nodes before 1.10
+                    let compute_units = tx_status.meta.as_ref().and_then(|meta| {
+                        Option::<&u64>::from(meta.compute_units_consumed.as_ref()).copied()
+                    });
+                    user_compute_units = add_known(user_compute_units, compute_units);
+                    // The fee payer
+                    user_senders.insert(&fee_payer);
                 }
             }
 
@@ -106,9 +297,62 @@ fn solana_block_to_block(block: UiConfirmedBlock, slot_number: u64) -> Result<Bl
             debug!("solana total txs: None");
         }
 
-        u64::try_from(num_user_txs).expect("u64")
+        let num_vote_txs = block.transactions.as_ref().map(|block_txs| {
+            let vote_txs = block_txs
+                .len()
+                .checked_sub(num_user_txs)
+                .expect("underflow");
+            u64::try_from(vote_txs).expect("u64")
+        });
+
+        TxCounts {
+            num_user_txs: u64::try_from(num_user_txs).expect("u64"),
+            num_vote_txs,
+            num_user_txs_failed,
+            user_fees,
+            user_compute_units: block.transactions.as_ref().and(user_compute_units),
+            user_senders: block.transactions.as_ref().map(|_| user_senders),
+        }
+    }
+
+    /// Whether a transaction only votes, and its fee payer.
+    fn classify_tx(tx_status: &EncodedTransactionWithStatusMeta) -> (bool, String) {
+        if let EncodedTransaction::Accounts(accounts) = &tx_status.transaction {
+            trace!("tx accounts: {:#?}", accounts);
+            return (
+                is_vote_accounts(accounts),
+                accounts.account_keys[0].pubkey.clone(),
+            );
+        }
+
+        let tx = tx_status.transaction.decode().unwrap();
+        trace!("tx: {:#?}", tx);
+
+        let account_keys = match &tx.message {
+            VersionedMessage::Legacy(message) => &message.account_keys,
+            VersionedMessage::V0(message) => &message.account_keys,
+        };
+
+        let mut num_vote_instrs = 0;
+        for instr in tx.message.instructions() {
+            let program_id_index = instr.program_id_index;
+            let program_id = account_keys[usize::from(program_id_index)];
+
+            if program_id == solana_sdk::vote::program::id() {
+                num_vote_instrs += 1;
+                trace!("found vote instruction");
+            } else {
+                trace!("non-vote instruction");
+            }
+        }
+        (
+            num_vote_instrs == tx.message.instructions().len(),
+            account_keys[0].to_string(),
+        )
     }
 
+    let tx_counts = calc_user_txs(&block);
+
     Ok(Block {
         chain: Chain::Solana,
         block_number: slot_number,
@@ -118,8 +362,88 @@ fn solana_block_to_block(block: UiConfirmedBlock, slot_number: u64) -> Result<Bl
                 .block_time
                 .ok_or_else(|| anyhow!("block time unavailable for solana slot {}", slot_number))?,
         )?,
-        num_txs: calc_user_txs(&block),
+        num_txs: tx_counts.num_user_txs,
         hash: block.blockhash,
         parent_hash: block.previous_blockhash,
+        gas_used: tx_counts.user_compute_units,
+        gas_limit: tx_counts
+            .user_compute_units
+            .map(|_| MAX_BLOCK_COMPUTE_UNITS),
+        fees: tx_counts.user_fees,
+        value_transferred: None,
+        num_token_transfers: None,
+        num_receipts: None,
+        num_internal_calls: None,
+        producer: None,
+        num_vote_txs: tx_counts.num_vote_txs,
+        num_txs_failed: tx_counts.num_user_txs_failed,
+        senders: tx_counts.user_senders,
     })
 }
+
+/// Accounts lists don't say which programs are called, but a vote calls only
+/// the vote program, and otherwise reads only sysvars. Any other program
+/// would be a read-only account too.
+fn is_vote_accounts(accounts: &UiAccountsList) -> bool {
+    let vote_program_id = solana_sdk::vote::program::id().to_string();
+    let mut calls_vote_program = false;
+    for account in &accounts.account_keys {
+        if account.pubkey == vote_program_id {
+            calls_vote_program = true;
+        } else if !account.writable && !account.signer && !account.pubkey.starts_with("Sysvar") {
+            return false;
+        }
+    }
+    calls_vote_program
+}
+
+#[cfg(test)]
+mod test_solana {
+    use super::is_vote_accounts;
+    use solana_transaction_status::parse_accounts::{ParsedAccount, ParsedAccountSource};
+    use solana_transaction_status::UiAccountsList;
+
+    fn account(pubkey: &str, writable: bool, signer: bool) -> ParsedAccount {
+        ParsedAccount {
+            pubkey: pubkey.to_string(),
+            writable,
+            signer,
+            source: Some(ParsedAccountSource::Transaction),
+        }
+    }
+
+    fn accounts_list(account_keys: Vec<ParsedAccount>) -> UiAccountsList {
+        UiAccountsList {
+            signatures: vec![],
+            account_keys,
+        }
+    }
+
+    #[test]
+    fn classify_votes_by_accounts() {
+        let vote = accounts_list(vec![
+            account("Validator1111111111111111111111111111111111", true, true),
+            account("VoteAccount11111111111111111111111111111111", true, false),
+            account("SysvarS1otHashes111111111111111111111111111", false, false),
+            account("SysvarC1ock11111111111111111111111111111111", false, false),
+            account("Vote111111111111111111111111111111111111111", false, false),
+        ]);
+        assert!(is_vote_accounts(&vote));
+
+        let transfer = accounts_list(vec![
+            account("Sender1111111111111111111111111111111111111", true, true),
+            account("Recipient111111111111111111111111111111111", true, false),
+            account("11111111111111111111111111111111", false, false),
+        ]);
+        assert!(!is_vote_accounts(&transfer));
+
+        // Calls another program alongside the vote program
+        let vote_and_more = accounts_list(vec![
+            account("Validator1111111111111111111111111111111111", true, true),
+            account("VoteAccount11111111111111111111111111111111", true, false),
+            account("Vote111111111111111111111111111111111111111", false, false),
+            account("ComputeBudget111111111111111111111111111111", false, false),
+        ]);
+        assert!(!is_vote_accounts(&vote_and_more));
+    }
+}
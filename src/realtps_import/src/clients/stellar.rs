@@ -9,9 +9,9 @@ pub struct StellarClient {
 }
 
 impl StellarClient {
-    pub fn new(url: &str) -> Result<Self> {
+    pub fn new(url: &str, client: reqwest::Client) -> Result<Self> {
         Ok(Self {
-            client: reqwest::Client::new(),
+            client,
             url: url.to_string(),
         })
     }
@@ -66,6 +66,17 @@ impl Client for StellarClient {
             // sub-transactions, within the outer transaction object.
             hash: ledger.hash,
             parent_hash: ledger.prev_hash,
+            gas_used: None,
+            gas_limit: None,
+            fees: None,
+            value_transferred: None,
+            num_token_transfers: None,
+            num_receipts: None,
+            num_internal_calls: None,
+            producer: None,
+            num_vote_txs: None,
+            num_txs_failed: None,
+            senders: None,
         }))
     }
 }
@@ -78,7 +89,7 @@ mod test_stellar {
 
     #[tokio::test]
     async fn client_version() -> Result<(), anyhow::Error> {
-        let client = StellarClient::new(RPC_URL)?;
+        let client = StellarClient::new(RPC_URL, reqwest::Client::new())?;
         let ver = client.client_version().await?;
         println!("client_version: {}", ver);
         assert!(!ver.is_empty());
@@ -87,7 +98,7 @@ mod test_stellar {
 
     #[tokio::test]
     async fn get_latest_block_number() -> Result<(), anyhow::Error> {
-        let client = StellarClient::new(RPC_URL)?;
+        let client = StellarClient::new(RPC_URL, reqwest::Client::new())?;
         let latest_block_number = client.get_latest_block_number().await?;
         println!("latest_block_number: {}", latest_block_number);
         assert!(latest_block_number > 0);
@@ -96,7 +107,7 @@ mod test_stellar {
 
     #[tokio::test]
     async fn get_block() -> Result<(), anyhow::Error> {
-        let client = StellarClient::new(RPC_URL)?;
+        let client = StellarClient::new(RPC_URL, reqwest::Client::new())?;
         let latest_block_number = client.get_latest_block_number().await?;
         println!("latest_block_number: {}", latest_block_number);
         let block = client.get_block(latest_block_number).await?;
@@ -1,24 +1,47 @@
 use crate::client::Client;
-use anyhow::{anyhow, bail, Result};
+use anyhow::{bail, Result};
 use async_trait::async_trait;
 use hex::FromHex;
-use jsonrpc_core::types::{Params, Value};
-use jsonrpc_core_client::transports::http;
-use jsonrpc_core_client::RawClient;
 use log::trace;
 use realtps_common::{chain::Chain, db::Block};
+use serde_json::{json, Value};
 use sp_storage::StorageKey;
 
 pub struct SubstrateClient {
     chain: Chain,
-    client: RawClient,
+    client: reqwest::Client,
+    url: String,
 }
 
 impl SubstrateClient {
-    pub async fn new(chain: Chain, url: &str) -> Result<Self> {
-        let client = http::connect(url).await.map_err(|e| anyhow!("{}", e))?;
+    pub fn new(chain: Chain, url: &str, client: reqwest::Client) -> Result<Self> {
+        Ok(SubstrateClient {
+            chain,
+            client,
+            url: url.to_string(),
+        })
+    }
 
-        Ok(SubstrateClient { chain, client })
+    async fn call_method(&self, method: &str, params: Value) -> Result<Value> {
+        let mut response: Value = self
+            .client
+            .post(&self.url)
+            .json(&json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "method": method,
+                "params": params,
+            }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        if let Some(error) = response.get("error") {
+            bail!("substrate {} failed: {}", method, error);
+        }
+        Ok(response["result"].take())
     }
 }
 
@@ -26,10 +49,8 @@ impl SubstrateClient {
 impl Client for SubstrateClient {
     async fn client_version(&self) -> Result<String> {
         let runtime_version = self
-            .client
-            .call_method("state_getRuntimeVersion", Params::None)
-            .await
-            .map_err(|e| anyhow!("{}", e))?;
+            .call_method("state_getRuntimeVersion", json!([]))
+            .await?;
 
         trace!("runtime_version: {:#?}", runtime_version);
         let impl_name = runtime_version
@@ -42,11 +63,7 @@ impl Client for SubstrateClient {
     }
 
     async fn get_latest_block_number(&self) -> Result<u64> {
-        let header = self
-            .client
-            .call_method("chain_getHeader", Params::None)
-            .await
-            .map_err(|e| anyhow!("{}", e))?;
+        let header = self.call_method("chain_getHeader", json!([])).await?;
 
         trace!("header: {:#?}", header);
 
@@ -60,13 +77,8 @@ impl Client for SubstrateClient {
     async fn get_block(&self, block_number: u64) -> Result<Option<Block>> {
         let block_number = u32::try_from(block_number)?;
         let hash = self
-            .client
-            .call_method(
-                "chain_getBlockHash",
-                Params::Array(vec![Value::from(block_number)]),
-            )
-            .await
-            .map_err(|e| anyhow!("{}", e))?;
+            .call_method("chain_getBlockHash", json!([block_number]))
+            .await?;
 
         trace!("hash: {:#?}", hash);
 
@@ -78,11 +90,7 @@ impl Client for SubstrateClient {
             }
         };
 
-        let block = self
-            .client
-            .call_method("chain_getBlock", Params::Array(vec![Value::from(hash)]))
-            .await
-            .map_err(|e| anyhow!("{}", e))?;
+        let block = self.call_method("chain_getBlock", json!([hash])).await?;
 
         trace!("block: {:#?}", block);
 
@@ -94,13 +102,8 @@ impl Client for SubstrateClient {
         let timestamp_storage_key = StorageKey(timestamp_storage_key);
         let timestamp_storage_key = serde_json::to_value(timestamp_storage_key)?;
         let timestamp = self
-            .client
-            .call_method(
-                "state_getStorage",
-                Params::Array(vec![timestamp_storage_key, Value::from(hash)]),
-            )
-            .await
-            .map_err(|e| anyhow!("{}", e))?;
+            .call_method("state_getStorage", json!([timestamp_storage_key, hash]))
+            .await?;
 
         trace!("timestamp: {:#?}", timestamp);
 
@@ -185,5 +188,16 @@ fn substrate_block_to_block(
         num_txs,
         hash,
         parent_hash,
+        gas_used: None,
+        gas_limit: None,
+        fees: None,
+        value_transferred: None,
+        num_token_transfers: None,
+        num_receipts: None,
+        num_internal_calls: None,
+        producer: None,
+        num_vote_txs: None,
+        num_txs_failed: None,
+        senders: None,
     })
 }
@@ -2,38 +2,64 @@ use crate::client::Client;
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use realtps_common::{chain::Chain, db::Block};
-use tendermint_rpc::{Client as TendermintClientTrait, HttpClient};
+use tendermint_rpc::{endpoint, Response, SimpleRequest};
 
+/// Requests are sent with our own HTTP client rather than tendermint-rpc's,
+/// which can't send custom headers.
 pub struct TendermintClient {
     chain: Chain,
-    client: HttpClient,
+    client: reqwest::Client,
+    url: String,
 }
 
 impl TendermintClient {
-    pub fn new(chain: Chain, url: &str) -> Result<Self> {
-        let client = HttpClient::new(url)?;
+    pub fn new(chain: Chain, url: &str, client: reqwest::Client) -> Result<Self> {
+        Ok(TendermintClient {
+            chain,
+            client,
+            url: url.to_string(),
+        })
+    }
+
+    async fn perform<R: SimpleRequest>(&self, request: R) -> Result<R::Response> {
+        let body = self
+            .client
+            .post(&self.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(request.into_json())
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+
+        Ok(R::Response::from_string(body)?)
+    }
 
-        Ok(TendermintClient { chain, client })
+    async fn status(&self) -> Result<endpoint::status::Response> {
+        self.perform(endpoint::status::Request).await
     }
 }
 
 #[async_trait]
 impl Client for TendermintClient {
     async fn client_version(&self) -> Result<String> {
-        let status = self.client.status().await?;
+        let status = self.status().await?;
 
         Ok(status.node_info.moniker.to_string())
     }
 
     async fn get_latest_block_number(&self) -> Result<u64> {
-        let status = self.client.status().await?;
+        let status = self.status().await?;
 
         Ok(status.sync_info.latest_block_height.value())
     }
 
     async fn get_block(&self, block_number: u64) -> Result<Option<Block>> {
         let tendermint_block_height = tendermint::block::Height::try_from(block_number)?;
-        let block_response = self.client.block(tendermint_block_height).await?;
+        let block_response = self
+            .perform(endpoint::block::Request::new(tendermint_block_height))
+            .await?;
 
         tendermint_block_to_block(self.chain, block_response, block_number).map(Some)
     }
@@ -61,5 +87,16 @@ fn tendermint_block_to_block(
             .ok_or_else(|| anyhow!("no previous block id"))?
             .hash
             .to_string(),
+        gas_used: None,
+        gas_limit: None,
+        fees: None,
+        value_transferred: None,
+        num_token_transfers: None,
+        num_receipts: None,
+        num_internal_calls: None,
+        producer: Some(block_response.block.header.proposer_address.to_string()),
+        num_vote_txs: None,
+        num_txs_failed: None,
+        senders: None,
     })
 }
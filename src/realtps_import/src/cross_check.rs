@@ -0,0 +1,104 @@
+use anyhow::Result;
+use realtps_common::{
+    chain::Chain,
+    db::{BucketMismatch, Db, ProviderCheck, TimestampIndex},
+};
+use std::sync::Arc;
+
+/// Compares the transactions imported from a chain's two providers, or
+/// `None` if either has imported nothing yet.
+///
+/// Only buckets both providers have imported all of are compared: not the
+/// oldest, which one may have started importing partway through, nor the
+/// newest, which are still being filled.
+pub async fn check_providers(
+    chain: Chain,
+    db: &Arc<dyn Db>,
+    secondary_db: &Arc<dyn Db>,
+    timestamp: u64,
+) -> Result<Option<ProviderCheck>> {
+    let index = db.load_timestamp_index(chain).await?;
+    let secondary_index = secondary_db.load_timestamp_index(chain).await?;
+
+    Ok(index
+        .zip(secondary_index)
+        .map(|(index, secondary_index)| compare_indexes(&index, &secondary_index, timestamp)))
+}
+
+fn compare_indexes(
+    index: &TimestampIndex,
+    secondary_index: &TimestampIndex,
+    timestamp: u64,
+) -> ProviderCheck {
+    let mut check = ProviderCheck {
+        timestamp,
+        num_buckets: 0,
+        mismatches: vec![],
+    };
+
+    let first_bucket_start = |index: &TimestampIndex| index.buckets.keys().next().copied();
+    let last_bucket_start = |index: &TimestampIndex| index.buckets.keys().next_back().copied();
+    let start = first_bucket_start(index).max(first_bucket_start(secondary_index));
+    let end = last_bucket_start(index).min(last_bucket_start(secondary_index));
+    let (start, end) = match (start, end) {
+        (Some(start), Some(end)) if start < end => (start, end),
+        _ => return check,
+    };
+
+    for (bucket_start, bucket) in index.buckets.range(start + 1..end) {
+        let secondary_num_txs = secondary_index
+            .buckets
+            .get(bucket_start)
+            .map_or(0, |bucket| bucket.num_txs);
+        check.num_buckets += 1;
+        if bucket.num_txs != secondary_num_txs {
+            check.mismatches.push(BucketMismatch {
+                bucket_start: *bucket_start,
+                num_txs: bucket.num_txs,
+                secondary_num_txs,
+            });
+        }
+    }
+
+    check
+}
+
+#[cfg(test)]
+mod test_cross_check {
+    use super::compare_indexes;
+    use crate::test_helpers::make_blocks;
+    use realtps_common::{
+        chain::Chain,
+        db::{TimestampIndex, TIMESTAMP_BUCKET_SECONDS},
+    };
+
+    const CHAIN: Chain = Chain::Ethereum;
+
+    #[test]
+    fn compare_complete_buckets() {
+        // Ten hours of blocks
+        let blocks = make_blocks(CHAIN, 600, 60, 5);
+
+        let mut index = TimestampIndex::default();
+        for block in &blocks {
+            index.insert(block);
+        }
+        // Started importing in the third hour, and missed a block in the sixth
+        let mut secondary_index = TimestampIndex::default();
+        for block in &blocks[150..] {
+            if block.block_number != 330 {
+                secondary_index.insert(block);
+            }
+        }
+
+        let check = compare_indexes(&index, &secondary_index, 100);
+        // The fourth to ninth hours
+        assert_eq!(check.num_buckets, 6);
+        assert_eq!(check.mismatches.len(), 1);
+        let mismatch = &check.mismatches[0];
+        let sixth_hour = *index.buckets.keys().next().unwrap() + 5 * TIMESTAMP_BUCKET_SECONDS;
+        assert_eq!(mismatch.bucket_start, sixth_hour);
+        assert_eq!(mismatch.num_txs, 300);
+        assert_eq!(mismatch.secondary_num_txs, 295);
+    }
+}
@@ -0,0 +1,256 @@
+use anyhow::{anyhow, Result};
+use chrono::{NaiveDate, TimeZone, Utc};
+use realtps_common::{
+    chain::Chain,
+    db::{Db, TimestampBucket, TIMESTAMP_BUCKET_SECONDS},
+    sketch::SenderSketch,
+};
+use std::collections::btree_map::Entry;
+use std::sync::Arc;
+
+const SECONDS_PER_DAY: u64 = 60 * 60 * 24;
+
+pub struct DayCalcs {
+    pub date: NaiveDate,
+    pub tps: f64,
+    pub active_addresses: Option<u64>,
+}
+
+/// Calculates TPS and active addresses for the whole UTC days in the
+/// timestamp index that haven't been calculated yet, and returns them.
+///
+/// Index buckets are hours, so a day is exactly 24 buckets, and no blocks
+/// need to be loaded.
+pub async fn update_daily_tps(chain: Chain, db: &Arc<dyn Db>) -> Result<Vec<DayCalcs>> {
+    let timestamp_index = match db.load_timestamp_index(chain).await? {
+        Some(timestamp_index) => timestamp_index,
+        None => return Ok(vec![]),
+    };
+
+    let buckets = &timestamp_index.buckets;
+    let (first_timestamp, last_timestamp) = match (buckets.values().next(), buckets.values().last())
+    {
+        (Some(first), Some(last)) => (first.first_timestamp, last.last_timestamp),
+        _ => return Ok(vec![]),
+    };
+
+    let mut daily_tps = db.load_daily_tps(chain).await?.unwrap_or_default();
+    let mut new_days = vec![];
+
+    // The index may not have all blocks of the day it starts in
+    let mut day_start = first_timestamp - first_timestamp % SECONDS_PER_DAY + SECONDS_PER_DAY;
+
+    // A day is complete once there are blocks after it
+    while day_start + SECONDS_PER_DAY <= last_timestamp {
+        let date = date_of(day_start)?;
+
+        if let Entry::Vacant(entry) = daily_tps.days.entry(date) {
+            let day_buckets = || {
+                buckets
+                    .range(day_start..day_start + SECONDS_PER_DAY)
+                    .map(|(_, bucket)| bucket)
+            };
+            let num_txs: u64 = day_buckets().map(|bucket| bucket.num_txs).sum();
+            let tps = num_txs as f64 / SECONDS_PER_DAY as f64;
+            entry.insert(tps);
+
+            let active_addresses = count_senders(day_buckets());
+            if let Some(active_addresses) = active_addresses {
+                daily_tps.active_addresses.insert(date, active_addresses);
+            }
+
+            new_days.push(DayCalcs {
+                date,
+                tps,
+                active_addresses,
+            });
+        }
+
+        day_start += SECONDS_PER_DAY;
+    }
+
+    if !new_days.is_empty() {
+        db.store_daily_tps(chain, &daily_tps).await?;
+    }
+
+    Ok(new_days)
+}
+
+/// Calculates TPS for the whole UTC hours in the timestamp index that
+/// haven't been calculated yet, dropping hours older than
+/// `retention_seconds`, and returns how many were calculated.
+pub async fn update_hourly_tps(
+    chain: Chain,
+    db: &Arc<dyn Db>,
+    retention_seconds: u64,
+) -> Result<usize> {
+    let timestamp_index = match db.load_timestamp_index(chain).await? {
+        Some(timestamp_index) => timestamp_index,
+        None => return Ok(0),
+    };
+
+    let buckets = &timestamp_index.buckets;
+    let last_timestamp = match buckets.values().last() {
+        Some(last) => last.last_timestamp,
+        None => return Ok(0),
+    };
+
+    let mut hourly_tps = db.load_hourly_tps(chain).await?.unwrap_or_default();
+    let mut num_new_hours = 0;
+
+    // The index may not have all blocks of the hour it starts in, and an
+    // hour is complete once there are blocks after it
+    for (hour_start, bucket) in buckets.iter().skip(1) {
+        if hour_start + TIMESTAMP_BUCKET_SECONDS > last_timestamp {
+            break;
+        }
+        if let Entry::Vacant(entry) = hourly_tps.hours.entry(*hour_start) {
+            entry.insert(bucket.num_txs as f64 / TIMESTAMP_BUCKET_SECONDS as f64);
+            num_new_hours += 1;
+        }
+    }
+
+    let min_timestamp = last_timestamp.saturating_sub(retention_seconds);
+    let num_hours = hourly_tps.hours.len();
+    hourly_tps
+        .hours
+        .retain(|hour_start, _| *hour_start >= min_timestamp);
+
+    if num_new_hours > 0 || hourly_tps.hours.len() < num_hours {
+        db.store_hourly_tps(chain, &hourly_tps).await?;
+    }
+
+    Ok(num_new_hours)
+}
+
+/// None if any bucket has no senders.
+fn count_senders<'a>(mut buckets: impl Iterator<Item = &'a TimestampBucket>) -> Option<u64> {
+    buckets
+        .try_fold(SenderSketch::default(), |mut senders, bucket| {
+            senders.merge(bucket.senders.as_ref()?);
+            Some(senders)
+        })
+        .map(|senders| senders.estimate())
+}
+
+fn date_of(timestamp: u64) -> Result<NaiveDate> {
+    let datetime = Utc
+        .timestamp_opt(i64::try_from(timestamp)?, 0)
+        .single()
+        .ok_or_else(|| anyhow!("invalid timestamp {}", timestamp))?;
+    Ok(datetime.naive_utc().date())
+}
+
+#[cfg(test)]
+mod test_daily {
+    use super::{update_daily_tps, update_hourly_tps};
+    use crate::test_helpers::{make_blocks, GENESIS_TIMESTAMP};
+    use chrono::NaiveDate;
+    use realtps_common::{
+        chain::Chain,
+        db::{Db, TimestampIndex},
+        memory_db::MemoryDb,
+        sketch::SenderSketch,
+    };
+    use std::sync::Arc;
+
+    const CHAIN: Chain = Chain::Ethereum;
+
+    #[tokio::test]
+    async fn calculate_whole_days() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+
+        // Three and a half days from midnight, at 0.1 tps
+        let mut timestamp_index = TimestampIndex::default();
+        for block in make_blocks(CHAIN, 500, 600, 60) {
+            timestamp_index.insert(&block);
+        }
+        db.store_timestamp_index(CHAIN, &timestamp_index).await?;
+
+        let new_days = update_daily_tps(CHAIN, &db).await?;
+        let dates: Vec<_> = new_days.iter().map(|day| day.date).collect();
+        assert_eq!(
+            dates,
+            vec![
+                NaiveDate::from_ymd_opt(2021, 5, 4).expect("date"),
+                NaiveDate::from_ymd_opt(2021, 5, 5).expect("date"),
+            ]
+        );
+        for day in new_days {
+            assert!((day.tps - 0.1).abs() < 1e-9);
+            assert_eq!(day.active_addresses, None);
+        }
+
+        // Days are only calculated once
+        assert!(update_daily_tps(CHAIN, &db).await?.is_empty());
+        assert_eq!(
+            db.load_daily_tps(CHAIN).await?.expect("daily").days.len(),
+            2
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn count_active_addresses() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+
+        // Each block has 20 senders, half of them seen every block
+        let mut timestamp_index = TimestampIndex::default();
+        for mut block in make_blocks(CHAIN, 300, 600, 60) {
+            let mut senders = SenderSketch::default();
+            for sender in 0..10 {
+                senders.insert(&format!("regular {}", sender));
+                senders.insert(&format!("{} {}", block.block_number, sender));
+            }
+            block.senders = Some(senders);
+            timestamp_index.insert(&block);
+        }
+        db.store_timestamp_index(CHAIN, &timestamp_index).await?;
+
+        let new_days = update_daily_tps(CHAIN, &db).await?;
+        assert_eq!(new_days.len(), 1);
+        // 10 regular senders and 10 new ones in each of 144 blocks
+        let active_addresses = new_days[0].active_addresses.expect("active addresses") as f64;
+        assert!((active_addresses - 1450.0).abs() < 1450.0 * 0.4);
+
+        let daily_tps = db.load_daily_tps(CHAIN).await?.expect("daily");
+        assert_eq!(daily_tps.active_addresses.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn calculate_whole_hours() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+
+        // Ten hours, each busier than the last, from half past the hour
+        let mut blocks = make_blocks(CHAIN, 630, 60, 0);
+        for block in &mut blocks {
+            block.num_txs = block.block_number / 60;
+        }
+        let mut timestamp_index = TimestampIndex::default();
+        for block in &blocks[30..] {
+            timestamp_index.insert(block);
+        }
+        db.store_timestamp_index(CHAIN, &timestamp_index).await?;
+
+        // The first hour is partial, and the last still being filled
+        assert_eq!(update_hourly_tps(CHAIN, &db, 60 * 60 * 24).await?, 9);
+        assert_eq!(update_hourly_tps(CHAIN, &db, 60 * 60 * 24).await?, 0);
+        let hourly_tps = db.load_hourly_tps(CHAIN).await?.expect("hourly");
+        let (first_hour, first_tps) = hourly_tps.hours.iter().next().expect("hour");
+        assert_eq!(*first_hour, GENESIS_TIMESTAMP + 60 * 60);
+        assert!((first_tps - 1.0 / 60.0).abs() < 1e-9);
+
+        // Genesis is a Monday at 0:00 UTC
+        let heatmap = hourly_tps.heatmap();
+        assert_eq!(heatmap[0][0], None);
+        assert!((heatmap[0][9].expect("tps") - 9.0 / 60.0).abs() < 1e-9);
+        assert_eq!(heatmap[1][0], None);
+
+        // Hours are dropped after the retention period
+        update_hourly_tps(CHAIN, &db, 60 * 60 * 3).await?;
+        let hourly_tps = db.load_hourly_tps(CHAIN).await?.expect("hourly");
+        assert_eq!(hourly_tps.hours.len(), 2);
+        Ok(())
+    }
+}
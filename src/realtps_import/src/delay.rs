@@ -1,13 +1,20 @@
+use crate::shutdown;
 use crate::Chain;
 use crate::Job;
 use anyhow::Result;
+use ethers::providers::{HttpClientError, ProviderError};
 use log::{debug, warn};
 use rand::{
     self,
     distributions::{Distribution, Uniform},
 };
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::{LazyLock, Mutex, RwLock};
 use tokio::time::{self, Duration};
 
 /// The default rate to request blocks at, in ms.
@@ -16,28 +23,201 @@ const DEFAULT_BLOCK_PACE: u64 = 500;
 /// The default time to wait between imports, in ms.
 const DEFAULT_RESCAN_DELAY: u64 = 30000;
 
+/// Rescan delays paced by a chain's block time are kept between these, in ms.
+const MIN_RESCAN_DELAY: u64 = 1000;
+const MAX_RESCAN_DELAY: u64 = 600000;
+
+/// Recalculation delays paced by a chain's block time are kept between these,
+/// in ms.
+const MIN_RECALCULATE_DELAY: u64 = 5000;
+const MAX_RECALCULATE_DELAY: u64 = 300000;
+
+/// The time to wait before repeating a job that failed with a fatal error,
+/// which is usually fixed by reconfiguring, in ms.
+const FATAL_ERROR_DELAY: u64 = 30 * 60 * 1000;
+
+/// Each chain's typical block time in ms, learned from its recently imported
+/// blocks.
+static BLOCK_TIMES: LazyLock<Mutex<HashMap<Chain, u64>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Records a chain's typical block time, to pace its imports by.
+pub fn learn_block_time(chain: Chain, block_time_ms: u64) {
+    debug!("block time for chain {}: {} ms", chain, block_time_ms);
+    BLOCK_TIMES
+        .lock()
+        .expect("lock")
+        .insert(chain, block_time_ms);
+}
+
+fn block_time(chain: Chain) -> Option<u64> {
+    BLOCK_TIMES.lock().expect("lock").get(&chain).copied()
+}
+
+/// JSON-RPC error codes for requests the RPC will never accept: method not
+/// found and invalid params.
+const FATAL_JSON_RPC_CODES: [i64; 2] = [-32601, -32602];
+
+/// How to retry failed requests and jobs.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// The delay before the first retry, in ms.
+    pub initial_delay_ms: u64,
+    /// How much longer each further retry waits than the last.
+    pub multiplier: f64,
+    /// The longest to wait between retries, in ms.
+    pub max_delay_ms: u64,
+    /// Up to this many ms are added to each delay at random.
+    pub jitter_ms: u64,
+    /// How many times to make a request before giving up. Failed jobs are
+    /// repeated until they succeed, unless the error is fatal.
+    pub max_attempts: u32,
+    /// After this many failures in a row of a chain's job, the chain is
+    /// quarantined until resumed. 0 never quarantines.
+    pub quarantine_after: u32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            initial_delay_ms: 500,
+            multiplier: 2.0,
+            max_delay_ms: 30000,
+            jitter_ms: 10,
+            max_attempts: 3,
+            quarantine_after: 100,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delay before the given retry, counting from 1, without jitter.
+    pub fn delay_ms(&self, retry_num: u32) -> u64 {
+        let exponent = i32::try_from(retry_num.saturating_sub(1)).unwrap_or(i32::MAX);
+        let delay_ms = self.initial_delay_ms as f64 * self.multiplier.powi(exponent);
+        // Saturates for delays too long for a u64
+        (delay_ms as u64).min(self.max_delay_ms)
+    }
+}
+
+struct RetryPolicies {
+    default: RetryPolicy,
+    chains: HashMap<Chain, RetryPolicy>,
+}
+
+static RETRY_POLICIES: RwLock<Option<RetryPolicies>> = RwLock::new(None);
+
+/// Sets the retry policy for all chains, and those for some chains in its
+/// place, replacing any set before, as when the config is reloaded. Until
+/// set, the default policy is used.
+pub fn set_retry_policies(default: RetryPolicy, chains: HashMap<Chain, RetryPolicy>) {
+    *RETRY_POLICIES.write().expect("lock") = Some(RetryPolicies { default, chains });
+}
+
+/// The retry policy for a chain, or for jobs of no one chain.
+pub fn retry_policy(chain: Option<Chain>) -> RetryPolicy {
+    match &*RETRY_POLICIES.read().expect("lock") {
+        Some(policies) => chain
+            .and_then(|chain| policies.chains.get(&chain))
+            .copied()
+            .unwrap_or(policies.default),
+        None => RetryPolicy::default(),
+    }
+}
+
+/// An error that retrying can't fix, like a chain without a working RPC.
+#[derive(Debug)]
+pub struct FatalError(pub String);
+
+impl fmt::Display for FatalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for FatalError {}
+
+/// Whether retrying can't fix an error. These are usually a misconfiguration,
+/// like an RPC rejecting an API key or not serving a method, so they are
+/// reported instead of retried forever.
+pub fn is_fatal(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        if cause.is::<FatalError>() {
+            true
+        } else if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            is_fatal_reqwest_error(e)
+        } else if let Some(ProviderError::JsonRpcClientError(e)) =
+            cause.downcast_ref::<ProviderError>()
+        {
+            match e.downcast_ref::<HttpClientError>() {
+                Some(HttpClientError::ReqwestError(e)) => is_fatal_reqwest_error(e),
+                Some(HttpClientError::JsonRpcError(e)) => FATAL_JSON_RPC_CODES.contains(&e.code),
+                _ => false,
+            }
+        } else {
+            false
+        }
+    })
+}
+
+/// Invalid requests and client errors other than timeouts and rate limiting.
+fn is_fatal_reqwest_error(e: &reqwest::Error) -> bool {
+    e.is_builder()
+        || e.status().is_some_and(|status| {
+            status.is_client_error()
+                && status != StatusCode::REQUEST_TIMEOUT
+                && status != StatusCode::TOO_MANY_REQUESTS
+        })
+}
+
 /// The pace we want to request blocks at, in ms.
+///
+/// Once the chain's block time is known, blocks are requested at least a
+/// little faster than the chain makes them, so imports can catch up.
 pub fn block_pace(chain: Chain) -> u64 {
-    match chain {
+    let block_pace = match chain {
         Chain::Arbitrum => 400, // Subsecond block time
         Chain::Bitcoin => 2000,
         Chain::Elrond => 1000,   // 6s block time
         Chain::Optimism => 2000, // Got blocked at 1000ms, unclear what rate they want
         // Need to go fast to keep up.
         // Solana's RpcClient will use its built in rate limiter when connecting to public nodes.
-        Chain::Solana => 0,
+        Chain::Solana | Chain::SolanaDevnet => 0,
         _ => DEFAULT_BLOCK_PACE,
+    };
+
+    match block_time(chain) {
+        Some(block_time) => block_pace.min(block_time * 9 / 10),
+        None => block_pace,
     }
 }
 
-/// Wait between imports, in s.
+/// Wait between imports.
 ///
 /// This should be somewhat longer than the average block production time (or
 /// perhaps the block production time / 2) to avoid making requests for new
 /// blocks when there are none, but low enough that the block pace can catch up
-/// to new blocks.
+/// to new blocks. Once the chain's block time is known it is used, otherwise
+/// these guesses.
 pub async fn rescan_delay(chain: Chain) {
-    let delay_msecs = match chain {
+    let delay_msecs = match block_time(chain) {
+        Some(block_time) => (block_time * 5 / 4).clamp(MIN_RESCAN_DELAY, MAX_RESCAN_DELAY),
+        None => default_rescan_delay(chain),
+    };
+
+    debug!("delaying {} ms to rescan chain {}", delay_msecs, chain);
+    delay(delay_msecs).await
+}
+
+/// A chain's block time in ms, as learned, or guessed from its default rescan
+/// delay until then.
+pub fn expected_block_time(chain: Chain) -> u64 {
+    block_time(chain).unwrap_or_else(|| default_rescan_delay(chain))
+}
+
+fn default_rescan_delay(chain: Chain) -> u64 {
+    match chain {
         Chain::Arbitrum => 5000, // Subsecond block time
         Chain::Bitcoin => 600000,
         Chain::Hedera => 10000,
@@ -45,30 +225,68 @@ pub async fn rescan_delay(chain: Chain) {
         Chain::Optimism => 15000, // Unclear, just experimenting
         Chain::Pivx => 5000,
         Chain::Polkadot => 7000, // 6s block time, server rate-limited, can't wait too long
-        Chain::Solana => 1000,   // Need to go fast to keep up
+        Chain::Solana | Chain::SolanaDevnet => 1000, // Need to go fast to keep up
         _ => DEFAULT_RESCAN_DELAY,
-    };
-
-    debug!("delaying {} ms to rescan chain {}", delay_msecs, chain);
-    delay(delay_msecs).await
+    }
 }
 
 async fn delay(base_ms: u64) {
-    let jitter = Uniform::from(0..10);
-    let delay_msecs = base_ms + jitter.sample(&mut rand::thread_rng());
+    delay_with_jitter(base_ms, 10).await
+}
+
+async fn delay_with_jitter(base_ms: u64, jitter_ms: u64) {
+    let jitter = Uniform::from(0..=jitter_ms);
+    let delay_msecs = base_ms.saturating_add(jitter.sample(&mut rand::thread_rng()));
     let delay_time = Duration::from_millis(delay_msecs);
-    time::sleep(delay_time).await;
+    // Cut short to shut down
+    tokio::select! {
+        _ = time::sleep(delay_time) => {}
+        _ = shutdown::wait() => {}
+    }
 }
 
-pub async fn job_error_delay(job: &Job) {
-    let msecs = 1000;
+/// Waits to repeat a job after its `num_failures`th failure in a row.
+pub async fn job_error_delay(job: &Job, num_failures: u32) {
+    let policy = retry_policy(job.chain());
+    let msecs = policy.delay_ms(num_failures);
     debug!("delaying {} ms to retry job {:?}", msecs, job);
+    delay_with_jitter(msecs, policy.jitter_ms).await;
+}
+
+/// Waits to repeat a job after an error retrying can't fix, for jobs that
+/// aren't quarantined.
+pub async fn fatal_error_delay(job: &Job) {
+    let msecs = FATAL_ERROR_DELAY;
+    debug!(
+        "delaying {} ms to retry job {:?} after a fatal error",
+        msecs, job
+    );
     delay(msecs).await;
 }
 
-pub async fn recalculate_delay() {
-    let msecs = 5000;
-    debug!("delaying {} ms before recaclulating", msecs);
+/// Waits to calculate a chain's TPS again, for about as long as it takes to
+/// make a block, since there's nothing new to calculate before then.
+pub async fn recalculate_delay(chain: Chain) {
+    let msecs = recalculate_delay_ms(block_time(chain));
+    debug!("delaying {} ms before recalculating chain {}", msecs, chain);
+    delay(msecs).await;
+}
+
+fn recalculate_delay_ms(block_time: Option<u64>) -> u64 {
+    block_time
+        .unwrap_or(MIN_RECALCULATE_DELAY)
+        .clamp(MIN_RECALCULATE_DELAY, MAX_RECALCULATE_DELAY)
+}
+
+pub async fn aggregate_delay() {
+    let msecs = MIN_RECALCULATE_DELAY;
+    debug!("delaying {} ms before aggregating", msecs);
+    delay(msecs).await;
+}
+
+pub async fn daily_tps_delay() {
+    let msecs = 60 * 60 * 1000;
+    debug!("delaying {} ms to calculate daily tps", msecs);
     delay(msecs).await;
 }
 
@@ -78,12 +296,47 @@ pub async fn remove_data_delay() {
     delay(msecs).await;
 }
 
+pub async fn backfill_delay() {
+    let msecs = 60 * 60 * 1000;
+    debug!("delaying {} ms to backfill missing blocks", msecs);
+    delay(msecs).await;
+}
+
+/// Wait to check again whether a paused chain has been resumed.
+pub async fn paused_delay() {
+    let msecs = 30 * 1000;
+    debug!("delaying {} ms to check for resumed chains", msecs);
+    delay(msecs).await;
+}
+
+/// Wait to check again whether another instance's lease of a chain has
+/// expired. Jittered widely, so instances waiting on it don't all try to
+/// take it at once.
+pub async fn leased_delay() {
+    let msecs = 30 * 1000;
+    debug!("delaying {} ms to check for expired leases", msecs);
+    delay_with_jitter(msecs, 10 * 1000).await;
+}
+
+/// Leases are renewed a few times before they expire, so one failed renewal
+/// doesn't lose them.
+pub async fn lease_renew_delay(lease_secs: u64) {
+    let msecs = lease_secs.saturating_mul(1000) / 3;
+    debug!("delaying {} ms to renew leases", msecs);
+    delay(msecs).await;
+}
+
+pub async fn gap_report_delay() {
+    let msecs = 60 * 60 * 24 * 1000;
+    debug!("delaying {} ms to report missing blocks", msecs);
+    delay(msecs).await;
+}
+
 pub async fn retry_if_err<'caller, F, T>(chain: Chain, f: F) -> Result<T>
 where
     F: Fn() -> Pin<Box<dyn Future<Output = Result<T>> + Send + 'caller>>,
 {
-    let tries = 3;
-    let base_delay_ms = 500;
+    let policy = retry_policy(Some(chain));
     let mut try_num = 1;
 
     loop {
@@ -91,15 +344,15 @@ where
         match r {
             Ok(r) => break Ok(r),
             Err(e) => {
-                if try_num == tries {
+                if try_num >= policy.max_attempts || is_fatal(&e) {
                     break Err(e);
                 } else {
-                    let delay_ms = base_delay_ms * try_num;
+                    let delay_ms = policy.delay_ms(try_num);
                     warn!(
                         "for chain {} received err {}. retrying in {} ms",
                         chain, e, delay_ms
                     );
-                    delay(delay_ms).await;
+                    delay_with_jitter(delay_ms, policy.jitter_ms).await;
                 }
             }
         }
@@ -111,8 +364,7 @@ pub async fn retry_if_none<'caller, F, T>(chain: Chain, f: F) -> Result<Option<T
 where
     F: Fn() -> Pin<Box<dyn Future<Output = Result<Option<T>>> + Send + 'caller>>,
 {
-    let tries = 3;
-    let base_delay_ms = 500;
+    let policy = retry_policy(Some(chain));
     let mut try_num = 1;
 
     loop {
@@ -120,18 +372,56 @@ where
         match r {
             Some(r) => break Ok(Some(r)),
             None => {
-                if try_num == tries {
+                if try_num >= policy.max_attempts {
                     break Ok(None);
                 } else {
-                    let delay_ms = base_delay_ms * try_num;
+                    let delay_ms = policy.delay_ms(try_num);
                     warn!(
                         "for chain {} received None. retrying in {} ms",
                         chain, delay_ms
                     );
-                    delay(delay_ms).await;
+                    delay_with_jitter(delay_ms, policy.jitter_ms).await;
                 }
             }
         }
         try_num += 1;
     }
 }
+
+#[cfg(test)]
+mod test_delay {
+    use super::{is_fatal, recalculate_delay_ms, FatalError, RetryPolicy};
+    use anyhow::{anyhow, Context};
+
+    #[test]
+    fn back_off_up_to_max_delay() {
+        let policy = RetryPolicy {
+            initial_delay_ms: 100,
+            multiplier: 3.0,
+            max_delay_ms: 1000,
+            ..RetryPolicy::default()
+        };
+        assert_eq!(policy.delay_ms(1), 100);
+        assert_eq!(policy.delay_ms(2), 300);
+        assert_eq!(policy.delay_ms(3), 900);
+        assert_eq!(policy.delay_ms(4), 1000);
+        assert_eq!(policy.delay_ms(u32::MAX), 1000);
+    }
+
+    #[test]
+    fn recalculate_about_once_a_block() {
+        assert_eq!(recalculate_delay_ms(None), 5000);
+        assert_eq!(recalculate_delay_ms(Some(400)), 5000);
+        assert_eq!(recalculate_delay_ms(Some(12000)), 12000);
+        assert_eq!(recalculate_delay_ms(Some(600000)), 300000);
+    }
+
+    #[test]
+    fn classify_fatal_errors() {
+        assert!(!is_fatal(&anyhow!("connection reset")));
+        let e = anyhow::Error::new(FatalError("no client for ethereum".to_string()));
+        assert!(is_fatal(&e));
+        let e: anyhow::Result<()> = Err(e);
+        assert!(is_fatal(&e.context("importing").unwrap_err()));
+    }
+}
@@ -0,0 +1,295 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use realtps_common::{
+    chain::Chain,
+    db::{Db, TpsPoint, TpsWindow},
+    metadata::{self, ChainKind, ChainMetadata, MetadataOverride},
+};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+#[derive(clap::ArgEnum, Debug, Copy, Clone)]
+pub enum ExportFormat {
+    Csv,
+    Json,
+}
+
+/// A chain's published TPS and metrics, for `export`.
+#[derive(Serialize, Debug)]
+pub struct ChainExport {
+    pub chain: Chain,
+    pub metadata: ChainMetadata,
+    pub calculated_at: Option<DateTime<Utc>>,
+    /// The chain is disabled, so these are no longer updated
+    pub stale: bool,
+    pub windows: Vec<WindowExport>,
+    /// Oldest first. Only with `--history`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub history: Option<Vec<TpsPoint>>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct WindowExport {
+    pub window: TpsWindow,
+    pub tps: f64,
+    pub gas_per_second: Option<f64>,
+    pub fees_per_second: Option<f64>,
+    pub value_per_second: Option<f64>,
+    pub value_usd_per_second: Option<f64>,
+    pub token_transfers_per_second: Option<f64>,
+    pub utilization: Option<f64>,
+    pub empty_block_share: Option<f64>,
+}
+
+const CURRENT_CSV_HEADER: &str = "chain,window,tps,gas_per_second,fees_per_second,value_per_second,value_usd_per_second,token_transfers_per_second,utilization,empty_block_share,calculated_at,stale,name,ticker,kind";
+const HISTORY_CSV_HEADER: &str = "chain,timestamp,window,tps";
+
+/// Writes each chain's published TPS and metrics over every window, or with
+/// `history`, also its TPS history, to `output` or stdout. As CSV, the
+/// history replaces the current values, one row per calculation and window.
+/// Chains that were never calculated are left out. Each chain's metadata is
+/// its built-in one with any of `metadata_overrides` applied.
+pub async fn export(
+    chains: &[Chain],
+    db: &Arc<dyn Db>,
+    metadata_overrides: &HashMap<Chain, MetadataOverride>,
+    format: ExportFormat,
+    history: bool,
+    output: Option<&Path>,
+) -> Result<()> {
+    let mut exports = vec![];
+    for chain in chains {
+        let chain_metadata = metadata::chain_metadata(*chain, metadata_overrides);
+        if let Some(export) = load_export(*chain, db, chain_metadata, history).await? {
+            exports.push(export);
+        }
+    }
+
+    let mut writer: Box<dyn Write> = match output {
+        Some(path) => Box::new(BufWriter::new(
+            File::create(path).context(format!("unable to create {}", path.display()))?,
+        )),
+        None => Box::new(io::stdout().lock()),
+    };
+    match format {
+        ExportFormat::Json => {
+            serde_json::to_writer_pretty(&mut writer, &exports)?;
+            writeln!(writer)?;
+        }
+        ExportFormat::Csv if history => write_history_csv(&mut writer, &exports)?,
+        ExportFormat::Csv => write_current_csv(&mut writer, &exports)?,
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// A chain's export, `None` if it has no TPS.
+pub async fn load_export(
+    chain: Chain,
+    db: &Arc<dyn Db>,
+    metadata: ChainMetadata,
+    history: bool,
+) -> Result<Option<ChainExport>> {
+    let stats = db.load_chain_stats(chain).await?.unwrap_or_default();
+    let mut windows = vec![];
+    for window in TpsWindow::all_windows() {
+        let tps = match db.load_tps(chain, window).await? {
+            Some(tps) => tps,
+            None => continue,
+        };
+        let window_stats = stats.windows.get(&window).cloned().unwrap_or_default();
+        windows.push(WindowExport {
+            window,
+            tps,
+            gas_per_second: db.load_gas_per_second(chain, window).await?,
+            fees_per_second: window_stats.fees_per_second,
+            value_per_second: window_stats.value_per_second,
+            value_usd_per_second: window_stats.value_usd_per_second,
+            token_transfers_per_second: window_stats.token_transfers_per_second,
+            utilization: window_stats.utilization,
+            empty_block_share: window_stats.empty_block_share,
+        });
+    }
+    if windows.is_empty() {
+        return Ok(None);
+    }
+
+    let calculation_log = db.load_calculation_log(chain).await?;
+    let history = match history {
+        true => Some(db.load_tps_history(chain).await?),
+        false => None,
+    };
+    Ok(Some(ChainExport {
+        chain,
+        metadata,
+        calculated_at: calculation_log.as_ref().map(|log| log.calculating_end),
+        stale: calculation_log.is_some_and(|log| log.stale),
+        windows,
+        history,
+    }))
+}
+
+pub fn write_current_csv(writer: &mut dyn Write, exports: &[ChainExport]) -> Result<()> {
+    writeln!(writer, "{}", CURRENT_CSV_HEADER)?;
+    for export in exports {
+        let calculated_at = export
+            .calculated_at
+            .map(|time| time.to_rfc3339())
+            .unwrap_or_default();
+        for window in &export.windows {
+            writeln!(
+                writer,
+                "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+                export.chain,
+                window_name(window.window),
+                window.tps,
+                csv_value(window.gas_per_second),
+                csv_value(window.fees_per_second),
+                csv_value(window.value_per_second),
+                csv_value(window.value_usd_per_second),
+                csv_value(window.token_transfers_per_second),
+                csv_value(window.utilization),
+                csv_value(window.empty_block_share),
+                calculated_at,
+                export.stale,
+                csv_text(&export.metadata.name),
+                csv_text(&export.metadata.ticker),
+                kind_name(export.metadata.kind),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+pub fn write_history_csv(writer: &mut dyn Write, exports: &[ChainExport]) -> Result<()> {
+    writeln!(writer, "{}", HISTORY_CSV_HEADER)?;
+    for export in exports {
+        for point in export.history.iter().flatten() {
+            for (window, tps) in &point.tps {
+                writeln!(
+                    writer,
+                    "{},{},{},{}",
+                    export.chain,
+                    point.timestamp,
+                    window_name(*window),
+                    tps
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// As named in the JSON, rather than displayed like `7d`.
+fn window_name(window: TpsWindow) -> String {
+    format!("{:?}", window).to_lowercase()
+}
+
+/// As named in the JSON, like `sidechain`.
+fn kind_name(kind: ChainKind) -> String {
+    format!("{:?}", kind).to_lowercase()
+}
+
+/// Quoted if it has commas or quotes, which names may.
+fn csv_text(text: &str) -> String {
+    if text.contains([',', '"']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+/// Unknown values are left empty.
+fn csv_value(value: Option<f64>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test_export {
+    use super::{load_export, write_current_csv, write_history_csv};
+    use realtps_common::{
+        chain::Chain,
+        db::{Db, TpsPoint, TpsWindow},
+        memory_db::MemoryDb,
+        metadata::{chain_metadata, MetadataOverride},
+    };
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    const CHAIN: Chain = Chain::Ethereum;
+
+    #[tokio::test]
+    async fn export_current_and_history() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        db.store_tps(CHAIN, TpsWindow::Week, 12.5).await?;
+        db.store_tps(CHAIN, TpsWindow::Hour, 15.0).await?;
+        db.store_gas_per_second(CHAIN, TpsWindow::Hour, 1000.0)
+            .await?;
+        db.append_tps_history(
+            CHAIN,
+            &TpsPoint {
+                timestamp: 1_650_000_000,
+                tps: [(TpsWindow::Week, 12.5)].into(),
+            },
+        )
+        .await?;
+
+        let metadata = CHAIN.metadata();
+        assert!(
+            load_export(Chain::Solana, &db, Chain::Solana.metadata(), false)
+                .await?
+                .is_none()
+        );
+
+        let export = load_export(CHAIN, &db, metadata.clone(), false)
+            .await?
+            .expect("export");
+        assert!(export.history.is_none());
+        let mut csv = vec![];
+        write_current_csv(&mut csv, &[export])?;
+        let csv = String::from_utf8(csv)?;
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("chain,window,tps,gas_per_second,"));
+        assert!(lines[1].starts_with("ethereum,hour,15,1000,"));
+        assert!(lines[2].starts_with("ethereum,week,12.5,,"));
+        assert!(lines[2].ends_with(",false,Ethereum,ETH,l1"));
+
+        let export = load_export(CHAIN, &db, metadata, true)
+            .await?
+            .expect("export");
+        let mut csv = vec![];
+        write_history_csv(&mut csv, &[export])?;
+        assert_eq!(
+            String::from_utf8(csv)?,
+            "chain,timestamp,window,tps\nethereum,1650000000,week,12.5\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn override_metadata() {
+        let overrides = HashMap::from([(
+            Chain::Polygon,
+            MetadataOverride {
+                name: Some("Polygon, PoS".to_string()),
+                ticker: Some("POL".to_string()),
+                ..MetadataOverride::default()
+            },
+        )]);
+        let metadata = chain_metadata(Chain::Polygon, &overrides);
+        assert_eq!(metadata.name, "Polygon, PoS");
+        assert_eq!(metadata.ticker, "POL");
+        assert_eq!(metadata.website, Chain::Polygon.metadata().website);
+        assert_eq!(
+            chain_metadata(Chain::Ethereum, &overrides),
+            Chain::Ethereum.metadata()
+        );
+        assert_eq!(super::csv_text(&metadata.name), "\"Polygon, PoS\"");
+    }
+}
@@ -0,0 +1,325 @@
+use crate::client::{Client, FinalizedBlock};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+use log::{info, warn};
+use realtps_common::{
+    chain::Chain,
+    db::{Block, EndpointHealth, EndpointScore},
+};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use tokio::time::{self, Duration, Instant};
+
+/// Requests taking longer than this are treated as failed.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often to send a request to a degraded endpoint first, to notice when
+/// it recovers.
+const PROBE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// The weight of each request in an endpoint's moving averages.
+const SCORE_WEIGHT: f64 = 0.3;
+
+/// Endpoints failing more often than this are only used when the others
+/// fail, or to probe them.
+const DEGRADED_ERROR_RATE: f64 = 0.5;
+
+/// Sends a chain's requests to the healthiest of several endpoints, moving on
+/// to the next healthiest when one fails or times out.
+///
+/// Endpoints are ranked by their recent error rate and latency. Until an
+/// endpoint has been measured, the configured order is used.
+pub struct FailoverClient {
+    chain: Chain,
+    /// In the configured order, the primary first
+    endpoints: Vec<Endpoint>,
+    scores: Mutex<Vec<EndpointScore>>,
+    last_probe: Mutex<Instant>,
+    /// Which of the degraded endpoints to probe next
+    probe_turn: AtomicUsize,
+}
+
+pub struct Endpoint {
+    /// The endpoint's host, which its score is stored under. Not its URL,
+    /// which may contain an API key.
+    pub name: String,
+    pub client: Box<dyn Client>,
+    /// Only used when every other endpoint fails, like an indexer API with a
+    /// tight rate limit
+    pub last_resort: bool,
+}
+
+impl FailoverClient {
+    /// Starts from the scores in `health`, as stored by a previous run.
+    pub fn new(
+        chain: Chain,
+        endpoints: Vec<Endpoint>,
+        health: Option<EndpointHealth>,
+    ) -> FailoverClient {
+        assert!(!endpoints.is_empty());
+        let health = health.unwrap_or_default();
+        let scores = endpoints
+            .iter()
+            .map(|endpoint| {
+                health
+                    .endpoints
+                    .get(&endpoint.name)
+                    .cloned()
+                    .unwrap_or_default()
+            })
+            .collect();
+        FailoverClient {
+            chain,
+            endpoints,
+            scores: Mutex::new(scores),
+            last_probe: Mutex::new(Instant::now()),
+            probe_turn: AtomicUsize::new(0),
+        }
+    }
+
+    /// Makes a request to each endpoint in order of health until one
+    /// succeeds, returning the last error if none do.
+    async fn request<'a, T>(
+        &'a self,
+        request: impl Fn(&'a dyn Client) -> BoxFuture<'a, Result<T>>,
+    ) -> Result<T> {
+        let mut last_error = None;
+
+        for index in self.endpoint_order() {
+            let endpoint = &self.endpoints[index];
+            let start = Instant::now();
+            let result = time::timeout(REQUEST_TIMEOUT, request(endpoint.client.as_ref()))
+                .await
+                .unwrap_or_else(|_| Err(anyhow!("timed out after {:?}", REQUEST_TIMEOUT)));
+            match result {
+                Ok(result) => {
+                    self.record(index, Some(start.elapsed()));
+                    return Ok(result);
+                }
+                Err(e) => {
+                    warn!(
+                        "endpoint {} for chain {} failed: {}",
+                        endpoint.name, self.chain, e
+                    );
+                    self.record(index, None);
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        Err(last_error.expect("an endpoint"))
+    }
+
+    /// The healthiest endpoints first: those not degraded, then those
+    /// measured, then the fastest, with last resorts after all of them.
+    /// Every `PROBE_INTERVAL` a degraded or unmeasured endpoint goes first
+    /// instead, unless it's a last resort.
+    fn endpoint_order(&self) -> Vec<usize> {
+        let scores = self.scores.lock().expect("lock");
+        let is_degraded = |index: usize| scores[index].error_rate > DEGRADED_ERROR_RATE;
+        let is_unmeasured = |index: usize| scores[index].latency_ms.is_none();
+        let is_last_resort = |index: usize| self.endpoints[index].last_resort;
+        let cost = |index: usize| {
+            let score = &scores[index];
+            score.latency_ms.unwrap_or_default() * (1.0 + score.error_rate)
+        };
+
+        let mut order: Vec<usize> = (0..self.endpoints.len()).collect();
+        // Stable, so ties keep the configured order
+        order.sort_by(|a, b| {
+            (is_last_resort(*a), is_degraded(*a), is_unmeasured(*a))
+                .cmp(&(is_last_resort(*b), is_degraded(*b), is_unmeasured(*b)))
+                .then(cost(*a).total_cmp(&cost(*b)))
+        });
+
+        let unhealthy: Vec<usize> = order
+            .iter()
+            .copied()
+            .skip(1)
+            .filter(|index| !is_last_resort(*index))
+            .filter(|index| is_degraded(*index) || is_unmeasured(*index))
+            .collect();
+        if !unhealthy.is_empty() {
+            let mut last_probe = self.last_probe.lock().expect("lock");
+            if last_probe.elapsed() >= PROBE_INTERVAL {
+                *last_probe = Instant::now();
+                let turn = self.probe_turn.fetch_add(1, Ordering::SeqCst);
+                let probed = unhealthy[turn % unhealthy.len()];
+                info!(
+                    "probing endpoint {} for chain {}",
+                    self.endpoints[probed].name, self.chain
+                );
+                order.retain(|index| *index != probed);
+                order.insert(0, probed);
+            }
+        }
+
+        order
+    }
+
+    /// Updates an endpoint's moving averages with a request's latency, or
+    /// `None` if it failed.
+    fn record(&self, index: usize, latency: Option<Duration>) {
+        let mut scores = self.scores.lock().expect("lock");
+        let score = &mut scores[index];
+        let failed = if latency.is_some() { 0.0 } else { 1.0 };
+        score.error_rate = score.error_rate * (1.0 - SCORE_WEIGHT) + failed * SCORE_WEIGHT;
+        if let Some(latency) = latency {
+            let latency_ms = latency.as_secs_f64() * 1000.0;
+            score.latency_ms = Some(match score.latency_ms {
+                Some(average) => average * (1.0 - SCORE_WEIGHT) + latency_ms * SCORE_WEIGHT,
+                None => latency_ms,
+            });
+        }
+        score.num_requests += 1;
+    }
+}
+
+#[async_trait]
+impl Client for FailoverClient {
+    async fn client_version(&self) -> Result<String> {
+        self.request(|client| client.client_version()).await
+    }
+
+    async fn get_latest_block_number(&self) -> Result<u64> {
+        self.request(|client| client.get_latest_block_number())
+            .await
+    }
+
+    async fn get_block(&self, block_number: u64) -> Result<Option<Block>> {
+        self.request(|client| client.get_block(block_number)).await
+    }
+
+    async fn get_blocks(&self, block_numbers: &[u64]) -> Result<Vec<Block>> {
+        self.request(|client| client.get_blocks(block_numbers))
+            .await
+    }
+
+    async fn get_finalized_block(&self) -> Result<Option<FinalizedBlock>> {
+        self.request(|client| client.get_finalized_block()).await
+    }
+
+    async fn get_native_tps(&self) -> Result<Option<f64>> {
+        self.request(|client| client.get_native_tps()).await
+    }
+
+    fn endpoint_health(&self) -> Option<EndpointHealth> {
+        let scores = self.scores.lock().expect("lock");
+        let endpoints = self
+            .endpoints
+            .iter()
+            .zip(scores.iter())
+            .map(|(endpoint, score)| (endpoint.name.clone(), score.clone()))
+            .collect();
+        Some(EndpointHealth { endpoints })
+    }
+}
+
+#[cfg(test)]
+mod test_failover {
+    use super::{Endpoint, FailoverClient};
+    use crate::client::Client;
+    use crate::test_helpers::{make_blocks, MockClient};
+    use realtps_common::{
+        chain::Chain,
+        db::{EndpointHealth, EndpointScore},
+    };
+
+    const CHAIN: Chain = Chain::Ethereum;
+
+    fn make_endpoints(primary: MockClient, fallback: MockClient) -> Vec<Endpoint> {
+        vec![
+            Endpoint {
+                name: "primary".to_string(),
+                client: Box::new(primary),
+                last_resort: false,
+            },
+            Endpoint {
+                name: "fallback".to_string(),
+                client: Box::new(fallback),
+                last_resort: false,
+            },
+        ]
+    }
+
+    #[tokio::test]
+    async fn fail_over_to_next_endpoint() -> Result<(), anyhow::Error> {
+        let primary = MockClient::new(make_blocks(CHAIN, 11, 1, 5));
+        primary.set_failing(true);
+        let fallback = MockClient::new(make_blocks(CHAIN, 11, 1, 7));
+        let client = FailoverClient::new(CHAIN, make_endpoints(primary, fallback), None);
+
+        let block = client.get_block(5).await?.expect("block");
+        assert_eq!(block.num_txs, 7);
+        // The fallback is now preferred
+        assert_eq!(client.endpoint_order(), vec![1, 0]);
+
+        let health = client.endpoint_health().expect("health");
+        assert!(health.endpoints["primary"].error_rate > 0.0);
+        assert!(health.endpoints["fallback"].latency_ms.is_some());
+        assert_eq!(health.endpoints["fallback"].num_requests, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fail_when_all_endpoints_fail() {
+        let primary = MockClient::new(make_blocks(CHAIN, 11, 1, 5));
+        primary.set_failing(true);
+        let fallback = MockClient::new(make_blocks(CHAIN, 11, 1, 7));
+        fallback.set_failing(true);
+        let client = FailoverClient::new(CHAIN, make_endpoints(primary, fallback), None);
+
+        assert!(client.get_latest_block_number().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn prefer_stored_healthier_endpoint() {
+        let primary = MockClient::new(make_blocks(CHAIN, 11, 1, 5));
+        let fallback = MockClient::new(make_blocks(CHAIN, 11, 1, 7));
+        let mut health = EndpointHealth::default();
+        health.endpoints.insert(
+            "primary".to_string(),
+            EndpointScore {
+                latency_ms: Some(900.0),
+                error_rate: 0.1,
+                num_requests: 100,
+            },
+        );
+        health.endpoints.insert(
+            "fallback".to_string(),
+            EndpointScore {
+                latency_ms: Some(100.0),
+                error_rate: 0.0,
+                num_requests: 100,
+            },
+        );
+        let client = FailoverClient::new(CHAIN, make_endpoints(primary, fallback), Some(health));
+
+        assert_eq!(client.endpoint_order(), vec![1, 0]);
+    }
+
+    #[tokio::test]
+    async fn use_last_resort_only_when_others_fail() -> Result<(), anyhow::Error> {
+        let primary = MockClient::new(make_blocks(CHAIN, 11, 1, 5));
+        primary.set_failing(true);
+        let indexer = MockClient::new(make_blocks(CHAIN, 11, 1, 7));
+        let mut endpoints = make_endpoints(primary, indexer);
+        endpoints[1].last_resort = true;
+        let mut health = EndpointHealth::default();
+        health.endpoints.insert(
+            "fallback".to_string(),
+            EndpointScore {
+                latency_ms: Some(100.0),
+                error_rate: 0.0,
+                num_requests: 100,
+            },
+        );
+        let client = FailoverClient::new(CHAIN, endpoints, Some(health));
+
+        assert_eq!(client.get_block(5).await?.expect("block").num_txs, 7);
+        // Though failing, the primary is still tried first
+        assert_eq!(client.endpoint_order(), vec![0, 1]);
+        Ok(())
+    }
+}
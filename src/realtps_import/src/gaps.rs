@@ -0,0 +1,111 @@
+use crate::helpers::*;
+use anyhow::Result;
+use log::{info, warn};
+use realtps_common::{chain::Chain, db::Db};
+use std::sync::Arc;
+
+/// A run of block numbers missing from the db, inclusive.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Gap {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl Gap {
+    /// The number of block numbers in the gap. For chains that skip block
+    /// numbers, like Solana, more than the blocks missing.
+    pub fn count(&self) -> u64 {
+        self.end - self.start + 1
+    }
+}
+
+/// Logs the gaps in each chain's stored blocks.
+pub async fn report_gaps(chains: &[Chain], db: &Arc<dyn Db>) -> Result<()> {
+    for chain in chains {
+        let gaps = find_gaps(*chain, db).await?;
+        for gap in &gaps {
+            warn!(
+                "chain {} is missing blocks {} to {} ({} blocks)",
+                chain,
+                gap.start,
+                gap.end,
+                gap.count()
+            );
+        }
+        let num_missing: u64 = gaps.iter().map(Gap::count).sum();
+        info!(
+            "found {} gaps of {} blocks for chain {}",
+            gaps.len(),
+            num_missing,
+            chain
+        );
+    }
+
+    Ok(())
+}
+
+/// Finds the runs of blocks missing between the oldest and newest stored
+/// blocks.
+///
+/// Only the blocks after a jump in block numbers are loaded, to tell missing
+/// blocks from block numbers the chain skipped.
+pub async fn find_gaps(chain: Chain, db: &Arc<dyn Db>) -> Result<Vec<Gap>> {
+    let block_numbers = db.load_block_numbers(chain).await?;
+
+    let mut gaps = vec![];
+    for pair in block_numbers.windows(2) {
+        let (lower_number, block_number) = (pair[0], pair[1]);
+        if block_number == lower_number + 1 {
+            continue;
+        }
+
+        let block = match load_block(chain, db, block_number).await? {
+            Some(block) => block,
+            None => continue,
+        };
+        match block.prev_block_number {
+            Some(prev_block_number) if prev_block_number > lower_number => {
+                gaps.push(Gap {
+                    start: lower_number + 1,
+                    end: prev_block_number.min(block_number - 1),
+                });
+            }
+            _ => {}
+        }
+    }
+
+    Ok(gaps)
+}
+
+#[cfg(test)]
+mod test_gaps {
+    use super::{find_gaps, Gap};
+    use crate::test_helpers::make_blocks;
+    use realtps_common::{chain::Chain, db::Db, memory_db::MemoryDb};
+    use std::sync::Arc;
+
+    const CHAIN: Chain = Chain::Solana;
+
+    #[tokio::test]
+    async fn find_missing_blocks() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let mut blocks = make_blocks(CHAIN, 100, 1, 5);
+        // Slot 50 was skipped by the chain
+        blocks[51].prev_block_number = Some(49);
+        let blocks = blocks
+            .into_iter()
+            .filter(|block| block.block_number != 50)
+            .filter(|block| !(20..30).contains(&block.block_number))
+            .filter(|block| block.block_number != 80)
+            .collect();
+        db.store_blocks(CHAIN, blocks).await?;
+
+        let gaps = find_gaps(CHAIN, &db).await?;
+        assert_eq!(
+            gaps,
+            vec![Gap { start: 20, end: 29 }, Gap { start: 80, end: 80 }]
+        );
+        assert_eq!(gaps[0].count(), 10);
+        Ok(())
+    }
+}
@@ -1,13 +1,12 @@
 use crate::client::Client;
 use crate::delay::{retry_if_err, retry_if_none};
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use log::debug;
 use realtps_common::{
     chain::Chain,
     db::{Block, CalculationLog, Db},
 };
 use std::sync::Arc;
-use tokio::task;
 
 pub async fn fetch_live_head_block_number(chain: Chain, client: &dyn Client) -> Result<u64> {
     let live_head_block_number =
@@ -32,29 +31,11 @@ pub async fn fetch_block(chain: Chain, client: &dyn Client, block_number: u64) -
     Ok(block)
 }
 
-pub async fn store_highest_known_block_number(
-    chain: Chain,
-    db: &Arc<dyn Db>,
-    block_number: u64,
-) -> Result<()> {
-    let db = db.clone();
-    task::spawn_blocking(move || db.store_highest_block_number(chain, block_number)).await??;
-
-    debug!(
-        "new highest known block number for chain {}: {}",
-        chain, block_number
-    );
-
-    Ok(())
-}
-
 pub async fn load_highest_known_block_number(
     chain: Chain,
     db: &Arc<dyn Db>,
 ) -> Result<Option<u64>> {
-    let db = db.clone();
-    let highest_known_block_number =
-        task::spawn_blocking(move || db.load_highest_block_number(chain)).await??;
+    let highest_known_block_number = db.load_highest_block_number(chain).await?;
 
     debug!(
         "highest known block number for chain {}: {:?}",
@@ -64,34 +45,22 @@ pub async fn load_highest_known_block_number(
     Ok(highest_known_block_number)
 }
 
-pub async fn store_block(db: &Arc<dyn Db>, block: Block) -> Result<()> {
-    let db = db.clone();
-    task::spawn_blocking(move || db.store_block(block)).await??;
-    Ok(())
-}
-
 pub async fn load_block(
     chain: Chain,
     db: &Arc<dyn Db>,
     block_number: u64,
 ) -> Result<Option<Block>> {
-    let db = db.clone();
-    let block = task::spawn_blocking(move || db.load_block(chain, block_number)).await??;
-    Ok(block)
+    db.load_block(chain, block_number).await
 }
 
 pub async fn remove_blocks(chain: Chain, db: &Arc<dyn Db>, blocks: Vec<u64>) -> Result<()> {
-    let db = db.clone();
-
-    task::spawn_blocking(move || {
-        for block in blocks {
-            db.remove_block(chain, block)
-                .unwrap_or_else(|_| panic!("error removing block {} for chain {}", block, chain));
-        }
-    })
-    .await?;
+    for block in blocks {
+        db.remove_block(chain, block)
+            .await
+            .with_context(|| format!("error removing block {} for chain {}", block, chain))?;
+    }
 
-    Ok(())
+    db.flush().await
 }
 
 pub async fn write_calculation_log(
@@ -99,8 +68,5 @@ pub async fn write_calculation_log(
     db: &Arc<dyn Db>,
     log: CalculationLog,
 ) -> Result<()> {
-    let db = db.clone();
-    task::spawn_blocking(move || db.store_calculation_log(chain, &log)).await??;
-
-    Ok(())
+    db.store_calculation_log(chain, &log).await
 }
@@ -0,0 +1,312 @@
+use crate::client::Client;
+use crate::delay::retry_if_err;
+use crate::helpers::*;
+use crate::shutdown;
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{Duration, NaiveDate, TimeZone, Utc};
+use log::{debug, info};
+use realtps_common::{
+    chain::Chain,
+    db::{Block, Db},
+    sketch::SenderSketch,
+};
+use std::collections::HashSet;
+use std::sync::Arc;
+
+const SECONDS_PER_DAY: u64 = 60 * 60 * 24;
+
+/// How many blocks to request at once when counting a whole day.
+const HISTORY_BATCH_SIZE: usize = 50;
+
+/// Calculates the daily TPS of whole UTC days from yesterday back to `since`
+/// from an archive node, returning how many days were stored. Days already
+/// calculated are skipped, so an interrupted backfill picks up where it left
+/// off. It stops early at the first day without blocks, taking it for before
+/// the chain started.
+///
+/// Each day's blocks are found by binary search on their timestamps. With
+/// `samples_per_day`, only that many evenly spaced blocks of a day are
+/// fetched, and its transactions are estimated from them.
+pub async fn backfill_history(
+    chain: Chain,
+    client: &dyn Client,
+    db: &Arc<dyn Db>,
+    since: NaiveDate,
+    samples_per_day: Option<u64>,
+) -> Result<u64> {
+    let head_block_number = fetch_live_head_block_number(chain, client).await?;
+    let mut daily_tps = db.load_daily_tps(chain).await?.unwrap_or_default();
+
+    let mut num_days = 0;
+    // The first block of the day after `date`, once found
+    let mut next_day_start: Option<(NaiveDate, u64)> = None;
+    let mut date = Utc::now().naive_utc().date() - Duration::days(1);
+    while date >= since && !shutdown::requested() {
+        if daily_tps.days.contains_key(&date) {
+            debug!(
+                "daily tps for {} is already stored for chain {}",
+                date, chain
+            );
+            next_day_start = None;
+            date -= Duration::days(1);
+            continue;
+        }
+
+        let day_start = day_start_timestamp(date)?;
+        let end = match next_day_start {
+            Some((next_date, block_number)) if next_date == date + Duration::days(1) => {
+                block_number
+            }
+            _ => {
+                first_block_from(
+                    chain,
+                    client,
+                    day_start + SECONDS_PER_DAY,
+                    0,
+                    head_block_number,
+                )
+                .await?
+            }
+        };
+        let start = first_block_from(chain, client, day_start, 0, end).await?;
+        if start == end {
+            info!(
+                "no blocks on {} for chain {}; assuming the chain hadn't started",
+                date, chain
+            );
+            break;
+        }
+
+        let day = count_day(chain, client, start, end, samples_per_day)
+            .await
+            .with_context(|| {
+                format!(
+                    "error fetching blocks {} to {} for chain {}. is the RPC an archive node?",
+                    start,
+                    end - 1,
+                    chain
+                )
+            })?;
+        let tps = day.num_txs as f64 / SECONDS_PER_DAY as f64;
+        daily_tps.days.insert(date, tps);
+        if let Some(senders) = &day.senders {
+            daily_tps.active_addresses.insert(date, senders.estimate());
+        }
+        db.store_daily_tps(chain, &daily_tps).await?;
+        num_days += 1;
+        info!(
+            "backfilled {} tps on {} from blocks {} to {} for chain {}",
+            tps,
+            date,
+            start,
+            end - 1,
+            chain
+        );
+
+        next_day_start = Some((date, start));
+        date -= Duration::days(1);
+    }
+
+    Ok(num_days)
+}
+
+/// A day's transactions, and its senders if every block of it was fetched
+/// and has them.
+struct DayCount {
+    num_txs: u64,
+    senders: Option<SenderSketch>,
+}
+
+/// Counts the transactions in blocks `start..end`, or estimates them from
+/// `samples_per_day` of the blocks.
+async fn count_day(
+    chain: Chain,
+    client: &dyn Client,
+    start: u64,
+    end: u64,
+    samples_per_day: Option<u64>,
+) -> Result<DayCount> {
+    let num_blocks = end - start;
+    let sampled = samples_per_day.filter(|samples| *samples < num_blocks);
+    let block_numbers: Vec<u64> = match sampled {
+        Some(samples) => (0..samples)
+            .map(|i| start + i * num_blocks / samples)
+            .collect(),
+        None => (start..end).collect(),
+    };
+
+    let mut num_txs = 0;
+    let mut senders = Some(SenderSketch::default());
+    for batch in block_numbers.chunks(HISTORY_BATCH_SIZE) {
+        for block in fetch_blocks(chain, client, batch).await? {
+            num_txs += block.num_txs;
+            senders = senders.and_then(|mut senders| {
+                senders.merge(block.senders.as_ref()?);
+                Some(senders)
+            });
+        }
+    }
+
+    match sampled {
+        // Skipped block numbers count as blocks without transactions
+        Some(samples) => Ok(DayCount {
+            num_txs: u64::try_from(
+                u128::from(num_txs) * u128::from(num_blocks) / u128::from(samples),
+            )?,
+            senders: None,
+        }),
+        None => Ok(DayCount { num_txs, senders }),
+    }
+}
+
+/// Fetches the blocks in `block_numbers`, leaving out the block numbers the
+/// chain skipped.
+async fn fetch_blocks(
+    chain: Chain,
+    client: &dyn Client,
+    block_numbers: &[u64],
+) -> Result<Vec<Block>> {
+    let mut blocks = retry_if_err(chain, || Box::pin(client.get_blocks(block_numbers))).await?;
+
+    // Clients leave out the blocks they failed to fetch along with the
+    // skipped ones, so ask for each of those again
+    let fetched: HashSet<u64> = blocks.iter().map(|block| block.block_number).collect();
+    for block_number in block_numbers {
+        if fetched.contains(block_number) {
+            continue;
+        }
+        let block_number = *block_number;
+        if let Some(block) =
+            retry_if_err(chain, || Box::pin(client.get_block(block_number))).await?
+        {
+            blocks.push(block);
+        }
+    }
+
+    Ok(blocks)
+}
+
+/// The lowest block number from `lo` to `hi` after which every block is
+/// stamped at or after `timestamp`, or `hi` if none before it is.
+async fn first_block_from(
+    chain: Chain,
+    client: &dyn Client,
+    timestamp: u64,
+    mut lo: u64,
+    mut hi: u64,
+) -> Result<u64> {
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        match first_block_in(chain, client, mid, hi).await? {
+            Some(block) if block.timestamp < timestamp => lo = block.block_number + 1,
+            _ => hi = mid,
+        }
+    }
+    Ok(hi)
+}
+
+/// The first block from `from` up to, but not including, `to`, as the chain
+/// may have skipped block numbers.
+async fn first_block_in(
+    chain: Chain,
+    client: &dyn Client,
+    from: u64,
+    to: u64,
+) -> Result<Option<Block>> {
+    for block_number in from..to {
+        if let Some(block) =
+            retry_if_err(chain, || Box::pin(client.get_block(block_number))).await?
+        {
+            return Ok(Some(block));
+        }
+    }
+    Ok(None)
+}
+
+fn day_start_timestamp(date: NaiveDate) -> Result<u64> {
+    let midnight = date
+        .and_hms_opt(0, 0, 0)
+        .ok_or_else(|| anyhow!("invalid date {}", date))?;
+    let timestamp = Utc.from_utc_datetime(&midnight).timestamp();
+    if timestamp < 0 {
+        bail!("date {} is before 1970", date);
+    }
+    Ok(u64::try_from(timestamp)?)
+}
+
+#[cfg(test)]
+mod test_history {
+    use super::backfill_history;
+    use crate::test_helpers::{make_blocks, MockClient, GENESIS_TIMESTAMP};
+    use chrono::{Duration, NaiveDate, Utc};
+    use realtps_common::{chain::Chain, db::Db, memory_db::MemoryDb};
+    use std::sync::Arc;
+
+    const CHAIN: Chain = Chain::Ethereum;
+
+    /// Blocks every ten minutes, from genesis to about now
+    fn make_chain(num_txs: u64) -> Vec<realtps_common::db::Block> {
+        let now = u64::try_from(Utc::now().timestamp()).expect("u64");
+        let count = (now - GENESIS_TIMESTAMP) / 600;
+        make_blocks(CHAIN, count, 600, num_txs)
+    }
+
+    fn days_ago(days: i64) -> NaiveDate {
+        Utc::now().naive_utc().date() - Duration::days(days)
+    }
+
+    #[tokio::test]
+    async fn backfill_whole_days() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let client = MockClient::new(make_chain(60));
+
+        assert_eq!(
+            backfill_history(CHAIN, &client, &db, days_ago(3), None).await?,
+            3
+        );
+        let daily_tps = db.load_daily_tps(CHAIN).await?.expect("daily");
+        let dates: Vec<_> = daily_tps.days.keys().copied().collect();
+        assert_eq!(dates, vec![days_ago(3), days_ago(2), days_ago(1)]);
+        for tps in daily_tps.days.values() {
+            assert!((tps - 0.1).abs() < 1e-9);
+        }
+
+        // Stored days are skipped
+        assert_eq!(
+            backfill_history(CHAIN, &client, &db, days_ago(4), None).await?,
+            1
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn estimate_from_samples() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let client = MockClient::new(make_chain(60));
+
+        backfill_history(CHAIN, &client, &db, days_ago(1), Some(12)).await?;
+        let daily_tps = db.load_daily_tps(CHAIN).await?.expect("daily");
+        assert!((daily_tps.days[&days_ago(1)] - 0.1).abs() < 1e-9);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stop_before_genesis() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let client = MockClient::new(make_chain(60));
+
+        // Genesis is at midnight, so its day is the first whole one
+        let genesis_date = NaiveDate::from_ymd_opt(2021, 5, 3).expect("date");
+        backfill_history(
+            CHAIN,
+            &client,
+            &db,
+            genesis_date - Duration::days(5),
+            Some(1),
+        )
+        .await?;
+        let daily_tps = db.load_daily_tps(CHAIN).await?.expect("daily");
+        assert_eq!(daily_tps.days.keys().next(), Some(&genesis_date));
+        Ok(())
+    }
+}
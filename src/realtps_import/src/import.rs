@@ -2,35 +2,109 @@
 use crate::client::Client;
 use crate::delay;
 use crate::helpers::*;
+use crate::new_heads::NewHeads;
 use crate::pace_setter::PaceSetter;
-use anyhow::{anyhow, Result};
+use crate::pause;
+use crate::progress::ProgressReporter;
+use crate::shutdown;
+use anyhow::{anyhow, bail, Result};
+use chrono::Utc;
 use log::{debug, info, warn};
 use realtps_common::{
     chain::Chain,
-    db::{Block, Db},
+    db::{
+        Block, ChainHealth, Db, DbWrite, FinalityLag, ImportCheckpoint, InProgressImport,
+        NativeTps, TimestampIndex, TpsWindow, STALL_SECONDS,
+    },
+    sketch::SenderSketch,
 };
+use std::collections::HashMap;
 use std::sync::Arc;
 
-pub async fn import(chain: Chain, client: &dyn Client, db: &Arc<dyn Db>) -> Result<()> {
-    let res = import_no_rescan_delay(chain, client, db).await;
+/// How many fetched blocks to hold before writing them to the db together.
+const BLOCK_WRITE_BATCH_SIZE: usize = 100;
 
-    match res {
-        Ok(res) => {
-            delay::rescan_delay(chain).await;
-            Ok(res)
-        }
-        Err(e) => {
-            // Delay will be handled by general error handler
-            Err(e)
+/// Larger differences from a chain's own TPS figure, as a fraction of the
+/// larger of the two, suggest we are counting wrong.
+const MAX_NATIVE_TPS_DIVERGENCE: f64 = 0.25;
+
+/// Timestamp index buckets are kept a little longer than the longest window
+/// the calculation looks back over.
+const TIMESTAMP_INDEX_RETENTION_SECONDS: u64 = TpsWindow::Month.seconds() + 60 * 60 * 24;
+
+/// How many of the newest timestamp index buckets to learn a chain's block
+/// time from.
+const BLOCK_TIME_BUCKETS: usize = 3;
+
+/// The fewest block intervals to learn a block time from.
+const MIN_BLOCK_TIME_INTERVALS: u64 = 10;
+
+/// Per-chain settings for importing.
+pub struct ImportOptions {
+    /// How many blocks to fetch at a time
+    pub concurrency: usize,
+    /// How many blocks behind the head to stop, to leave out blocks that may
+    /// yet be reorged
+    pub confirmations: u64,
+    /// Roughly how many bytes of fetched blocks to hold at once, split
+    /// between the blocks fetched ahead and those waiting to be written.
+    /// Unbounded if `None`.
+    pub memory_budget: Option<usize>,
+}
+
+impl Default for ImportOptions {
+    fn default() -> ImportOptions {
+        ImportOptions {
+            concurrency: 1,
+            confirmations: 0,
+            memory_budget: None,
         }
     }
 }
 
-async fn import_no_rescan_delay(chain: Chain, client: &dyn Client, db: &Arc<dyn Db>) -> Result<()> {
+/// Waits after a successful import for the next. Chains subscribed to
+/// `new_heads` wait for a new block instead of polling, unless the
+/// subscription is down. Failed imports are delayed by the general error
+/// handler instead.
+pub async fn wait_to_rescan(chain: Chain, new_heads: Option<&NewHeads>) {
+    match new_heads {
+        Some(new_heads) => new_heads.wait(delay::rescan_delay(chain)).await,
+        None => delay::rescan_delay(chain).await,
+    }
+}
+
+/// Imports new blocks, without waiting after, so the wait doesn't hold an
+/// import slot.
+pub async fn import_no_rescan_delay(
+    chain: Chain,
+    client: &dyn Client,
+    db: &Arc<dyn Db>,
+    options: &ImportOptions,
+) -> Result<()> {
     info!("beginning import for {}", chain);
 
-    let highest_known_block_number = load_highest_known_block_number(chain, db).await?;
     let live_head_block_number = fetch_live_head_block_number(chain, client).await?;
+    // Blocks nearer the head could still be reorged away
+    let confirmed_block_number = live_head_block_number.saturating_sub(options.confirmations);
+
+    import_to_head(chain, client, db, confirmed_block_number, options).await?;
+
+    // Checked even when there are no new blocks, as that's when it matters
+    if let Err(e) = check_chain_health(chain, client, db, live_head_block_number).await {
+        warn!("unable to check health of chain {}: {}", chain, e);
+    }
+
+    Ok(())
+}
+
+async fn import_to_head(
+    chain: Chain,
+    client: &dyn Client,
+    db: &Arc<dyn Db>,
+    live_head_block_number: u64,
+    options: &ImportOptions,
+) -> Result<()> {
+    let highest_known_block_number = load_highest_known_block_number(chain, db).await?;
 
     // If we've never synced this chain before, then just establish the first
     // few blocks, and the highest_known_block_number, and wait until next time.
@@ -68,9 +142,131 @@ async fn import_no_rescan_delay(chain: Chain, client: &dyn Client, db: &Arc<dyn
         db,
         highest_known_block_number,
         live_head_block_number,
+        options,
     )
     .await?;
 
+    // Not being able to tell shouldn't stop imports
+    if let Err(e) = sample_finality_lag(chain, client, db, live_head_block_number).await {
+        warn!("unable to sample finality lag for chain {}: {}", chain, e);
+    }
+    if let Err(e) = sample_native_tps(chain, client, db).await {
+        warn!("unable to sample native tps for chain {}: {}", chain, e);
+    }
+
+    Ok(())
+}
+
+/// Stores how far the finalized block is behind the just imported head, for
+/// chains whose clients know the finalized block.
+async fn sample_finality_lag(
+    chain: Chain,
+    client: &dyn Client,
+    db: &Arc<dyn Db>,
+    head_block_number: u64,
+) -> Result<()> {
+    let finalized_block = match client.get_finalized_block().await? {
+        Some(finalized_block) => finalized_block,
+        None => return Ok(()),
+    };
+    let head_block = match load_block(chain, db, head_block_number).await? {
+        Some(head_block) => head_block,
+        None => return Ok(()),
+    };
+
+    // The finalized block was fetched after the head, and may be newer
+    let finality_lag = FinalityLag {
+        timestamp: head_block.timestamp,
+        blocks: head_block_number.saturating_sub(finalized_block.block_number),
+        seconds: head_block
+            .timestamp
+            .saturating_sub(finalized_block.timestamp),
+        finalized_block_number: Some(finalized_block.block_number),
+    };
+    debug!("finality lag for chain {}: {:?}", chain, finality_lag);
+    db.store_finality_lag(chain, &finality_lag).await?;
+
+    Ok(())
+}
+
+/// Stores the chain's own TPS figure next to ours, for chains that publish
+/// one, warning if they disagree.
+async fn sample_native_tps(chain: Chain, client: &dyn Client, db: &Arc<dyn Db>) -> Result<()> {
+    let native_tps = match client.get_native_tps().await? {
+        Some(native_tps) => native_tps,
+        None => return Ok(()),
+    };
+
+    // Native figures count consensus transactions
+    let raw_tps = db
+        .load_chain_stats(chain)
+        .await?
+        .and_then(|stats| stats.windows.get(&TpsWindow::Hour)?.raw_tps);
+    let calculated_tps = match raw_tps {
+        Some(raw_tps) => Some(raw_tps),
+        None => db.load_tps(chain, TpsWindow::Hour).await?,
+    };
+
+    if let Some(calculated_tps) = calculated_tps {
+        let divergence = (native_tps - calculated_tps).abs() / native_tps.max(calculated_tps);
+        if divergence > MAX_NATIVE_TPS_DIVERGENCE {
+            warn!(
+                "native tps for chain {} is {}, but we calculated {}",
+                chain, native_tps, calculated_tps
+            );
+        }
+    }
+
+    let native_tps = NativeTps {
+        timestamp: u64::try_from(Utc::now().timestamp())?,
+        native_tps,
+        calculated_tps,
+    };
+    debug!("native tps for chain {}: {:?}", chain, native_tps);
+    db.store_native_tps(chain, &native_tps).await?;
+
+    Ok(())
+}
+
+/// Stores how old the newest imported block and the chain's latest block are.
+async fn check_chain_health(
+    chain: Chain,
+    client: &dyn Client,
+    db: &Arc<dyn Db>,
+    head_block_number: u64,
+) -> Result<()> {
+    let highest_block_number = match db.load_highest_block_number(chain).await? {
+        Some(highest_block_number) => highest_block_number,
+        None => return Ok(()),
+    };
+    let highest_block = load_block(chain, db, highest_block_number)
+        .await?
+        .ok_or_else(|| anyhow!("missing highest block {}", highest_block_number))?;
+    // Usually just imported
+    let head_block = match load_block(chain, db, head_block_number).await? {
+        Some(head_block) => head_block,
+        None => client
+            .get_block(head_block_number)
+            .await?
+            .ok_or_else(|| anyhow!("missing head block {}", head_block_number))?,
+    };
+
+    let now = u64::try_from(Utc::now().timestamp())?;
+    let seconds_since_latest_block = now.saturating_sub(head_block.timestamp);
+    let chain_health = ChainHealth {
+        checked_at: now,
+        seconds_since_import: now.saturating_sub(highest_block.timestamp),
+        seconds_since_latest_block,
+        stalled: seconds_since_latest_block > STALL_SECONDS,
+    };
+    if chain_health.stalled {
+        warn!(
+            "chain {} has produced no blocks for {} s",
+            chain, seconds_since_latest_block
+        );
+    }
+    db.store_chain_health(chain, &chain_health).await?;
+
     Ok(())
 }
 
@@ -78,30 +274,159 @@ async fn import_no_rescan_delay(chain: Chain, client: &dyn Client, db: &Arc<dyn
 /// backwards until it reaches `highest_known_block_number`, accounting for
 /// chain reorgs, and missing blocks from previous imports, and finally storing
 /// a new highest known block number to disk.
+///
+/// Each fetched block must be the parent of the block fetched before it. If
+/// not, it is fetched again, in case the node was mid-reorg or another node
+/// behind a load balancer answered, and if it still isn't, the import fails
+/// and is retried rather than storing blocks that aren't in the chain.
+///
+/// Stored blocks replaced by a reorg are swapped for the new ones in the
+/// timestamp index, so TPS isn't calculated over orphaned blocks.
+///
+/// With a `concurrency` above 1, the blocks below each fetched block are
+/// fetched alongside it, as they are usually the next ones needed. They are
+/// still stored in the order they are walked back through.
+///
+/// With a memory budget, half of it bounds the blocks fetched ahead, going by
+/// the largest block seen, and the other half the blocks waiting to be
+/// written, which are written early once they fill it.
 async fn sync(
     chain: Chain,
     client: &dyn Client,
     db: &Arc<dyn Db>,
     highest_known_block_number: u64,
     live_head_block_number: u64,
+    options: &ImportOptions,
 ) -> Result<()> {
-    // todo: this doesn't check whether the blocks we're receiving have hash
-    // chains that are consistent - we could be in the middle of a reorg, or get
-    // conflicting info from different nodes behind a load balancer. The latter
-    // case could leave us with blocks that aren't actually in the chain.
-
     let mut block_number = live_head_block_number;
+    // The hash the next block must have to be the parent of the last one
+    let mut expected_hash: Option<String> = None;
     let joined_chain_block_number;
     let joined_chain_block_hash;
 
     let mut pace = PaceSetter::new(chain);
+    let mut progress =
+        ProgressReporter::new(chain, live_head_block_number, highest_known_block_number);
+
+    // Blocks are fetched in descending order, so every block we need to look
+    // up below has a lower number than the buffered blocks, and has either
+    // been written or isn't known at all.
+    let mut block_buffer = Vec::with_capacity(BLOCK_WRITE_BATCH_SIZE);
+    let mut buffered_bytes = 0;
+    let mut largest_block_size = 0;
+
+    // Only blocks above the previous highest block are new to the index.
+    // Earlier blocks fetched because of a reorg replace blocks that have
+    // already been counted.
+    let mut timestamp_index = TimestampIndex::default();
+    let mut replaced_blocks = vec![];
+
+    let mut prefetched_blocks = HashMap::new();
+
+    // Where an interrupted import got to, to skip the blocks it stored
+    let mut in_progress = db
+        .load_import_checkpoint(chain)
+        .await?
+        .and_then(|checkpoint| checkpoint.in_progress);
+    // The first block fetched, the newest of those this import stores
+    let mut head: Option<(u64, String)> = None;
 
     loop {
-        let block = fetch_block(chain, client, block_number).await?;
+        let block = match prefetched_blocks.remove(&block_number) {
+            Some(block) => block,
+            None => {
+                let (block, next_blocks) = fetch_blocks_ahead(
+                    chain,
+                    client,
+                    block_number,
+                    highest_known_block_number,
+                    fetch_concurrency(options, largest_block_size),
+                )
+                .await?;
+                prefetched_blocks = next_blocks;
+                largest_block_size = prefetched_blocks
+                    .values()
+                    .map(estimated_size)
+                    .fold(largest_block_size, usize::max);
+                block
+            }
+        };
+        let block = match &expected_hash {
+            Some(expected_hash) if block.hash != *expected_hash => {
+                warn!(
+                    "block {} for chain {} has hash {}, not {}; fetching again",
+                    block_number, chain, block.hash, expected_hash
+                );
+                let block = fetch_block(chain, client, block_number).await?;
+                if block.hash != *expected_hash {
+                    bail!(
+                        "block {} for chain {} is not the parent of the block after it",
+                        block_number,
+                        chain
+                    );
+                }
+                block
+            }
+            _ => block,
+        };
+        progress.fetched(db, block.block_number).await;
         let prev_block_number = block.prev_block_number.expect("not genesis block");
         let prev_block_hash = block.parent_hash.clone();
+        let (head_block_number, head_hash) = head
+            .get_or_insert_with(|| (block.block_number, block.hash.clone()))
+            .clone();
 
-        store_block(db, block).await?;
+        if block.block_number > highest_known_block_number {
+            timestamp_index.insert(&block);
+        } else if let Some(stored_block) = load_block(chain, db, block.block_number).await? {
+            if stored_block.hash != block.hash {
+                replaced_blocks.push((stored_block, block.clone()));
+            }
+        }
+        let block_size = estimated_size(&block);
+        largest_block_size = largest_block_size.max(block_size);
+        buffered_bytes += block_size;
+        block_buffer.push(block);
+        let buffer_full = block_buffer.len() >= BLOCK_WRITE_BATCH_SIZE
+            || options
+                .memory_budget
+                .is_some_and(|memory_budget| buffered_bytes >= memory_budget / 2);
+        let shutting_down = shutdown::requested();
+        // Checked with each write, as paused chains are usually being asked
+        // to stop making requests
+        let pausing = buffer_full && pause::is_paused(chain, db).await?;
+        if buffer_full || shutting_down {
+            let checkpoint = ImportCheckpoint {
+                contiguous_block_number: highest_known_block_number,
+                in_progress: Some(InProgressImport {
+                    head_block_number,
+                    head_hash,
+                    next_block_number: prev_block_number,
+                    next_hash: prev_block_hash.clone(),
+                    timestamp_index: timestamp_index.clone(),
+                    replaced_blocks: replaced_blocks.clone(),
+                }),
+            };
+            store_checkpointed_blocks(chain, db, std::mem::take(&mut block_buffer), checkpoint)
+                .await?;
+            buffered_bytes = 0;
+        }
+
+        // The next import resumes from the checkpoint
+        if shutting_down {
+            info!(
+                "stopped import of chain {} at block {} to shut down",
+                chain, block_number
+            );
+            return Ok(());
+        }
+        if pausing {
+            info!(
+                "stopped import of chain {} at block {} as it is paused",
+                chain, block_number
+            );
+            return Ok(());
+        }
 
         let prev_stored_block = load_block(chain, db, prev_block_number).await?;
 
@@ -111,7 +436,9 @@ async fn sync(
         // fetched previous block hash, and whether we already have the previous
         // block from a previous import that failed to complete.
 
-        let block_number_to_fetch_next = if let Some(prev_stored_block) = prev_stored_block {
+        let (block_number_to_fetch_next, hash_to_fetch_next) = if let Some(prev_stored_block) =
+            prev_stored_block
+        {
             let chain_reorg = prev_stored_block.hash != prev_block_hash;
             if !chain_reorg {
                 if prev_block_number <= highest_known_block_number {
@@ -122,10 +449,26 @@ async fn sync(
                 } else {
                     // This is a block we've seen before, but it has a higher block
                     // number than our highest_known_block. This indicates a previous
-                    // incomplete import. To avoid wasting a lot of time and bandwidth
+                    // incomplete import. Its checkpoint says where it stopped, or
+                    // without one, to avoid wasting a lot of time and bandwidth
                     // "fast-forward" through all the blocks we already know.
-                    let highest_unknown_block = fast_forward(chain, db, prev_stored_block).await?;
-                    highest_unknown_block
+                    match in_progress.take() {
+                        Some(resumed)
+                            if resumed.head_block_number == prev_block_number
+                                && resumed.head_hash == prev_block_hash =>
+                        {
+                            info!(
+                                "resuming import of chain {} from block {}",
+                                chain, resumed.next_block_number
+                            );
+                            timestamp_index.merge(resumed.timestamp_index);
+                            replaced_blocks.extend(resumed.replaced_blocks);
+                            (resumed.next_block_number, resumed.next_hash)
+                        }
+                        _ => {
+                            fast_forward(chain, db, prev_stored_block, &mut timestamp_index).await?
+                        }
+                    }
                 }
             } else {
                 warn!(
@@ -133,10 +476,10 @@ async fn sync(
                     chain, prev_block_number, prev_stored_block.hash, prev_block_hash
                 );
                 // continue - have wrong version of prev block
-                prev_block_number
+                (prev_block_number, prev_block_hash)
             }
         } else {
-            prev_block_number
+            (prev_block_number, prev_block_hash)
         };
 
         debug!(
@@ -144,11 +487,23 @@ async fn sync(
             block_number_to_fetch_next, chain
         );
         block_number = block_number_to_fetch_next;
+        expected_hash = Some(hash_to_fetch_next);
 
-        pace.wait().await;
+        if !prefetched_blocks.contains_key(&block_number) {
+            pace.wait().await;
+        }
     }
 
-    store_highest_known_block_number(chain, db, live_head_block_number).await?;
+    complete_import(
+        chain,
+        db,
+        block_buffer,
+        timestamp_index,
+        &replaced_blocks,
+        live_head_block_number,
+    )
+    .await?;
+    progress.finish(db).await;
 
     info!(
         "completed import of chain {} to block {} / {}",
@@ -158,6 +513,68 @@ async fn sync(
     Ok(())
 }
 
+/// How many blocks to fetch at a time, fewer than the configured concurrency
+/// if that many blocks of `largest_block_size` would overrun half the memory
+/// budget.
+fn fetch_concurrency(options: &ImportOptions, largest_block_size: usize) -> usize {
+    match options.memory_budget {
+        Some(memory_budget) if largest_block_size > 0 => {
+            (memory_budget / 2 / largest_block_size).clamp(1, options.concurrency.max(1))
+        }
+        _ => options.concurrency,
+    }
+}
+
+/// Roughly how many bytes a block takes in memory.
+fn estimated_size(block: &Block) -> usize {
+    std::mem::size_of::<Block>()
+        + block.hash.len()
+        + block.parent_hash.len()
+        + block.producer.as_ref().map_or(0, String::len)
+        + block.senders.as_ref().map_or(0, SenderSketch::size)
+}
+
+/// Fetches `block_number`, and concurrently the blocks below it, down to
+/// `concurrency` blocks in all but not to `highest_known_block_number`. The
+/// blocks below are fetched together, in batch requests where the client
+/// supports them.
+///
+/// Only `block_number` is retried. The blocks below are left out if they
+/// fail, or don't exist, like Solana's skipped slots, and fetched again if
+/// needed.
+async fn fetch_blocks_ahead(
+    chain: Chain,
+    client: &dyn Client,
+    block_number: u64,
+    highest_known_block_number: u64,
+    concurrency: usize,
+) -> Result<(Block, HashMap<u64, Block>)> {
+    let num_ahead = u64::try_from(concurrency.saturating_sub(1))?;
+    let lowest_block_number = block_number
+        .saturating_sub(num_ahead)
+        .max(highest_known_block_number + 1);
+
+    let block_numbers_ahead: Vec<u64> = (lowest_block_number..block_number).collect();
+    let (block, blocks_ahead) = futures::join!(
+        fetch_block(chain, client, block_number),
+        client.get_blocks(&block_numbers_ahead),
+    );
+
+    let blocks_ahead = match blocks_ahead {
+        Ok(blocks_ahead) => blocks_ahead,
+        Err(e) => {
+            debug!("unable to fetch blocks ahead for chain {}: {}", chain, e);
+            vec![]
+        }
+    };
+    let blocks_ahead = blocks_ahead
+        .into_iter()
+        .map(|block| (block.block_number, block))
+        .collect();
+
+    Ok((block?, blocks_ahead))
+}
+
 async fn import_first_blocks(
     chain: Chain,
     client: &dyn Client,
@@ -176,38 +593,80 @@ async fn import_first_blocks(
         return Err(anyhow!("first blocks' hashes don't match for {}", chain));
     }
 
-    store_block(db, head_block).await?;
-    store_block(db, prev_block).await?;
-    store_highest_known_block_number(chain, db, head_block_number).await?;
+    let mut timestamp_index = TimestampIndex::default();
+    timestamp_index.insert(&head_block);
+    timestamp_index.insert(&prev_block);
+
+    complete_import(
+        chain,
+        db,
+        vec![head_block, prev_block],
+        timestamp_index,
+        &[],
+        head_block_number,
+    )
+    .await?;
 
     info!("completed first import for chain {}", chain);
 
     Ok(())
 }
 
+/// Stores fetched blocks together with a checkpoint of the import so far, so
+/// an interrupted import resumes below them.
+async fn store_checkpointed_blocks(
+    chain: Chain,
+    db: &Arc<dyn Db>,
+    blocks: Vec<Block>,
+    checkpoint: ImportCheckpoint,
+) -> Result<()> {
+    if blocks.is_empty() {
+        return Ok(());
+    }
+
+    debug!("storing {} blocks for chain {}", blocks.len(), chain);
+    let mut writes: Vec<DbWrite> = blocks
+        .into_iter()
+        .map(|block| DbWrite::Block(Box::new(block)))
+        .collect();
+    writes.push(DbWrite::ImportCheckpoint(checkpoint));
+    db.store_atomically(chain, writes).await
+}
+
 /// Starting from a known good block, fast-forward until we see a block with a
-/// hash mismatch, or that we don't have yet.
-async fn fast_forward(chain: Chain, db: &Arc<dyn Db>, known_block: Block) -> Result<u64> {
+/// hash mismatch, or that we don't have yet, returning its number and the hash
+/// it should have.
+///
+/// The skipped blocks were stored by an import that didn't finish, so may be
+/// missing from the timestamp index.
+async fn fast_forward(
+    chain: Chain,
+    db: &Arc<dyn Db>,
+    known_block: Block,
+    timestamp_index: &mut TimestampIndex,
+) -> Result<(u64, String)> {
     let mut block = known_block;
+    timestamp_index.insert(&block);
 
     info!(
         "fast-forwarding chain {} from block {}",
         chain, block.block_number
     );
 
-    let next_block_number_to_sync = loop {
+    let (next_block_number_to_sync, next_block_hash) = loop {
         let prev_block_number = block.prev_block_number.expect("not genesis block");
 
         let prev_block = load_block(chain, db, prev_block_number).await?;
 
         if let Some(prev_block) = prev_block {
             if prev_block.hash != block.parent_hash {
-                break prev_block_number;
+                break (prev_block_number, block.parent_hash);
             } else {
+                timestamp_index.insert(&prev_block);
                 block = prev_block;
             }
         } else {
-            break prev_block_number;
+            break (prev_block_number, block.parent_hash);
         }
     };
 
@@ -216,5 +675,454 @@ async fn fast_forward(chain: Chain, db: &Arc<dyn Db>, known_block: Block) -> Res
         chain, next_block_number_to_sync
     );
 
-    Ok(next_block_number_to_sync)
+    Ok((next_block_number_to_sync, next_block_hash))
+}
+
+/// Stores the last fetched blocks, the timestamp index updated with all the
+/// newly imported blocks, and the new highest block number together, so the
+/// highest block number never points past blocks or index entries that
+/// weren't stored.
+async fn complete_import(
+    chain: Chain,
+    db: &Arc<dyn Db>,
+    blocks: Vec<Block>,
+    new_blocks_index: TimestampIndex,
+    replaced_blocks: &[(Block, Block)],
+    highest_block_number: u64,
+) -> Result<()> {
+    let mut timestamp_index = db.load_timestamp_index(chain).await?.unwrap_or_default();
+    timestamp_index.merge(new_blocks_index);
+    for (old_block, new_block) in replaced_blocks {
+        timestamp_index.replace(old_block, new_block);
+    }
+
+    if let Some(newest_bucket_start) = timestamp_index.buckets.keys().next_back() {
+        let min_timestamp = newest_bucket_start.saturating_sub(TIMESTAMP_INDEX_RETENTION_SECONDS);
+        timestamp_index.remove_before(min_timestamp);
+    }
+
+    if let Some(block_time_ms) = recent_block_time_ms(&timestamp_index) {
+        delay::learn_block_time(chain, block_time_ms);
+    }
+
+    let mut writes: Vec<DbWrite> = blocks
+        .into_iter()
+        .map(|block| DbWrite::Block(Box::new(block)))
+        .collect();
+    writes.push(DbWrite::TimestampIndex(timestamp_index));
+    writes.push(DbWrite::HighestBlockNumber(highest_block_number));
+    writes.push(DbWrite::ImportCheckpoint(ImportCheckpoint {
+        contiguous_block_number: highest_block_number,
+        in_progress: None,
+    }));
+
+    db.store_atomically(chain, writes).await?;
+
+    debug!(
+        "new highest known block number for chain {}: {}",
+        chain, highest_block_number
+    );
+
+    Ok(())
+}
+
+/// The average time between blocks in the newest buckets of the index, in ms,
+/// if they hold enough blocks to tell. Time between buckets isn't counted, so
+/// blocks missing between them don't make the chain look slower.
+fn recent_block_time_ms(timestamp_index: &TimestampIndex) -> Option<u64> {
+    let mut num_intervals = 0;
+    let mut seconds = 0;
+    for bucket in timestamp_index
+        .buckets
+        .values()
+        .rev()
+        .take(BLOCK_TIME_BUCKETS)
+    {
+        // Older buckets didn't count their blocks
+        let num_blocks = match bucket.num_blocks {
+            Some(num_blocks) => num_blocks,
+            None => continue,
+        };
+        num_intervals += num_blocks.saturating_sub(1);
+        seconds += bucket.last_timestamp.saturating_sub(bucket.first_timestamp);
+    }
+
+    if num_intervals < MIN_BLOCK_TIME_INTERVALS {
+        return None;
+    }
+    Some(seconds * 1000 / num_intervals)
+}
+
+#[cfg(test)]
+mod test_import {
+    use super::{
+        estimated_size, fetch_concurrency, import_no_rescan_delay, recent_block_time_ms,
+        ImportOptions,
+    };
+    use crate::pause;
+    use crate::test_helpers::{make_block, make_blocks, MockClient};
+    use chrono::Utc;
+    use realtps_common::{
+        chain::Chain,
+        db::{Db, ImportCheckpoint, InProgressImport, TimestampIndex, TpsWindow, STALL_SECONDS},
+        memory_db::MemoryDb,
+    };
+    use std::sync::Arc;
+
+    // Solana has no block pace, so tests don't wait between blocks.
+    const CHAIN: Chain = Chain::Solana;
+
+    #[tokio::test]
+    async fn first_import() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let client = MockClient::new(make_blocks(CHAIN, 11, 1, 5));
+
+        import_no_rescan_delay(CHAIN, &client, &db, &ImportOptions::default()).await?;
+
+        assert_eq!(db.load_highest_block_number(CHAIN).await?, Some(10));
+        assert_eq!(db.load_block_numbers(CHAIN).await?, vec![9, 10]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sync_new_blocks() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let client = MockClient::new(make_blocks(CHAIN, 21, 1, 5));
+
+        client.set_head(10);
+        import_no_rescan_delay(CHAIN, &client, &db, &ImportOptions::default()).await?;
+        client.set_head(20);
+        import_no_rescan_delay(CHAIN, &client, &db, &ImportOptions::default()).await?;
+
+        assert_eq!(db.load_highest_block_number(CHAIN).await?, Some(20));
+        assert_eq!(
+            db.load_block_numbers(CHAIN).await?,
+            (9..=20).collect::<Vec<_>>()
+        );
+
+        let timestamp_index = db.load_timestamp_index(CHAIN).await?.expect("index");
+        let indexed_txs: u64 = timestamp_index
+            .buckets
+            .values()
+            .map(|bucket| bucket.num_txs)
+            .sum();
+        assert_eq!(indexed_txs, 12 * 5);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sync_new_blocks_concurrently() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let client = MockClient::new(make_blocks(CHAIN, 31, 1, 5));
+        let options = ImportOptions {
+            concurrency: 4,
+            ..ImportOptions::default()
+        };
+
+        client.set_head(10);
+        import_no_rescan_delay(CHAIN, &client, &db, &options).await?;
+        client.set_head(30);
+        import_no_rescan_delay(CHAIN, &client, &db, &options).await?;
+
+        assert_eq!(db.load_highest_block_number(CHAIN).await?, Some(30));
+        assert_eq!(
+            db.load_block_numbers(CHAIN).await?,
+            (9..=30).collect::<Vec<_>>()
+        );
+
+        let timestamp_index = db.load_timestamp_index(CHAIN).await?.expect("index");
+        let indexed_txs: u64 = timestamp_index
+            .buckets
+            .values()
+            .map(|bucket| bucket.num_txs)
+            .sum();
+        assert_eq!(indexed_txs, 22 * 5);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sync_within_memory_budget() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let blocks = make_blocks(CHAIN, 31, 1, 5);
+        let block_size = estimated_size(&blocks[0]);
+        let client = MockClient::new(blocks);
+        // Room for two blocks fetched ahead and two waiting to be written
+        let options = ImportOptions {
+            concurrency: 8,
+            memory_budget: Some(block_size * 4),
+            ..ImportOptions::default()
+        };
+        assert_eq!(fetch_concurrency(&options, block_size), 2);
+
+        client.set_head(10);
+        import_no_rescan_delay(CHAIN, &client, &db, &options).await?;
+        client.set_head(30);
+        import_no_rescan_delay(CHAIN, &client, &db, &options).await?;
+
+        assert_eq!(db.load_highest_block_number(CHAIN).await?, Some(30));
+        assert_eq!(
+            db.load_block_numbers(CHAIN).await?,
+            (9..=30).collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn fetch_at_least_one_block() {
+        let options = ImportOptions {
+            concurrency: 8,
+            memory_budget: Some(100),
+            ..ImportOptions::default()
+        };
+        assert_eq!(fetch_concurrency(&options, 1000), 1);
+        assert_eq!(fetch_concurrency(&options, 0), 8);
+    }
+
+    #[tokio::test]
+    async fn stop_short_of_head() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let client = MockClient::new(make_blocks(CHAIN, 21, 1, 5));
+        let options = ImportOptions {
+            confirmations: 3,
+            ..ImportOptions::default()
+        };
+
+        client.set_head(10);
+        import_no_rescan_delay(CHAIN, &client, &db, &options).await?;
+        client.set_head(20);
+        import_no_rescan_delay(CHAIN, &client, &db, &options).await?;
+
+        assert_eq!(db.load_highest_block_number(CHAIN).await?, Some(17));
+        assert_eq!(
+            db.load_block_numbers(CHAIN).await?,
+            (6..=17).collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sync_reorg() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let client = MockClient::new(make_blocks(CHAIN, 26, 1, 5));
+
+        client.set_head(10);
+        import_no_rescan_delay(CHAIN, &client, &db, &ImportOptions::default()).await?;
+        client.set_head(20);
+        import_no_rescan_delay(CHAIN, &client, &db, &ImportOptions::default()).await?;
+
+        // Blocks from 18 on are replaced, with more transactions
+        for block_number in 18..=25 {
+            let mut block = make_block(CHAIN, block_number, 1, 7);
+            block.hash = format!("b{}", block_number);
+            if block_number > 18 {
+                block.parent_hash = format!("b{}", block_number - 1);
+            }
+            client.replace_block(block);
+        }
+        client.set_head(25);
+        import_no_rescan_delay(CHAIN, &client, &db, &ImportOptions::default()).await?;
+
+        assert_eq!(db.load_highest_block_number(CHAIN).await?, Some(25));
+        assert_eq!(db.load_block(CHAIN, 17).await?.expect("block").hash, "17");
+        for block_number in 18..=25 {
+            let block = db.load_block(CHAIN, block_number).await?.expect("block");
+            assert_eq!(block.hash, format!("b{}", block_number));
+        }
+
+        // The replaced blocks aren't counted
+        let timestamp_index = db.load_timestamp_index(CHAIN).await?.expect("index");
+        let indexed_txs: u64 = timestamp_index
+            .buckets
+            .values()
+            .map(|bucket| bucket.num_txs)
+            .sum();
+        assert_eq!(indexed_txs, 9 * 5 + 8 * 7);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stop_when_paused() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let client = MockClient::new(make_blocks(CHAIN, 261, 1, 5));
+
+        client.set_head(10);
+        import_no_rescan_delay(CHAIN, &client, &db, &ImportOptions::default()).await?;
+
+        // Stops after writing the first batch
+        pause::set_paused(CHAIN, &db, true, None).await?;
+        client.set_head(260);
+        import_no_rescan_delay(CHAIN, &client, &db, &ImportOptions::default()).await?;
+        assert_eq!(db.load_highest_block_number(CHAIN).await?, Some(10));
+        assert_eq!(db.load_block_numbers(CHAIN).await?.len(), 2 + 100);
+
+        pause::set_paused(CHAIN, &db, false, None).await?;
+        import_no_rescan_delay(CHAIN, &client, &db, &ImportOptions::default()).await?;
+        assert_eq!(db.load_highest_block_number(CHAIN).await?, Some(260));
+        assert_eq!(
+            db.load_block_numbers(CHAIN).await?,
+            (9..=260).collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn resume_from_checkpoint() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let client = MockClient::new(make_blocks(CHAIN, 41, 1, 5));
+
+        client.set_head(10);
+        import_no_rescan_delay(CHAIN, &client, &db, &ImportOptions::default()).await?;
+
+        // An import to block 30 stored blocks 21 to 30 before it was stopped
+        let stored_blocks = make_blocks(CHAIN, 31, 1, 5).split_off(21);
+        let mut stored_index = TimestampIndex::default();
+        for block in &stored_blocks {
+            stored_index.insert(block);
+        }
+        db.store_blocks(CHAIN, stored_blocks).await?;
+        let checkpoint = ImportCheckpoint {
+            contiguous_block_number: 10,
+            in_progress: Some(InProgressImport {
+                head_block_number: 30,
+                head_hash: "30".to_string(),
+                next_block_number: 20,
+                next_hash: "20".to_string(),
+                timestamp_index: stored_index,
+                replaced_blocks: vec![],
+            }),
+        };
+        db.store_import_checkpoint(CHAIN, &checkpoint).await?;
+
+        // Fetching the stored blocks again would change their counts
+        for block_number in 21..=30 {
+            client.replace_block(make_block(CHAIN, block_number, 1, 9));
+        }
+        client.set_head(40);
+        import_no_rescan_delay(CHAIN, &client, &db, &ImportOptions::default()).await?;
+
+        assert_eq!(db.load_highest_block_number(CHAIN).await?, Some(40));
+        assert_eq!(
+            db.load_block_numbers(CHAIN).await?,
+            (9..=40).collect::<Vec<_>>()
+        );
+        assert_eq!(db.load_block(CHAIN, 25).await?.expect("block").num_txs, 5);
+
+        let timestamp_index = db.load_timestamp_index(CHAIN).await?.expect("index");
+        let indexed_txs: u64 = timestamp_index
+            .buckets
+            .values()
+            .map(|bucket| bucket.num_txs)
+            .sum();
+        assert_eq!(indexed_txs, 32 * 5);
+
+        let checkpoint = db.load_import_checkpoint(CHAIN).await?.expect("checkpoint");
+        assert_eq!(checkpoint.contiguous_block_number, 40);
+        assert!(checkpoint.in_progress.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reject_inconsistent_blocks() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let client = MockClient::new(make_blocks(CHAIN, 21, 1, 5));
+
+        client.set_head(10);
+        import_no_rescan_delay(CHAIN, &client, &db, &ImportOptions::default()).await?;
+
+        // A block that isn't the parent of the block after it
+        let mut block = make_block(CHAIN, 15, 1, 5);
+        block.hash = "x15".to_string();
+        client.replace_block(block);
+        client.set_head(20);
+        assert!(
+            import_no_rescan_delay(CHAIN, &client, &db, &ImportOptions::default())
+                .await
+                .is_err()
+        );
+        assert_eq!(db.load_highest_block_number(CHAIN).await?, Some(10));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sample_finality_lag() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let client = MockClient::new(make_blocks(CHAIN, 21, 2, 5));
+
+        client.set_head(10);
+        import_no_rescan_delay(CHAIN, &client, &db, &ImportOptions::default()).await?;
+        assert!(db.load_finality_lag(CHAIN).await?.is_none());
+
+        client.set_head(20);
+        client.set_finality_depth(3);
+        import_no_rescan_delay(CHAIN, &client, &db, &ImportOptions::default()).await?;
+
+        let finality_lag = db.load_finality_lag(CHAIN).await?.expect("finality lag");
+        assert_eq!(finality_lag.blocks, 3);
+        assert_eq!(finality_lag.seconds, 6);
+        assert_eq!(finality_lag.finalized_block_number, Some(17));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn check_chain_health() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let mut blocks = make_blocks(CHAIN, 11, 1, 5);
+        // The head block a minute old
+        let now = u64::try_from(Utc::now().timestamp())?;
+        let offset = now - 60 - blocks[10].timestamp;
+        for block in &mut blocks {
+            block.timestamp += offset;
+        }
+        let client = MockClient::new(blocks);
+        import_no_rescan_delay(CHAIN, &client, &db, &ImportOptions::default()).await?;
+
+        let chain_health = db.load_chain_health(CHAIN).await?.expect("chain health");
+        assert!(!chain_health.stalled);
+        assert!(chain_health.seconds_since_latest_block >= 60);
+        assert!(chain_health.seconds_since_latest_block < STALL_SECONDS);
+
+        // Blocks from years ago
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let client = MockClient::new(make_blocks(CHAIN, 11, 1, 5));
+        import_no_rescan_delay(CHAIN, &client, &db, &ImportOptions::default()).await?;
+
+        let chain_health = db.load_chain_health(CHAIN).await?.expect("chain health");
+        assert!(chain_health.stalled);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sample_native_tps() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let client = MockClient::new(make_blocks(CHAIN, 21, 2, 5));
+
+        client.set_head(10);
+        import_no_rescan_delay(CHAIN, &client, &db, &ImportOptions::default()).await?;
+        assert!(db.load_native_tps(CHAIN).await?.is_none());
+
+        db.store_tps(CHAIN, TpsWindow::Hour, 2.5).await?;
+        client.set_head(20);
+        client.set_native_tps(3.0);
+        import_no_rescan_delay(CHAIN, &client, &db, &ImportOptions::default()).await?;
+
+        let native_tps = db.load_native_tps(CHAIN).await?.expect("native tps");
+        assert_eq!(native_tps.native_tps, 3.0);
+        assert_eq!(native_tps.calculated_tps, Some(2.5));
+        Ok(())
+    }
+
+    #[test]
+    fn learn_block_time_from_index() {
+        let mut timestamp_index = TimestampIndex::default();
+        for block in make_blocks(CHAIN, 5, 12, 1) {
+            timestamp_index.insert(&block);
+        }
+        // Too few blocks to tell
+        assert_eq!(recent_block_time_ms(&timestamp_index), None);
+
+        for block in make_blocks(CHAIN, 1000, 12, 1) {
+            timestamp_index.insert(&block);
+        }
+        assert_eq!(recent_block_time_ms(&timestamp_index), Some(12000));
+    }
 }
@@ -0,0 +1,121 @@
+use anyhow::{anyhow, bail, Result};
+
+/// Replaces `${NAME}` in every string and key of a TOML document with the
+/// environment variable `NAME`, so secrets like API keys in RPC URLs can be
+/// kept out of the file. `$${` is a literal `${`.
+pub fn interpolate_env(value: &mut toml::Value) -> Result<()> {
+    interpolate_with(value, &|name| std::env::var(name).ok())
+}
+
+fn interpolate_with(
+    value: &mut toml::Value,
+    lookup: &dyn Fn(&str) -> Option<String>,
+) -> Result<()> {
+    match value {
+        toml::Value::String(s) => *s = interpolate_str(s, lookup)?,
+        toml::Value::Array(values) => {
+            for value in values {
+                interpolate_with(value, lookup)?;
+            }
+        }
+        toml::Value::Table(table) => {
+            let entries = std::mem::take(table);
+            for (key, mut value) in entries {
+                interpolate_with(&mut value, lookup)?;
+                table.insert(interpolate_str(&key, lookup)?, value);
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn interpolate_str(s: &str, lookup: &dyn Fn(&str) -> Option<String>) -> Result<String> {
+    let mut interpolated = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find('$') {
+        interpolated.push_str(&rest[..start]);
+        let after = &rest[start..];
+        if let Some(escaped) = after.strip_prefix("$${") {
+            interpolated.push_str("${");
+            rest = escaped;
+        } else if let Some(reference) = after.strip_prefix("${") {
+            let end = reference
+                .find('}')
+                .ok_or_else(|| anyhow!("unclosed `${{` in `{}`", s))?;
+            let name = &reference[..end];
+            if name.is_empty() {
+                bail!("empty `${{}}` in `{}`", s);
+            }
+            let value =
+                lookup(name).ok_or_else(|| anyhow!("environment variable {} isn't set", name))?;
+            interpolated.push_str(&value);
+            rest = &reference[end + 1..];
+        } else {
+            interpolated.push('$');
+            rest = &after[1..];
+        }
+    }
+    interpolated.push_str(rest);
+    Ok(interpolated)
+}
+
+#[cfg(test)]
+mod test_interpolate {
+    use super::{interpolate_str, interpolate_with};
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "INFURA_KEY" => Some("abc123".to_string()),
+            "HOST" => Some("rpc.example".to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn replace_references() -> Result<(), anyhow::Error> {
+        assert_eq!(
+            interpolate_str("https://mainnet.infura.io/v3/${INFURA_KEY}", &lookup)?,
+            "https://mainnet.infura.io/v3/abc123"
+        );
+        assert_eq!(
+            interpolate_str("https://${HOST}/${INFURA_KEY}", &lookup)?,
+            "https://rpc.example/abc123"
+        );
+        assert_eq!(
+            interpolate_str("$${HOST} costs $5", &lookup)?,
+            "${HOST} costs $5"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reject_unset_and_malformed_references() {
+        assert!(interpolate_str("https://${MISSING}", &lookup).is_err());
+        assert!(interpolate_str("https://${HOST", &lookup).is_err());
+        assert!(interpolate_str("https://${}", &lookup).is_err());
+    }
+
+    #[test]
+    fn replace_in_keys_and_nested_values() -> Result<(), anyhow::Error> {
+        let mut value: toml::Value = toml::from_str(
+            r#"
+            [chains]
+            ethereum = ["https://${HOST}", "https://mainnet.infura.io/v3/${INFURA_KEY}"]
+
+            [rate_limits]
+            "https://mainnet.infura.io/v3/${INFURA_KEY}" = { requests_per_second = 10 }
+            "#,
+        )?;
+        interpolate_with(&mut value, &lookup)?;
+        assert_eq!(
+            value["chains"]["ethereum"][1].as_str(),
+            Some("https://mainnet.infura.io/v3/abc123")
+        );
+        assert!(value["rate_limits"]
+            .as_table()
+            .expect("table")
+            .contains_key("https://mainnet.infura.io/v3/abc123"));
+        Ok(())
+    }
+}
@@ -1,114 +1,923 @@
-use crate::calculate;
+use crate::aggregate;
+use crate::backfill;
+use crate::block_cache::BlockCacheDb;
+use crate::calculate::{self, ChainCalcs};
 use crate::client::Client;
-use crate::delay;
-use crate::import;
+use crate::cross_check;
+use crate::daily;
+use crate::delay::{self, FatalError};
+use crate::gaps;
+use crate::import::{self, ImportOptions};
+use crate::lease::Leases;
+use crate::log_format;
+use crate::new_heads::NewHeads;
+use crate::pause;
 use crate::remove;
-use anyhow::{Context, Result};
-use futures::future::FutureExt;
-use futures::stream::{FuturesUnordered, StreamExt};
-use log::{error, info};
+use crate::repair;
+use crate::scheduler::Scheduler;
+use crate::shutdown;
+use crate::status;
+use crate::verify;
+use crate::webhooks::Webhooks;
+use anyhow::{bail, Result};
+use chrono::Utc;
+use futures::future;
+use log::{debug, error, info, warn};
 use rand::prelude::*;
-use realtps_common::{chain::Chain, db::Db};
-use std::collections::HashMap;
-use std::sync::Arc;
+use realtps_common::{
+    chain::Chain,
+    db::{Db, LeaseKind, TpsPoint, TpsWindow},
+};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
 use std::time::Instant;
 use tokio::task;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Job {
     Import(Chain),
-    Calculate(Vec<Chain>),
+    /// Import from a chain's second provider
+    ImportSecondary(Chain),
+    Calculate(Chain),
+    /// Total the chains' TPS
+    Aggregate(Vec<Chain>),
     Remove(Vec<Chain>),
+    DailyTps(Vec<Chain>),
+    ReportGaps(Vec<Chain>),
+    /// Fetch blocks missing from the retained window
+    Backfill(Chain),
+}
+
+impl Job {
+    /// The chain of a job for a single chain.
+    pub fn chain(&self) -> Option<Chain> {
+        match self {
+            Job::Import(chain)
+            | Job::ImportSecondary(chain)
+            | Job::Backfill(chain)
+            | Job::Calculate(chain) => Some(*chain),
+            _ => None,
+        }
+    }
+
+    /// As labelled in structured logs.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Job::Import(_) => "import",
+            Job::ImportSecondary(_) => "import-secondary",
+            Job::Calculate(_) => "calculate",
+            Job::Aggregate(_) => "aggregate",
+            Job::Remove(_) => "remove",
+            Job::DailyTps(_) => "daily-tps",
+            Job::ReportGaps(_) => "report-gaps",
+            Job::Backfill(_) => "backfill",
+        }
+    }
+
+    /// The lease an instance must hold to run a job, when sharing a db.
+    /// Backfills write the same blocks as imports, so share their lease.
+    pub fn lease_kind(&self) -> Option<LeaseKind> {
+        match self {
+            Job::Import(_) | Job::Backfill(_) => Some(LeaseKind::Import),
+            Job::ImportSecondary(_) => Some(LeaseKind::ImportSecondary),
+            Job::Calculate(_) => Some(LeaseKind::Calculate),
+            _ => None,
+        }
+    }
 }
 
 pub struct JobRunner {
     pub db: Arc<dyn Db>,
+    /// The same `Db` as `db`, if it is cached, for reporting cache stats.
+    pub block_cache: Option<Arc<BlockCacheDb>>,
+    /// Replaced when the config is reloaded
+    pub clients: RwLock<HashMap<Chain, Arc<dyn Client>>>,
+    pub options: CalculateOptions,
+    /// Chains that aren't imported, whose last calculations are left
+    /// published, flagged as stale, rather than recalculated. Replaced when
+    /// the config is reloaded.
+    pub disabled_chains: RwLock<HashSet<Chain>>,
+    pub secondary: Option<Secondary>,
+    /// How many blocks to fetch at a time when importing, for chains that
+    /// fetch more than one
+    pub import_concurrency: HashMap<Chain, usize>,
+    /// Roughly how many bytes of fetched blocks all imports together may
+    /// hold, split evenly between the chains imported
+    pub import_memory_budget: Option<usize>,
+    /// How many blocks behind the head to import to, for chains whose recent
+    /// blocks may be reorged
+    pub confirmations: HashMap<Chain, u64>,
+    /// Chains imported as their new heads are pushed, rather than polled
+    pub new_heads: HashMap<Chain, NewHeads>,
+    /// Shares the import slots between chains
+    pub scheduler: Scheduler,
+    /// Shares the chains with other instances using the same db
+    pub leases: Option<Arc<Leases>>,
+}
+
+/// Chains also imported from a second provider, into a namespace of their
+/// own, to check the first provider's transaction counts against.
+pub struct Secondary {
+    pub db: Arc<dyn Db>,
+    pub chains: HashSet<Chain>,
+    /// Only for importing
     pub clients: HashMap<Chain, Box<dyn Client>>,
 }
 
+/// Settings for calculating TPS and keeping data.
+pub struct CalculateOptions {
+    /// The windows to calculate TPS over, shortest first. Blocks are kept
+    /// for the longest.
+    pub tps_windows: Vec<TpsWindow>,
+    /// Chains whose failed transactions don't count towards TPS
+    pub exclude_failed_txs: HashSet<Chain>,
+    /// Chains whose TPS is calculated over at most this window
+    pub max_tps_window: HashMap<Chain, TpsWindow>,
+    pub tps_history_retention_seconds: u64,
+    /// The weight of each new calculation in the published TPS, which is an
+    /// exponential moving average. 1 publishes calculations as they are.
+    pub tps_smoothing: f64,
+    /// USD per whole native unit, for chains whose value throughput is
+    /// converted to USD
+    pub usd_prices: HashMap<Chain, f64>,
+    /// Posted to as alert conditions start and stop holding
+    pub webhooks: Webhooks,
+}
+
 impl JobRunner {
+    /// Runs a job, repeating it with backoff until it succeeds. A chain whose
+    /// job keeps failing, or fails fatally, is quarantined if quarantining is
+    /// configured; otherwise fatal errors are retried after a long delay.
     pub async fn do_job(&self, job: Job) -> Vec<Job> {
-        let r = match job {
-            Job::Import(chain) => self.import(chain).await,
-            Job::Calculate(ref chains) => self.calculate(chains.to_vec()).await,
-            Job::Remove(ref chains) => self.remove(chains.to_vec()).await,
-        };
+        log_format::in_job(&job, self.repeat_job(job.clone())).await
+    }
 
-        match r {
-            Ok(new_jobs) => new_jobs,
-            Err(e) => {
-                print_error(&e);
-                error!("error running job. repeating");
-                delay::job_error_delay(&job).await;
-                vec![job]
+    async fn repeat_job(&self, job: Job) -> Vec<Job> {
+        let mut num_failures = 0;
+        loop {
+            let r = match job {
+                Job::Import(chain) => self.import(chain).await,
+                Job::ImportSecondary(chain) => self.import_secondary(chain).await,
+                Job::Calculate(chain) => self.calculate(chain).await,
+                Job::Aggregate(ref chains) => self.aggregate(chains.to_vec()).await,
+                Job::Remove(ref chains) => self.remove(chains.to_vec()).await,
+                Job::DailyTps(ref chains) => self.daily_tps(chains.to_vec()).await,
+                Job::ReportGaps(ref chains) => self.report_gaps(chains.to_vec()).await,
+                Job::Backfill(chain) => self.backfill(chain).await,
+            };
+
+            match r {
+                Ok(new_jobs) => break new_jobs,
+                Err(e) => {
+                    print_error(&e);
+                    if shutdown::requested() {
+                        break vec![];
+                    }
+                    num_failures += 1;
+                    let fatal = delay::is_fatal(&e);
+                    if self
+                        .quarantine_if_failing(&job, num_failures, fatal, &e)
+                        .await
+                    {
+                        // Waits in the job until the chain is resumed
+                        break vec![job];
+                    }
+                    if fatal {
+                        // In case the RPC or config is fixed meanwhile
+                        error!("fatal error running job {:?}. repeating later", job);
+                        delay::fatal_error_delay(&job).await;
+                    } else {
+                        error!("error running job. repeating");
+                        delay::job_error_delay(&job, num_failures).await;
+                    }
+                }
             }
         }
     }
 
     async fn import(&self, chain: Chain) -> Result<Vec<Job>> {
-        let client = self
+        let job = Job::Import(chain);
+        let client = match self.client_or_wait(&job).await {
+            Some(client) => client,
+            None => return Ok(vec![job]),
+        };
+        if self.wait_if_paused(&job).await? || self.wait_if_leased(&job).await? {
+            return Ok(vec![job]);
+        }
+        self.import_to_head(chain, client.as_ref()).await?;
+
+        import::wait_to_rescan(chain, self.new_heads.get(&chain)).await;
+        Ok(vec![job])
+    }
+
+    /// Imports a chain up to its head, recording the outcome in its status.
+    async fn import_to_head(&self, chain: Chain, client: &dyn Client) -> Result<()> {
+        let job = Job::Import(chain);
+        let result = {
+            let _slot = self.scheduler.acquire(&job).await;
+            import::import_no_rescan_delay(chain, client, &self.db, &self.import_options(chain))
+                .await
+        };
+        self.record_head(&job, &self.db).await;
+        store_endpoint_health(chain, client, &self.db).await;
+        let error = result.as_ref().err().map(|e| format!("{:#}", e));
+        status::record_job(chain, LeaseKind::Import, &self.db, error.as_deref()).await;
+        result
+    }
+
+    async fn import_secondary(&self, chain: Chain) -> Result<Vec<Job>> {
+        let secondary = self
+            .secondary
+            .as_ref()
+            .ok_or_else(|| FatalError("no secondary providers".to_string()))?;
+        let client = secondary
             .clients
             .get(&chain)
-            .context(format!("no client for {}", chain))?;
-        import::import(chain, client.as_ref(), &self.db).await?;
+            .ok_or_else(|| FatalError(format!("no secondary client for {}", chain)))?;
+        let job = Job::ImportSecondary(chain);
+        if self.wait_if_paused(&job).await? || self.wait_if_leased(&job).await? {
+            return Ok(vec![job]);
+        }
+        let result = {
+            let _slot = self.scheduler.acquire(&job).await;
+            import::import_no_rescan_delay(
+                chain,
+                client.as_ref(),
+                &secondary.db,
+                &self.import_options(chain),
+            )
+            .await
+        };
+        self.record_head(&job, &secondary.db).await;
+        store_endpoint_health(chain, client.as_ref(), &secondary.db).await;
+        result?;
 
-        Ok(vec![Job::Import(chain)])
+        import::wait_to_rescan(chain, None).await;
+        Ok(vec![job])
     }
 
-    async fn calculate(&self, chains: Vec<Chain>) -> Result<Vec<Job>> {
-        info!("beginning tps calculation");
+    /// Quarantines the chain of a job that has failed `num_failures` times in
+    /// a row, if that's as many as its retry policy allows or the last error
+    /// was fatal, returning whether it did.
+    async fn quarantine_if_failing(
+        &self,
+        job: &Job,
+        num_failures: u32,
+        fatal: bool,
+        e: &anyhow::Error,
+    ) -> bool {
+        // Only jobs making requests, as quarantine is for broken RPCs
+        let chain = match job {
+            Job::Import(chain) | Job::ImportSecondary(chain) | Job::Backfill(chain) => *chain,
+            _ => return false,
+        };
+        let quarantine_after = delay::retry_policy(Some(chain)).quarantine_after;
+        // Retrying won't fix a fatal error, so there's no waiting for more
+        if quarantine_after == 0 || (!fatal && num_failures < quarantine_after) {
+            return false;
+        }
 
-        let start = Instant::now();
+        let reason = format!(
+            "job {:?} failed {} times in a row, last with: {}",
+            job, num_failures, e
+        );
+        match pause::quarantine(chain, &self.db, reason).await {
+            Ok(()) => true,
+            Err(e) => {
+                error!("unable to quarantine chain {}: {}", chain, e);
+                false
+            }
+        }
+    }
 
-        let mut tasks: FuturesUnordered<_> = chains
-            .iter()
-            .map(|chain| {
-                let chain = *chain;
-                let calc_future = calculate::calculate_for_chain(chain, self.db.clone());
-                let calc_future = task::spawn(calc_future);
-                calc_future.map(move |calcs| (chain, calcs))
-            })
-            .collect();
-
-        while let Some((chain, calcs)) = tasks.next().await {
-            let calcs = calcs?;
-            match calcs {
-                Ok(calcs) => {
-                    info!("calculated {} tps for chain {}", calcs.tps, calcs.chain);
-                    let db = self.db.clone();
-                    task::spawn_blocking(move || db.store_tps(calcs.chain, calcs.tps)).await??;
+    pub fn client(&self, chain: Chain) -> Option<Arc<dyn Client>> {
+        self.clients.read().expect("lock").get(&chain).cloned()
+    }
+
+    fn is_disabled(&self, chain: Chain) -> bool {
+        self.disabled_chains.read().expect("lock").contains(&chain)
+    }
+
+    /// The client for the job's chain, or `None` after waiting a while if the
+    /// chain was disabled or lost its endpoints when the config was
+    /// reloaded, so the job is tried again instead of run.
+    async fn client_or_wait(&self, job: &Job) -> Option<Arc<dyn Client>> {
+        let chain = job.chain().expect("chain job");
+        match self.client(chain) {
+            Some(client) if !self.is_disabled(chain) => Some(client),
+            _ => {
+                debug!(
+                    "chain {} is disabled or has no client; skipping job {:?}",
+                    chain, job
+                );
+                delay::paused_delay().await;
+                None
+            }
+        }
+    }
+
+    /// Waits a while if the job's chain is paused, returning whether it was,
+    /// so the job is tried again instead of run.
+    async fn wait_if_paused(&self, job: &Job) -> Result<bool> {
+        let chain = job.chain().expect("chain job");
+        let paused = pause::is_paused(chain, &self.db).await?;
+        if paused {
+            debug!("chain {} is paused; skipping job {:?}", chain, job);
+            delay::paused_delay().await;
+        }
+        Ok(paused)
+    }
+
+    /// Waits a while if another instance holds the lease the job needs,
+    /// returning whether it does, so the job is tried again instead of run.
+    async fn wait_if_leased(&self, job: &Job) -> Result<bool> {
+        let leases = match &self.leases {
+            Some(leases) => leases,
+            None => return Ok(false),
+        };
+        let chain = job.chain().expect("chain job");
+        let kind = job.lease_kind().expect("leased job");
+        let leased = !leases.acquire(chain, kind, &self.db).await?;
+        if leased {
+            debug!(
+                "chain {} is leased by another instance; skipping job {:?}",
+                chain, job
+            );
+            delay::leased_delay().await;
+        }
+        Ok(leased)
+    }
+
+    /// Whether this instance runs the chain's jobs of a kind, when sharing a
+    /// db, for jobs across chains to leave out the others' chains.
+    fn holds_lease(&self, chain: Chain, kind: LeaseKind) -> bool {
+        match &self.leases {
+            Some(leases) => leases.holds(chain, kind),
+            None => true,
+        }
+    }
+
+    /// Tells the scheduler how far an import got, to prioritize the next.
+    async fn record_head(&self, job: &Job, db: &Arc<dyn Db>) {
+        let chain = job.chain().expect("import job");
+        let head = match db.load_highest_block_number(chain).await {
+            Ok(Some(block_number)) => db.load_block(chain, block_number).await,
+            Ok(None) => Ok(None),
+            Err(e) => Err(e),
+        };
+        match head {
+            Ok(Some(head)) => self.scheduler.record_head(job, head.timestamp),
+            Ok(None) => {}
+            Err(e) => warn!("unable to load head of chain {}: {}", chain, e),
+        }
+    }
+
+    /// Imports each chain up to its head once, calculates its TPS, daily TPS
+    /// and the aggregate once, and with `prune` removes old data, then
+    /// returns, for running from a scheduler rather than as a daemon.
+    /// Disabled chains are only flagged as stale, and paused ones only
+    /// calculated. Every chain is tried, but if any failed, so does this.
+    pub async fn run_once(
+        &self,
+        chains: &[Chain],
+        disabled_chains: &[Chain],
+        prune: bool,
+    ) -> Result<()> {
+        let imports = chains.iter().map(|chain| async move {
+            let client = self
+                .client(*chain)
+                .ok_or_else(|| FatalError(format!("no client for {}", chain)))?;
+            if pause::is_paused(*chain, &self.db).await? {
+                info!("chain {} is paused; not importing it", chain);
+                return Ok(());
+            }
+            self.import_to_head(*chain, client.as_ref()).await
+        });
+        let import_results = future::join_all(imports).await;
+
+        let mut failed_chains = vec![];
+        for (chain, result) in chains.iter().zip(import_results) {
+            if let Err(e) = result {
+                print_error(&e);
+                error!("error importing chain {}", chain);
+                failed_chains.push(*chain);
+            }
+        }
+
+        for chain in chains {
+            if let Err(e) = self.calculate_tps(*chain).await? {
+                print_error(&e);
+                error!("error calculating for {}", chain);
+                if !failed_chains.contains(chain) {
+                    failed_chains.push(*chain);
                 }
-                Err(e) => {
-                    print_error(&e);
-                    error!("error calculating for {}", chain);
+            }
+        }
+        for chain in disabled_chains {
+            calculate::mark_stale(*chain, &self.db).await?;
+        }
+
+        let all_chains: Vec<Chain> = chains.iter().chain(disabled_chains).copied().collect();
+        self.store_aggregate_tps(&all_chains).await?;
+        self.update_daily_tps(chains).await;
+        if prune {
+            self.prune(&all_chains).await?;
+        }
+
+        if !failed_chains.is_empty() {
+            let names: Vec<String> = failed_chains.iter().map(Chain::to_string).collect();
+            bail!(
+                "{} of {} chains failed: {}",
+                failed_chains.len(),
+                chains.len(),
+                names.join(", ")
+            );
+        }
+        info!("imported and calculated {} chains", chains.len());
+        Ok(())
+    }
+
+    /// Fetches the missing blocks in a range for each chain, outside of the
+    /// job loop.
+    pub async fn import_range(&self, chains: &[Chain], from: u64, to: u64) -> Result<()> {
+        for chain in chains {
+            let client = self
+                .client(*chain)
+                .ok_or_else(|| FatalError(format!("no client for {}", chain)))?;
+            let num_imported =
+                backfill::import_range(*chain, client.as_ref(), &self.db, from, to).await?;
+            info!(
+                "imported {} blocks from {} to {} for chain {}",
+                num_imported, from, to, chain
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Fetches each chain's stored blocks in a range again, overwriting the
+    /// changed ones, outside of the job loop.
+    pub async fn repair(&self, chains: &[Chain], from: u64, to: u64) -> Result<()> {
+        for chain in chains {
+            let client = self
+                .client(*chain)
+                .ok_or_else(|| FatalError(format!("no client for {}", chain)))?;
+            let stats = repair::repair(*chain, client.as_ref(), &self.db, from, to).await?;
+            info!(
+                "repaired {} of {} blocks checked from {} to {} for chain {}",
+                stats.num_changed, stats.num_checked, from, to, chain
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Fetches a sample of each chain's stored blocks again, reporting those
+    /// that differ, outside of the job loop. Fails if any do.
+    pub async fn verify(&self, chains: &[Chain], samples: usize) -> Result<()> {
+        let mut num_mismatches = 0;
+        for chain in chains {
+            let client = self
+                .client(*chain)
+                .ok_or_else(|| FatalError(format!("no client for {}", chain)))?;
+            let report = verify::verify(*chain, client.as_ref(), &self.db, samples).await?;
+            for mismatch in &report.mismatches {
+                warn!(
+                    "block {} for chain {} differs: timestamp {} stored, {} fetched; {} txs stored, {} fetched",
+                    mismatch.block_number,
+                    chain,
+                    mismatch.stored_timestamp,
+                    mismatch.timestamp,
+                    mismatch.stored_num_txs,
+                    mismatch.num_txs
+                );
+            }
+            info!(
+                "{} of {} blocks checked differ for chain {}",
+                report.mismatches.len(),
+                report.num_checked,
+                chain
+            );
+            num_mismatches += report.mismatches.len();
+        }
+
+        if num_mismatches > 0 {
+            bail!(
+                "{} blocks differ from those fetched again; fix them with `repair`",
+                num_mismatches
+            );
+        }
+        Ok(())
+    }
+
+    async fn calculate(&self, chain: Chain) -> Result<Vec<Job>> {
+        if self.wait_if_leased(&Job::Calculate(chain)).await? {
+            return Ok(vec![Job::Calculate(chain)]);
+        }
+
+        // Repeated, to calculate again once the chain is enabled
+        if self.is_disabled(chain) {
+            calculate::mark_stale(chain, &self.db).await?;
+            delay::paused_delay().await;
+            return Ok(vec![Job::Calculate(chain)]);
+        }
+
+        let start = Instant::now();
+
+        if let Err(e) = self.calculate_tps(chain).await? {
+            print_error(&e);
+            error!("error calculating for {}", chain);
+        }
+
+        if let Err(e) = self.options.webhooks.check(chain, &self.db).await {
+            warn!("unable to check webhooks for chain {}: {}", chain, e);
+        }
+
+        if let Some(secondary) = &self.secondary {
+            if secondary.chains.contains(&chain) {
+                let timestamp = u64::try_from(Utc::now().timestamp())?;
+                let check =
+                    cross_check::check_providers(chain, &self.db, &secondary.db, timestamp).await?;
+                if let Some(check) = check {
+                    for mismatch in &check.mismatches {
+                        warn!(
+                            "providers disagree on chain {} in the hour from {}: {} txs, {} from the secondary",
+                            chain,
+                            mismatch.bucket_start,
+                            mismatch.num_txs,
+                            mismatch.secondary_num_txs
+                        );
+                    }
+                    info!(
+                        "compared {} hours of chain {} between providers",
+                        check.num_buckets, chain
+                    );
+                    self.db.store_provider_check(chain, &check).await?;
                 }
             }
         }
 
-        let end = Instant::now();
-        let duration = end - start;
-        info!("calculation took {} s", duration.as_secs());
+        let duration = Instant::now() - start;
+        info!(
+            "calculation for chain {} took {} s",
+            chain,
+            duration.as_secs()
+        );
 
-        delay::recalculate_delay().await;
+        delay::recalculate_delay(chain).await;
 
-        Ok(vec![Job::Calculate(chains)])
+        Ok(vec![Job::Calculate(chain)])
     }
 
-    async fn remove(&self, chains: Vec<Chain>) -> Result<Vec<Job>> {
-        info!("removing old data");
+    /// Calculates and publishes a chain's TPS, recording the outcome in its
+    /// status. An error calculating is returned inside, for the caller to
+    /// report, and one storing the results outside.
+    async fn calculate_tps(&self, chain: Chain) -> Result<Result<()>> {
+        info!("beginning tps calculation for chain {}", chain);
 
-        let mut rng = rand::thread_rng();
-        let mut chains = chains;
-        chains.shuffle(&mut rng);
+        // Spawned, as calculating is CPU-bound and would hold up other jobs
+        let calcs = task::spawn(calculate::calculate_for_chain(
+            chain,
+            self.db.clone(),
+            self.options.tps_windows.clone(),
+            self.options.exclude_failed_txs.contains(&chain),
+            self.options.max_tps_window.get(&chain).copied(),
+        ))
+        .await?;
 
-        for chain in &chains {
-            remove::remove_old_data_for_chain(*chain, self.db.clone()).await?;
+        match calcs {
+            Ok(calcs) => {
+                self.store_calcs(calcs).await?;
+                status::record_job(chain, LeaseKind::Calculate, &self.db, None).await;
+                Ok(Ok(()))
+            }
+            Err(e) => {
+                let error = format!("{:#}", e);
+                status::record_job(chain, LeaseKind::Calculate, &self.db, Some(&error)).await;
+                Ok(Err(e))
+            }
         }
+    }
+
+    async fn store_calcs(&self, mut calcs: ChainCalcs) -> Result<()> {
+        if let Some(usd_price) = self.options.usd_prices.get(&calcs.chain) {
+            calculate::convert_value_to_usd(&mut calcs.stats, *usd_price);
+        }
+        for (window, tps) in &calcs.tps {
+            info!(
+                "calculated {} tps over {} for chain {}",
+                tps, window, calcs.chain
+            );
+            let published_tps = self.db.load_tps(calcs.chain, *window).await?;
+            let published_tps =
+                calculate::smooth_tps(published_tps, *tps, self.options.tps_smoothing);
+            self.db
+                .store_tps(calcs.chain, *window, published_tps)
+                .await?;
+        }
+        let point = TpsPoint {
+            timestamp: calcs.timestamp,
+            tps: calcs.tps,
+        };
+        self.db.append_tps_history(calcs.chain, &point).await?;
+        for (window, gas) in calcs.gas_per_second {
+            info!(
+                "calculated {} gas/s over {} for chain {}",
+                gas, window, calcs.chain
+            );
+            self.db
+                .store_gas_per_second(calcs.chain, window, gas)
+                .await?;
+        }
+        for (window, window_stats) in &calcs.stats.windows {
+            if let Some(raw_tps) = window_stats.raw_tps {
+                info!(
+                    "calculated {} tps including votes over {} for chain {}",
+                    raw_tps, window, calcs.chain
+                );
+            }
+            if let Some(value_per_second) = window_stats.value_per_second {
+                info!(
+                    "calculated {} native value/s over {} for chain {}",
+                    value_per_second, window, calcs.chain
+                );
+            }
+            if let Some(transfers_per_second) = window_stats.token_transfers_per_second {
+                info!(
+                    "calculated {} token transfers/s over {} for chain {}",
+                    transfers_per_second, window, calcs.chain
+                );
+            }
+            if let Some(receipts_per_second) = window_stats.receipts_per_second {
+                info!(
+                    "calculated {} receipts/s over {} for chain {}",
+                    receipts_per_second, window, calcs.chain
+                );
+            }
+            if let Some(calls_per_second) = window_stats.internal_calls_per_second {
+                info!(
+                    "calculated {} internal calls/s over {} for chain {}",
+                    calls_per_second, window, calcs.chain
+                );
+            }
+            if let Some(utilization) = window_stats.utilization {
+                info!(
+                    "calculated {:.1}% block utilization over {} for chain {}",
+                    utilization * 100.0,
+                    window,
+                    calcs.chain
+                );
+            }
+        }
+        if let Some(max_tps) = &calcs.stats.max_tps {
+            info!(
+                "estimated max {} tps over {} for chain {}",
+                max_tps.max_tps, max_tps.window, calcs.chain
+            );
+        }
+        debug!("stats for chain {}: {:?}", calcs.chain, calcs.stats);
+        self.db.store_chain_stats(calcs.chain, &calcs.stats).await?;
+        let peaks = calcs.peak_tps.retained();
+        if let (Some(block), Some(minute)) = (peaks.block, peaks.minute) {
+            info!(
+                "peak tps for chain {}: {} in a block, {} in a minute",
+                calcs.chain, block.tps, minute.tps
+            );
+        }
+        Ok(())
+    }
+
+    /// Totals the TPS last calculated for each chain.
+    async fn aggregate(&self, chains: Vec<Chain>) -> Result<Vec<Job>> {
+        // Only one instance sharing the db appends aggregate TPS: the one
+        // calculating the first chain
+        if let Some(first_chain) = chains.first() {
+            if !self.holds_lease(*first_chain, LeaseKind::Calculate) {
+                delay::aggregate_delay().await;
+                return Ok(vec![Job::Aggregate(chains)]);
+            }
+        }
+
+        self.store_aggregate_tps(&chains).await?;
+
+        delay::aggregate_delay().await;
+
+        Ok(vec![Job::Aggregate(chains)])
+    }
+
+    async fn store_aggregate_tps(&self, chains: &[Chain]) -> Result<()> {
+        let timestamp = u64::try_from(Utc::now().timestamp())?;
+        let aggregate_tps = aggregate::calculate_aggregate_tps(
+            chains,
+            &self.db,
+            &self.options.tps_windows,
+            timestamp,
+        )
+        .await?;
+        for (window, tps) in &aggregate_tps.tps {
+            info!(
+                "calculated {} tps over {} for all {} chains",
+                tps, window, aggregate_tps.num_chains
+            );
+        }
+        self.db.append_aggregate_tps(&aggregate_tps).await?;
+
+        if let Some(block_cache) = &self.block_cache {
+            let (hits, misses) = block_cache.take_stats();
+            info!("block cache: {} hits, {} misses", hits, misses);
+        }
+
+        Ok(())
+    }
+
+    async fn remove(&self, chains: Vec<Chain>) -> Result<Vec<Job>> {
+        self.prune(&chains).await?;
 
         delay::remove_data_delay().await;
 
         Ok(vec![Job::Remove(chains)])
     }
+
+    /// Removes blocks older than each chain's retention window, and TPS
+    /// history older than its retention, once.
+    ///
+    /// When sharing a db, each chain's blocks are pruned by the instance
+    /// importing it, and its TPS history by the instance calculating it, so
+    /// instances don't remove the same blocks, or rewrite history being
+    /// appended to.
+    pub async fn prune(&self, chains: &[Chain]) -> Result<()> {
+        info!("removing old data");
+
+        let history_start = u64::try_from(Utc::now().timestamp())?
+            .saturating_sub(self.options.tps_history_retention_seconds);
+
+        let mut shuffled_chains = chains.to_vec();
+        shuffled_chains.shuffle(&mut rand::thread_rng());
+
+        for chain in &shuffled_chains {
+            if self.holds_lease(*chain, LeaseKind::Import) {
+                let retention_window = self.retention_window(*chain);
+                remove::remove_old_data_for_chain(
+                    *chain,
+                    self.db.clone(),
+                    retention_window.seconds(),
+                )
+                .await?;
+            }
+            if self.holds_lease(*chain, LeaseKind::Calculate) {
+                self.db
+                    .remove_tps_history_before(*chain, history_start)
+                    .await?;
+            }
+        }
+        // Like aggregate TPS, only pruned by the instance calculating the
+        // first chain, so `chains` must be in the aggregate job's order
+        if let Some(first_chain) = chains.first() {
+            if self.holds_lease(*first_chain, LeaseKind::Calculate) {
+                self.db.remove_aggregate_tps_before(history_start).await?;
+            }
+        }
+
+        if let Some(secondary) = &self.secondary {
+            for chain in chains.iter().filter(|chain| {
+                secondary.chains.contains(chain)
+                    && self.holds_lease(**chain, LeaseKind::ImportSecondary)
+            }) {
+                let retention_window = self.retention_window(*chain);
+                // The secondary may not have imported anything yet
+                if let Err(e) = remove::remove_old_data_for_chain(
+                    *chain,
+                    secondary.db.clone(),
+                    retention_window.seconds(),
+                )
+                .await
+                {
+                    warn!("unable to remove old secondary data for {}: {}", chain, e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Blocks are kept for the longest window a chain's TPS is calculated over.
+    fn retention_window(&self, chain: Chain) -> TpsWindow {
+        let longest_window = *self.options.tps_windows.last().expect("tps windows");
+        match self.options.max_tps_window.get(&chain) {
+            Some(max_window) => longest_window.min(*max_window),
+            None => longest_window,
+        }
+    }
+
+    /// Blocks are fetched one at a time unless configured otherwise.
+    fn import_options(&self, chain: Chain) -> ImportOptions {
+        ImportOptions {
+            concurrency: self
+                .import_concurrency
+                .get(&chain)
+                .copied()
+                .unwrap_or(1)
+                .max(1),
+            confirmations: self.confirmations.get(&chain).copied().unwrap_or_default(),
+            memory_budget: self.import_memory_budget.map(|memory_budget| {
+                let num_imported_chains = self.clients.read().expect("lock").len()
+                    + self
+                        .secondary
+                        .as_ref()
+                        .map_or(0, |secondary| secondary.clients.len());
+                memory_budget / num_imported_chains.max(1)
+            }),
+        }
+    }
+
+    async fn daily_tps(&self, chains: Vec<Chain>) -> Result<Vec<Job>> {
+        self.update_daily_tps(&chains).await;
+
+        delay::daily_tps_delay().await;
+
+        Ok(vec![Job::DailyTps(chains)])
+    }
+
+    async fn update_daily_tps(&self, chains: &[Chain]) {
+        for chain in chains
+            .iter()
+            .filter(|chain| self.holds_lease(**chain, LeaseKind::Calculate))
+        {
+            match daily::update_daily_tps(*chain, &self.db).await {
+                Ok(new_days) => {
+                    for day in new_days {
+                        info!(
+                            "calculated {} tps on {} for chain {}",
+                            day.tps, day.date, chain
+                        );
+                        if let Some(active_addresses) = day.active_addresses {
+                            info!(
+                                "estimated {} active addresses on {} for chain {}",
+                                active_addresses, day.date, chain
+                            );
+                        }
+                    }
+                }
+                Err(e) => {
+                    print_error(&e);
+                    error!("error calculating daily tps for {}", chain);
+                }
+            }
+            let retention_seconds = self.options.tps_history_retention_seconds;
+            match daily::update_hourly_tps(*chain, &self.db, retention_seconds).await {
+                Ok(num_new_hours) => {
+                    debug!(
+                        "calculated {} hours of tps for chain {}",
+                        num_new_hours, chain
+                    );
+                }
+                Err(e) => {
+                    print_error(&e);
+                    error!("error calculating hourly tps for {}", chain);
+                }
+            }
+        }
+    }
+
+    async fn report_gaps(&self, chains: Vec<Chain>) -> Result<Vec<Job>> {
+        gaps::report_gaps(&chains, &self.db).await?;
+
+        delay::gap_report_delay().await;
+
+        Ok(vec![Job::ReportGaps(chains)])
+    }
+
+    async fn backfill(&self, chain: Chain) -> Result<Vec<Job>> {
+        let job = Job::Backfill(chain);
+        let client = match self.client_or_wait(&job).await {
+            Some(client) => client,
+            None => return Ok(vec![job]),
+        };
+        if self.wait_if_paused(&job).await? || self.wait_if_leased(&job).await? {
+            return Ok(vec![job]);
+        }
+        let now = u64::try_from(Utc::now().timestamp())?;
+        let min_timestamp = now.saturating_sub(self.retention_window(chain).seconds());
+        let num_backfilled =
+            backfill::backfill(chain, client.as_ref(), &self.db, min_timestamp).await?;
+        if num_backfilled > 0 {
+            info!("backfilled {} blocks for chain {}", num_backfilled, chain);
+        }
+
+        delay::backfill_delay().await;
+
+        Ok(vec![job])
+    }
+}
+
+/// Persists the scores of a client's endpoints, so the next run starts with
+/// the healthiest.
+async fn store_endpoint_health(chain: Chain, client: &dyn Client, db: &Arc<dyn Db>) {
+    if let Some(health) = client.endpoint_health() {
+        if let Err(e) = db.store_endpoint_health(chain, &health).await {
+            warn!("failed to store endpoint health for {}: {}", chain, e);
+        }
+    }
 }
 
 fn print_error(e: &anyhow::Error) {
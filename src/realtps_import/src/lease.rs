@@ -0,0 +1,253 @@
+use crate::delay;
+use crate::shutdown;
+use anyhow::Result;
+use chrono::Utc;
+use log::{info, warn};
+use realtps_common::{
+    chain::Chain,
+    db::{ChainLease, Db, LeaseKind},
+};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How long to wait after taking a lease before checking it's still ours.
+/// The db can't compare and swap, so two instances taking a free lease at
+/// once both write it, and the last write wins.
+const SETTLE_DELAY: Duration = Duration::from_secs(2);
+
+/// Shares chains between importer instances using one db. Each chain's jobs
+/// of a kind only run in the instance holding the chain's lease for that
+/// kind, which it renews while running them. The leases of an instance that
+/// crashes expire, and other instances take over its chains.
+pub struct Leases {
+    holder: String,
+    duration_secs: u64,
+    /// The most chains to hold leases for, so the rest are left to other
+    /// instances
+    max_chains: Option<usize>,
+    settle_delay: Duration,
+    held: Mutex<HashSet<(Chain, LeaseKind)>>,
+}
+
+impl Leases {
+    pub fn new(holder: String, duration_secs: u64, max_chains: Option<usize>) -> Leases {
+        Leases {
+            holder,
+            duration_secs,
+            max_chains,
+            settle_delay: SETTLE_DELAY,
+            held: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub fn holder(&self) -> &str {
+        &self.holder
+    }
+
+    /// Whether this instance holds a lease, as of its last renewal.
+    pub fn holds(&self, chain: Chain, kind: LeaseKind) -> bool {
+        self.held.lock().expect("lock").contains(&(chain, kind))
+    }
+
+    /// Takes the lease if it's free or expired, returning whether this
+    /// instance holds it. Held leases are left to `keep_renewed`.
+    pub async fn acquire(&self, chain: Chain, kind: LeaseKind, db: &Arc<dyn Db>) -> Result<bool> {
+        if self.holds(chain, kind) {
+            return Ok(true);
+        }
+        if self.at_max_chains(chain) || !self.store_unless_held(chain, kind, db).await? {
+            return Ok(false);
+        }
+
+        tokio::time::sleep(self.settle_delay).await;
+        // Another instance's write may have landed after ours
+        let holder = db.load_lease(chain, kind).await?.map(|lease| lease.holder);
+        if holder.as_ref() != Some(&self.holder) {
+            return Ok(false);
+        }
+        info!(
+            "took the {} lease of chain {} as {}",
+            kind, chain, self.holder
+        );
+        self.held.lock().expect("lock").insert((chain, kind));
+        Ok(true)
+    }
+
+    /// Renews every lease held, forgetting those another instance has taken
+    /// since.
+    pub async fn renew_held(&self, db: &Arc<dyn Db>) {
+        let held: Vec<_> = self.held.lock().expect("lock").iter().copied().collect();
+        for (chain, kind) in held {
+            match self.store_unless_held(chain, kind, db).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    warn!(
+                        "lost the {} lease of chain {} to another instance",
+                        kind, chain
+                    );
+                    self.held.lock().expect("lock").remove(&(chain, kind));
+                }
+                Err(e) => warn!(
+                    "unable to renew the {} lease of chain {}: {}",
+                    kind, chain, e
+                ),
+            }
+        }
+    }
+
+    /// Renews the leases held until shutdown, a few times per lease.
+    pub async fn keep_renewed(&self, db: &Arc<dyn Db>) {
+        while !shutdown::requested() {
+            delay::lease_renew_delay(self.duration_secs).await;
+            self.renew_held(db).await;
+        }
+    }
+
+    /// Expires the leases held, so other instances take over the chains
+    /// without waiting.
+    pub async fn release_all(&self, db: &Arc<dyn Db>) {
+        let held: Vec<_> = self.held.lock().expect("lock").drain().collect();
+        for (chain, kind) in held {
+            let lease = ChainLease {
+                holder: self.holder.clone(),
+                expires_at: 0,
+            };
+            match db.store_lease(chain, kind, &lease).await {
+                Ok(()) => info!("released the {} lease of chain {}", kind, chain),
+                Err(e) => warn!(
+                    "unable to release the {} lease of chain {}: {}",
+                    kind, chain, e
+                ),
+            }
+        }
+    }
+
+    /// Writes the lease as this instance's for another `duration_secs`,
+    /// unless another instance holds it, returning whether it was written.
+    async fn store_unless_held(
+        &self,
+        chain: Chain,
+        kind: LeaseKind,
+        db: &Arc<dyn Db>,
+    ) -> Result<bool> {
+        let now = now();
+        if let Some(lease) = db.load_lease(chain, kind).await? {
+            if lease.holder != self.holder && lease.expires_at > now {
+                return Ok(false);
+            }
+        }
+        let lease = ChainLease {
+            holder: self.holder.clone(),
+            expires_at: now + self.duration_secs,
+        };
+        db.store_lease(chain, kind, &lease).await?;
+        Ok(true)
+    }
+
+    /// Whether taking a lease for `chain` would hold more chains than allowed.
+    fn at_max_chains(&self, chain: Chain) -> bool {
+        let max_chains = match self.max_chains {
+            Some(max_chains) => max_chains,
+            None => return false,
+        };
+        let held = self.held.lock().expect("lock");
+        let chains: HashSet<Chain> = held.iter().map(|(chain, _)| *chain).collect();
+        !chains.contains(&chain) && chains.len() >= max_chains
+    }
+}
+
+fn now() -> u64 {
+    u64::try_from(Utc::now().timestamp()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test_lease {
+    use super::Leases;
+    use realtps_common::{
+        chain::Chain,
+        db::{ChainLease, Db, LeaseKind},
+        memory_db::MemoryDb,
+    };
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    const KIND: LeaseKind = LeaseKind::Import;
+
+    fn make_leases(holder: &str, max_chains: Option<usize>) -> Leases {
+        let mut leases = Leases::new(holder.to_string(), 300, max_chains);
+        leases.settle_delay = Duration::ZERO;
+        leases
+    }
+
+    #[tokio::test]
+    async fn one_holder_per_chain() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let a = make_leases("a", None);
+        let b = make_leases("b", None);
+
+        assert!(a.acquire(Chain::Ethereum, KIND, &db).await?);
+        assert!(!b.acquire(Chain::Ethereum, KIND, &db).await?);
+        assert!(b.acquire(Chain::Polygon, KIND, &db).await?);
+        a.renew_held(&db).await;
+        assert!(a.holds(Chain::Ethereum, KIND));
+        // Kinds are leased apart
+        assert!(
+            b.acquire(Chain::Ethereum, LeaseKind::Calculate, &db)
+                .await?
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn take_over_expired_and_released() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let a = make_leases("a", None);
+        let b = make_leases("b", None);
+
+        // As left by a crashed instance
+        let expired = ChainLease {
+            holder: "crashed".to_string(),
+            expires_at: 1,
+        };
+        db.store_lease(Chain::Ethereum, KIND, &expired).await?;
+        assert!(a.acquire(Chain::Ethereum, KIND, &db).await?);
+
+        a.release_all(&db).await;
+        assert!(!a.holds(Chain::Ethereum, KIND));
+        assert!(b.acquire(Chain::Ethereum, KIND, &db).await?);
+        assert!(!a.acquire(Chain::Ethereum, KIND, &db).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn forget_lost_leases() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let a = make_leases("a", None);
+        assert!(a.acquire(Chain::Ethereum, KIND, &db).await?);
+
+        // Taken over while `a` stalled past its expiry
+        let taken = ChainLease {
+            holder: "b".to_string(),
+            expires_at: u64::MAX,
+        };
+        db.store_lease(Chain::Ethereum, KIND, &taken).await?;
+        a.renew_held(&db).await;
+        assert!(!a.holds(Chain::Ethereum, KIND));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn hold_at_most_max_chains() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let a = make_leases("a", Some(1));
+
+        assert!(a.acquire(Chain::Ethereum, KIND, &db).await?);
+        assert!(
+            a.acquire(Chain::Ethereum, LeaseKind::Calculate, &db)
+                .await?
+        );
+        assert!(!a.acquire(Chain::Polygon, KIND, &db).await?);
+        Ok(())
+    }
+}
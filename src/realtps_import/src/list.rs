@@ -0,0 +1,203 @@
+use crate::pause;
+use anyhow::Result;
+use realtps_common::{
+    chain::Chain,
+    db::{Db, TpsWindow},
+};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Arc;
+
+/// A chain's config and what the db holds for it.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ChainListing {
+    pub chain: Chain,
+    /// The hosts of the chain's RPC URLs, without their API keys
+    pub endpoints: Vec<String>,
+    /// Not in `disabled_chains`
+    pub enabled: bool,
+    pub paused: bool,
+    pub num_blocks: usize,
+    pub oldest_block: Option<u64>,
+    pub newest_block: Option<u64>,
+    /// Over the week
+    pub tps: Option<f64>,
+    /// The chain is disabled, so its TPS is no longer updated
+    pub stale: bool,
+}
+
+/// Prints each chain's endpoints, whether it is imported, its stored blocks
+/// and its last calculated TPS, as a table or as JSON.
+pub async fn list_chains(
+    chains: &[Chain],
+    db: &Arc<dyn Db>,
+    endpoints: &HashMap<Chain, Vec<String>>,
+    enabled: impl Fn(Chain) -> bool,
+    json: bool,
+) -> Result<()> {
+    let mut listings = vec![];
+    for chain in chains {
+        let chain_endpoints = endpoints.get(chain).cloned().unwrap_or_default();
+        listings.push(load_listing(*chain, db, chain_endpoints, enabled(*chain)).await?);
+    }
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&listings)?);
+    } else {
+        print!("{}", format_table(&listings));
+    }
+    Ok(())
+}
+
+/// The listing of one chain, given its endpoints and whether it is enabled.
+pub async fn load_listing(
+    chain: Chain,
+    db: &Arc<dyn Db>,
+    endpoints: Vec<String>,
+    enabled: bool,
+) -> Result<ChainListing> {
+    let block_numbers = db.load_block_numbers(chain).await?;
+    let calculation_log = db.load_calculation_log(chain).await?;
+    Ok(ChainListing {
+        chain,
+        endpoints,
+        enabled,
+        paused: pause::is_paused(chain, db).await?,
+        num_blocks: block_numbers.len(),
+        oldest_block: block_numbers.first().copied(),
+        newest_block: block_numbers.last().copied(),
+        tps: db.load_tps(chain, TpsWindow::Week).await?,
+        stale: calculation_log.is_some_and(|log| log.stale),
+    })
+}
+
+/// Lines up the listings in columns under a header, one chain per line.
+pub fn format_table(listings: &[ChainListing]) -> String {
+    let rows: Vec<[String; 5]> = listings
+        .iter()
+        .map(|listing| {
+            let status = match (listing.enabled, listing.paused) {
+                (false, _) => "disabled",
+                (true, true) => "paused",
+                (true, false) => "enabled",
+            };
+            let blocks = match (listing.oldest_block, listing.newest_block) {
+                (Some(oldest), Some(newest)) => {
+                    format!("{}-{} ({})", oldest, newest, listing.num_blocks)
+                }
+                _ => "-".to_string(),
+            };
+            let tps = match (listing.tps, listing.stale) {
+                (Some(tps), false) => format!("{:.2}", tps),
+                (Some(tps), true) => format!("{:.2} (stale)", tps),
+                (None, _) => "-".to_string(),
+            };
+            let endpoints = if listing.endpoints.is_empty() {
+                "-".to_string()
+            } else {
+                listing.endpoints.join(", ")
+            };
+            [
+                listing.chain.to_string(),
+                status.to_string(),
+                blocks,
+                tps,
+                endpoints,
+            ]
+        })
+        .collect();
+
+    format_columns(["CHAIN", "STATUS", "BLOCKS", "TPS", "ENDPOINTS"], &rows)
+}
+
+/// Lines up rows of cells under a header, padding every column but the last
+/// to its widest cell.
+pub fn format_columns<const N: usize>(header: [&str; N], rows: &[[String; N]]) -> String {
+    let header = header.map(String::from);
+    let mut widths = header.clone().map(|title| title.len());
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let mut table = String::new();
+    for row in std::iter::once(&header).chain(rows) {
+        let (last, cells) = row.split_last().expect("columns");
+        for (cell, width) in cells.iter().zip(widths) {
+            write!(table, "{:width$}  ", cell, width = width).expect("write to string");
+        }
+        writeln!(table, "{}", last).expect("write to string");
+    }
+    table
+}
+
+#[cfg(test)]
+mod test_list {
+    use super::{format_table, load_listing};
+    use crate::test_helpers::make_blocks;
+    use chrono::Utc;
+    use realtps_common::{
+        chain::Chain,
+        db::{CalculationLog, Db, TpsWindow},
+        memory_db::MemoryDb,
+    };
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn list_stored_blocks_and_tps() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        db.store_blocks(Chain::Ethereum, make_blocks(Chain::Ethereum, 10, 12, 5))
+            .await?;
+        db.store_tps(Chain::Ethereum, TpsWindow::Week, 12.345)
+            .await?;
+
+        let listing = load_listing(
+            Chain::Ethereum,
+            &db,
+            vec!["cloudflare-eth.com".to_string()],
+            true,
+        )
+        .await?;
+        assert_eq!(listing.num_blocks, 10);
+        assert_eq!(listing.oldest_block, Some(0));
+        assert_eq!(listing.newest_block, Some(9));
+        assert_eq!(listing.tps, Some(12.345));
+        assert!(!listing.stale);
+
+        let empty = load_listing(Chain::Solana, &db, vec![], false).await?;
+        assert_eq!(empty.num_blocks, 0);
+        assert_eq!(empty.newest_block, None);
+
+        let table = format_table(&[listing, empty]);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("CHAIN "));
+        assert!(lines[1].contains("0-9 (10)"));
+        assert!(lines[1].contains("12.35"));
+        assert!(lines[1].ends_with("cloudflare-eth.com"));
+        assert!(lines[2].contains("disabled"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn list_disabled_chain_as_stale() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        db.store_tps(Chain::Solana, TpsWindow::Week, 2000.0).await?;
+        let now = Utc::now();
+        let log = CalculationLog {
+            calculating_start: now,
+            calculating_end: now,
+            newest_block_timestamp: now,
+            oldest_block_timestamp: now,
+            stale: true,
+        };
+        db.store_calculation_log(Chain::Solana, &log).await?;
+
+        let listing = load_listing(Chain::Solana, &db, vec![], false).await?;
+        assert!(listing.stale);
+        assert!(format_table(&[listing]).contains("2000.00 (stale)"));
+        Ok(())
+    }
+}
@@ -0,0 +1,255 @@
+use crate::jobs::Job;
+use anyhow::Result;
+use chrono::{SecondsFormat, Utc};
+use log::{Level, Record};
+use log4rs::config::{Deserialize, Deserializers};
+use log4rs::encode::{Encode, Write};
+use realtps_common::chain::Chain;
+use serde::Serialize;
+use std::future::Future;
+
+/// The encoder kind that `--log-format json` gives every appender.
+pub const JSON_ENCODER_KIND: &str = "structured_json";
+
+#[derive(clap::ArgEnum, Debug, Copy, Clone)]
+pub enum LogFormat {
+    /// As configured in the log config
+    Text,
+    /// One JSON object a line, with the chain, job, block number, duration
+    /// and error class as fields, for log stores like Loki and Elastic
+    Json,
+}
+
+/// The job a log record was written from.
+#[derive(Debug, Copy, Clone)]
+struct JobContext {
+    chain: Option<Chain>,
+    job: &'static str,
+}
+
+tokio::task_local! {
+    static JOB_CONTEXT: JobContext;
+}
+
+/// Runs `f` as `job`, so its log records carry the job and its chain.
+pub async fn in_job<F: Future>(job: &Job, f: F) -> F::Output {
+    let context = JobContext {
+        chain: job.chain(),
+        job: job.name(),
+    };
+    JOB_CONTEXT.scope(context, f).await
+}
+
+/// Sets every appender in a log4rs YAML config to encode as JSON, labelling
+/// records logged outside of jobs with `job_name`.
+pub fn use_json_encoder(config: &str, job_name: &str) -> Result<String> {
+    let mut config: serde_yaml::Value = serde_yaml::from_str(config)?;
+    if let Some(appenders) = config
+        .get_mut("appenders")
+        .and_then(serde_yaml::Value::as_mapping_mut)
+    {
+        for (_, appender) in appenders.iter_mut() {
+            if let Some(appender) = appender.as_mapping_mut() {
+                let encoder = serde_yaml::to_value(JsonEncoderConfig {
+                    kind: JSON_ENCODER_KIND.to_string(),
+                    job: job_name.to_string(),
+                })?;
+                appender.insert("encoder".into(), encoder);
+            }
+        }
+    }
+    Ok(serde_yaml::to_string(&config)?)
+}
+
+/// The log4rs deserializers, with the JSON encoder.
+pub fn deserializers() -> Deserializers {
+    let mut deserializers = Deserializers::default();
+    deserializers.insert(JSON_ENCODER_KIND, JsonEncoderDeserializer);
+    deserializers
+}
+
+#[derive(Serialize, serde::Deserialize)]
+struct JsonEncoderConfig {
+    #[serde(default, skip_deserializing)]
+    kind: String,
+    job: String,
+}
+
+struct JsonEncoderDeserializer;
+
+impl Deserialize for JsonEncoderDeserializer {
+    type Trait = dyn Encode;
+    type Config = JsonEncoderConfig;
+
+    fn deserialize(&self, config: JsonEncoderConfig, _: &Deserializers) -> Result<Box<dyn Encode>> {
+        Ok(Box::new(JsonEncoder { job: config.job }))
+    }
+}
+
+/// Encodes each record as a line of JSON. The chain and job are those of
+/// the job the record was logged in. Outside of jobs, the job is the
+/// command's, and the chain, like the block number and duration, is read
+/// from the message, from phrases like `chain solana`, `block 123` and
+/// `took 5 s`.
+#[derive(Debug)]
+struct JsonEncoder {
+    job: String,
+}
+
+#[derive(Serialize, Debug, Default, PartialEq)]
+struct MessageFields {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chain: Option<Chain>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    block_number: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_ms: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct JsonRecord<'a> {
+    time: String,
+    level: Level,
+    target: &'a str,
+    message: &'a str,
+    job: &'a str,
+    #[serde(flatten)]
+    fields: MessageFields,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_class: Option<&'static str>,
+}
+
+impl Encode for JsonEncoder {
+    fn encode(&self, w: &mut dyn Write, record: &Record) -> Result<()> {
+        let message = record.args().to_string();
+        let context = JOB_CONTEXT.try_with(|context| *context).ok();
+        let mut fields = message_fields(&message);
+        if let Some(chain) = context.and_then(|context| context.chain) {
+            fields.chain = Some(chain);
+        }
+        let json_record = JsonRecord {
+            time: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            level: record.level(),
+            target: record.target(),
+            message: &message,
+            job: context.map_or(&self.job, |context| context.job),
+            fields,
+            error_class: match record.level() {
+                Level::Error | Level::Warn => Some(error_class(&message)),
+                _ => None,
+            },
+        };
+        serde_json::to_writer(&mut *w, &json_record)?;
+        w.write_all(b"\n")?;
+        Ok(())
+    }
+}
+
+fn message_fields(message: &str) -> MessageFields {
+    let mut fields = MessageFields::default();
+    let words: Vec<&str> = message
+        .split_whitespace()
+        .map(|word| word.trim_matches(|c: char| matches!(c, ',' | '.' | ':' | ';' | '(' | ')')))
+        .collect();
+    for pair in words.windows(2) {
+        match pair {
+            ["chain", chain] if fields.chain.is_none() => {
+                fields.chain = clap::ArgEnum::from_str(chain, false).ok();
+            }
+            ["block", number] if fields.block_number.is_none() => {
+                fields.block_number = number.parse().ok();
+            }
+            _ => {}
+        }
+    }
+    for triple in words.windows(3) {
+        if let ["took", amount, unit] = triple {
+            let amount: Option<f64> = amount.parse().ok();
+            let ms_per_unit = match *unit {
+                "ms" => Some(1.0),
+                "s" => Some(1000.0),
+                _ => None,
+            };
+            if let (Some(amount), Some(ms_per_unit)) = (amount, ms_per_unit) {
+                fields.duration_ms = Some((amount * ms_per_unit) as u64);
+            }
+        }
+    }
+    fields
+}
+
+/// A coarse class of what went wrong, to alert on, for warnings and errors.
+fn error_class(message: &str) -> &'static str {
+    let message = message.to_lowercase();
+    let has = |patterns: &[&str]| patterns.iter().any(|pattern| message.contains(pattern));
+    if has(&["fatal", "giving up", "quarantined"]) {
+        "fatal"
+    } else if has(&["rate limit", "too many requests", "429"]) {
+        "rate_limit"
+    } else if has(&["timed out", "timeout", "deadline"]) {
+        "timeout"
+    } else if has(&["connection", "connect", "dns", "tls"]) {
+        "connection"
+    } else if has(&["received none", "returned none", "not found", "missing"]) {
+        "missing_data"
+    } else if has(&["parse", "decode", "deserializ", "invalid"]) {
+        "invalid_data"
+    } else if has(&["disk", "no space", "permission denied"]) {
+        "storage"
+    } else {
+        "other"
+    }
+}
+
+#[cfg(test)]
+mod test_log_format {
+    use super::{error_class, message_fields, use_json_encoder, MessageFields};
+    use realtps_common::chain::Chain;
+
+    #[test]
+    fn read_fields_from_messages() {
+        assert_eq!(
+            message_fields("fetching block 15000000 for chain ethereum"),
+            MessageFields {
+                chain: Some(Chain::Ethereum),
+                block_number: Some(15_000_000),
+                duration_ms: None,
+            }
+        );
+        assert_eq!(
+            message_fields("calculation for chain solana took 12 s").duration_ms,
+            Some(12_000)
+        );
+        assert_eq!(message_fields("no block here"), MessageFields::default());
+    }
+
+    #[test]
+    fn classify_errors() {
+        assert_eq!(
+            error_class("fatal error running job Import(Near). giving up"),
+            "fatal"
+        );
+        assert_eq!(
+            error_class("error: HTTP status 429 Too Many Requests"),
+            "rate_limit"
+        );
+        assert_eq!(error_class("error: operation timed out"), "timeout");
+        assert_eq!(
+            error_class("for chain near received None. retrying in 100 ms"),
+            "missing_data"
+        );
+        assert_eq!(error_class("error running job. repeating"), "other");
+    }
+
+    #[test]
+    fn encode_all_appenders_as_json() -> Result<(), anyhow::Error> {
+        let config = use_json_encoder(include_str!("../../../log_config.yml"), "import")?;
+        let config: serde_yaml::Value = serde_yaml::from_str(&config)?;
+        for appender in ["console", "rolling_file"] {
+            let encoder = &config["appenders"][appender]["encoder"];
+            assert_eq!(encoder["kind"].as_str(), Some("structured_json"));
+            assert_eq!(encoder["job"].as_str(), Some("import"));
+        }
+        Ok(())
+    }
+}
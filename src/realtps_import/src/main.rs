@@ -1,118 +1,1012 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use auth::EndpointAuth;
+use block_cache::BlockCacheDb;
+use chrono::NaiveDate;
 use clap::{Parser, Subcommand};
 use client::Client;
 use clients::*;
-use delay::retry_if_err;
+use delay::{retry_if_err, RetryPolicy};
+use failover::{Endpoint, FailoverClient};
 use futures::future::FutureExt;
 use futures::stream::{FuturesUnordered, StreamExt};
-use jobs::{Job, JobRunner};
-use log::{error, info};
+use jobs::{CalculateOptions, Job, JobRunner, Secondary};
+use lease::Leases;
+use log::{error, info, warn};
+use log_format::LogFormat;
+use new_heads::NewHeads;
+use profile::Profile;
+use rate_limit::{RateLimit, RateLimitedClient, RateLimiter};
 use realtps_common::{
     chain::{Chain, ChainType},
-    db::JsonDb,
+    db::{Db, TpsWindow, JSON_DB_DIR},
+    db_config::{make_db, DbBackend, DbConfig},
+    metadata::MetadataOverride,
 };
+use reload::Reloader;
+use reqwest::header::HeaderMap;
+use scheduler::Scheduler;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
-use std::path::Path;
-use std::sync::Arc;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 use tempdir::TempDir;
 use tokio::task;
+use webhooks::{WebhookConfig, Webhooks};
 
+mod aggregate;
+mod auth;
+mod backfill;
+mod bigquery;
+mod block_cache;
 mod calculate;
+mod check;
 mod client;
 mod clients;
+mod cross_check;
+mod daily;
 mod delay;
+mod export;
+mod failover;
+mod gaps;
 mod helpers;
+mod history;
 mod import;
+mod interpolate;
 mod jobs;
+mod lease;
+mod list;
+mod log_format;
+mod migrate;
+mod new_heads;
 mod pace_setter;
+mod pause;
+mod peaks;
+mod profile;
+mod progress;
+mod rate_limit;
+mod reload;
 mod remove;
+mod repair;
+mod scheduler;
+mod shutdown;
+mod snapshot;
+mod status;
+#[cfg(test)]
+mod test_helpers;
+mod validate;
+mod verify;
+mod webhooks;
 
 #[derive(Parser, Debug)]
 struct Opts {
     #[clap(subcommand)]
     cmd: Option<Command>,
 
+    /// A chain to work on, rather than all of them. May be repeated.
+    #[clap(long = "chain", value_name = "CHAIN", arg_enum, global = true)]
+    chains: Vec<Chain>,
+
+    /// Store data in an object store, e.g. `s3://bucket/prefix`, instead of
+    /// the local `db` directory.
+    #[clap(long, global = true)]
+    object_store: Option<String>,
+
+    /// The directory to store data in, in place of `db`.
+    #[clap(long, global = true)]
+    db_path: Option<String>,
+
+    /// How to store data. Defaults to `object-store` with `--object-store`,
+    /// and `json` otherwise.
     #[clap(long, arg_enum, global = true)]
-    chain: Option<Chain>,
+    db_backend: Option<DbBackend>,
+
+    /// How to write logs. `json` writes one object a line with structured
+    /// fields, in place of each appender's encoder in `log_config.yml`.
+    #[clap(long, arg_enum, global = true, default_value = "text")]
+    log_format: LogFormat,
+
+    /// How many recently loaded blocks to keep in memory. 0 disables the
+    /// cache.
+    #[clap(long, global = true, default_value = "100000")]
+    block_cache_size: usize,
+
+    /// A window to calculate TPS over. May be repeated. Defaults to all
+    /// windows.
+    #[clap(long = "tps-window", arg_enum, global = true)]
+    tps_windows: Vec<TpsWindow>,
+
+    /// How many days of calculated TPS to keep.
+    #[clap(long, global = true, default_value = "365")]
+    tps_history_days: u64,
+
+    /// The weight, between 0 and 1, of each new calculation in the published
+    /// TPS. Lower values smooth out jumps. 1 disables smoothing.
+    #[clap(long, global = true, default_value = "1")]
+    tps_smoothing: f64,
+
+    /// Exit if a chain has no RPC URL configured or its client fails to
+    /// start, rather than importing the other chains without it.
+    #[clap(long, global = true)]
+    strict: bool,
+
+    /// Names this instance in the leases of the chains it runs, when
+    /// `leases` is configured. Defaults to the host name and process ID.
+    #[clap(long, global = true)]
+    instance_id: Option<String>,
+
+    /// The RPC config to load.
+    #[clap(long = "config", global = true, default_value = RPC_CONFIG_PATH)]
+    config_path: PathBuf,
+
+    /// A profile under `[profiles]` in the RPC config to run as, with its
+    /// own data directory and chains.
+    #[clap(long, global = true)]
+    profile: Option<String>,
 }
 
 #[derive(Subcommand, Debug)]
 enum Command {
-    Run,
-    Import,
-    Calculate,
+    /// Import, calculate and remove old data continuously. The default.
+    Run {
+        /// Import each chain up to its head and calculate once, then exit,
+        /// with an error if any chain failed, for running from cron or a
+        /// timer
+        #[clap(long)]
+        once: bool,
+        /// With `--once`, also remove old data, as `prune` does
+        #[clap(long, requires = "once")]
+        prune: bool,
+    },
+    Import {
+        /// Instead of following the head, fetch the missing blocks from this
+        /// block number to `--to` and exit
+        #[clap(long, requires = "to")]
+        from: Option<u64>,
+        /// The end of the range started by `--from`, inclusive
+        #[clap(long, requires = "from")]
+        to: Option<u64>,
+    },
+    Calculate {
+        /// Instead of calculating the configured windows, print TPS from
+        /// this unix timestamp to `--to` and exit
+        #[clap(long, requires = "to")]
+        from: Option<u64>,
+        /// The end of the range started by `--from`
+        #[clap(long, requires = "from")]
+        to: Option<u64>,
+        /// With `--print-only`, print TPS over this window before the newest
+        /// block, like `90m`, `24h` or `7d`, rather than the configured ones
+        #[clap(
+            long,
+            requires = "print-only",
+            conflicts_with = "from",
+            value_parser = calculate::parse_window
+        )]
+        window: Option<u64>,
+        /// Print the calculations as JSON and exit, rather than publish them
+        #[clap(long)]
+        print_only: bool,
+    },
     Remove,
+    /// Remove blocks older than each chain's longest TPS window, and TPS
+    /// history older than `--tps-history-days`, once, rather than daily
+    /// like `remove`
+    Prune,
+    /// Upgrade the db to the current storage format
+    Migrate,
+    /// Check stored blocks for corruption and inconsistencies
+    DbCheck {
+        /// What to do with corrupt and misplaced blocks
+        #[clap(long, arg_enum)]
+        fix: Option<check::Fix>,
+    },
+    /// Report runs of blocks missing from the db
+    Gaps,
+    /// List the chains with their endpoints, whether they are imported,
+    /// their stored blocks and their last calculated TPS
+    ListChains {
+        /// Print JSON rather than a table, for scripts
+        #[clap(long)]
+        json: bool,
+    },
+    /// Report how far behind each chain's import is, when its imports and
+    /// calculations last succeeded, their errors since, and its db size
+    Status {
+        /// Print JSON rather than a table, for monitoring
+        #[clap(long)]
+        json: bool,
+    },
+    /// Write each chain's published TPS and metrics over every window, and
+    /// with `--history` its TPS history, for analysis
+    Export {
+        #[clap(long, arg_enum, default_value = "csv")]
+        format: export::ExportFormat,
+        /// Include the TPS history, which as CSV replaces the current values
+        #[clap(long)]
+        history: bool,
+        /// Where to write the export, rather than stdout
+        #[clap(long)]
+        output: Option<PathBuf>,
+    },
+    /// Write all chain data to a compressed snapshot file
+    ExportSnapshot {
+        path: PathBuf,
+    },
+    /// Load chain data from a snapshot file
+    ImportSnapshot {
+        path: PathBuf,
+    },
+    /// Stop importing the `--chain`s given, including in a running process,
+    /// until they are resumed
+    Pause {
+        /// Why, for the logs
+        #[clap(long)]
+        reason: Option<String>,
+    },
+    /// Resume importing the paused `--chain`s given
+    Resume,
+    /// Fetch the stored blocks from `--from` to `--to` again, overwriting
+    /// those that changed, after a provider served bad data
+    Repair {
+        #[clap(long)]
+        from: u64,
+        /// Inclusive
+        #[clap(long)]
+        to: u64,
+    },
+    /// Calculate the daily TPS of the `--chain` given back to `--since` from
+    /// an archive node, for history from before the chain was imported
+    Backfill {
+        /// The archive node's RPC URL, as the chain's configured RPC may not
+        /// serve old blocks
+        #[clap(long)]
+        archive_rpc: String,
+        /// The oldest UTC day to calculate, e.g. 2021-01-01
+        #[clap(long)]
+        since: NaiveDate,
+        /// Estimate each day's transactions from this many of its blocks,
+        /// rather than fetching every block
+        #[clap(long)]
+        samples_per_day: Option<u64>,
+    },
+    /// Store the `--chain`'s blocks over the longest TPS window from its
+    /// BigQuery public dataset, to seed it before importing. Authorized with
+    /// an OAuth access token in `BIGQUERY_ACCESS_TOKEN`.
+    SeedBigquery {
+        /// The Google Cloud project to bill the query to
+        #[clap(long)]
+        project: String,
+    },
+    /// Fetch a random sample of each chain's stored blocks again and report
+    /// those whose timestamps or transaction counts differ, exiting with an
+    /// error if any do
+    Verify {
+        /// How many blocks to check per chain
+        #[clap(long, default_value = "100")]
+        samples: usize,
+    },
+    /// Delete all the `--chain`'s blocks, TPS and indexes, for a clean
+    /// re-import after it was delisted or its data was corrupted. Stop
+    /// importing it first.
+    RemoveChain {
+        /// Really delete them, rather than report what would be deleted
+        #[clap(long)]
+        yes: bool,
+    },
+    /// Check `rpc_config.toml` for unknown chains and settings, chains
+    /// without an RPC URL, and malformed URLs, exiting with an error if there
+    /// are problems
+    ValidateConfig {
+        /// Also ask each of the chains' endpoints for its version and latest
+        /// block
+        #[clap(long)]
+        probe: bool,
+    },
 }
 
 #[derive(Deserialize, Serialize)]
 struct RpcConfig {
-    chains: HashMap<Chain, String>,
+    chains: HashMap<Chain, RpcUrls>,
+    /// Chains not to import, keeping their config and data. Their last
+    /// calculations stay published, flagged as stale.
+    #[serde(default)]
+    disabled_chains: HashSet<Chain>,
+    /// Etherscan-style explorer APIs for EVM chains, used when all of the
+    /// chain's RPCs fail, or alone for chains without one.
+    #[serde(default)]
+    indexers: HashMap<Chain, IndexerConfig>,
+    /// Chains to count only successful transactions for. Failed transactions
+    /// are counted on import where the client supports it, and excluded from
+    /// TPS.
+    #[serde(default)]
+    exclude_failed_txs: HashSet<Chain>,
+    /// Chains to calculate TPS over at most this window, like those whose
+    /// RPCs can't serve a week of blocks. Longer windows are calculated over
+    /// it instead.
+    #[serde(default)]
+    max_tps_window: HashMap<Chain, TpsWindow>,
+    /// EVM chains to count token transfers for, from the logs of every
+    /// block.
+    #[serde(default)]
+    count_token_transfers: HashSet<Chain>,
+    /// EVM chains to import only block headers and transaction counts for,
+    /// rather than blocks with their transaction hashes, to save bandwidth.
+    /// The RPC must serve `eth_getHeaderByNumber`.
+    #[serde(default)]
+    light_import: HashSet<Chain>,
+    /// EVM chains to count internal calls for, by tracing every block with
+    /// the given method, which the RPC must serve
+    #[serde(default)]
+    count_internal_calls: HashMap<Chain, TraceMethod>,
+    /// USD per whole native unit, to convert value transferred per second to
+    /// USD. Static, so a price feed would have to rewrite it and restart.
+    #[serde(default)]
+    usd_prices: HashMap<Chain, f64>,
+    /// A second, independent RPC for some chains, imported into a namespace
+    /// of its own, to check the transaction counts from `chains` against.
+    #[serde(default)]
+    secondary_chains: HashMap<Chain, RpcUrls>,
+    /// Chains to fetch this many blocks at a time for when importing, to
+    /// catch up faster than one block per request and block pace.
+    #[serde(default)]
+    import_concurrency: HashMap<Chain, usize>,
+    /// Roughly how many megabytes of fetched blocks all imports together may
+    /// hold in memory
+    #[serde(default)]
+    import_memory_budget_mb: Option<usize>,
+    /// How many chains to import at once. The chains furthest behind are
+    /// imported first. Unlimited if unset.
+    #[serde(default)]
+    max_concurrent_imports: Option<usize>,
+    /// Chains to import only blocks at least this many blocks behind the
+    /// head, so short reorgs don't end up in the data
+    #[serde(default)]
+    confirmations: HashMap<Chain, u64>,
+    /// Rate limits for RPC endpoints, keyed by URL, shared by every chain
+    /// using the endpoint.
+    #[serde(default)]
+    rate_limits: HashMap<String, RateLimit>,
+    /// Headers, bearer tokens or basic auth for RPC endpoints, keyed by URL,
+    /// for providers that don't take API keys in the URL
+    #[serde(default)]
+    auth: HashMap<String, EndpointAuth>,
+    /// Websocket URLs for chains to import as their new blocks are pushed,
+    /// rather than polling `chains`, which blocks are still fetched from
+    #[serde(default)]
+    ws_urls: HashMap<Chain, String>,
+    /// How to retry failed requests and jobs
+    #[serde(default)]
+    retry: RetryPolicy,
+    /// Retry policies for some chains, in place of `retry`
+    #[serde(default)]
+    chain_retry: HashMap<Chain, RetryPolicy>,
+    /// How long to wait on RPC endpoints before failing a request
+    #[serde(default)]
+    timeouts: Timeouts,
+    /// Timeouts for some chains, in place of `timeouts`
+    #[serde(default)]
+    chain_timeouts: HashMap<Chain, Timeouts>,
+    /// How much of each transaction to fetch with Solana blocks. `accounts`
+    /// fetches much less, but leaves out compute units.
+    #[serde(default)]
+    solana_block_details: SolanaBlockDetails,
+    /// Lets several instances share one db, each running the jobs of the
+    /// chains it holds leases for. Without it, instances sharing a db write
+    /// over each other.
+    #[serde(default)]
+    leases: Option<LeaseConfig>,
+    /// Deployments sharing this config, each with its own data directory
+    /// and chains, selected with `--profile`
+    #[serde(default)]
+    profiles: HashMap<String, Profile>,
+    /// Where to store data, unless given on the command line
+    #[serde(default)]
+    db: DbConfig,
+    /// Names, tickers, explorers, kinds and websites for some chains, in
+    /// place of the built-in ones, for exports
+    #[serde(default)]
+    metadata: HashMap<Chain, MetadataOverride>,
+    /// URLs to post to when a chain's TPS crosses a threshold or it stalls,
+    /// checked after each calculation
+    #[serde(default)]
+    webhooks: Vec<WebhookConfig>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy)]
+struct LeaseConfig {
+    /// How long a lease lasts without being renewed, and so how long a
+    /// crashed instance's chains wait to be taken over
+    #[serde(default = "default_lease_secs")]
+    duration_secs: u64,
+    /// The most chains each instance runs, so the rest are left to others
+    #[serde(default)]
+    max_chains: Option<usize>,
+}
+
+fn default_lease_secs() -> u64 {
+    300
+}
+
+/// How long to wait on an endpoint before failing a request, so one that
+/// hangs is retried promptly rather than stalling the chain.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+struct Timeouts {
+    /// To connect, in seconds
+    connect_secs: u64,
+    /// For the whole request, from connecting to the end of the response,
+    /// in seconds
+    request_secs: u64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Timeouts {
+        Timeouts {
+            connect_secs: 10,
+            request_secs: 30,
+        }
+    }
+}
+
+impl Timeouts {
+    fn connect(&self) -> Duration {
+        Duration::from_secs(self.connect_secs)
+    }
+
+    fn request(&self) -> Duration {
+        Duration::from_secs(self.request_secs)
+    }
+}
+
+impl RpcConfig {
+    fn chain_timeouts(&self, chain: Chain) -> Timeouts {
+        self.chain_timeouts
+            .get(&chain)
+            .copied()
+            .unwrap_or(self.timeouts)
+    }
+}
+
+/// An Etherscan-style explorer API for a chain.
+#[derive(Deserialize, Serialize, Clone)]
+struct IndexerConfig {
+    /// The API's URL, like `https://api.etherscan.io/api`
+    url: String,
+    #[serde(default)]
+    api_key: Option<String>,
+    /// Explorer APIs often allow only a few requests per second
+    #[serde(default)]
+    rate_limit: Option<RateLimit>,
+    /// Headers, a bearer token or basic auth for the API, in place of any
+    /// under `auth` for its URL
+    #[serde(default)]
+    auth: Option<EndpointAuth>,
+}
+
+/// A chain's RPC URL, or several to fail over between, the primary first.
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum RpcUrls {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl RpcUrls {
+    fn urls(&self) -> &[String] {
+        match self {
+            RpcUrls::One(url) => std::slice::from_ref(url),
+            RpcUrls::Many(urls) => urls,
+        }
+    }
 }
 
 static RPC_CONFIG_PATH: &str = "rpc_config.toml";
+/// Where chains imported from a second provider are kept, beside the local
+/// db directory with this suffix, like `db_secondary`, or under the object
+/// store's prefix.
+static SECONDARY_DB_SUFFIX: &str = "_secondary";
+static SECONDARY_OBJECT_STORE_PREFIX: &str = "secondary";
 static LOG_CONFIG_PATH: &str = "log_config.yml";
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let opts = Opts::parse();
+    let mut opts = Opts::parse();
 
-    init_log(LOG_CONFIG_PATH, &opts.cmd)?;
+    // Resolved before entering a profile's data directory
+    let working_dir = std::env::current_dir()?;
+    opts.config_path = working_dir.join(&opts.config_path);
+    let log_config_path = working_dir.join(LOG_CONFIG_PATH);
+    if let Some(name) = &opts.profile {
+        let profile = profile::load_profile(&opts.config_path, name)?;
+        profile::enter_data_dir(&profile, &opts.config_path)?;
+        if opts.chains.is_empty() {
+            opts.chains = profile.chains;
+        }
+        if opts.object_store.is_none() {
+            opts.object_store = profile.object_store;
+        }
+    }
+
+    init_log(log_config_path, &opts.cmd, opts.log_format)?;
+    if let Some(name) = &opts.profile {
+        info!(
+            "running as profile {} in {}",
+            name,
+            std::env::current_dir()?.display()
+        );
+    }
 
-    let rpc_config = load_rpc_config(RPC_CONFIG_PATH)?;
+    // Before loading the config, which fails at its first problem
+    if let Some(Command::ValidateConfig { probe }) = opts.cmd {
+        let chains = get_chains(&opts.chains);
+        return validate::validate_config(&opts.config_path, &chains, probe, opts.strict).await;
+    }
+
+    let rpc_config = load_rpc_config(&opts.config_path)?;
 
     run(opts, rpc_config).await
 }
 
 async fn run(opts: Opts, rpc_config: RpcConfig) -> Result<()> {
-    let chains = get_chains(opts.chain);
-    let cmd = opts.cmd.unwrap_or(Command::Run);
+    delay::set_retry_policies(rpc_config.retry, rpc_config.chain_retry.clone());
+    let chains = get_chains(&opts.chains);
+    let db_config = db_config(&opts, &rpc_config);
+    let db = make_db(&db_config)?;
+    let cmd = opts.cmd.unwrap_or(Command::Run {
+        once: false,
+        prune: false,
+    });
+
+    if let Command::Migrate = cmd {
+        return migrate::migrate(&db).await;
+    }
+
+    migrate::check_schema_version(&db).await?;
+    db.recover().await?;
+
+    if let Command::DbCheck { fix } = cmd {
+        return check::check(&chains, &db, fix).await;
+    }
+
+    if let Command::Gaps = cmd {
+        return gaps::report_gaps(&chains, &db).await;
+    }
+
+    if let Command::ListChains { json } = cmd {
+        let endpoints = rpc_config
+            .chains
+            .iter()
+            .map(|(chain, rpc_urls)| {
+                let names = rpc_urls
+                    .urls()
+                    .iter()
+                    .enumerate()
+                    .map(|(priority, url)| endpoint_name(url, priority))
+                    .collect();
+                (*chain, names)
+            })
+            .collect();
+        let disabled_chains = &rpc_config.disabled_chains;
+        return list::list_chains(
+            &chains,
+            &db,
+            &endpoints,
+            |chain| !disabled_chains.contains(&chain),
+            json,
+        )
+        .await;
+    }
+
+    if let Command::Status { json } = cmd {
+        // Only the primary endpoint, without checking its version, so each
+        // chain costs one request
+        let mut clients = HashMap::new();
+        for chain in &chains {
+            if rpc_config.disabled_chains.contains(chain) {
+                continue;
+            }
+            let rpc_url = match rpc_config.chains.get(chain).map(RpcUrls::urls) {
+                Some([rpc_url, ..]) => rpc_url,
+                _ => continue,
+            };
+            let auth = rpc_config.auth.get(rpc_url).cloned();
+            let options = ClientOptions::new(&rpc_config, *chain);
+            match build_client(*chain, rpc_url, auth, options) {
+                Ok(Some(client)) => {
+                    clients.insert(*chain, client);
+                }
+                Ok(None) => {}
+                Err(e) => warn!("unable to create client for {}: {:#}", chain, e),
+            }
+        }
+        return status::report_status(&chains, &db, &clients, json).await;
+    }
+
+    if let Command::Calculate {
+        from: Some(from),
+        to: Some(to),
+        ..
+    } = cmd
+    {
+        return calculate::print_range_tps(&chains, &db, from, to, &rpc_config.exclude_failed_txs)
+            .await;
+    }
+
+    if let Command::Export {
+        format,
+        history,
+        output,
+    } = &cmd
+    {
+        return export::export(
+            &chains,
+            &db,
+            &rpc_config.metadata,
+            *format,
+            *history,
+            output.as_deref(),
+        )
+        .await;
+    }
+
+    if let Command::ExportSnapshot { path } = &cmd {
+        return snapshot::export_snapshot(&chains, &db, path).await;
+    }
+
+    if let Command::ImportSnapshot { path } = &cmd {
+        return snapshot::import_snapshot(&chains, &db, path).await;
+    }
+
+    if let Command::Pause { .. } | Command::Resume = cmd {
+        if opts.chains.is_empty() {
+            bail!("pausing and resuming need a --chain");
+        }
+        let (paused, reason) = match cmd {
+            Command::Pause { reason } => (true, reason),
+            _ => (false, None),
+        };
+        for chain in &chains {
+            pause::set_paused(*chain, &db, paused, reason.clone()).await?;
+        }
+        return Ok(());
+    }
+
+    if let Command::RemoveChain { yes } = cmd {
+        let chain = single_chain(&opts.chains, "removing a chain")?;
+        return remove::remove_chain(chain, &db, yes).await;
+    }
+
+    if let Command::Backfill {
+        archive_rpc,
+        since,
+        samples_per_day,
+    } = cmd
+    {
+        let chain = single_chain(&opts.chains, "backfilling history")?;
+        return backfill_history(chain, &rpc_config, &db, archive_rpc, since, samples_per_day)
+            .await;
+    }
+
+    let mut tps_windows = opts.tps_windows;
+    if tps_windows.is_empty() {
+        tps_windows = TpsWindow::all_windows();
+    }
+    tps_windows.sort();
+    tps_windows.dedup();
+
+    if let Command::Calculate {
+        window,
+        print_only: true,
+        ..
+    } = cmd
+    {
+        let exclude_failed_txs = &rpc_config.exclude_failed_txs;
+        return match window {
+            Some(seconds) => {
+                calculate::print_window_tps(&chains, &db, seconds, exclude_failed_txs).await
+            }
+            None => {
+                calculate::print_chain_calcs(
+                    &chains,
+                    &db,
+                    &tps_windows,
+                    exclude_failed_txs,
+                    &rpc_config.max_tps_window,
+                )
+                .await
+            }
+        };
+    }
+
+    if !(opts.tps_smoothing > 0.0 && opts.tps_smoothing <= 1.0) {
+        bail!("--tps-smoothing must be between 0 and 1");
+    }
+
+    if let Command::SeedBigquery { project } = &cmd {
+        let chain = single_chain(&opts.chains, "seeding from bigquery")?;
+        let access_token = std::env::var("BIGQUERY_ACCESS_TOKEN")
+            .context("seeding from bigquery needs an access token in BIGQUERY_ACCESS_TOKEN")?;
+        let longest_window = tps_windows.last().expect("a tps window");
+        let now = u64::try_from(chrono::Utc::now().timestamp())?;
+        let since = now.saturating_sub(longest_window.seconds());
+        shutdown::listen_for_signals()?;
+        let num_blocks =
+            bigquery::seed_from_bigquery(chain, &db, project, &access_token, since).await?;
+        info!("seeded {} blocks for chain {}", num_blocks, chain);
+        return Ok(());
+    }
+
+    let options = CalculateOptions {
+        tps_windows,
+        exclude_failed_txs: rpc_config.exclude_failed_txs.clone(),
+        max_tps_window: rpc_config.max_tps_window.clone(),
+        tps_history_retention_seconds: opts.tps_history_days * 60 * 60 * 24,
+        tps_smoothing: opts.tps_smoothing,
+        usd_prices: rpc_config.usd_prices.clone(),
+        webhooks: Webhooks::new(rpc_config.webhooks.clone())?,
+    };
+
+    // Disabled chains are only calculated, to flag them as stale
+    let (disabled_chains, chains): (Vec<Chain>, Vec<Chain>) = match &cmd {
+        Command::Calculate { .. } => (vec![], chains),
+        _ => chains
+            .into_iter()
+            .partition(|chain| rpc_config.disabled_chains.contains(chain)),
+    };
+    for chain in &disabled_chains {
+        info!("chain {} is disabled; not importing it", chain);
+    }
+
+    let secondary_chains: HashSet<Chain> = chains
+        .iter()
+        .copied()
+        .filter(|chain| rpc_config.secondary_chains.contains_key(chain))
+        .collect();
+    let secondary = if secondary_chains.is_empty() {
+        None
+    } else {
+        let secondary_db = make_secondary_db(&db_config)?;
+        migrate::check_schema_version(&secondary_db).await?;
+        secondary_db.recover().await?;
+        Some(Secondary {
+            db: secondary_db,
+            chains: secondary_chains.clone(),
+            clients: HashMap::new(),
+        })
+    };
+
+    let leases = rpc_config.leases.map(|lease_config| {
+        let holder = opts.instance_id.clone().unwrap_or_else(default_instance_id);
+        let leases = Leases::new(holder, lease_config.duration_secs, lease_config.max_chains);
+        info!("sharing the db with other instances as {}", leases.holder());
+        Arc::new(leases)
+    });
+
+    let mut job_runner = match &cmd {
+        Command::Run { .. }
+        | Command::Import { .. }
+        | Command::Repair { .. }
+        | Command::Verify { .. } => {
+            make_job_runner_with_clients(
+                db,
+                opts.block_cache_size,
+                options,
+                secondary,
+                &chains,
+                &rpc_config,
+                opts.strict,
+            )
+            .await?
+        }
+        Command::Calculate { .. } | Command::Remove | Command::Prune => {
+            make_job_runner(db, opts.block_cache_size, options, secondary)?
+        }
+        Command::Migrate
+        | Command::DbCheck { .. }
+        | Command::Gaps
+        | Command::ListChains { .. }
+        | Command::Status { .. }
+        | Command::Export { .. }
+        | Command::ExportSnapshot { .. }
+        | Command::ImportSnapshot { .. }
+        | Command::Pause { .. }
+        | Command::Resume
+        | Command::Backfill { .. }
+        | Command::SeedBigquery { .. }
+        | Command::RemoveChain { .. }
+        | Command::ValidateConfig { .. } => unreachable!(),
+    };
+
+    // Chains without a working client are skipped
+    let (chains, secondary_chains) = match &cmd {
+        Command::Run { .. }
+        | Command::Import { .. }
+        | Command::Repair { .. }
+        | Command::Verify { .. } => {
+            let chains: Vec<Chain> = chains
+                .into_iter()
+                .filter(|chain| job_runner.client(*chain).is_some())
+                .collect();
+            // Running only disabled chains still flags them as stale
+            let only_disabled = matches!(cmd, Command::Run { .. }) && !disabled_chains.is_empty();
+            if chains.is_empty() && !only_disabled {
+                bail!("no chains with a working RPC to import");
+            }
+            let secondary_chains = match &job_runner.secondary {
+                Some(secondary) => secondary_chains
+                    .into_iter()
+                    .filter(|chain| secondary.clients.contains_key(chain))
+                    .collect(),
+                None => secondary_chains,
+            };
+            (chains, secondary_chains)
+        }
+        _ => (chains, secondary_chains),
+    };
+
+    shutdown::listen_for_signals()?;
+
+    if let Command::Import {
+        from: Some(from),
+        to: Some(to),
+    } = cmd
+    {
+        return job_runner.import_range(&chains, from, to).await;
+    }
 
-    let job_runner = match &cmd {
-        Command::Run | Command::Import => {
-            make_job_runner_with_clients(&chains, &rpc_config).await?
+    if let Command::Repair { from, to } = cmd {
+        return job_runner.repair(&chains, from, to).await;
+    }
+
+    if let Command::Verify { samples } = cmd {
+        return job_runner.verify(&chains, samples).await;
+    }
+
+    if let Command::Prune = cmd {
+        return job_runner.prune(&chains).await;
+    }
+
+    if let Command::Run { once: true, prune } = cmd {
+        return job_runner.run_once(&chains, &disabled_chains, prune).await;
+    }
+
+    job_runner.disabled_chains = RwLock::new(rpc_config.disabled_chains.clone());
+    job_runner.leases = leases.clone();
+    if let Some(leases) = &leases {
+        let leases = leases.clone();
+        let db = job_runner.db.clone();
+        task::spawn(async move { leases.keep_renewed(&db).await });
+    }
+
+    // Continuous imports pick up changes to the config on SIGHUP
+    let (reloader, mut hangups) = match &cmd {
+        Command::Run { .. } | Command::Import { .. } => {
+            let calculated: Vec<Chain> = match &cmd {
+                Command::Run { .. } => chains.iter().chain(&disabled_chains).copied().collect(),
+                _ => vec![],
+            };
+            let reloader = Reloader::new(
+                opts.config_path.clone(),
+                &rpc_config,
+                get_chains(&opts.chains),
+                &chains,
+                &calculated,
+                matches!(cmd, Command::Run { .. }),
+            );
+            (Some(reloader), Some(reload::listen_for_hangups()?))
         }
-        Command::Calculate | Command::Remove => make_job_runner()?,
+        _ => (None, None),
     };
 
-    let init_jobs = init_jobs(&chains, cmd);
+    let init_jobs = init_jobs(&chains, &secondary_chains, &disabled_chains, cmd);
 
     let mut jobs: FuturesUnordered<_> = init_jobs
         .into_iter()
-        .map(|job| job_runner.do_job(job))
+        .map(|job| job_runner.do_job(job).boxed_local())
         .collect();
 
     loop {
-        let new_jobs = jobs.next().await;
+        let new_jobs = tokio::select! {
+            new_jobs = jobs.next() => new_jobs,
+            _ = reload::hangup(&mut hangups) => {
+                if let Some(reloader) = &reloader {
+                    jobs.push(reloader.reload(&job_runner).boxed_local());
+                }
+                continue;
+            }
+        };
         if let Some(new_jobs) = new_jobs {
+            // Jobs finishing after shutdown is requested aren't repeated
+            if shutdown::requested() {
+                continue;
+            }
             for new_job in new_jobs {
-                jobs.push(job_runner.do_job(new_job));
+                jobs.push(job_runner.do_job(new_job).boxed_local());
+            }
+        } else if shutdown::requested() {
+            if let Some(leases) = &leases {
+                leases.release_all(&job_runner.db).await;
+            }
+            // Remote backends may be holding writes
+            if let Err(e) = job_runner.db.flush().await {
+                error!("unable to flush db: {}", e);
             }
+            if let Some(secondary) = &job_runner.secondary {
+                if let Err(e) = secondary.db.flush().await {
+                    error!("unable to flush secondary db: {}", e);
+                }
+            }
+            info!("shut down");
+            break;
         } else {
             error!("no more jobs?!");
             break;
         }
     }
 
+    log::logger().flush();
+
     Ok(())
 }
 
-fn get_chains(maybe_chain: Option<Chain>) -> Vec<Chain> {
-    if let Some(chain) = maybe_chain {
-        vec![chain]
-    } else {
-        Chain::all_chains()
+/// Unique to this process on this machine, and recognizable in the db.
+fn default_instance_id() -> String {
+    let host = std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| fs::read_to_string("/etc/hostname").ok())
+        .map(|host| host.trim().to_string())
+        .filter(|host| !host.is_empty())
+        .unwrap_or_else(|| "localhost".to_string());
+    format!("{}-{}", host, std::process::id())
+}
+
+fn get_chains(chains: &[Chain]) -> Vec<Chain> {
+    if chains.is_empty() {
+        return Chain::all_chains();
     }
+    let mut seen = HashSet::new();
+    chains
+        .iter()
+        .copied()
+        .filter(|chain| seen.insert(*chain))
+        .collect()
 }
 
-fn init_log<P: AsRef<Path>>(path: P, job: &Option<Command>) -> Result<()> {
+/// The one chain given, for commands that work on a single chain.
+fn single_chain(chains: &[Chain], doing: &str) -> Result<Chain> {
+    match get_chains(chains)[..] {
+        [chain] if !chains.is_empty() => Ok(chain),
+        _ => bail!("{} needs exactly one --chain", doing),
+    }
+}
+
+fn init_log<P: AsRef<Path>>(path: P, job: &Option<Command>, format: LogFormat) -> Result<()> {
     let job_name = if let Some(job) = job {
         match job {
-            Command::Run => "all-jobs",
-            Command::Import => "import",
-            Command::Calculate => "calculate",
+            Command::Run { .. } => "all-jobs",
+            Command::Import { .. } => "import",
+            Command::Calculate { .. } => "calculate",
             Command::Remove => "remove",
+            Command::Prune => "prune",
+            Command::Migrate => "migrate",
+            Command::DbCheck { .. } => "db-check",
+            Command::Gaps => "gaps",
+            Command::ListChains { .. } => "list-chains",
+            Command::Status { .. } => "status",
+            Command::Export { .. } => "export",
+            Command::ExportSnapshot { .. } => "export-snapshot",
+            Command::ImportSnapshot { .. } => "import-snapshot",
+            Command::Pause { .. } => "pause",
+            Command::Resume => "resume",
+            Command::Repair { .. } => "repair",
+            Command::Backfill { .. } => "backfill",
+            Command::SeedBigquery { .. } => "seed-bigquery",
+            Command::Verify { .. } => "verify",
+            Command::RemoveChain { .. } => "remove-chain",
+            Command::ValidateConfig { .. } => "validate-config",
         }
     } else {
         "all-jobs"
@@ -121,11 +1015,17 @@ fn init_log<P: AsRef<Path>>(path: P, job: &Option<Command>) -> Result<()> {
     let temp_dir = TempDir::new("temp_dir")?;
     let temp_config_dir = temp_dir.path().join("log_config.yml");
 
-    let mut config_file = std::fs::read_to_string(path)?;
+    let mut config_file = std::fs::read_to_string(&path)
+        .with_context(|| format!("unable to load {}", path.as_ref().display()))?;
     config_file = config_file.replace("job-name", job_name);
+    if let LogFormat::Json = format {
+        config_file = log_format::use_json_encoder(&config_file, job_name)
+            .context("unable to set the log format")?;
+    }
     std::fs::write(&temp_config_dir, config_file)?;
 
-    let log_config = log4rs::config::load_config_file(&temp_config_dir, Default::default())?;
+    let log_config =
+        log4rs::config::load_config_file(&temp_config_dir, log_format::deserializers())?;
 
     log4rs::init_config(log_config)?;
 
@@ -138,96 +1038,534 @@ fn init_log<P: AsRef<Path>>(path: P, job: &Option<Command>) -> Result<()> {
 fn load_rpc_config<P: AsRef<Path>>(path: P) -> Result<RpcConfig> {
     let rpc_config_file = fs::read_to_string(path).context("unable to load RPC configuration")?;
 
-    let rpc_config = toml::from_str::<RpcConfig>(&rpc_config_file)
+    let mut value = toml::from_str::<toml::Value>(&rpc_config_file)
         .context("unable to parse RPC configuration")?;
+    interpolate::interpolate_env(&mut value).context("unable to interpolate RPC configuration")?;
+    let mut rpc_config = value
+        .try_into::<RpcConfig>()
+        .context("unable to parse RPC configuration")?;
+    apply_rpc_overrides(&mut rpc_config);
 
     Ok(rpc_config)
 }
 
-fn init_jobs(chains: &[Chain], cmd: Command) -> Vec<Job> {
+/// The environment variable overriding a chain's RPC URLs, like
+/// `REALTPS_RPC_ETHEREUM`.
+fn rpc_override_var(chain: Chain) -> String {
+    format!(
+        "REALTPS_RPC_{}",
+        chain.to_string().to_uppercase().replace('-', "_")
+    )
+}
+
+/// Replaces the RPC URLs of chains with those from their override
+/// variable, a comma-separated list, the primary first. Chains not in the
+/// file are added.
+fn apply_rpc_overrides(rpc_config: &mut RpcConfig) {
+    for chain in Chain::all_chains()
+        .into_iter()
+        .chain(Chain::testnet_chains())
+    {
+        let var = rpc_override_var(chain);
+        if let Ok(value) = std::env::var(&var) {
+            let urls: Vec<String> = value
+                .split(',')
+                .map(str::trim)
+                .filter(|url| !url.is_empty())
+                .map(String::from)
+                .collect();
+            if urls.is_empty() {
+                continue;
+            }
+            info!("RPC URLs for chain {} overridden by {}", chain, var);
+            rpc_config.chains.insert(chain, RpcUrls::Many(urls));
+        }
+    }
+}
+
+/// Disabled chains are left out of all but calculating.
+fn init_jobs(
+    chains: &[Chain],
+    secondary_chains: &HashSet<Chain>,
+    disabled_chains: &[Chain],
+    cmd: Command,
+) -> Vec<Job> {
     match cmd {
-        Command::Run => {
-            let import_jobs = init_jobs(chains, Command::Import);
-            let calculate_jobs = init_jobs(chains, Command::Calculate);
-            let remove_jobs = init_jobs(chains, Command::Remove);
+        Command::Run { .. } => {
+            let import_jobs = init_jobs(
+                chains,
+                secondary_chains,
+                &[],
+                Command::Import {
+                    from: None,
+                    to: None,
+                },
+            );
+            let calculated_chains: Vec<Chain> =
+                chains.iter().chain(disabled_chains).copied().collect();
+            let calculate_jobs = init_jobs(
+                &calculated_chains,
+                secondary_chains,
+                &[],
+                Command::Calculate {
+                    from: None,
+                    to: None,
+                    window: None,
+                    print_only: false,
+                },
+            );
+            let remove_jobs = init_jobs(chains, secondary_chains, &[], Command::Remove);
+            let gap_jobs = vec![Job::ReportGaps(chains.to_vec())];
+            let backfill_jobs = chains.iter().cloned().map(Job::Backfill);
             import_jobs
                 .into_iter()
                 .chain(calculate_jobs.into_iter())
                 .chain(remove_jobs.into_iter())
+                .chain(gap_jobs.into_iter())
+                .chain(backfill_jobs)
                 .collect()
         }
-        Command::Import => chains.iter().cloned().map(Job::Import).collect(),
-        Command::Calculate => vec![Job::Calculate(chains.to_vec())],
+        Command::Import { .. } => {
+            let secondary_jobs = secondary_chains.iter().cloned().map(Job::ImportSecondary);
+            chains
+                .iter()
+                .cloned()
+                .map(Job::Import)
+                .chain(secondary_jobs)
+                .collect()
+        }
+        Command::Calculate { .. } => chains
+            .iter()
+            .cloned()
+            .map(Job::Calculate)
+            .chain([
+                Job::Aggregate(chains.to_vec()),
+                Job::DailyTps(chains.to_vec()),
+            ])
+            .collect(),
         Command::Remove => vec![Job::Remove(chains.to_vec())],
+        Command::Prune
+        | Command::Migrate
+        | Command::DbCheck { .. }
+        | Command::Gaps
+        | Command::ListChains { .. }
+        | Command::Status { .. }
+        | Command::Export { .. }
+        | Command::ExportSnapshot { .. }
+        | Command::ImportSnapshot { .. }
+        | Command::Pause { .. }
+        | Command::Resume
+        | Command::Repair { .. }
+        | Command::Verify { .. }
+        | Command::Backfill { .. }
+        | Command::SeedBigquery { .. }
+        | Command::RemoveChain { .. }
+        | Command::ValidateConfig { .. } => unreachable!(),
     }
 }
 
-fn make_job_runner() -> Result<JobRunner> {
+/// The db settings from the command line, or else from the config.
+fn db_config(opts: &Opts, rpc_config: &RpcConfig) -> DbConfig {
+    DbConfig {
+        backend: opts.db_backend.or(rpc_config.db.backend),
+        path: opts.db_path.clone().or_else(|| rpc_config.db.path.clone()),
+        object_store: opts
+            .object_store
+            .clone()
+            .or_else(|| rpc_config.db.object_store.clone()),
+    }
+}
+
+/// A db with the same backend as the primary one, beside it.
+fn make_secondary_db(db_config: &DbConfig) -> Result<Arc<dyn Db>> {
+    let secondary_config = DbConfig {
+        backend: db_config.backend,
+        path: Some(format!(
+            "{}{}",
+            db_config.path.as_deref().unwrap_or(JSON_DB_DIR),
+            SECONDARY_DB_SUFFIX
+        )),
+        object_store: db_config.object_store.as_ref().map(|url| {
+            format!(
+                "{}/{}",
+                url.trim_end_matches('/'),
+                SECONDARY_OBJECT_STORE_PREFIX
+            )
+        }),
+    };
+    make_db(&secondary_config)
+}
+
+fn make_job_runner(
+    db: Arc<dyn Db>,
+    block_cache_size: usize,
+    options: CalculateOptions,
+    secondary: Option<Secondary>,
+) -> Result<JobRunner> {
+    let (db, block_cache) = add_block_cache(db, block_cache_size);
+
     Ok(JobRunner {
-        db: Arc::new(JsonDb),
-        clients: HashMap::new(),
+        db,
+        block_cache,
+        clients: RwLock::new(HashMap::new()),
+        options,
+        disabled_chains: RwLock::new(HashSet::new()),
+        secondary,
+        import_concurrency: HashMap::new(),
+        import_memory_budget: None,
+        confirmations: HashMap::new(),
+        new_heads: HashMap::new(),
+        scheduler: Scheduler::new(None),
+        leases: None,
     })
 }
+
 async fn make_job_runner_with_clients(
+    db: Arc<dyn Db>,
+    block_cache_size: usize,
+    options: CalculateOptions,
+    mut secondary: Option<Secondary>,
     chains: &[Chain],
     rpc_config: &RpcConfig,
+    strict: bool,
 ) -> Result<JobRunner> {
-    let clients = make_all_clients(chains, rpc_config).await?;
+    let (db, block_cache) = add_block_cache(db, block_cache_size);
+    let limiters = make_rate_limiters(rpc_config);
+    let clients = make_all_clients(
+        chains,
+        &rpc_config.chains,
+        &rpc_config.indexers,
+        rpc_config,
+        &limiters,
+        &db,
+        strict,
+    )
+    .await?;
+    if let Some(secondary) = &mut secondary {
+        let secondary_chains: Vec<Chain> = secondary.chains.iter().copied().collect();
+        secondary.clients = make_all_clients(
+            &secondary_chains,
+            &rpc_config.secondary_chains,
+            // The secondary provider is the RPC alone
+            &HashMap::new(),
+            rpc_config,
+            &limiters,
+            &secondary.db,
+            strict,
+        )
+        .await?;
+    }
 
     Ok(JobRunner {
-        db: Arc::new(JsonDb),
-        clients,
+        db,
+        block_cache,
+        clients: RwLock::new(
+            clients
+                .into_iter()
+                .map(|(chain, client)| (chain, Arc::from(client)))
+                .collect(),
+        ),
+        options,
+        disabled_chains: RwLock::new(HashSet::new()),
+        secondary,
+        import_concurrency: rpc_config.import_concurrency.clone(),
+        import_memory_budget: rpc_config
+            .import_memory_budget_mb
+            .map(|megabytes| megabytes.saturating_mul(1024 * 1024)),
+        confirmations: rpc_config.confirmations.clone(),
+        new_heads: subscribe_new_heads(chains, &rpc_config.ws_urls),
+        scheduler: Scheduler::new(rpc_config.max_concurrent_imports),
+        leases: None,
     })
 }
 
+/// The rate limiter of each endpoint with a rate limit, shared by the chains
+/// using it.
+fn make_rate_limiters(rpc_config: &RpcConfig) -> HashMap<String, Arc<RateLimiter>> {
+    rpc_config
+        .rate_limits
+        .iter()
+        .map(|(url, rate_limit)| (url.clone(), Arc::new(RateLimiter::new(*rate_limit))))
+        .collect()
+}
+
+/// Subscribes to the new heads of the chains with websocket URLs.
+fn subscribe_new_heads(
+    chains: &[Chain],
+    ws_urls: &HashMap<Chain, String>,
+) -> HashMap<Chain, NewHeads> {
+    let mut new_heads = HashMap::new();
+    for chain in chains {
+        if let Some(ws_url) = ws_urls.get(chain) {
+            match NewHeads::subscribe(*chain, ws_url.clone()) {
+                Ok(chain_new_heads) => {
+                    new_heads.insert(*chain, chain_new_heads);
+                }
+                Err(e) => error!("{}", e),
+            }
+        }
+    }
+    new_heads
+}
+
+fn add_block_cache(
+    db: Arc<dyn Db>,
+    block_cache_size: usize,
+) -> (Arc<dyn Db>, Option<Arc<BlockCacheDb>>) {
+    if let Some(capacity) = NonZeroUsize::new(block_cache_size) {
+        let block_cache = Arc::new(BlockCacheDb::new(db, capacity));
+        (block_cache.clone(), Some(block_cache))
+    } else {
+        (db, None)
+    }
+}
+
 async fn make_all_clients(
     chains: &[Chain],
+    rpc_urls: &HashMap<Chain, RpcUrls>,
+    indexers: &HashMap<Chain, IndexerConfig>,
     rpc_config: &RpcConfig,
+    limiters: &HashMap<String, Arc<RateLimiter>>,
+    db: &Arc<dyn Db>,
+    strict: bool,
 ) -> Result<HashMap<Chain, Box<dyn Client>>> {
     let mut client_futures = FuturesUnordered::new();
 
     for chain in chains {
-        let rpc_url = get_rpc_url(chain, rpc_config).to_string();
-        let client_future = task::spawn(make_client(*chain, rpc_url));
-        let client_future = client_future.map(move |client| (*chain, client));
-        client_futures.push(client_future);
+        let indexer = indexers.get(chain);
+        let chain_rpc_urls = match rpc_urls.get(chain) {
+            Some(chain_rpc_urls) => chain_rpc_urls.urls(),
+            None if indexer.is_some() => &[],
+            None if strict => bail!("no RPC URL configured for chain {}", chain),
+            None => {
+                warn!("no RPC URL configured for chain {}; skipping it", chain);
+                continue;
+            }
+        };
+        let options = ClientOptions::new(rpc_config, *chain);
+        if options.light && !matches!(chain.chain_type(), ChainType::Ethers) {
+            warn!(
+                "light import isn't supported for chain {}; importing whole blocks",
+                chain
+            );
+        }
+        if options.trace_method.is_some() && !matches!(chain.chain_type(), ChainType::Ethers) {
+            warn!(
+                "internal calls can't be counted for chain {}; not tracing it",
+                chain
+            );
+        }
+        for (priority, rpc_url) in chain_rpc_urls.iter().enumerate() {
+            let limiter = limiters.get(rpc_url).cloned();
+            let name = endpoint_name(rpc_url, priority);
+            let auth = rpc_config.auth.get(rpc_url).cloned();
+            let client_future = task::spawn(make_client(*chain, rpc_url.clone(), auth, options));
+            let client_future = client_future
+                .map(move |client| (*chain, priority, name, limiter, false, client))
+                .boxed();
+            client_futures.push(client_future);
+        }
+        // After the RPCs, as explorer APIs are slower and more limited
+        if let Some(indexer) = indexer {
+            let priority = chain_rpc_urls.len();
+            let limiter = indexer
+                .rate_limit
+                .map(|rate_limit| Arc::new(RateLimiter::new(rate_limit)))
+                .or_else(|| limiters.get(&indexer.url).cloned());
+            let name = endpoint_name(&indexer.url, priority);
+            let auth = indexer
+                .auth
+                .clone()
+                .or_else(|| rpc_config.auth.get(&indexer.url).cloned());
+            let timeouts = rpc_config.chain_timeouts(*chain);
+            let client_future =
+                task::spawn(make_indexer_client(*chain, indexer.clone(), auth, timeouts))
+                    .map(move |client| (*chain, priority, name, limiter, true, client))
+                    .boxed();
+            client_futures.push(client_future);
+        }
     }
 
-    let mut clients = HashMap::new();
+    // Each chain's clients by priority
+    let mut chain_clients = HashMap::new();
 
-    while let Some((chain, client)) = client_futures.next().await {
+    while let Some((chain, priority, name, limiter, last_resort, client)) =
+        client_futures.next().await
+    {
         let client = client?;
         match client {
             Ok(Some(client)) => {
-                clients.insert(chain, client);
+                let client: Box<dyn Client> = match limiter {
+                    Some(limiter) => Box::new(RateLimitedClient::new(client, limiter)),
+                    None => client,
+                };
+                chain_clients
+                    .entry(chain)
+                    .or_insert_with(BTreeMap::new)
+                    .insert(
+                        priority,
+                        Endpoint {
+                            name,
+                            client,
+                            last_resort,
+                        },
+                    );
             }
             Ok(None) => { /* pass */ }
+            Err(e) if strict => return Err(e),
             Err(e) => {
                 error!("{}", e);
             }
         }
     }
 
+    // Endpoints that failed to connect are left out
+    let mut clients = HashMap::new();
+    for (chain, endpoint_clients) in chain_clients {
+        let mut endpoints: Vec<Endpoint> = endpoint_clients.into_values().collect();
+        let client = if endpoints.len() == 1 {
+            endpoints.pop().expect("endpoint").client
+        } else {
+            let health = db.load_endpoint_health(chain).await?;
+            Box::new(FailoverClient::new(chain, endpoints, health))
+        };
+        clients.insert(chain, client);
+    }
+
+    for chain in chains {
+        let configured = rpc_urls.contains_key(chain) || indexers.contains_key(chain);
+        if configured && !clients.contains_key(chain) {
+            warn!("no working RPC for chain {}; skipping it", chain);
+        }
+    }
+
     Ok(clients)
 }
 
-async fn make_client(chain: Chain, rpc_url: String) -> Result<Option<Box<dyn Client>>> {
-    info!("creating client for {} at {}", chain, rpc_url);
-
-    let client: Option<Box<dyn Client>> = match chain.chain_type() {
-        ChainType::Algorand => Some(Box::new(AlgorandClient::new(&rpc_url)?)),
-        ChainType::Esplora => Some(Box::new(EsploraClient::new(&rpc_url)?)),
-        ChainType::Elrond => Some(Box::new(ElrondClient::new(&rpc_url)?)),
-        ChainType::Ethers => Some(Box::new(EthersClient::new(chain, &rpc_url)?)),
-        ChainType::Hedera => Some(Box::new(HederaClient::new(&rpc_url)?)),
-        ChainType::Near => Some(Box::new(NearClient::new(&rpc_url)?)),
-        ChainType::Pivx => Some(Box::new(PivxClient::new(&rpc_url)?)),
-        ChainType::Solana => Some(Box::new(SolanaClient::new(&rpc_url)?)),
-        ChainType::Stellar => Some(Box::new(StellarClient::new(&rpc_url)?)),
-        ChainType::Tendermint => Some(Box::new(TendermintClient::new(chain, &rpc_url)?)),
-        ChainType::Substrate => Some(Box::new(SubstrateClient::new(chain, &rpc_url).await?)),
+async fn backfill_history(
+    chain: Chain,
+    rpc_config: &RpcConfig,
+    db: &Arc<dyn Db>,
+    archive_rpc: String,
+    since: NaiveDate,
+    samples_per_day: Option<u64>,
+) -> Result<()> {
+    let limiter = rpc_config
+        .rate_limits
+        .get(&archive_rpc)
+        .map(|rate_limit| Arc::new(RateLimiter::new(*rate_limit)));
+    let options = ClientOptions {
+        solana_block_details: rpc_config.solana_block_details,
+        timeouts: rpc_config.chain_timeouts(chain),
+        ..ClientOptions::default()
+    };
+    let auth = rpc_config.auth.get(&archive_rpc).cloned();
+    let client = make_client(chain, archive_rpc, auth, options)
+        .await?
+        .ok_or_else(|| anyhow!("no client for chain {}", chain))?;
+    let client: Box<dyn Client> = match limiter {
+        Some(limiter) => Box::new(RateLimitedClient::new(client, limiter)),
+        None => client,
     };
 
+    shutdown::listen_for_signals()?;
+    let num_days =
+        history::backfill_history(chain, client.as_ref(), db, since, samples_per_day).await?;
+    info!("backfilled {} days of tps for chain {}", num_days, chain);
+    Ok(())
+}
+
+async fn make_indexer_client(
+    chain: Chain,
+    indexer: IndexerConfig,
+    auth: Option<EndpointAuth>,
+    timeouts: Timeouts,
+) -> Result<Option<Box<dyn Client>>> {
+    if !matches!(chain.chain_type(), ChainType::Ethers) {
+        bail!(
+            "explorer APIs are only supported for EVM chains, not {}",
+            chain
+        );
+    }
+    info!(
+        "creating explorer api client for {} at {}",
+        chain, indexer.url
+    );
+
+    let headers = endpoint_headers(auth.as_ref())?;
+    let http = make_http_client(&headers, timeouts)?;
+    let client = EtherscanClient::new(chain, &indexer.url, indexer.api_key, http)?;
+    retry_if_err(chain, || client.client_version())
+        .await
+        .context(format!("error reaching explorer api for {}", chain))?;
+
+    Ok(Some(Box::new(client)))
+}
+
+/// The host of an RPC URL, to name an endpoint without its API key.
+fn endpoint_name(rpc_url: &str, priority: usize) -> String {
+    endpoint_host(rpc_url).unwrap_or_else(|| format!("endpoint {}", priority))
+}
+
+/// The host of an RPC URL, since the rest of it may hold an API key.
+fn endpoint_host(rpc_url: &str) -> Option<String> {
+    reqwest::Url::parse(rpc_url)
+        .ok()
+        .and_then(|url| url.host_str().map(String::from))
+}
+
+/// How to import a chain, the same for each of its endpoints.
+#[derive(Serialize, Clone, Copy, Default)]
+struct ClientOptions {
+    count_failed_txs: bool,
+    count_token_transfers: bool,
+    light: bool,
+    trace_method: Option<TraceMethod>,
+    solana_block_details: SolanaBlockDetails,
+    timeouts: Timeouts,
+}
+
+impl ClientOptions {
+    fn new(rpc_config: &RpcConfig, chain: Chain) -> ClientOptions {
+        ClientOptions {
+            count_failed_txs: rpc_config.exclude_failed_txs.contains(&chain),
+            count_token_transfers: rpc_config.count_token_transfers.contains(&chain),
+            light: rpc_config.light_import.contains(&chain),
+            trace_method: rpc_config.count_internal_calls.get(&chain).copied(),
+            solana_block_details: rpc_config.solana_block_details,
+            timeouts: rpc_config.chain_timeouts(chain),
+        }
+    }
+}
+
+/// The HTTP client for an endpoint, sending its headers with every request.
+fn make_http_client(headers: &HeaderMap, timeouts: Timeouts) -> Result<reqwest::Client> {
+    Ok(reqwest::Client::builder()
+        .default_headers(headers.clone())
+        .connect_timeout(timeouts.connect())
+        .timeout(timeouts.request())
+        .build()?)
+}
+
+/// The headers to send to an endpoint, none without auth.
+fn endpoint_headers(auth: Option<&EndpointAuth>) -> Result<HeaderMap> {
+    Ok(auth
+        .map(EndpointAuth::headers)
+        .transpose()?
+        .unwrap_or_default())
+}
+
+async fn make_client(
+    chain: Chain,
+    rpc_url: String,
+    auth: Option<EndpointAuth>,
+    options: ClientOptions,
+) -> Result<Option<Box<dyn Client>>> {
+    let host = endpoint_host(&rpc_url).unwrap_or_else(|| "an unparseable url".to_string());
+    info!("creating client for {} at {}", chain, host);
+
+    let client = build_client(chain, &rpc_url, auth, options)?;
+
     if let Some(ref client) = client {
         let version = retry_if_err(chain, || client.client_version())
             .await
@@ -238,10 +1576,49 @@ async fn make_client(chain: Chain, rpc_url: String) -> Result<Option<Box<dyn Cli
     Ok(client)
 }
 
-fn get_rpc_url<'a>(chain: &Chain, rpc_config: &'a RpcConfig) -> &'a str {
-    if let Some(url) = rpc_config.chains.get(chain) {
-        url
-    } else {
-        todo!()
-    }
+/// The client for an endpoint, without checking that it answers.
+fn build_client(
+    chain: Chain,
+    rpc_url: &str,
+    auth: Option<EndpointAuth>,
+    options: ClientOptions,
+) -> Result<Option<Box<dyn Client>>> {
+    let headers =
+        endpoint_headers(auth.as_ref()).with_context(|| format!("bad auth for chain {}", chain))?;
+    let http = make_http_client(&headers, options.timeouts)?;
+    let client: Option<Box<dyn Client>> = match chain.chain_type() {
+        ChainType::Algorand => Some(Box::new(AlgorandClient::new(
+            rpc_url,
+            &headers,
+            options.timeouts.request(),
+        )?)),
+        ChainType::Esplora => Some(Box::new(EsploraClient::new(rpc_url, http)?)),
+        ChainType::Elrond => Some(Box::new(ElrondClient::new(rpc_url, http)?)),
+        ChainType::Ethers => Some(Box::new(EthersClient::new(
+            chain,
+            rpc_url,
+            options.count_failed_txs,
+            options.count_token_transfers,
+            options.light,
+            options.trace_method,
+            http,
+        )?)),
+        ChainType::Hedera => Some(Box::new(HederaClient::new(rpc_url, http)?)),
+        ChainType::Near => Some(Box::new(NearClient::new(
+            rpc_url,
+            options.count_failed_txs,
+            http,
+        )?)),
+        ChainType::Pivx => Some(Box::new(PivxClient::new(rpc_url, http)?)),
+        ChainType::Solana => Some(Box::new(SolanaClient::new(
+            rpc_url,
+            options.solana_block_details,
+            http,
+        )?)),
+        ChainType::Stellar => Some(Box::new(StellarClient::new(rpc_url, http)?)),
+        ChainType::Tendermint => Some(Box::new(TendermintClient::new(chain, rpc_url, http)?)),
+        ChainType::Substrate => Some(Box::new(SubstrateClient::new(chain, rpc_url, http)?)),
+    };
+
+    Ok(client)
 }
@@ -0,0 +1,134 @@
+use anyhow::{bail, Result};
+use log::{info, warn};
+use realtps_common::db::Db;
+use std::sync::Arc;
+
+/// Checks that the stored data is in a format we understand, and records the
+/// current format version in a new db.
+pub async fn check_schema_version(db: &Arc<dyn Db>) -> Result<()> {
+    let version = db.schema_version();
+
+    match db.load_schema_version().await? {
+        None => db.store_schema_version(version).await,
+        Some(stored_version) if stored_version == version => Ok(()),
+        Some(stored_version) if stored_version < version => {
+            warn!(
+                "db schema version {} is older than version {}. run `realtps_import migrate` to upgrade it",
+                stored_version, version
+            );
+            Ok(())
+        }
+        Some(stored_version) => bail!(
+            "db schema version {} is newer than supported version {}",
+            stored_version,
+            version
+        ),
+    }
+}
+
+pub async fn migrate(db: &Arc<dyn Db>) -> Result<()> {
+    let version = db.schema_version();
+    let stored_version = db.load_schema_version().await?;
+
+    if let Some(stored_version) = stored_version {
+        if stored_version > version {
+            bail!(
+                "db schema version {} is newer than supported version {}",
+                stored_version,
+                version
+            );
+        }
+    }
+
+    info!(
+        "migrating db from schema version {:?} to {}",
+        stored_version, version
+    );
+
+    db.migrate().await?;
+
+    info!("migrated db to schema version {}", version);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_migrate {
+    use super::{check_schema_version, migrate};
+    use anyhow::Result;
+    use realtps_common::{
+        chain::Chain,
+        db::{Db, JsonDb, TpsWindow, JSON_DB_SCHEMA_VERSION},
+    };
+    use std::sync::Arc;
+    use tempdir::TempDir;
+
+    fn temp_db(dir: &TempDir) -> Arc<dyn Db> {
+        let db_dir = dir.path().join("db");
+        Arc::new(JsonDb::new(db_dir.to_str().expect("path")))
+    }
+
+    #[tokio::test]
+    async fn stamp_a_new_db() -> Result<()> {
+        let dir = TempDir::new("migrate")?;
+        let db = temp_db(&dir);
+        assert_eq!(db.load_schema_version().await?, None);
+
+        check_schema_version(&db).await?;
+        assert_eq!(
+            db.load_schema_version().await?,
+            Some(JSON_DB_SCHEMA_VERSION)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn migrate_and_stamp_an_unversioned_db() -> Result<()> {
+        let dir = TempDir::new("migrate")?;
+        let db = temp_db(&dir);
+        // Data from before the version file existed
+        db.store_tps(Chain::Ethereum, TpsWindow::Week, 15.0).await?;
+        assert_eq!(db.load_schema_version().await?, Some(1));
+
+        // Only warns
+        check_schema_version(&db).await?;
+        assert_eq!(db.load_schema_version().await?, Some(1));
+
+        migrate(&db).await?;
+        assert_eq!(
+            db.load_schema_version().await?,
+            Some(JSON_DB_SCHEMA_VERSION)
+        );
+        check_schema_version(&db).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn migrate_and_stamp_an_older_db() -> Result<()> {
+        let dir = TempDir::new("migrate")?;
+        let db = temp_db(&dir);
+        db.store_schema_version(JSON_DB_SCHEMA_VERSION - 1).await?;
+
+        migrate(&db).await?;
+        assert_eq!(
+            db.load_schema_version().await?,
+            Some(JSON_DB_SCHEMA_VERSION)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refuse_a_newer_db() -> Result<()> {
+        let dir = TempDir::new("migrate")?;
+        let db = temp_db(&dir);
+        db.store_schema_version(JSON_DB_SCHEMA_VERSION + 1).await?;
+
+        assert!(check_schema_version(&db).await.is_err());
+        assert!(migrate(&db).await.is_err());
+        assert_eq!(
+            db.load_schema_version().await?,
+            Some(JSON_DB_SCHEMA_VERSION + 1)
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,167 @@
+use crate::shutdown;
+use anyhow::{anyhow, bail, Result};
+use futures::stream::StreamExt;
+use jsonrpc_core::types::Params;
+use jsonrpc_core_client::transports::ws;
+use jsonrpc_core_client::RawClient;
+use log::{debug, info, warn};
+use realtps_common::chain::{Chain, ChainType};
+use serde_json::json;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::{watch, Mutex};
+use tokio::time::{self, Duration};
+
+/// How long to wait before reconnecting a dropped subscription. Imports poll
+/// in the meantime.
+const RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// How often to poll a subscribed chain anyway, in case new heads stop
+/// arriving without the subscription dropping.
+const SUBSCRIBED_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Notices of a chain's new blocks, pushed by a websocket subscription, so
+/// the chain is imported as soon as a block is made rather than polled.
+pub struct NewHeads {
+    /// Counts new heads
+    notices: Mutex<watch::Receiver<u64>>,
+    connected: Arc<AtomicBool>,
+}
+
+/// The JSON-RPC methods to subscribe to new heads with.
+struct SubscribeMethods {
+    subscribe: &'static str,
+    params: Params,
+    notification: &'static str,
+    unsubscribe: &'static str,
+}
+
+impl NewHeads {
+    /// Subscribes to the chain's new heads at `ws_url` in the background,
+    /// reconnecting whenever the subscription drops.
+    pub fn subscribe(chain: Chain, ws_url: String) -> Result<NewHeads> {
+        // Checked up front, so unsupported chains are reported at startup
+        subscribe_methods(chain)?;
+
+        let (sender, notices) = watch::channel(0);
+        let connected = Arc::new(AtomicBool::new(false));
+        let task_connected = connected.clone();
+        tokio::spawn(async move {
+            while !shutdown::requested() {
+                let result = follow(chain, &ws_url, &sender, &task_connected).await;
+                task_connected.store(false, Ordering::SeqCst);
+                match result {
+                    Ok(()) => warn!("new heads subscription for chain {} ended", chain),
+                    Err(e) => warn!("new heads subscription for chain {} failed: {}", chain, e),
+                }
+                info!(
+                    "polling chain {} until resubscribing in {:?}",
+                    chain, RECONNECT_DELAY
+                );
+                tokio::select! {
+                    _ = time::sleep(RECONNECT_DELAY) => {}
+                    _ = shutdown::wait() => {}
+                }
+            }
+        });
+
+        Ok(NewHeads {
+            notices: Mutex::new(notices),
+            connected,
+        })
+    }
+
+    /// Waits for a new head since the last call, or while the subscription
+    /// is down, for `poll_delay`.
+    pub async fn wait(&self, poll_delay: impl Future<Output = ()>) {
+        if self.connected.load(Ordering::SeqCst) {
+            let mut notices = self.notices.lock().await;
+            tokio::select! {
+                _ = notices.changed() => {}
+                _ = time::sleep(SUBSCRIBED_POLL_INTERVAL) => {}
+                _ = shutdown::wait() => {}
+            }
+        } else {
+            poll_delay.await;
+        }
+    }
+}
+
+/// Sends a notice for each new head until the subscription ends.
+async fn follow(
+    chain: Chain,
+    ws_url: &str,
+    sender: &watch::Sender<u64>,
+    connected: &AtomicBool,
+) -> Result<()> {
+    let methods = subscribe_methods(chain)?;
+    let client: RawClient = ws::try_connect(ws_url)
+        .map_err(|e| anyhow!("{}", e))?
+        .await
+        .map_err(|e| anyhow!("{}", e))?;
+    let mut heads = client
+        .subscribe(
+            methods.subscribe,
+            methods.params,
+            methods.notification,
+            methods.unsubscribe,
+        )
+        .map_err(|e| anyhow!("{}", e))?;
+    info!("subscribed to new heads for chain {}", chain);
+    connected.store(true, Ordering::SeqCst);
+
+    while let Some(head) = heads.next().await {
+        head.map_err(|e| anyhow!("{}", e))?;
+        debug!("new head for chain {}", chain);
+        sender.send_modify(|num_notices| *num_notices += 1);
+    }
+
+    Ok(())
+}
+
+fn subscribe_methods(chain: Chain) -> Result<SubscribeMethods> {
+    match chain.chain_type() {
+        ChainType::Ethers => Ok(SubscribeMethods {
+            subscribe: "eth_subscribe",
+            params: Params::Array(vec![json!("newHeads")]),
+            notification: "eth_subscription",
+            unsubscribe: "eth_unsubscribe",
+        }),
+        ChainType::Substrate => Ok(SubscribeMethods {
+            subscribe: "chain_subscribeNewHeads",
+            params: Params::None,
+            notification: "chain_newHead",
+            unsubscribe: "chain_unsubscribeNewHeads",
+        }),
+        _ => bail!("chain {} can't subscribe to new heads", chain),
+    }
+}
+
+#[cfg(test)]
+mod test_new_heads {
+    use super::NewHeads;
+    use realtps_common::chain::Chain;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[tokio::test]
+    async fn poll_while_unsubscribed() -> Result<(), anyhow::Error> {
+        // Nothing listens on the discard port
+        let new_heads = NewHeads::subscribe(Chain::Ethereum, "ws://127.0.0.1:9".to_string())?;
+        tokio::task::yield_now().await;
+
+        let polled = AtomicBool::new(false);
+        new_heads
+            .wait(async {
+                polled.store(true, Ordering::SeqCst);
+            })
+            .await;
+        assert!(polled.load(Ordering::SeqCst));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reject_chains_without_subscriptions() {
+        assert!(NewHeads::subscribe(Chain::Solana, "ws://127.0.0.1:9".to_string()).is_err());
+    }
+}
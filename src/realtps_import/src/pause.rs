@@ -0,0 +1,87 @@
+use anyhow::Result;
+use chrono::Utc;
+use log::{error, info};
+use realtps_common::{
+    chain::Chain,
+    db::{Db, ImportPause},
+};
+use std::sync::Arc;
+
+/// Pauses or resumes importing `chain`. Running imports check between
+/// writes, so this takes effect without restarting them.
+pub async fn set_paused(
+    chain: Chain,
+    db: &Arc<dyn Db>,
+    paused: bool,
+    reason: Option<String>,
+) -> Result<()> {
+    let pause = ImportPause {
+        paused,
+        reason,
+        changed: Utc::now(),
+        quarantined: false,
+    };
+    db.store_import_pause(chain, &pause).await?;
+
+    match (paused, &pause.reason) {
+        (true, Some(reason)) => info!("paused imports of chain {}: {}", chain, reason),
+        (true, None) => info!("paused imports of chain {}", chain),
+        (false, _) => info!("resumed imports of chain {}", chain),
+    }
+    Ok(())
+}
+
+/// Pauses importing a chain whose jobs keep failing, so a broken endpoint
+/// doesn't use up RPC quota. It stays paused until resumed.
+pub async fn quarantine(chain: Chain, db: &Arc<dyn Db>, reason: String) -> Result<()> {
+    error!(
+        "quarantined chain {} until resumed: {}. resume it with `realtps_import resume --chain {}`",
+        chain, reason, chain
+    );
+    let pause = ImportPause {
+        paused: true,
+        reason: Some(reason),
+        changed: Utc::now(),
+        quarantined: true,
+    };
+    db.store_import_pause(chain, &pause).await
+}
+
+pub async fn is_paused(chain: Chain, db: &Arc<dyn Db>) -> Result<bool> {
+    let pause = db.load_import_pause(chain).await?;
+    Ok(pause.is_some_and(|pause| pause.paused))
+}
+
+#[cfg(test)]
+mod test_pause {
+    use super::{is_paused, quarantine, set_paused};
+    use realtps_common::{chain::Chain, db::Db, memory_db::MemoryDb};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn pause_and_resume() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        assert!(!is_paused(Chain::Ethereum, &db).await?);
+
+        set_paused(Chain::Ethereum, &db, true, Some("rate limited".to_string())).await?;
+        assert!(is_paused(Chain::Ethereum, &db).await?);
+        assert!(!is_paused(Chain::Polygon, &db).await?);
+
+        set_paused(Chain::Ethereum, &db, false, None).await?;
+        assert!(!is_paused(Chain::Ethereum, &db).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn resume_quarantined() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        quarantine(Chain::Ethereum, &db, "100 failures".to_string()).await?;
+        assert!(is_paused(Chain::Ethereum, &db).await?);
+        let pause = db.load_import_pause(Chain::Ethereum).await?.expect("pause");
+        assert!(pause.quarantined);
+
+        set_paused(Chain::Ethereum, &db, false, None).await?;
+        assert!(!is_paused(Chain::Ethereum, &db).await?);
+        Ok(())
+    }
+}
@@ -0,0 +1,160 @@
+use crate::helpers::*;
+use anyhow::Result;
+use realtps_common::{
+    chain::Chain,
+    db::{Block, Db, Peak, PeakTps},
+};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+const SECONDS_PER_MINUTE: u64 = 60;
+const SECONDS_PER_HOUR: u64 = 60 * 60;
+
+/// Counts the blocks imported since the last update into the chain's peak
+/// TPS, and drops peaks of blocks more than `retention_seconds` older than
+/// `latest_block`.
+///
+/// The first update walks back through all retained blocks.
+pub async fn update_peak_tps(
+    chain: Chain,
+    db: &Arc<dyn Db>,
+    latest_block: &Block,
+    retention_seconds: u64,
+) -> Result<PeakTps> {
+    let mut peak_tps = db.load_peak_tps(chain).await?.unwrap_or_default();
+
+    let min_timestamp = latest_block.timestamp.saturating_sub(retention_seconds);
+    let counted_block_number = peak_tps.newest_block_number;
+
+    if matches!(counted_block_number, Some(number) if number >= latest_block.block_number) {
+        return Ok(peak_tps);
+    }
+
+    // Transactions per minute, of the new blocks, and of the blocks already
+    // counted in the newest minute
+    let mut minutes: BTreeMap<u64, u64> = BTreeMap::new();
+    if let Some((minute_start, num_txs)) = peak_tps.newest_minute {
+        minutes.insert(minute_start, num_txs);
+    }
+
+    let mut block = latest_block.clone();
+
+    loop {
+        let minute_start = block.timestamp - block.timestamp % SECONDS_PER_MINUTE;
+        let minute_txs = minutes.entry(minute_start).or_default();
+        *minute_txs = minute_txs.checked_add(block.num_txs).expect("overflow");
+
+        let prev_block = match block.prev_block_number {
+            Some(prev_block_number) => load_block(chain, db, prev_block_number).await?,
+            None => None,
+        };
+        let prev_block = match prev_block {
+            Some(prev_block) => prev_block,
+            None => break,
+        };
+
+        // Blocks in the same second have no meaningful rate
+        if block.timestamp > prev_block.timestamp {
+            let seconds = block.timestamp - prev_block.timestamp;
+            let tps = block.num_txs as f64 / seconds as f64;
+            let peak = Peak {
+                tps,
+                timestamp: block.timestamp,
+            };
+            peak_tps
+                .hourly
+                .entry(hour_start(block.timestamp))
+                .or_default()
+                .update_block(peak.clone());
+            peak_tps.all_time.update_block(peak);
+        }
+
+        let already_counted =
+            matches!(counted_block_number, Some(number) if prev_block.block_number <= number);
+        if already_counted || prev_block.timestamp < min_timestamp {
+            break;
+        }
+
+        block = prev_block;
+    }
+
+    // The newest minute may get more blocks, so isn't a peak yet
+    let newest_minute = minutes
+        .iter()
+        .next_back()
+        .map(|(start, txs)| (*start, *txs));
+    if let Some((minute_start, _)) = newest_minute {
+        minutes.remove(&minute_start);
+    }
+    peak_tps.newest_minute = newest_minute;
+
+    for (minute_start, num_txs) in minutes {
+        let peak = Peak {
+            tps: num_txs as f64 / SECONDS_PER_MINUTE as f64,
+            timestamp: minute_start,
+        };
+        peak_tps
+            .hourly
+            .entry(hour_start(minute_start))
+            .or_default()
+            .update_minute(peak.clone());
+        peak_tps.all_time.update_minute(peak);
+    }
+
+    peak_tps.newest_block_number = Some(latest_block.block_number);
+    peak_tps
+        .hourly
+        .retain(|hour_start, _| hour_start + SECONDS_PER_HOUR > min_timestamp);
+
+    db.store_peak_tps(chain, &peak_tps).await?;
+
+    Ok(peak_tps)
+}
+
+fn hour_start(timestamp: u64) -> u64 {
+    timestamp - timestamp % SECONDS_PER_HOUR
+}
+
+#[cfg(test)]
+mod test_peaks {
+    use super::update_peak_tps;
+    use crate::test_helpers::{make_block, make_blocks, GENESIS_TIMESTAMP};
+    use realtps_common::{chain::Chain, db::Db, memory_db::MemoryDb};
+    use std::sync::Arc;
+
+    const CHAIN: Chain = Chain::Ethereum;
+    const RETENTION_SECONDS: u64 = 60 * 60 * 24;
+
+    #[tokio::test]
+    async fn track_block_and_minute_peaks() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let mut blocks = make_blocks(CHAIN, 200, 1, 10);
+        blocks[150].num_txs = 100;
+        db.store_blocks(CHAIN, blocks.clone()).await?;
+
+        let peak_tps = update_peak_tps(CHAIN, &db, &blocks[199], RETENTION_SECONDS).await?;
+        let block_peak = peak_tps.all_time.block.expect("block peak");
+        assert_eq!(block_peak.tps, 100.0);
+        assert_eq!(block_peak.timestamp, blocks[150].timestamp);
+        // Blocks 120..180, including block 150
+        let minute_peak = peak_tps.all_time.minute.expect("minute peak");
+        assert_eq!(minute_peak.tps, 690.0 / 60.0);
+        assert_eq!(minute_peak.timestamp, GENESIS_TIMESTAMP + 120);
+        // Blocks 180..200 are still open
+        assert_eq!(peak_tps.newest_minute, Some((GENESIS_TIMESTAMP + 180, 200)));
+
+        // Later blocks complete the open minute
+        let new_blocks: Vec<_> = (200..260).map(|n| make_block(CHAIN, n, 1, 30)).collect();
+        db.store_blocks(CHAIN, new_blocks.clone()).await?;
+
+        let peak_tps = update_peak_tps(CHAIN, &db, &new_blocks[59], RETENTION_SECONDS).await?;
+        assert_eq!(peak_tps.newest_block_number, Some(259));
+        assert_eq!(peak_tps.all_time.block.expect("block peak").tps, 100.0);
+        let minute_peak = peak_tps.all_time.minute.expect("minute peak");
+        assert_eq!(minute_peak.tps, 1400.0 / 60.0);
+        assert_eq!(minute_peak.timestamp, GENESIS_TIMESTAMP + 180);
+        let stored = db.load_peak_tps(CHAIN).await?.expect("peak tps");
+        assert_eq!(stored.newest_block_number, Some(259));
+        Ok(())
+    }
+}
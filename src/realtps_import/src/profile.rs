@@ -0,0 +1,98 @@
+use crate::interpolate;
+use anyhow::{anyhow, Context, Result};
+use realtps_common::chain::Chain;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// One of several deployments sharing a config, like `mainnet` and
+/// `testnet`, selected with `--profile`.
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct Profile {
+    /// Where the profile's `db`, `db_secondary` and `log` directories are
+    /// kept, relative to the config. Created if missing.
+    #[serde(default)]
+    pub data_dir: Option<PathBuf>,
+    /// The chains to work on when no `--chain` is given. All chains if
+    /// empty.
+    #[serde(default)]
+    pub chains: Vec<Chain>,
+    /// An object store to use in place of the data directory's `db`, when
+    /// no `--object-store` is given
+    #[serde(default)]
+    pub object_store: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Profiles {
+    #[serde(default)]
+    profiles: HashMap<String, Profile>,
+}
+
+/// Loads the profile named `name` from the config at `path`, without the
+/// rest of the config, so a profile can be chosen before it is validated.
+pub fn load_profile(path: &Path, name: &str) -> Result<Profile> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("unable to load RPC configuration {}", path.display()))?;
+    let mut value = toml::from_str::<toml::Value>(&text)
+        .with_context(|| format!("unable to parse RPC configuration {}", path.display()))?;
+    interpolate::interpolate_env(&mut value).context("unable to interpolate RPC configuration")?;
+    let mut profiles = value
+        .try_into::<Profiles>()
+        .with_context(|| format!("unable to parse profiles in {}", path.display()))?
+        .profiles;
+    profiles
+        .remove(name)
+        .ok_or_else(|| anyhow!("no profile `{}` in {}", name, path.display()))
+}
+
+/// Makes the profile's data directory, relative to the config at
+/// `config_path`, the working directory, so its data and logs are kept
+/// apart from those of other profiles.
+pub fn enter_data_dir(profile: &Profile, config_path: &Path) -> Result<()> {
+    if let Some(data_dir) = &profile.data_dir {
+        let config_dir = config_path.parent().unwrap_or_else(|| Path::new("."));
+        let data_dir = config_dir.join(data_dir);
+        fs::create_dir_all(&data_dir)
+            .with_context(|| format!("unable to create {}", data_dir.display()))?;
+        std::env::set_current_dir(&data_dir)
+            .with_context(|| format!("unable to change to {}", data_dir.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_profile {
+    use super::load_profile;
+    use realtps_common::chain::Chain;
+    use std::fs;
+    use std::path::Path;
+    use tempdir::TempDir;
+
+    #[test]
+    fn load_named_profile() -> Result<(), anyhow::Error> {
+        let dir = TempDir::new("profile")?;
+        let path = dir.path().join("rpc_config.toml");
+        fs::write(
+            &path,
+            r#"
+            [chains]
+            ethereum = "https://eth.example"
+
+            [profiles.staging]
+            data_dir = "staging"
+            chains = ["ethereum", "polygon"]
+            "#,
+        )?;
+
+        let profile = load_profile(&path, "staging")?;
+        assert_eq!(profile.data_dir.as_deref(), Some(Path::new("staging")));
+        assert_eq!(profile.chains, vec![Chain::Ethereum, Chain::Polygon]);
+        assert_eq!(profile.object_store, None);
+
+        let e = load_profile(&path, "mainnet").expect_err("no such profile");
+        assert!(e.to_string().starts_with("no profile `mainnet`"));
+        Ok(())
+    }
+}
@@ -0,0 +1,165 @@
+use crate::delay;
+use chrono::Utc;
+use log::{info, warn};
+use realtps_common::{
+    chain::Chain,
+    db::{Db, ImportProgress},
+};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How often an import catching up reports its progress.
+const REPORT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Logs and stores an import's progress while it catches up, so it's clear
+/// whether the chain will ever catch up.
+pub struct ProgressReporter {
+    chain: Chain,
+    head_block_number: u64,
+    highest_known_block_number: u64,
+    start: Instant,
+    last_report: Instant,
+    num_fetched: u64,
+}
+
+impl ProgressReporter {
+    pub fn new(
+        chain: Chain,
+        head_block_number: u64,
+        highest_known_block_number: u64,
+    ) -> ProgressReporter {
+        let now = Instant::now();
+        ProgressReporter {
+            chain,
+            head_block_number,
+            highest_known_block_number,
+            start: now,
+            last_report: now,
+            num_fetched: 0,
+        }
+    }
+
+    /// Counts a fetched block, reporting progress every so often. The import
+    /// walks back from the head, so `block_number` is the lowest so far.
+    pub async fn fetched(&mut self, db: &Arc<dyn Db>, block_number: u64) {
+        self.num_fetched += 1;
+        if self.last_report.elapsed() < REPORT_INTERVAL {
+            return;
+        }
+        self.last_report = Instant::now();
+
+        let blocks_behind = block_number.saturating_sub(self.highest_known_block_number);
+        let progress = self.progress(blocks_behind);
+        match progress.seconds_to_catch_up {
+            Some(seconds_to_catch_up) => info!(
+                "chain {} is {} blocks behind block {}, importing {:.1} blocks/s, caught up in about {}",
+                self.chain,
+                blocks_behind,
+                self.head_block_number,
+                progress.blocks_per_second,
+                format_seconds(seconds_to_catch_up)
+            ),
+            None => warn!(
+                "chain {} is {} blocks behind block {}, importing {:.1} blocks/s, slower than the chain makes them",
+                self.chain, blocks_behind, self.head_block_number, progress.blocks_per_second
+            ),
+        }
+        self.store(db, &progress).await;
+    }
+
+    /// Records that the import caught up, if it reported being behind.
+    pub async fn finish(&self, db: &Arc<dyn Db>) {
+        if self.last_report == self.start {
+            return;
+        }
+        info!(
+            "chain {} caught up to block {} in {}",
+            self.chain,
+            self.head_block_number,
+            format_seconds(self.start.elapsed().as_secs())
+        );
+        self.store(db, &self.progress(0)).await;
+    }
+
+    /// Only for reporting, so failing to store it doesn't fail the import.
+    async fn store(&self, db: &Arc<dyn Db>, progress: &ImportProgress) {
+        if let Err(e) = db.store_import_progress(self.chain, progress).await {
+            warn!(
+                "unable to store import progress for chain {}: {}",
+                self.chain, e
+            );
+        }
+    }
+
+    fn progress(&self, blocks_behind: u64) -> ImportProgress {
+        let blocks_per_second = self.num_fetched as f64 / self.start.elapsed().as_secs_f64();
+        ImportProgress {
+            updated_at: u64::try_from(Utc::now().timestamp()).unwrap_or_default(),
+            head_block_number: self.head_block_number,
+            blocks_behind,
+            blocks_per_second,
+            seconds_to_catch_up: seconds_to_catch_up(
+                blocks_behind,
+                blocks_per_second,
+                delay::expected_block_time(self.chain),
+            ),
+        }
+    }
+}
+
+/// How long it takes to fetch `blocks_behind` blocks, while the chain makes
+/// a block every `block_time_ms`. `None` if the chain makes them faster.
+fn seconds_to_catch_up(
+    blocks_behind: u64,
+    blocks_per_second: f64,
+    block_time_ms: u64,
+) -> Option<u64> {
+    if blocks_behind == 0 {
+        return Some(0);
+    }
+    let chain_blocks_per_second = 1000.0 / block_time_ms.max(1) as f64;
+    let gain_per_second = blocks_per_second - chain_blocks_per_second;
+    if gain_per_second <= 0.0 {
+        return None;
+    }
+    Some((blocks_behind as f64 / gain_per_second).ceil() as u64)
+}
+
+fn format_seconds(seconds: u64) -> String {
+    let (days, hours, minutes) = (seconds / 86400, seconds / 3600 % 24, seconds / 60 % 60);
+    if days > 0 {
+        format!("{}d {}h", days, hours)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, minutes)
+    } else if minutes > 0 {
+        format!("{}m", minutes)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+#[cfg(test)]
+mod test_progress {
+    use super::{format_seconds, seconds_to_catch_up};
+
+    #[test]
+    fn catch_up_allowing_for_new_blocks() {
+        // Fetching 3 blocks a second gains 2 a second on a 1s block time
+        assert_eq!(seconds_to_catch_up(100, 3.0, 1000), Some(50));
+        assert_eq!(seconds_to_catch_up(0, 0.0, 1000), Some(0));
+    }
+
+    #[test]
+    fn never_catch_up_when_slower_than_chain() {
+        assert_eq!(seconds_to_catch_up(100, 0.5, 1000), None);
+        assert_eq!(seconds_to_catch_up(100, 1.0, 1000), None);
+    }
+
+    #[test]
+    fn format_durations() {
+        assert_eq!(format_seconds(45), "45s");
+        assert_eq!(format_seconds(125), "2m");
+        assert_eq!(format_seconds(3 * 3600 + 120), "3h 2m");
+        assert_eq!(format_seconds(2 * 86400 + 5 * 3600), "2d 5h");
+    }
+}
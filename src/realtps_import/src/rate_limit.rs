@@ -0,0 +1,142 @@
+use crate::client::{Client, FinalizedBlock};
+use anyhow::Result;
+use async_trait::async_trait;
+use realtps_common::db::Block;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio::time::{self, Duration, Instant};
+
+/// The most requests per second to make to an RPC endpoint.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub requests_per_second: f64,
+    /// How many requests can be made at once after a pause. Defaults to a
+    /// second's worth.
+    #[serde(default)]
+    pub burst: Option<f64>,
+}
+
+/// A token bucket, shared by the clients of one endpoint.
+pub struct RateLimiter {
+    requests_per_second: f64,
+    burst: f64,
+    bucket: Mutex<TokenBucket>,
+}
+
+struct TokenBucket {
+    /// Negative when requests are waiting for tokens
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate_limit: RateLimit) -> RateLimiter {
+        let burst = rate_limit
+            .burst
+            .unwrap_or(rate_limit.requests_per_second)
+            .max(1.0);
+        RateLimiter {
+            requests_per_second: rate_limit.requests_per_second,
+            burst,
+            bucket: Mutex::new(TokenBucket {
+                tokens: burst,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Waits until `num_requests` more requests can be made.
+    ///
+    /// Tokens are taken straight away, going into debt if there aren't
+    /// enough, so concurrent callers queue in turn rather than racing for
+    /// each refill.
+    pub async fn acquire(&self, num_requests: u32) {
+        let wait_seconds = {
+            let mut bucket = self.bucket.lock().await;
+            let now = Instant::now();
+            let refilled =
+                now.duration_since(bucket.last_refill).as_secs_f64() * self.requests_per_second;
+            bucket.tokens = (bucket.tokens + refilled).min(self.burst);
+            bucket.last_refill = now;
+            bucket.tokens -= f64::from(num_requests);
+            -bucket.tokens / self.requests_per_second
+        };
+
+        if wait_seconds > 0.0 {
+            time::sleep(Duration::from_secs_f64(wait_seconds)).await;
+        }
+    }
+}
+
+/// Makes a client wait for its endpoint's rate limit before each request.
+pub struct RateLimitedClient {
+    client: Box<dyn Client>,
+    limiter: Arc<RateLimiter>,
+}
+
+impl RateLimitedClient {
+    pub fn new(client: Box<dyn Client>, limiter: Arc<RateLimiter>) -> RateLimitedClient {
+        RateLimitedClient { client, limiter }
+    }
+}
+
+#[async_trait]
+impl Client for RateLimitedClient {
+    async fn client_version(&self) -> Result<String> {
+        self.limiter.acquire(1).await;
+        self.client.client_version().await
+    }
+
+    async fn get_latest_block_number(&self) -> Result<u64> {
+        self.limiter.acquire(1).await;
+        self.client.get_latest_block_number().await
+    }
+
+    async fn get_block(&self, block_number: u64) -> Result<Option<Block>> {
+        self.limiter.acquire(1).await;
+        self.client.get_block(block_number).await
+    }
+
+    /// Providers count each block of a batch request as a request.
+    async fn get_blocks(&self, block_numbers: &[u64]) -> Result<Vec<Block>> {
+        self.limiter
+            .acquire(u32::try_from(block_numbers.len())?)
+            .await;
+        self.client.get_blocks(block_numbers).await
+    }
+
+    async fn get_finalized_block(&self) -> Result<Option<FinalizedBlock>> {
+        self.limiter.acquire(1).await;
+        self.client.get_finalized_block().await
+    }
+
+    async fn get_native_tps(&self) -> Result<Option<f64>> {
+        self.limiter.acquire(1).await;
+        self.client.get_native_tps().await
+    }
+}
+
+#[cfg(test)]
+mod test_rate_limit {
+    use super::{RateLimit, RateLimiter};
+    use tokio::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn limit_requests_after_burst() {
+        let limiter = RateLimiter::new(RateLimit {
+            requests_per_second: 100.0,
+            burst: Some(5.0),
+        });
+
+        let start = Instant::now();
+        for _ in 0..5 {
+            limiter.acquire(1).await;
+        }
+        assert!(start.elapsed() < Duration::from_millis(50));
+
+        // Ten more at 100 per second
+        limiter.acquire(10).await;
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+}
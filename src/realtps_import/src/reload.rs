@@ -0,0 +1,275 @@
+use crate::client::Client;
+use crate::delay;
+use crate::jobs::{Job, JobRunner};
+use crate::{
+    load_rpc_config, make_all_clients, make_rate_limiters, ClientOptions, RpcConfig, RpcUrls,
+};
+use anyhow::Result;
+use futures::future;
+use log::{error, info, warn};
+use realtps_common::chain::Chain;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::Mutex;
+
+/// Applies changes to `rpc_config.toml` while importing, on SIGHUP. Chains
+/// whose endpoints changed get new clients, and chains enabled or configured
+/// since get clients and start importing. Jobs in progress carry on, and
+/// those of chains that were disabled or lost their endpoints wait.
+pub struct Reloader {
+    config_path: PathBuf,
+    /// The chains that may be imported, enabled or not
+    chains: Vec<Chain>,
+    /// Whether chains that start importing are also calculated and
+    /// backfilled, as when running all jobs
+    all_jobs: bool,
+    state: Mutex<ReloadState>,
+}
+
+struct ReloadState {
+    /// The endpoint config each chain's client was made from
+    endpoints: HashMap<Chain, String>,
+    /// Chains with import jobs, which wait rather than end while the chain
+    /// is disabled
+    imported: HashSet<Chain>,
+    calculated: HashSet<Chain>,
+}
+
+impl Reloader {
+    /// `imported` are the chains with clients, and `calculated` the chains
+    /// with calculate jobs.
+    pub fn new(
+        config_path: PathBuf,
+        rpc_config: &RpcConfig,
+        chains: Vec<Chain>,
+        imported: &[Chain],
+        calculated: &[Chain],
+        all_jobs: bool,
+    ) -> Reloader {
+        let endpoints = imported
+            .iter()
+            .filter_map(|chain| Some((*chain, endpoint_config(rpc_config, *chain)?)))
+            .collect();
+        Reloader {
+            config_path,
+            chains,
+            all_jobs,
+            state: Mutex::new(ReloadState {
+                endpoints,
+                imported: imported.iter().copied().collect(),
+                calculated: calculated.iter().copied().collect(),
+            }),
+        }
+    }
+
+    /// Reloads the config, returning the jobs of chains that start importing.
+    /// A config that fails to load is logged and ignored.
+    pub async fn reload(&self, job_runner: &JobRunner) -> Vec<Job> {
+        // One at a time, if signalled again while reloading
+        let mut state = self.state.lock().await;
+
+        let path = self.config_path.display();
+        info!("reloading {}", path);
+        let rpc_config = match load_rpc_config(&self.config_path) {
+            Ok(rpc_config) => rpc_config,
+            Err(e) => {
+                error!("not reloading {}: {:#}", path, e);
+                return vec![];
+            }
+        };
+        delay::set_retry_policies(rpc_config.retry, rpc_config.chain_retry.clone());
+
+        let disabled_chains = &rpc_config.disabled_chains;
+        for chain in &self.chains {
+            let was_disabled = job_runner
+                .disabled_chains
+                .read()
+                .expect("lock")
+                .contains(chain);
+            match (was_disabled, disabled_chains.contains(chain)) {
+                (false, true) => info!("chain {} is disabled; not importing it", chain),
+                (true, false) => info!("chain {} is enabled", chain),
+                _ => {}
+            }
+        }
+        *job_runner.disabled_chains.write().expect("lock") = disabled_chains.clone();
+
+        // Chains without endpoints any more keep their client until
+        // configured again, but stop importing
+        let mut changed = vec![];
+        for chain in &self.chains {
+            match endpoint_config(&rpc_config, *chain) {
+                None => {
+                    if state.endpoints.remove(chain).is_some() {
+                        warn!(
+                            "no RPC URL configured for chain {}; not importing it",
+                            chain
+                        );
+                        job_runner.clients.write().expect("lock").remove(chain);
+                    }
+                }
+                Some(_) if disabled_chains.contains(chain) => {}
+                Some(config) if state.endpoints.get(chain) != Some(&config) => {
+                    changed.push(*chain);
+                }
+                Some(_) => {}
+            }
+        }
+
+        if !changed.is_empty() {
+            let names: Vec<String> = changed.iter().map(Chain::to_string).collect();
+            info!("making new clients for {}", names.join(", "));
+            match make_reloaded_clients(&changed, &rpc_config, job_runner).await {
+                Ok(clients) => {
+                    for chain in &changed {
+                        match clients.get(chain) {
+                            Some(client) => {
+                                job_runner
+                                    .clients
+                                    .write()
+                                    .expect("lock")
+                                    .insert(*chain, client.clone());
+                                if let Some(config) = endpoint_config(&rpc_config, *chain) {
+                                    state.endpoints.insert(*chain, config);
+                                }
+                            }
+                            // Retried on the next reload
+                            None if job_runner.client(*chain).is_some() => {
+                                warn!("keeping the previous client for chain {}", chain);
+                            }
+                            None => {}
+                        }
+                    }
+                }
+                Err(e) => error!("unable to make new clients: {:#}", e),
+            }
+        }
+
+        let mut new_jobs = vec![];
+        for chain in &self.chains {
+            let chain = *chain;
+            let enabled = !disabled_chains.contains(&chain);
+            let has_client = job_runner.client(chain).is_some();
+            if enabled && has_client && state.imported.insert(chain) {
+                info!("starting to import chain {}", chain);
+                new_jobs.push(Job::Import(chain));
+                if self.all_jobs {
+                    new_jobs.push(Job::Backfill(chain));
+                }
+            }
+            let calculable = has_client || !enabled;
+            if self.all_jobs && calculable && state.calculated.insert(chain) {
+                new_jobs.push(Job::Calculate(chain));
+            }
+        }
+
+        info!("reloaded {}", path);
+        new_jobs
+    }
+}
+
+async fn make_reloaded_clients(
+    chains: &[Chain],
+    rpc_config: &RpcConfig,
+    job_runner: &JobRunner,
+) -> Result<HashMap<Chain, Arc<dyn Client>>> {
+    let limiters = make_rate_limiters(rpc_config);
+    let clients = make_all_clients(
+        chains,
+        &rpc_config.chains,
+        &rpc_config.indexers,
+        rpc_config,
+        &limiters,
+        &job_runner.db,
+        false,
+    )
+    .await?;
+    Ok(clients
+        .into_iter()
+        .map(|(chain, client)| (chain, Arc::from(client)))
+        .collect())
+}
+
+/// Everything a chain's client is made from, to tell whether the config
+/// changed. `None` if the chain has no endpoints.
+fn endpoint_config(rpc_config: &RpcConfig, chain: Chain) -> Option<String> {
+    let rpc_urls = rpc_config
+        .chains
+        .get(&chain)
+        .map(RpcUrls::urls)
+        .unwrap_or_default();
+    let indexer = rpc_config.indexers.get(&chain);
+    if rpc_urls.is_empty() && indexer.is_none() {
+        return None;
+    }
+
+    let urls = rpc_urls.iter().chain(indexer.map(|indexer| &indexer.url));
+    let endpoints: Vec<_> = urls
+        .map(|url| {
+            (
+                url,
+                rpc_config.auth.get(url),
+                rpc_config.rate_limits.get(url),
+            )
+        })
+        .collect();
+    let config = (endpoints, indexer, ClientOptions::new(rpc_config, chain));
+    serde_json::to_string(&config).ok()
+}
+
+/// Listens for SIGHUP, which otherwise ends the process.
+pub fn listen_for_hangups() -> Result<Signal> {
+    Ok(signal(SignalKind::hangup())?)
+}
+
+/// Waits for the next SIGHUP, forever if not listening for them.
+pub async fn hangup(hangups: &mut Option<Signal>) {
+    match hangups {
+        Some(hangups) => {
+            hangups.recv().await;
+        }
+        None => future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod test_reload {
+    use super::endpoint_config;
+    use crate::RpcConfig;
+    use realtps_common::chain::Chain;
+
+    fn parse(text: &str) -> RpcConfig {
+        toml::from_str(text).expect("config")
+    }
+
+    #[test]
+    fn reload_only_changed_endpoints() {
+        let old = parse(
+            r#"
+            [chains]
+            ethereum = "https://eth.example"
+            solana = "https://sol.example"
+            "#,
+        );
+        let new = parse(
+            r#"
+            [chains]
+            ethereum = "https://eth.example"
+            solana = "https://sol.example"
+
+            [rate_limits]
+            "https://sol.example" = { requests_per_second = 5.0 }
+            "#,
+        );
+        let unchanged = endpoint_config(&old, Chain::Ethereum);
+        assert!(unchanged.is_some());
+        assert_eq!(unchanged, endpoint_config(&new, Chain::Ethereum));
+        assert_ne!(
+            endpoint_config(&old, Chain::Solana),
+            endpoint_config(&new, Chain::Solana)
+        );
+        assert_eq!(endpoint_config(&new, Chain::Polygon), None);
+    }
+}
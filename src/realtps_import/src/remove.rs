@@ -1,63 +1,56 @@
-#![allow(unused)]
-
-use crate::client::Client;
-use crate::delay;
 use crate::helpers::*;
-use anyhow::{anyhow, Result};
-use log::{debug, info, warn};
-use realtps_common::{
-    chain::Chain,
-    db::{Block, Db},
-};
+use anyhow::{anyhow, bail, Result};
+use log::info;
+use realtps_common::{chain::Chain, db::Db};
 use std::sync::Arc;
 
-pub async fn remove_old_data_for_chain(chain: Chain, db: Arc<dyn Db>) -> Result<()> {
-    let highest_block_number = load_highest_known_block_number(chain, &db).await?;
-    let highest_block_number =
-        highest_block_number.ok_or_else(|| anyhow!("no data for chain {}", chain))?;
-
-    let load_block = |number| load_block(chain, &db, number);
-
-    let latest_timestamp = load_block(highest_block_number)
-        .await?
-        .expect("first block")
-        .timestamp;
-
-    let seconds_per_week = 60 * 60 * 24 * 7;
-    let min_timestamp = latest_timestamp
-        .checked_sub(seconds_per_week)
-        .expect("underflow");
-
-    let mut current_block = load_block(highest_block_number).await?.expect("firt_block");
-    let mut to_remove_blocks = vec![];
-    let mut is_old_block = false;
-
-    loop {
-        if is_old_block {
-            to_remove_blocks.push(current_block.block_number);
+/// Removes the blocks older than `retention_seconds` before the newest block,
+/// returning how many were removed.
+///
+/// Timestamps rise with block numbers, so the oldest block to keep is found by
+/// binary search over the stored blocks, and every stored block before it is
+/// removed, including those behind gaps.
+///
+/// Some chains stamp the odd block before the block preceding it. If the
+/// search ends at such a block it may be inside the window, so it is kept and
+/// the search repeats over the blocks before it.
+pub async fn remove_old_data_for_chain(
+    chain: Chain,
+    db: Arc<dyn Db>,
+    retention_seconds: u64,
+) -> Result<usize> {
+    let highest_block_number = match load_highest_known_block_number(chain, &db).await? {
+        Some(block_number) => block_number,
+        None => {
+            info!("no data in chain {}", chain);
+            return Ok(0);
         }
-
-        let prev_block_number = current_block.prev_block_number;
-
-        if prev_block_number.is_none() {
-            break;
+    };
+    let latest_timestamp = load_timestamp(chain, &db, highest_block_number).await?;
+    let min_timestamp = latest_timestamp.saturating_sub(retention_seconds);
+
+    let block_numbers = db.load_block_numbers(chain).await?;
+    let mut end = block_numbers.len();
+    let low = loop {
+        let (mut low, mut high) = (0, end);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if load_timestamp(chain, &db, block_numbers[mid]).await? < min_timestamp {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
         }
-        let prev_block_number = prev_block_number.unwrap();
-
-        let prev_block = load_block(prev_block_number).await?;
-
-        if prev_block.is_none() {
-            break;
+        if low < 2 {
+            break low;
         }
-
-        let prev_block = prev_block.unwrap();
-
-        if !is_old_block && prev_block.timestamp < min_timestamp {
-            is_old_block = true;
+        let oldest_removed = load_timestamp(chain, &db, block_numbers[low - 1]).await?;
+        if oldest_removed >= load_timestamp(chain, &db, block_numbers[low - 2]).await? {
+            break low;
         }
-
-        current_block = prev_block;
-    }
+        end = low - 1;
+    };
+    let to_remove_blocks = block_numbers[..low].to_vec();
 
     if !to_remove_blocks.is_empty() {
         info!(
@@ -66,12 +59,144 @@ pub async fn remove_old_data_for_chain(chain: Chain, db: Arc<dyn Db>) -> Result<
             chain
         );
 
-        to_remove_blocks.reverse();
-
         remove_blocks(chain, &db, to_remove_blocks).await?;
     } else {
         info!("no old data in chain {}", chain);
     }
 
+    Ok(low)
+}
+
+async fn load_timestamp(chain: Chain, db: &Arc<dyn Db>, block_number: u64) -> Result<u64> {
+    Ok(load_block(chain, db, block_number)
+        .await?
+        .ok_or_else(|| anyhow!("no block {} for chain {}", block_number, chain))?
+        .timestamp)
+}
+
+/// Removes everything stored for `chain`, for a clean re-import after it
+/// was delisted or its data was corrupted. Unless `confirmed`, only reports
+/// what would be removed, and fails.
+pub async fn remove_chain(chain: Chain, db: &Arc<dyn Db>, confirmed: bool) -> Result<()> {
+    let num_blocks = db.load_block_numbers(chain).await?.len();
+    if !confirmed {
+        bail!(
+            "not removing chain {} and its {} blocks without --yes",
+            chain,
+            num_blocks
+        );
+    }
+
+    db.remove_chain(chain).await?;
+    info!("removed chain {} and its {} blocks", chain, num_blocks);
     Ok(())
 }
+
+#[cfg(test)]
+mod test_remove {
+    use super::{remove_chain, remove_old_data_for_chain};
+    use crate::test_helpers::{make_blocks, GENESIS_TIMESTAMP};
+    use realtps_common::{
+        chain::Chain,
+        db::{Db, TpsWindow},
+        memory_db::MemoryDb,
+    };
+    use std::sync::Arc;
+
+    const CHAIN: Chain = Chain::Ethereum;
+
+    #[tokio::test]
+    async fn remove_blocks_older_than_a_week() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        // A block every 10 minutes, so 1008 blocks a week
+        db.store_blocks(CHAIN, make_blocks(CHAIN, 2000, 600, 60))
+            .await?;
+        db.store_highest_block_number(CHAIN, 1999).await?;
+
+        remove_old_data_for_chain(CHAIN, db.clone(), TpsWindow::Week.seconds()).await?;
+
+        assert_eq!(
+            db.load_block_numbers(CHAIN).await?,
+            (991..2000).collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keep_recent_blocks() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        db.store_blocks(CHAIN, make_blocks(CHAIN, 100, 600, 60))
+            .await?;
+        db.store_highest_block_number(CHAIN, 99).await?;
+
+        remove_old_data_for_chain(CHAIN, db.clone(), TpsWindow::Week.seconds()).await?;
+
+        assert_eq!(db.load_block_numbers(CHAIN).await?.len(), 100);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn remove_old_blocks_behind_gaps() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let blocks = make_blocks(CHAIN, 2000, 600, 60)
+            .into_iter()
+            .filter(|block| !(500..510).contains(&block.block_number))
+            .filter(|block| !(1500..1510).contains(&block.block_number))
+            .collect();
+        db.store_blocks(CHAIN, blocks).await?;
+        db.store_highest_block_number(CHAIN, 1999).await?;
+
+        let num_removed =
+            remove_old_data_for_chain(CHAIN, db.clone(), TpsWindow::Week.seconds()).await?;
+
+        assert_eq!(num_removed, 981);
+        let block_numbers = db.load_block_numbers(CHAIN).await?;
+        assert_eq!(block_numbers.first(), Some(&991));
+        assert_eq!(block_numbers.len(), 999);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keep_early_stamped_blocks_in_the_window() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let mut blocks = make_blocks(CHAIN, 2000, 600, 60);
+        // Where the search first looks, then where it would end
+        blocks[1000].timestamp = GENESIS_TIMESTAMP;
+        blocks[1500].timestamp = GENESIS_TIMESTAMP;
+        db.store_blocks(CHAIN, blocks).await?;
+        db.store_highest_block_number(CHAIN, 1999).await?;
+
+        remove_old_data_for_chain(CHAIN, db.clone(), TpsWindow::Week.seconds()).await?;
+
+        assert_eq!(
+            db.load_block_numbers(CHAIN).await?,
+            (991..2000).collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn remove_only_the_chain_given() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        for chain in [CHAIN, Chain::Polygon] {
+            db.store_blocks(chain, make_blocks(chain, 10, 600, 60))
+                .await?;
+            db.store_highest_block_number(chain, 9).await?;
+            db.store_tps(chain, TpsWindow::Week, 1.0).await?;
+        }
+
+        assert!(remove_chain(CHAIN, &db, false).await.is_err());
+        assert_eq!(db.load_block_numbers(CHAIN).await?.len(), 10);
+
+        remove_chain(CHAIN, &db, true).await?;
+        assert!(db.load_block_numbers(CHAIN).await?.is_empty());
+        assert_eq!(db.load_highest_block_number(CHAIN).await?, None);
+        assert_eq!(db.load_tps(CHAIN, TpsWindow::Week).await?, None);
+        assert_eq!(db.load_block_numbers(Chain::Polygon).await?.len(), 10);
+        assert_eq!(
+            db.load_tps(Chain::Polygon, TpsWindow::Week).await?,
+            Some(1.0)
+        );
+        Ok(())
+    }
+}
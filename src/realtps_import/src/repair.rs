@@ -0,0 +1,161 @@
+use crate::client::Client;
+use crate::helpers::*;
+use crate::pace_setter::PaceSetter;
+use crate::shutdown;
+use anyhow::{bail, Result};
+use log::{debug, info, warn};
+use realtps_common::{
+    chain::Chain,
+    db::{Block, Db, DbWrite},
+};
+use std::sync::Arc;
+
+/// How many stored blocks to fetch again before writing the changed ones, so
+/// an interrupted repair keeps what it fixed.
+const REPAIR_BATCH_SIZE: usize = 100;
+
+/// How many blocks a repair checked, and how many of those changed.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RepairStats {
+    pub num_checked: u64,
+    pub num_changed: u64,
+}
+
+/// Fetches the stored blocks from `from` to `to`, inclusive, again, and
+/// overwrites those that differ, for after a provider turns out to have
+/// served bad data. The changed blocks are recounted in the timestamp index;
+/// the next calculation picks them up.
+///
+/// Blocks that aren't stored are left for `import --from --to`.
+pub async fn repair(
+    chain: Chain,
+    client: &dyn Client,
+    db: &Arc<dyn Db>,
+    from: u64,
+    to: u64,
+) -> Result<RepairStats> {
+    if from > to {
+        bail!("range starts at block {} after it ends at {}", from, to);
+    }
+
+    let block_numbers: Vec<u64> = db
+        .load_block_numbers(chain)
+        .await?
+        .into_iter()
+        .filter(|block_number| (from..=to).contains(block_number))
+        .collect();
+    info!(
+        "repairing {} stored blocks from {} to {} for chain {}",
+        block_numbers.len(),
+        from,
+        to,
+        chain
+    );
+
+    let mut pace = PaceSetter::new(chain);
+    let mut stats = RepairStats::default();
+    for batch in block_numbers.chunks(REPAIR_BATCH_SIZE) {
+        if shutdown::requested() {
+            warn!(
+                "repair of chain {} stopped after {} of {} blocks",
+                chain,
+                stats.num_checked,
+                block_numbers.len()
+            );
+            break;
+        }
+
+        let mut changed_blocks = vec![];
+        for block_number in batch {
+            let stored_block = match load_block(chain, db, *block_number).await? {
+                Some(stored_block) => stored_block,
+                None => continue,
+            };
+            let block = fetch_block(chain, client, *block_number).await?;
+            stats.num_checked += 1;
+            if block != stored_block {
+                debug!("block {} for chain {} changed", block_number, chain);
+                changed_blocks.push((stored_block, block));
+            }
+            pace.wait().await;
+        }
+
+        stats.num_changed += u64::try_from(changed_blocks.len())?;
+        store_repaired_blocks(chain, db, changed_blocks).await?;
+    }
+
+    Ok(stats)
+}
+
+/// Overwrites the stored blocks with the repaired ones, swapping their
+/// counts in the timestamp index.
+async fn store_repaired_blocks(
+    chain: Chain,
+    db: &Arc<dyn Db>,
+    changed_blocks: Vec<(Block, Block)>,
+) -> Result<()> {
+    if changed_blocks.is_empty() {
+        return Ok(());
+    }
+
+    let mut timestamp_index = db.load_timestamp_index(chain).await?.unwrap_or_default();
+    for (old_block, new_block) in &changed_blocks {
+        timestamp_index.replace(old_block, new_block);
+    }
+
+    let mut writes: Vec<DbWrite> = changed_blocks
+        .into_iter()
+        .map(|(_, block)| DbWrite::Block(Box::new(block)))
+        .collect();
+    writes.push(DbWrite::TimestampIndex(timestamp_index));
+
+    db.store_atomically(chain, writes).await
+}
+
+#[cfg(test)]
+mod test_repair {
+    use super::{repair, RepairStats};
+    use crate::backfill::import_range;
+    use crate::test_helpers::{make_block, make_blocks, MockClient};
+    use realtps_common::{chain::Chain, db::Db, memory_db::MemoryDb};
+    use std::sync::Arc;
+
+    // Solana has no block pace, so tests don't wait between blocks.
+    const CHAIN: Chain = Chain::Solana;
+
+    #[tokio::test]
+    async fn overwrite_changed_blocks() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let client = MockClient::new(make_blocks(CHAIN, 51, 1, 5));
+        import_range(CHAIN, &client, &db, 10, 30).await?;
+
+        // The provider served blocks 15 and 16 with the wrong counts
+        client.replace_block(make_block(CHAIN, 15, 1, 9));
+        client.replace_block(make_block(CHAIN, 16, 1, 9));
+        let stats = repair(CHAIN, &client, &db, 0, 20).await?;
+
+        assert_eq!(
+            stats,
+            RepairStats {
+                num_checked: 11,
+                num_changed: 2,
+            }
+        );
+        assert_eq!(db.load_block(CHAIN, 15).await?.expect("block").num_txs, 9);
+        assert_eq!(db.load_block(CHAIN, 14).await?.expect("block").num_txs, 5);
+        // Nothing missing is fetched
+        assert_eq!(
+            db.load_block_numbers(CHAIN).await?,
+            (10..=30).collect::<Vec<_>>()
+        );
+
+        let timestamp_index = db.load_timestamp_index(CHAIN).await?.expect("index");
+        let indexed_txs: u64 = timestamp_index
+            .buckets
+            .values()
+            .map(|bucket| bucket.num_txs)
+            .sum();
+        assert_eq!(indexed_txs, 21 * 5 + 2 * 4);
+        Ok(())
+    }
+}
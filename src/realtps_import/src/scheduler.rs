@@ -0,0 +1,179 @@
+use crate::delay;
+use crate::jobs::Job;
+use chrono::Utc;
+use log::debug;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::sync::oneshot;
+
+/// Shares a limited number of import slots between chains.
+///
+/// A freed slot goes to the waiting import with the most blocks to catch up
+/// on, estimated from how old its newest stored block is and the chain's
+/// block time. So chains with short block times get slots more often, and
+/// chains that are caught up wait for those that aren't.
+pub struct Scheduler {
+    /// Unlimited if `None`
+    max_imports: Option<usize>,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    running: usize,
+    waiting: Vec<Waiter>,
+    /// The timestamp of the newest stored block for each import
+    heads: HashMap<Job, u64>,
+}
+
+struct Waiter {
+    job: Job,
+    start: oneshot::Sender<()>,
+}
+
+/// A running import's slot, freed when dropped.
+pub struct Slot<'scheduler> {
+    scheduler: &'scheduler Scheduler,
+}
+
+impl Scheduler {
+    pub fn new(max_imports: Option<usize>) -> Scheduler {
+        Scheduler {
+            max_imports,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Waits for a slot to run an import `job` in.
+    pub async fn acquire(&self, job: &Job) -> Slot<'_> {
+        let started = {
+            let mut state = self.state.lock().expect("lock");
+            if self
+                .max_imports
+                .is_none_or(|max_imports| state.running < max_imports)
+            {
+                state.running += 1;
+                None
+            } else {
+                let (start, started) = oneshot::channel();
+                state.waiting.push(Waiter {
+                    job: job.clone(),
+                    start,
+                });
+                Some(started)
+            }
+        };
+
+        if let Some(started) = started {
+            debug!("waiting for an import slot for job {:?}", job);
+            // The slot is handed over by the import that frees it, so the
+            // running count stays the same
+            started.await.expect("scheduler dropped");
+        }
+
+        Slot { scheduler: self }
+    }
+
+    /// Records the timestamp of the newest block an import has stored.
+    pub fn record_head(&self, job: &Job, timestamp: u64) {
+        let mut state = self.state.lock().expect("lock");
+        state.heads.insert(job.clone(), timestamp);
+    }
+
+    fn release(&self) {
+        let mut state = self.state.lock().expect("lock");
+        let now = u64::try_from(Utc::now().timestamp()).unwrap_or_default();
+        loop {
+            let next = state
+                .waiting
+                .iter()
+                .enumerate()
+                // Earlier waiters first among equals
+                .max_by_key(|(index, waiter)| {
+                    (backlog(&state.heads, &waiter.job, now), Reverse(*index))
+                })
+                .map(|(index, _)| index);
+            match next {
+                Some(index) => {
+                    let waiter = state.waiting.remove(index);
+                    // Or the import stopped waiting, so try the next
+                    if waiter.start.send(()).is_ok() {
+                        debug!("starting job {:?} in a freed import slot", waiter.job);
+                        return;
+                    }
+                }
+                None => {
+                    state.running -= 1;
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        self.scheduler.release();
+    }
+}
+
+/// Roughly how many blocks an import has to catch up on. Imports not run
+/// yet come first.
+fn backlog(heads: &HashMap<Job, u64>, job: &Job, now: u64) -> u64 {
+    let head = match heads.get(job) {
+        Some(head) => *head,
+        None => return u64::MAX,
+    };
+    let block_time_ms = job.chain().map_or(1000, delay::expected_block_time);
+    now.saturating_sub(head).saturating_mul(1000) / block_time_ms.max(1)
+}
+
+#[cfg(test)]
+mod test_scheduler {
+    use super::Scheduler;
+    use crate::jobs::Job;
+    use chrono::Utc;
+    use realtps_common::chain::Chain;
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn furthest_behind_first() {
+        let scheduler = Arc::new(Scheduler::new(Some(1)));
+        let now = u64::try_from(Utc::now().timestamp()).expect("u64");
+        let caught_up = Job::Import(Chain::Ethereum);
+        let behind = Job::ImportSecondary(Chain::Ethereum);
+        scheduler.record_head(&caught_up, now - 10);
+        scheduler.record_head(&behind, now - 60 * 60);
+
+        let slot = scheduler.acquire(&Job::Import(Chain::Polygon)).await;
+        let started = Arc::new(Mutex::new(vec![]));
+        let tasks: Vec<_> = [caught_up.clone(), behind.clone()]
+            .into_iter()
+            .map(|job| {
+                let scheduler = scheduler.clone();
+                let started = started.clone();
+                tokio::spawn(async move {
+                    let _slot = scheduler.acquire(&job).await;
+                    started.lock().expect("lock").push(job);
+                })
+            })
+            .collect();
+        // Let both queue up behind the running import
+        tokio::task::yield_now().await;
+        assert!(started.lock().expect("lock").is_empty());
+
+        drop(slot);
+        for task in tasks {
+            task.await.expect("join");
+        }
+        assert_eq!(*started.lock().expect("lock"), vec![behind, caught_up]);
+    }
+
+    #[tokio::test]
+    async fn unlimited_without_max() {
+        let scheduler = Scheduler::new(None);
+        let _first = scheduler.acquire(&Job::Import(Chain::Ethereum)).await;
+        let _second = scheduler.acquire(&Job::Import(Chain::Polygon)).await;
+    }
+}
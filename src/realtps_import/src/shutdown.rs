@@ -0,0 +1,42 @@
+use anyhow::Result;
+use log::info;
+use std::sync::LazyLock;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+
+/// Whether shutdown has been requested. Jobs check it between writes, so
+/// they stop without leaving an import half stored.
+static SHUTDOWN: LazyLock<watch::Sender<bool>> = LazyLock::new(|| watch::channel(false).0);
+
+/// Requests shutdown on the first SIGINT or SIGTERM.
+pub fn listen_for_signals() -> Result<()> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = sigterm.recv() => {}
+        }
+        info!("shutting down once in-flight jobs finish");
+        request();
+    });
+    Ok(())
+}
+
+pub fn request() {
+    SHUTDOWN.send_replace(true);
+}
+
+pub fn requested() -> bool {
+    *SHUTDOWN.borrow()
+}
+
+/// Waits until shutdown is requested.
+pub async fn wait() {
+    let mut shutdown = SHUTDOWN.subscribe();
+    while !*shutdown.borrow_and_update() {
+        if shutdown.changed().await.is_err() {
+            // The sender is static, so this can't happen
+            return;
+        }
+    }
+}
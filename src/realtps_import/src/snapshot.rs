@@ -0,0 +1,206 @@
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use log::info;
+use realtps_common::{
+    chain::Chain,
+    db::{Block, CalculationLog, DailyTps, Db, PeakTps, TimestampIndex, TpsWindow},
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Bumped when the record format changes incompatibly.
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// Blocks are written to the db in batches of this size while importing.
+const IMPORT_BATCH_SIZE: usize = 1000;
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// A snapshot is a zstd-compressed file with one JSON record per line: a
+/// header, then for each chain its metadata followed by its blocks.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum SnapshotRecord {
+    Header {
+        version: u32,
+        created: DateTime<Utc>,
+    },
+    Chain {
+        chain: Chain,
+        highest_block_number: Option<u64>,
+        /// TPS over the week, from before other windows existed
+        tps: Option<f64>,
+        #[serde(default)]
+        window_tps: BTreeMap<TpsWindow, f64>,
+        calculation_log: Option<CalculationLog>,
+        #[serde(default)]
+        timestamp_index: Option<TimestampIndex>,
+        #[serde(default)]
+        peak_tps: Option<PeakTps>,
+        #[serde(default)]
+        daily_tps: Option<DailyTps>,
+    },
+    Block(Block),
+}
+
+pub async fn export_snapshot(chains: &[Chain], db: &Arc<dyn Db>, path: &Path) -> Result<()> {
+    let file = File::create(path).context(format!("unable to create {}", path.display()))?;
+    let mut encoder = zstd::Encoder::new(BufWriter::new(file), ZSTD_LEVEL)?;
+
+    write_record(
+        &mut encoder,
+        &SnapshotRecord::Header {
+            version: SNAPSHOT_VERSION,
+            created: Utc::now(),
+        },
+    )?;
+
+    for chain in chains {
+        let chain = *chain;
+
+        write_record(
+            &mut encoder,
+            &SnapshotRecord::Chain {
+                chain,
+                highest_block_number: db.load_highest_block_number(chain).await?,
+                tps: db.load_tps(chain, TpsWindow::Week).await?,
+                window_tps: load_window_tps(chain, db).await?,
+                calculation_log: db.load_calculation_log(chain).await?,
+                timestamp_index: db.load_timestamp_index(chain).await?,
+                peak_tps: db.load_peak_tps(chain).await?,
+                daily_tps: db.load_daily_tps(chain).await?,
+            },
+        )?;
+
+        let mut num_blocks = 0;
+        for block_number in db.load_block_numbers(chain).await? {
+            if let Some(block) = db.load_block(chain, block_number).await? {
+                write_record(&mut encoder, &SnapshotRecord::Block(block))?;
+                num_blocks += 1;
+            }
+        }
+
+        info!("exported {} blocks for chain {}", num_blocks, chain);
+    }
+
+    encoder.finish()?.flush()?;
+
+    info!("wrote snapshot to {}", path.display());
+
+    Ok(())
+}
+
+/// Loads a snapshot into the db, overwriting existing data for the chains it
+/// contains.
+pub async fn import_snapshot(chains: &[Chain], db: &Arc<dyn Db>, path: &Path) -> Result<()> {
+    let file = File::open(path).context(format!("unable to open {}", path.display()))?;
+    let reader = BufReader::new(zstd::Decoder::new(file)?);
+    let mut lines = reader.lines();
+
+    let header = lines.next().context("empty snapshot")??;
+    match serde_json::from_str(&header)? {
+        SnapshotRecord::Header { version, created } => {
+            if version != SNAPSHOT_VERSION {
+                bail!("unsupported snapshot version {}", version);
+            }
+            info!("importing snapshot created at {}", created);
+        }
+        _ => bail!("snapshot has no header"),
+    }
+
+    // Metadata is stored after the blocks, so the highest block number never
+    // points at a block that hasn't been imported yet.
+    let mut chain_records = vec![];
+    let mut blocks: Vec<Block> = vec![];
+
+    for line in lines {
+        match serde_json::from_str(&line?)? {
+            SnapshotRecord::Header { .. } => bail!("unexpected snapshot header"),
+            record @ SnapshotRecord::Chain { .. } => chain_records.push(record),
+            SnapshotRecord::Block(block) => {
+                if !chains.contains(&block.chain) {
+                    continue;
+                }
+                if matches!(blocks.last(), Some(last) if last.chain != block.chain)
+                    || blocks.len() >= IMPORT_BATCH_SIZE
+                {
+                    store_batch(db, std::mem::take(&mut blocks)).await?;
+                }
+                blocks.push(block);
+            }
+        }
+    }
+    store_batch(db, blocks).await?;
+
+    for record in chain_records {
+        if let SnapshotRecord::Chain {
+            chain,
+            highest_block_number,
+            tps,
+            window_tps,
+            calculation_log,
+            timestamp_index,
+            peak_tps,
+            daily_tps,
+        } = record
+        {
+            if !chains.contains(&chain) {
+                continue;
+            }
+            if let Some(timestamp_index) = timestamp_index {
+                db.store_timestamp_index(chain, &timestamp_index).await?;
+            }
+            if let Some(highest_block_number) = highest_block_number {
+                db.store_highest_block_number(chain, highest_block_number)
+                    .await?;
+            }
+            if let Some(tps) = tps {
+                db.store_tps(chain, TpsWindow::Week, tps).await?;
+            }
+            for (window, tps) in window_tps {
+                db.store_tps(chain, window, tps).await?;
+            }
+            if let Some(calculation_log) = calculation_log {
+                db.store_calculation_log(chain, &calculation_log).await?;
+            }
+            if let Some(peak_tps) = peak_tps {
+                db.store_peak_tps(chain, &peak_tps).await?;
+            }
+            if let Some(daily_tps) = daily_tps {
+                db.store_daily_tps(chain, &daily_tps).await?;
+            }
+            info!("imported chain {}", chain);
+        }
+    }
+
+    db.flush().await?;
+
+    Ok(())
+}
+
+async fn load_window_tps(chain: Chain, db: &Arc<dyn Db>) -> Result<BTreeMap<TpsWindow, f64>> {
+    let mut window_tps = BTreeMap::new();
+    for window in TpsWindow::all_windows() {
+        if let Some(tps) = db.load_tps(chain, window).await? {
+            window_tps.insert(window, tps);
+        }
+    }
+    Ok(window_tps)
+}
+
+async fn store_batch(db: &Arc<dyn Db>, blocks: Vec<Block>) -> Result<()> {
+    if let Some(first) = blocks.first() {
+        db.store_blocks(first.chain, blocks).await?;
+    }
+    Ok(())
+}
+
+fn write_record<W: Write>(writer: &mut W, record: &SnapshotRecord) -> Result<()> {
+    serde_json::to_writer(&mut *writer, record)?;
+    writer.write_all(b"\n")?;
+    Ok(())
+}
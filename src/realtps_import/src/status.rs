@@ -0,0 +1,268 @@
+use crate::client::Client;
+use crate::helpers::*;
+use crate::list;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use futures::future;
+use log::warn;
+use realtps_common::{
+    chain::Chain,
+    db::{Db, JobStatus, LeaseKind},
+};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// How far behind a chain's import is, and how its jobs are getting on.
+#[derive(Serialize, Debug)]
+pub struct ChainStatus {
+    pub chain: Chain,
+    pub head_block: Option<u64>,
+    /// Why the head couldn't be fetched
+    pub head_error: Option<String>,
+    pub newest_block: Option<u64>,
+    pub blocks_behind: Option<u64>,
+    /// The age of the newest stored block
+    pub seconds_behind: Option<u64>,
+    pub last_import: Option<DateTime<Utc>>,
+    /// Failed imports since the last successful one
+    pub import_errors: u64,
+    pub last_calculate: Option<DateTime<Utc>>,
+    pub calculate_errors: u64,
+    /// `None` for dbs that can't tell
+    pub db_bytes: Option<u64>,
+}
+
+/// Prints each chain's import lag, when its imports and calculations last
+/// succeeded, how many times they have failed since, and the size of its
+/// data, as a table or as JSON. Only the heads are fetched, once for each
+/// chain with a client.
+pub async fn report_status(
+    chains: &[Chain],
+    db: &Arc<dyn Db>,
+    clients: &HashMap<Chain, Box<dyn Client>>,
+    json: bool,
+) -> Result<()> {
+    let heads = future::join_all(chains.iter().map(|chain| async move {
+        match clients.get(chain) {
+            Some(client) => Some(client.get_latest_block_number().await),
+            None => None,
+        }
+    }))
+    .await;
+
+    let now = u64::try_from(Utc::now().timestamp())?;
+    let mut statuses = vec![];
+    for (chain, head) in chains.iter().zip(heads) {
+        statuses.push(load_status(*chain, db, head, now).await?);
+    }
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&statuses)?);
+    } else {
+        print!("{}", format_table(&statuses, now));
+    }
+    Ok(())
+}
+
+/// A chain's status given its head, if fetched, as of `now`.
+pub async fn load_status(
+    chain: Chain,
+    db: &Arc<dyn Db>,
+    head: Option<Result<u64>>,
+    now: u64,
+) -> Result<ChainStatus> {
+    let (head_block, head_error) = match head {
+        Some(Ok(head_block)) => (Some(head_block), None),
+        // Client errors repeat their causes, so only the last is kept
+        Some(Err(e)) => match e.chain().count() {
+            1 => (None, Some(e.to_string())),
+            _ => (None, Some(format!("{}: {}", e, e.root_cause()))),
+        },
+        None => (None, None),
+    };
+
+    let newest_block = load_highest_known_block_number(chain, db).await?;
+    let seconds_behind = match newest_block {
+        Some(block_number) => load_block(chain, db, block_number)
+            .await?
+            .map(|block| now.saturating_sub(block.timestamp)),
+        None => None,
+    };
+    let blocks_behind = match (head_block, newest_block) {
+        (Some(head_block), Some(newest_block)) => Some(head_block.saturating_sub(newest_block)),
+        _ => None,
+    };
+
+    let import = db
+        .load_job_status(chain, LeaseKind::Import)
+        .await?
+        .unwrap_or_default();
+    let calculate = db
+        .load_job_status(chain, LeaseKind::Calculate)
+        .await?
+        .unwrap_or_default();
+
+    Ok(ChainStatus {
+        chain,
+        head_block,
+        head_error,
+        newest_block,
+        blocks_behind,
+        seconds_behind,
+        last_import: import.last_success,
+        import_errors: import.num_errors,
+        last_calculate: calculate.last_success,
+        calculate_errors: calculate.num_errors,
+        db_bytes: db.chain_size(chain).await?,
+    })
+}
+
+fn format_table(statuses: &[ChainStatus], now: u64) -> String {
+    let ago = |time: Option<DateTime<Utc>>| match time {
+        Some(time) => format!(
+            "{} ago",
+            format_seconds(now.saturating_sub(u64::try_from(time.timestamp()).unwrap_or(0)))
+        ),
+        None => "never".to_string(),
+    };
+    let rows: Vec<[String; 8]> = statuses
+        .iter()
+        .map(|status| {
+            let head = match (status.head_block, &status.head_error) {
+                (Some(head_block), _) => head_block.to_string(),
+                (None, Some(_)) => "error".to_string(),
+                (None, None) => "-".to_string(),
+            };
+            [
+                status.chain.to_string(),
+                head,
+                status
+                    .blocks_behind
+                    .map_or("-".to_string(), |blocks| blocks.to_string()),
+                status
+                    .seconds_behind
+                    .map_or("-".to_string(), format_seconds),
+                format!(
+                    "{} ({} errors)",
+                    ago(status.last_import),
+                    status.import_errors
+                ),
+                format!(
+                    "{} ({} errors)",
+                    ago(status.last_calculate),
+                    status.calculate_errors
+                ),
+                status.db_bytes.map_or("-".to_string(), format_bytes),
+                status.head_error.clone().unwrap_or_default(),
+            ]
+        })
+        .collect();
+
+    list::format_columns(
+        [
+            "CHAIN",
+            "HEAD",
+            "BEHIND",
+            "LAG",
+            "LAST IMPORT",
+            "LAST CALCULATE",
+            "SIZE",
+            "HEAD ERROR",
+        ],
+        &rows,
+    )
+}
+
+fn format_seconds(seconds: u64) -> String {
+    match seconds {
+        0..=119 => format!("{}s", seconds),
+        120..=7199 => format!("{}m", seconds / 60),
+        7200..=172_799 => format!("{}h", seconds / 3600),
+        _ => format!("{}d", seconds / 86400),
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    format!("{:.1} MB", bytes as f64 / 1_000_000.0)
+}
+
+/// Records how a chain's job went, for `status`. Failing to store it is only
+/// logged, so doesn't fail the job.
+pub async fn record_job(chain: Chain, kind: LeaseKind, db: &Arc<dyn Db>, error: Option<&str>) {
+    let result = async {
+        let mut status = db.load_job_status(chain, kind).await?.unwrap_or_default();
+        match error {
+            Some(error) => {
+                status.num_errors += 1;
+                status.last_error = Some(error.to_string());
+            }
+            None => {
+                status = JobStatus {
+                    last_success: Some(Utc::now()),
+                    ..JobStatus::default()
+                };
+            }
+        }
+        db.store_job_status(chain, kind, &status).await
+    }
+    .await;
+    if let Err(e) = result {
+        warn!("failed to store {} job status for {}: {}", kind, chain, e);
+    }
+}
+
+#[cfg(test)]
+mod test_status {
+    use super::{format_seconds, load_status, record_job};
+    use crate::test_helpers::make_blocks;
+    use anyhow::anyhow;
+    use realtps_common::{
+        chain::Chain,
+        db::{Db, LeaseKind},
+        memory_db::MemoryDb,
+    };
+    use std::sync::Arc;
+
+    const CHAIN: Chain = Chain::Ethereum;
+
+    #[tokio::test]
+    async fn report_lag_and_errors() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let blocks = make_blocks(CHAIN, 10, 12, 5);
+        let newest_timestamp = blocks.last().expect("blocks").timestamp;
+        db.store_blocks(CHAIN, blocks).await?;
+        db.store_highest_block_number(CHAIN, 9).await?;
+
+        record_job(CHAIN, LeaseKind::Import, &db, Some("rate limited")).await;
+        record_job(CHAIN, LeaseKind::Calculate, &db, None).await;
+        record_job(CHAIN, LeaseKind::Calculate, &db, Some("no blocks")).await;
+        record_job(CHAIN, LeaseKind::Calculate, &db, Some("no blocks")).await;
+
+        let status = load_status(CHAIN, &db, Some(Ok(15)), newest_timestamp + 60).await?;
+        assert_eq!(status.newest_block, Some(9));
+        assert_eq!(status.blocks_behind, Some(6));
+        assert_eq!(status.seconds_behind, Some(60));
+        assert_eq!(status.last_import, None);
+        assert_eq!(status.import_errors, 1);
+        assert!(status.last_calculate.is_some());
+        assert_eq!(status.calculate_errors, 2);
+
+        // Succeeding resets the count
+        record_job(CHAIN, LeaseKind::Import, &db, None).await;
+        let status = load_status(CHAIN, &db, Some(Err(anyhow!("timed out"))), 0).await?;
+        assert_eq!(status.import_errors, 0);
+        assert!(status.last_import.is_some());
+        assert_eq!(status.head_error.as_deref(), Some("timed out"));
+        assert_eq!(status.blocks_behind, None);
+        Ok(())
+    }
+
+    #[test]
+    fn format_durations() {
+        assert_eq!(format_seconds(45), "45s");
+        assert_eq!(format_seconds(600), "10m");
+        assert_eq!(format_seconds(3 * 3600), "3h");
+        assert_eq!(format_seconds(3 * 86400), "3d");
+    }
+}
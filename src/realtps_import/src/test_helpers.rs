@@ -0,0 +1,135 @@
+use crate::client::{Client, FinalizedBlock};
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use realtps_common::{chain::Chain, db::Block};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// The timestamp of block 0, on an hour boundary.
+pub const GENESIS_TIMESTAMP: u64 = 1_620_000_000;
+
+/// A block with a hash derived from its number, linked to the block before it.
+pub fn make_block(chain: Chain, block_number: u64, seconds_per_block: u64, num_txs: u64) -> Block {
+    let prev_block_number = block_number.checked_sub(1);
+    Block {
+        chain,
+        block_number,
+        prev_block_number,
+        timestamp: GENESIS_TIMESTAMP + block_number * seconds_per_block,
+        num_txs,
+        hash: format!("{}", block_number),
+        parent_hash: prev_block_number
+            .map(|number| format!("{}", number))
+            .unwrap_or_default(),
+        gas_used: None,
+        gas_limit: None,
+        fees: None,
+        value_transferred: None,
+        num_token_transfers: None,
+        num_receipts: None,
+        num_internal_calls: None,
+        producer: None,
+        num_vote_txs: None,
+        num_txs_failed: None,
+        senders: None,
+    }
+}
+
+/// Blocks `0..count`.
+pub fn make_blocks(chain: Chain, count: u64, seconds_per_block: u64, num_txs: u64) -> Vec<Block> {
+    (0..count)
+        .map(|block_number| make_block(chain, block_number, seconds_per_block, num_txs))
+        .collect()
+}
+
+/// A `Client` serving a fixed list of blocks, indexed by block number.
+pub struct MockClient {
+    blocks: Mutex<Vec<Block>>,
+    head: AtomicU64,
+    /// How many blocks the finalized block is behind the head
+    finality_depth: Mutex<Option<u64>>,
+    native_tps: Mutex<Option<f64>>,
+    /// Whether requests fail, like a dead endpoint's
+    failing: AtomicBool,
+}
+
+impl MockClient {
+    /// Serves `blocks` with the last one as the head.
+    pub fn new(blocks: Vec<Block>) -> MockClient {
+        let head = blocks.last().expect("blocks").block_number;
+        MockClient {
+            blocks: Mutex::new(blocks),
+            head: AtomicU64::new(head),
+            finality_depth: Mutex::new(None),
+            native_tps: Mutex::new(None),
+            failing: AtomicBool::new(false),
+        }
+    }
+
+    pub fn set_failing(&self, failing: bool) {
+        self.failing.store(failing, Ordering::SeqCst);
+    }
+
+    fn check_failing(&self) -> Result<()> {
+        if self.failing.load(Ordering::SeqCst) {
+            bail!("mock endpoint is down");
+        }
+        Ok(())
+    }
+
+    pub fn set_native_tps(&self, tps: f64) {
+        *self.native_tps.lock().expect("lock") = Some(tps);
+    }
+
+    pub fn set_finality_depth(&self, depth: u64) {
+        *self.finality_depth.lock().expect("lock") = Some(depth);
+    }
+
+    pub fn set_head(&self, block_number: u64) {
+        self.head.store(block_number, Ordering::SeqCst);
+    }
+
+    pub fn replace_block(&self, block: Block) {
+        let mut blocks = self.blocks.lock().expect("lock");
+        let index = usize::try_from(block.block_number).expect("overflow");
+        blocks[index] = block;
+    }
+}
+
+#[async_trait]
+impl Client for MockClient {
+    async fn client_version(&self) -> Result<String> {
+        Ok("mock".to_string())
+    }
+
+    async fn get_latest_block_number(&self) -> Result<u64> {
+        self.check_failing()?;
+        Ok(self.head.load(Ordering::SeqCst))
+    }
+
+    async fn get_block(&self, block_number: u64) -> Result<Option<Block>> {
+        self.check_failing()?;
+        let blocks = self.blocks.lock().expect("lock");
+        let index = usize::try_from(block_number)?;
+        Ok(blocks.get(index).cloned())
+    }
+
+    async fn get_finalized_block(&self) -> Result<Option<FinalizedBlock>> {
+        let depth = match *self.finality_depth.lock().expect("lock") {
+            Some(depth) => depth,
+            None => return Ok(None),
+        };
+        let block_number = self.head.load(Ordering::SeqCst).saturating_sub(depth);
+        Ok(self
+            .get_block(block_number)
+            .await?
+            .map(|block| FinalizedBlock {
+                block_number,
+                timestamp: block.timestamp,
+            }))
+    }
+
+    async fn get_native_tps(&self) -> Result<Option<f64>> {
+        Ok(*self.native_tps.lock().expect("lock"))
+    }
+}
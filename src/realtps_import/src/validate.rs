@@ -0,0 +1,491 @@
+use crate::auth::EndpointAuth;
+use crate::delay;
+use crate::interpolate;
+use crate::{
+    apply_rpc_overrides, endpoint_name, make_client, make_indexer_client, ClientOptions,
+    IndexerConfig, RpcConfig, Timeouts,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use futures::future;
+use realtps_common::chain::Chain;
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+/// Sections of `rpc_config.toml` keyed by chain.
+const CHAIN_TABLES: &[&str] = &[
+    "chains",
+    "max_tps_window",
+    "count_internal_calls",
+    "usd_prices",
+    "secondary_chains",
+    "import_concurrency",
+    "confirmations",
+    "ws_urls",
+    "chain_retry",
+    "chain_timeouts",
+    "indexers",
+    "metadata",
+];
+
+/// Settings of `rpc_config.toml` listing chains.
+const CHAIN_LISTS: &[&str] = &[
+    "exclude_failed_txs",
+    "disabled_chains",
+    "count_token_transfers",
+    "light_import",
+];
+
+/// How long to wait for an endpoint to answer when probing it, including
+/// the client's own retries.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What's wrong with a config. Problems stop it working as intended, and
+/// fail validation, while warnings only may.
+#[derive(Default)]
+struct Report {
+    problems: Vec<String>,
+    warnings: Vec<String>,
+}
+
+impl Report {
+    fn problem(&mut self, problem: String) {
+        self.problems.push(problem);
+    }
+
+    fn warning(&mut self, warning: String) {
+        self.warnings.push(warning);
+    }
+}
+
+/// Checks the RPC config at `path` for unknown chains and settings, chains
+/// without an RPC URL, and malformed URLs, and with `probe`, whether each of
+/// the chains' endpoints answers. Prints what it finds, and fails if there
+/// are problems. With `strict`, chains without an RPC URL are problems, as
+/// `--strict` imports exit over them.
+pub async fn validate_config(
+    path: &Path,
+    chains: &[Chain],
+    probe: bool,
+    strict: bool,
+) -> Result<()> {
+    let text =
+        fs::read_to_string(path).with_context(|| format!("unable to read {}", path.display()))?;
+    let mut report = Report::default();
+
+    let rpc_config = check_config(&text, chains, strict, &mut report);
+    if let (Some(rpc_config), true) = (&rpc_config, probe) {
+        delay::set_retry_policies(rpc_config.retry, rpc_config.chain_retry.clone());
+        probe_endpoints(rpc_config, chains, &mut report).await;
+    }
+
+    for warning in &report.warnings {
+        println!("warning: {}", warning);
+    }
+    for problem in &report.problems {
+        println!("problem: {}", problem);
+    }
+    if !report.problems.is_empty() {
+        bail!(
+            "found {} problems in {}",
+            report.problems.len(),
+            path.display()
+        );
+    }
+    println!("{} is valid", path.display());
+    Ok(())
+}
+
+/// Checks the config without making requests, returning it if it parses.
+fn check_config(
+    text: &str,
+    chains: &[Chain],
+    strict: bool,
+    report: &mut Report,
+) -> Option<RpcConfig> {
+    let mut value: toml::Value = match toml::from_str(text) {
+        Ok(value) => value,
+        Err(e) => {
+            report.problem(format!("invalid TOML: {}", e));
+            return None;
+        }
+    };
+    if let Err(e) = interpolate::interpolate_env(&mut value) {
+        report.problem(format!("unable to interpolate: {}", e));
+        return None;
+    }
+    let table = value.as_table()?;
+
+    // All of them, as parsing stops at the first
+    for section in CHAIN_TABLES {
+        if let Some(entries) = table.get(*section).and_then(toml::Value::as_table) {
+            for key in entries.keys() {
+                if Chain::try_from(key.as_str()).is_err() {
+                    report.problem(format!("unknown chain `{}` in [{}]", key, section));
+                }
+            }
+        }
+    }
+    for setting in CHAIN_LISTS {
+        if let Some(list) = table.get(*setting).and_then(toml::Value::as_array) {
+            for item in list {
+                let known = item
+                    .as_str()
+                    .is_some_and(|name| Chain::try_from(name).is_ok());
+                if !known {
+                    report.problem(format!("unknown chain {} in `{}`", item, setting));
+                }
+            }
+        }
+    }
+    if let Some(profiles) = table.get("profiles").and_then(toml::Value::as_table) {
+        for (name, profile) in profiles {
+            let list = profile.get("chains").and_then(toml::Value::as_array);
+            for item in list.into_iter().flatten() {
+                let known = item
+                    .as_str()
+                    .is_some_and(|name| Chain::try_from(name).is_ok());
+                if !known {
+                    report.problem(format!("unknown chain {} in [profiles.{}]", item, name));
+                }
+            }
+        }
+    }
+
+    let mut rpc_config: RpcConfig = match value.clone().try_into() {
+        Ok(rpc_config) => rpc_config,
+        Err(e) => {
+            if report.problems.is_empty() {
+                report.problem(format!("invalid config: {}", e));
+            }
+            return None;
+        }
+    };
+    apply_rpc_overrides(&mut rpc_config);
+
+    // Settings the importer doesn't know are ignored, so are likely typos
+    let known_settings = serde_json::to_value(&rpc_config)
+        .ok()
+        .and_then(|value| value.as_object().cloned())
+        .unwrap_or_default();
+    for key in table.keys() {
+        if !known_settings.contains_key(key) {
+            report.problem(format!("unknown setting `{}`", key));
+        }
+    }
+
+    for chain in chains {
+        let configured =
+            rpc_config.chains.contains_key(chain) || rpc_config.indexers.contains_key(chain);
+        if !configured && !rpc_config.disabled_chains.contains(chain) {
+            let missing = format!("no RPC URL for chain {}; it won't be imported", chain);
+            if strict {
+                report.problem(missing);
+            } else {
+                report.warning(missing);
+            }
+        }
+    }
+
+    let mut timeouts = vec![("[timeouts]".to_string(), rpc_config.timeouts)];
+    timeouts.extend(
+        rpc_config
+            .chain_timeouts
+            .iter()
+            .map(|(chain, timeouts)| (format!("[chain_timeouts.{}]", chain), *timeouts)),
+    );
+    for (setting, timeouts) in timeouts {
+        if timeouts.connect_secs == 0 || timeouts.request_secs == 0 {
+            report.problem(format!(
+                "{} has a timeout of 0, so every request fails",
+                setting
+            ));
+        }
+    }
+
+    let mut endpoint_urls = HashSet::new();
+    for (section, rpc_urls) in [
+        ("chains", &rpc_config.chains),
+        ("secondary_chains", &rpc_config.secondary_chains),
+    ] {
+        for (chain, rpc_urls) in rpc_urls {
+            for url in rpc_urls.urls() {
+                check_url(
+                    &format!("[{}] {}", section, chain),
+                    url,
+                    &["http", "https"],
+                    report,
+                );
+                endpoint_urls.insert(url.as_str());
+            }
+        }
+    }
+    for (chain, indexer) in &rpc_config.indexers {
+        check_url(
+            &format!("[indexers.{}]", chain),
+            &indexer.url,
+            &["http", "https"],
+            report,
+        );
+        endpoint_urls.insert(indexer.url.as_str());
+    }
+    for (chain, ws_url) in &rpc_config.ws_urls {
+        check_url(
+            &format!("[ws_urls] {}", chain),
+            ws_url,
+            &["ws", "wss"],
+            report,
+        );
+    }
+    for url in rpc_config.rate_limits.keys() {
+        if !endpoint_urls.contains(url.as_str()) {
+            report.warning(format!(
+                "[rate_limits] {} matches no configured RPC URL, so limits nothing",
+                url
+            ));
+        }
+    }
+    for (url, auth) in &rpc_config.auth {
+        if !endpoint_urls.contains(url.as_str()) {
+            report.warning(format!(
+                "[auth] {} matches no configured RPC URL, so is never sent",
+                url
+            ));
+        }
+        if let Err(e) = auth.headers() {
+            report.problem(format!("[auth] {} is invalid: {:#}", url, e));
+        }
+    }
+    for (chain, indexer) in &rpc_config.indexers {
+        if let Some(Err(e)) = indexer.auth.as_ref().map(|auth| auth.headers()) {
+            report.problem(format!("[indexers.{}] auth is invalid: {:#}", chain, e));
+        }
+    }
+
+    Some(rpc_config)
+}
+
+fn check_url(setting: &str, url: &str, schemes: &[&str], report: &mut Report) {
+    match reqwest::Url::parse(url) {
+        Ok(parsed) if schemes.contains(&parsed.scheme()) => {}
+        Ok(parsed) => report.problem(format!(
+            "{} has a {} URL, not {}",
+            setting,
+            parsed.scheme(),
+            schemes.join(" or ")
+        )),
+        Err(e) => report.problem(format!("{} has a malformed URL `{}`: {}", setting, url, e)),
+    }
+}
+
+/// Asks each of the chains' endpoints for its version and latest block,
+/// printing whether it answered.
+async fn probe_endpoints(rpc_config: &RpcConfig, chains: &[Chain], report: &mut Report) {
+    let mut probes = vec![];
+    // Disabled chains' endpoints may well be down
+    for chain in chains
+        .iter()
+        .filter(|chain| !rpc_config.disabled_chains.contains(chain))
+    {
+        let rpc_urls = rpc_config
+            .chains
+            .get(chain)
+            .map(|rpc_urls| rpc_urls.urls())
+            .unwrap_or_default();
+        for (priority, url) in rpc_urls.iter().enumerate() {
+            let name = endpoint_name(url, priority);
+            probes.push(future::Either::Left(async move {
+                let result = probe_rpc(*chain, url, rpc_config).await;
+                (*chain, name, result)
+            }));
+        }
+        if let Some(indexer) = rpc_config.indexers.get(chain) {
+            let name = endpoint_name(&indexer.url, rpc_urls.len());
+            probes.push(future::Either::Right(async move {
+                let auth = indexer
+                    .auth
+                    .clone()
+                    .or_else(|| rpc_config.auth.get(&indexer.url).cloned());
+                let timeouts = rpc_config.chain_timeouts(*chain);
+                let result = probe_indexer(*chain, indexer.clone(), auth, timeouts).await;
+                (*chain, name, result)
+            }));
+        }
+    }
+
+    let results = future::join_all(probes).await;
+    let num_probes = results.len();
+    let num_passed = results
+        .iter()
+        .filter(|(_, _, result)| result.is_ok())
+        .count();
+    for (chain, name, result) in results {
+        match result {
+            Ok((version, block_number)) => println!(
+                "pass: {} at {}: {}, at block {}",
+                chain, name, version, block_number
+            ),
+            Err(e) => {
+                // Client errors repeat their causes, so only the last is shown
+                let error = match e.chain().count() {
+                    1 => e.to_string(),
+                    _ => format!("{}: {}", e, e.root_cause()),
+                };
+                println!("FAIL: {} at {}: {}", chain, name, error);
+                report.problem(format!("endpoint {} of chain {} failed", name, chain));
+            }
+        }
+    }
+    println!("{} of {} endpoints passed", num_passed, num_probes);
+}
+
+async fn probe_rpc(chain: Chain, url: &str, rpc_config: &RpcConfig) -> Result<(String, u64)> {
+    let probe = async {
+        let auth = rpc_config.auth.get(url).cloned();
+        let options = ClientOptions::new(rpc_config, chain);
+        let client = make_client(chain, url.to_string(), auth, options)
+            .await?
+            .ok_or_else(|| anyhow!("no client for chain {}", chain))?;
+        let version = client.client_version().await?;
+        let block_number = client.get_latest_block_number().await?;
+        Ok((version, block_number))
+    };
+    tokio::time::timeout(PROBE_TIMEOUT, probe)
+        .await
+        .map_err(|_| anyhow!("timed out after {} s", PROBE_TIMEOUT.as_secs()))?
+}
+
+async fn probe_indexer(
+    chain: Chain,
+    indexer: IndexerConfig,
+    auth: Option<EndpointAuth>,
+    timeouts: Timeouts,
+) -> Result<(String, u64)> {
+    let probe = async {
+        let client = make_indexer_client(chain, indexer, auth, timeouts)
+            .await?
+            .ok_or_else(|| anyhow!("no explorer api client for chain {}", chain))?;
+        let version = client.client_version().await?;
+        let block_number = client.get_latest_block_number().await?;
+        Ok((version, block_number))
+    };
+    tokio::time::timeout(PROBE_TIMEOUT, probe)
+        .await
+        .map_err(|_| anyhow!("timed out after {} s", PROBE_TIMEOUT.as_secs()))?
+}
+
+#[cfg(test)]
+mod test_validate {
+    use super::{check_config, Report};
+    use realtps_common::chain::Chain;
+
+    fn check(text: &str, chains: &[Chain]) -> Report {
+        let mut report = Report::default();
+        check_config(text, chains, false, &mut report);
+        report
+    }
+
+    #[test]
+    fn accept_valid_config() {
+        let report = check(
+            r#"
+            light_import = ["ethereum"]
+
+            [rate_limits]
+            "https://polygon-rpc.com" = { requests_per_second = 10 }
+
+            [chains]
+            ethereum = ["https://cloudflare-eth.com", "https://rpc.ankr.com/eth"]
+            polygon = "https://polygon-rpc.com"
+            "#,
+            &[Chain::Ethereum, Chain::Polygon],
+        );
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+        assert!(report.warnings.is_empty(), "{:?}", report.warnings);
+    }
+
+    #[test]
+    fn report_unknown_chains_and_settings() {
+        let report = check(
+            r#"
+            light_imports = ["ethereum"]
+            count_token_transfers = ["etherium"]
+
+            [chains]
+            ethereum = "https://cloudflare-eth.com"
+            dogecoin = "https://doge.example"
+
+            [profiles.staging]
+            chains = ["polygn"]
+            "#,
+            &[Chain::Ethereum],
+        );
+        assert_eq!(report.problems.len(), 3, "{:?}", report.problems);
+        assert!(report.problems[0].contains("dogecoin"));
+        assert!(report.problems[1].contains("etherium"));
+        assert!(report.problems[2].contains("[profiles.staging]"));
+
+        // Once the chains are fixed
+        let report = check(
+            r#"
+            light_imports = ["ethereum"]
+
+            [chains]
+            ethereum = "https://cloudflare-eth.com"
+            "#,
+            &[Chain::Ethereum],
+        );
+        assert_eq!(report.problems, vec!["unknown setting `light_imports`"]);
+    }
+
+    #[test]
+    fn report_missing_chains_and_bad_urls() {
+        let report = check(
+            r#"
+            [ws_urls]
+            ethereum = "https://cloudflare-eth.com"
+
+            [chains]
+            ethereum = "cloudflare-eth.com"
+            "#,
+            &[Chain::Ethereum, Chain::Polygon],
+        );
+        assert_eq!(report.problems.len(), 2, "{:?}", report.problems);
+        assert!(report.problems.iter().any(|p| p.contains("malformed URL")));
+        assert!(report.problems.iter().any(|p| p.contains("not ws or wss")));
+        assert_eq!(report.warnings.len(), 1);
+        assert!(report.warnings[0].contains("polygon"));
+    }
+
+    #[test]
+    fn report_zero_timeouts() {
+        let report = check(
+            r#"
+            [chains]
+            ethereum = "https://cloudflare-eth.com"
+
+            [chain_timeouts]
+            ethereum = { request_secs = 0 }
+            "#,
+            &[Chain::Ethereum],
+        );
+        assert_eq!(
+            report.problems,
+            vec!["[chain_timeouts.ethereum] has a timeout of 0, so every request fails"]
+        );
+    }
+
+    #[test]
+    fn report_unset_variables() {
+        let report = check(
+            r#"
+            [chains]
+            ethereum = "https://mainnet.infura.io/v3/${REALTPS_TEST_UNSET_KEY}"
+            "#,
+            &[Chain::Ethereum],
+        );
+        assert_eq!(report.problems.len(), 1, "{:?}", report.problems);
+        assert!(report.problems[0].contains("REALTPS_TEST_UNSET_KEY"));
+    }
+}
@@ -0,0 +1,125 @@
+use crate::client::Client;
+use crate::helpers::*;
+use crate::pace_setter::PaceSetter;
+use crate::shutdown;
+use anyhow::Result;
+use log::{info, warn};
+use rand::seq::SliceRandom;
+use realtps_common::{chain::Chain, db::Db};
+use std::sync::Arc;
+
+/// A stored block whose timestamp or transaction count differs from the
+/// block fetched again.
+#[derive(Debug, PartialEq, Eq)]
+pub struct BlockMismatch {
+    pub block_number: u64,
+    pub stored_timestamp: u64,
+    pub timestamp: u64,
+    pub stored_num_txs: u64,
+    pub num_txs: u64,
+}
+
+/// How many blocks a verification checked, and those that differed.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    pub num_checked: u64,
+    pub mismatches: Vec<BlockMismatch>,
+}
+
+/// Fetches up to `samples` of the chain's stored blocks, chosen at random,
+/// again, and compares their timestamps and transaction counts, to catch
+/// providers that served wrong data and clients that parse blocks wrongly.
+/// Nothing is written; mismatched blocks can be fixed with `repair`.
+pub async fn verify(
+    chain: Chain,
+    client: &dyn Client,
+    db: &Arc<dyn Db>,
+    samples: usize,
+) -> Result<VerifyReport> {
+    let mut block_numbers: Vec<u64> = db
+        .load_block_numbers(chain)
+        .await?
+        .choose_multiple(&mut rand::thread_rng(), samples)
+        .copied()
+        .collect();
+    block_numbers.sort_unstable();
+    info!(
+        "verifying {} stored blocks for chain {}",
+        block_numbers.len(),
+        chain
+    );
+
+    let mut pace = PaceSetter::new(chain);
+    let mut report = VerifyReport::default();
+    for block_number in &block_numbers {
+        if shutdown::requested() {
+            warn!(
+                "verification of chain {} stopped after {} of {} blocks",
+                chain,
+                report.num_checked,
+                block_numbers.len()
+            );
+            break;
+        }
+
+        let stored_block = match load_block(chain, db, *block_number).await? {
+            Some(stored_block) => stored_block,
+            None => continue,
+        };
+        let block = fetch_block(chain, client, *block_number).await?;
+        report.num_checked += 1;
+        if block.timestamp != stored_block.timestamp || block.num_txs != stored_block.num_txs {
+            report.mismatches.push(BlockMismatch {
+                block_number: *block_number,
+                stored_timestamp: stored_block.timestamp,
+                timestamp: block.timestamp,
+                stored_num_txs: stored_block.num_txs,
+                num_txs: block.num_txs,
+            });
+        }
+        pace.wait().await;
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test_verify {
+    use super::{verify, BlockMismatch};
+    use crate::backfill::import_range;
+    use crate::test_helpers::{make_block, make_blocks, MockClient};
+    use realtps_common::{chain::Chain, db::Db, memory_db::MemoryDb};
+    use std::sync::Arc;
+
+    // Solana has no block pace, so tests don't wait between blocks.
+    const CHAIN: Chain = Chain::Solana;
+
+    #[tokio::test]
+    async fn report_changed_blocks() -> Result<(), anyhow::Error> {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let client = MockClient::new(make_blocks(CHAIN, 31, 1, 5));
+        import_range(CHAIN, &client, &db, 10, 30).await?;
+
+        let report = verify(CHAIN, &client, &db, 5).await?;
+        assert_eq!(report.num_checked, 5);
+        assert!(report.mismatches.is_empty());
+
+        let changed_block = make_block(CHAIN, 15, 1, 9);
+        client.replace_block(changed_block.clone());
+        let report = verify(CHAIN, &client, &db, 100).await?;
+        assert_eq!(report.num_checked, 21);
+        assert_eq!(
+            report.mismatches,
+            vec![BlockMismatch {
+                block_number: 15,
+                stored_timestamp: changed_block.timestamp,
+                timestamp: changed_block.timestamp,
+                stored_num_txs: 5,
+                num_txs: 9,
+            }]
+        );
+        // Nothing is written
+        assert_eq!(db.load_block(CHAIN, 15).await?.expect("block").num_txs, 5);
+        Ok(())
+    }
+}
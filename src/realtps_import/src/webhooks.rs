@@ -0,0 +1,283 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use realtps_common::{
+    chain::Chain,
+    db::{ChainHealth, Db, TpsWindow},
+};
+use reqwest::header::CONTENT_TYPE;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How long to wait on a webhook before giving up on posting to it.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A URL to post to when a condition starts or stops holding for a chain.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Every chain if unset
+    #[serde(default)]
+    pub chain: Option<Chain>,
+    /// Like `tps_below = 500.0`, alongside the URL
+    #[serde(flatten)]
+    pub condition: AlertCondition,
+    /// The window whose TPS the condition is on
+    #[serde(default = "default_window")]
+    pub window: TpsWindow,
+}
+
+fn default_window() -> TpsWindow {
+    TpsWindow::Hour
+}
+
+#[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertCondition {
+    TpsBelow(f64),
+    TpsAbove(f64),
+    /// The chain has made no blocks for longer than this
+    StalledForSecs(u64),
+}
+
+impl AlertCondition {
+    fn holds(self, value: f64) -> bool {
+        match self {
+            AlertCondition::TpsBelow(tps) => value < tps,
+            AlertCondition::TpsAbove(tps) => value > tps,
+            AlertCondition::StalledForSecs(seconds) => value > seconds as f64,
+        }
+    }
+}
+
+/// Posted as JSON when a condition starts holding, and again when it stops.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct WebhookPayload {
+    pub chain: Chain,
+    pub condition: AlertCondition,
+    /// For TPS conditions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub window: Option<TpsWindow>,
+    /// Whether the condition started holding, rather than stopped
+    pub firing: bool,
+    /// The TPS, or seconds since the chain's latest block, checked
+    pub value: f64,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Posts to webhooks as their conditions start and stop holding, checked
+/// after each calculation.
+pub struct Webhooks {
+    configs: Vec<WebhookConfig>,
+    /// Whether each webhook's condition held for each chain when last
+    /// checked. Not stored, so a condition holding on startup fires again.
+    firing: Mutex<HashMap<(usize, Chain), bool>>,
+    http: reqwest::Client,
+}
+
+impl Webhooks {
+    pub fn new(configs: Vec<WebhookConfig>) -> Result<Webhooks> {
+        Ok(Webhooks {
+            configs,
+            firing: Mutex::new(HashMap::new()),
+            http: reqwest::Client::builder()
+                .timeout(WEBHOOK_TIMEOUT)
+                .build()?,
+        })
+    }
+
+    /// Posts to the webhooks whose conditions started or stopped holding for
+    /// `chain`. Failing to post is only logged, and not retried.
+    pub async fn check(&self, chain: Chain, db: &Arc<dyn Db>) -> Result<()> {
+        for (url, payload) in self.changes(chain, db, Utc::now()).await? {
+            // Webhook URLs often carry secrets, so aren't logged
+            match self.post(&url, &payload).await {
+                Ok(()) => info!(
+                    "posted {:?} {} for chain {} to webhook",
+                    payload.condition,
+                    if payload.firing { "firing" } else { "resolved" },
+                    chain
+                ),
+                Err(e) => warn!(
+                    "unable to post {:?} for chain {} to webhook: {}",
+                    payload.condition, chain, e
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    async fn changes(
+        &self,
+        chain: Chain,
+        db: &Arc<dyn Db>,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<(String, WebhookPayload)>> {
+        let mut changes = vec![];
+        for (index, config) in self.configs.iter().enumerate() {
+            if matches!(config.chain, Some(config_chain) if config_chain != chain) {
+                continue;
+            }
+            let (value, window) = match config.condition {
+                AlertCondition::TpsBelow(_) | AlertCondition::TpsAbove(_) => (
+                    db.load_tps(chain, config.window).await?,
+                    Some(config.window),
+                ),
+                AlertCondition::StalledForSecs(_) => (
+                    db.load_chain_health(chain)
+                        .await?
+                        .map(|health| seconds_stalled(&health, now)),
+                    None,
+                ),
+            };
+            let value = match value {
+                Some(value) => value,
+                None => continue,
+            };
+
+            let firing = config.condition.holds(value);
+            let was_firing = self
+                .firing
+                .lock()
+                .expect("lock")
+                .insert((index, chain), firing)
+                .unwrap_or(false);
+            if firing != was_firing {
+                let payload = WebhookPayload {
+                    chain,
+                    condition: config.condition,
+                    window,
+                    firing,
+                    value,
+                    timestamp: now,
+                };
+                changes.push((config.url.clone(), payload));
+            }
+        }
+        Ok(changes)
+    }
+
+    async fn post(&self, url: &str, payload: &WebhookPayload) -> Result<()> {
+        self.http
+            .post(url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(payload)?)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// Seconds since the chain's latest block, as of `now` rather than when its
+/// health was checked.
+fn seconds_stalled(health: &ChainHealth, now: DateTime<Utc>) -> f64 {
+    let now = u64::try_from(now.timestamp()).unwrap_or_default();
+    let since_check = now.saturating_sub(health.checked_at);
+    (health.seconds_since_latest_block + since_check) as f64
+}
+
+#[cfg(test)]
+mod test_webhooks {
+    use super::{AlertCondition, WebhookConfig, Webhooks};
+    use chrono::{TimeZone, Utc};
+    use realtps_common::{
+        chain::Chain,
+        db::{ChainHealth, Db, TpsWindow},
+        memory_db::MemoryDb,
+    };
+    use serde::Deserialize;
+    use std::sync::Arc;
+
+    #[derive(Deserialize)]
+    struct Config {
+        webhooks: Vec<WebhookConfig>,
+    }
+
+    fn webhooks(config: &str) -> Webhooks {
+        // As the RPC config is parsed
+        let config: toml::Value = toml::from_str(config).expect("config");
+        let config: Config = config.try_into().expect("config");
+        Webhooks::new(config.webhooks).expect("webhooks")
+    }
+
+    #[tokio::test]
+    async fn fire_and_resolve_tps_conditions() -> Result<(), anyhow::Error> {
+        let webhooks = webhooks(
+            r#"
+            [[webhooks]]
+            url = "http://localhost/solana"
+            chain = "solana"
+            tps_below = 500.0
+
+            [[webhooks]]
+            url = "http://localhost/any"
+            tps_above = 10000.0
+            window = "week"
+            "#,
+        );
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let now = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+
+        db.store_tps(Chain::Solana, TpsWindow::Hour, 400.0).await?;
+        db.store_tps(Chain::Solana, TpsWindow::Week, 2000.0).await?;
+        let changes = webhooks.changes(Chain::Solana, &db, now).await?;
+        assert_eq!(changes.len(), 1);
+        let (url, payload) = &changes[0];
+        assert_eq!(url, "http://localhost/solana");
+        assert!(payload.firing);
+        assert_eq!(payload.value, 400.0);
+        assert_eq!(payload.window, Some(TpsWindow::Hour));
+
+        // Fires once while the condition holds
+        assert!(webhooks.changes(Chain::Solana, &db, now).await?.is_empty());
+
+        db.store_tps(Chain::Solana, TpsWindow::Hour, 600.0).await?;
+        let changes = webhooks.changes(Chain::Solana, &db, now).await?;
+        assert_eq!(changes.len(), 1);
+        assert!(!changes[0].1.firing);
+
+        // Only the webhook for every chain applies to other chains
+        db.store_tps(Chain::Ethereum, TpsWindow::Hour, 10.0).await?;
+        db.store_tps(Chain::Ethereum, TpsWindow::Week, 20000.0)
+            .await?;
+        let changes = webhooks.changes(Chain::Ethereum, &db, now).await?;
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].0, "http://localhost/any");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fire_when_stalled_for_long_enough() -> Result<(), anyhow::Error> {
+        let webhooks = webhooks(
+            r#"
+            [[webhooks]]
+            url = "http://localhost/stalled"
+            stalled_for_secs = 600
+            "#,
+        );
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let checked_at = 1_700_000_000;
+        let health = ChainHealth {
+            checked_at,
+            seconds_since_import: 300,
+            seconds_since_latest_block: 300,
+            stalled: true,
+        };
+        db.store_chain_health(Chain::Near, &health).await?;
+
+        let now = Utc.timestamp_opt(checked_at as i64 + 60, 0).unwrap();
+        assert!(webhooks.changes(Chain::Near, &db, now).await?.is_empty());
+
+        // Not checked since, so still stalled
+        let now = Utc.timestamp_opt(checked_at as i64 + 400, 0).unwrap();
+        let changes = webhooks.changes(Chain::Near, &db, now).await?;
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].1.condition, AlertCondition::StalledForSecs(600));
+        assert_eq!(changes[0].1.value, 700.0);
+        assert!(changes[0].1.firing);
+        Ok(())
+    }
+}
@@ -0,0 +1,539 @@
+//! The JSON API under `/v1`, for apps using the numbers rather than the
+//! website.
+
+use crate::live::LiveUpdates;
+use crate::MetadataOverrides;
+use chrono::{DateTime, Utc};
+use log::warn;
+use realtps_common::{
+    chain::Chain,
+    db::{AggregateTpsPoint, Db, TpsPoint, TpsWindow},
+    metadata::{self, ChainMetadata},
+};
+use rocket::http::Status;
+use rocket::response::stream::{Event, EventStream};
+use rocket::serde::json::Json;
+use rocket::{Route, Shutdown, State};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+
+/// The most points a history is downsampled to.
+const MAX_HISTORY_POINTS: u64 = 1000;
+
+/// A chain with published TPS.
+#[derive(Serialize, Debug)]
+struct ChainSummary {
+    chain: Chain,
+    #[serde(flatten)]
+    metadata: ChainMetadata,
+    /// For testnets, the mainnet they test
+    mainnet: Option<Chain>,
+    calculated_at: Option<DateTime<Utc>>,
+    /// The chain is disabled, so its TPS is no longer updated
+    stale: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct ChainTps {
+    chain: Chain,
+    tps: BTreeMap<TpsWindow, f64>,
+    gas_per_second: BTreeMap<TpsWindow, f64>,
+    calculated_at: Option<DateTime<Utc>>,
+    stale: bool,
+    /// Oldest first. Only with `?history=true`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    history: Option<Vec<TpsPoint>>,
+}
+
+/// A chain's TPS history, averaged over each `resolution` seconds.
+#[derive(Serialize, Debug)]
+struct TpsHistory {
+    chain: Chain,
+    resolution: u64,
+    points: Vec<HistoryPoint>,
+}
+
+#[derive(Serialize, Debug)]
+struct HistoryPoint {
+    /// The start of the period
+    timestamp: u64,
+    /// The mean TPS calculated over each window in the period
+    tps: BTreeMap<TpsWindow, f64>,
+    /// How many calculations were averaged
+    num_points: usize,
+}
+
+#[derive(Serialize, Debug)]
+struct AllTps {
+    /// The latest summed TPS of the mainnets
+    aggregate: Option<AggregateTpsPoint>,
+    chains: Vec<ChainTps>,
+}
+
+/// `GET /v1/chains`: the chains with published TPS, and their metadata.
+#[get("/chains")]
+async fn chains(
+    db: &State<Arc<dyn Db>>,
+    overrides: &State<MetadataOverrides>,
+) -> Result<Json<Vec<ChainSummary>>, Status> {
+    let mut summaries = vec![];
+    for chain in tracked_chains() {
+        if let Some(chain_tps) = load_chain_tps(chain, db, false).await? {
+            summaries.push(ChainSummary {
+                chain,
+                metadata: metadata::chain_metadata(chain, &overrides.0),
+                mainnet: chain.mainnet(),
+                calculated_at: chain_tps.calculated_at,
+                stale: chain_tps.stale,
+            });
+        }
+    }
+    Ok(Json(summaries))
+}
+
+/// `GET /v1/tps`: every chain's published TPS over each window, and the
+/// aggregate.
+#[get("/tps")]
+async fn tps(db: &State<Arc<dyn Db>>) -> Result<Json<AllTps>, Status> {
+    let mut chains = vec![];
+    for chain in tracked_chains() {
+        chains.extend(load_chain_tps(chain, db, false).await?);
+    }
+    let aggregate = db
+        .load_aggregate_tps_history()
+        .await
+        .map_err(|_| Status::InternalServerError)?
+        .pop();
+    Ok(Json(AllTps { aggregate, chains }))
+}
+
+/// `GET /v1/chains/<chain>/tps`: a chain's published TPS over each window,
+/// and with `?history=true` its TPS history. Not found for unknown chains
+/// and chains without TPS.
+#[get("/chains/<chain>/tps?<history>")]
+async fn chain_tps(
+    chain: &str,
+    history: Option<bool>,
+    db: &State<Arc<dyn Db>>,
+) -> Result<Json<ChainTps>, Status> {
+    let chain = Chain::try_from(chain).map_err(|_| Status::NotFound)?;
+    load_chain_tps(chain, db, history.unwrap_or(false))
+        .await?
+        .map(Json)
+        .ok_or(Status::NotFound)
+}
+
+/// `GET /v1/chains/<chain>/tps/history`: a chain's TPS history from `from`
+/// to `to`, in seconds since the epoch, averaged over each `resolution`
+/// seconds. The resolution is raised to return at most `MAX_HISTORY_POINTS`
+/// points, and by default is the finest that does.
+#[get("/chains/<chain>/tps/history?<from>&<to>&<resolution>")]
+async fn chain_tps_history(
+    chain: &str,
+    from: Option<u64>,
+    to: Option<u64>,
+    resolution: Option<u64>,
+    db: &State<Arc<dyn Db>>,
+) -> Result<Json<TpsHistory>, Status> {
+    let chain = Chain::try_from(chain).map_err(|_| Status::NotFound)?;
+    let history = db
+        .load_tps_history(chain)
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    let from = from
+        .or_else(|| history.first().map(|point| point.timestamp))
+        .unwrap_or_default();
+    let to = to.unwrap_or(u64::MAX);
+    if from > to || resolution == Some(0) {
+        return Err(Status::BadRequest);
+    }
+
+    let last = history.last().map_or(from, |point| point.timestamp.min(to));
+    let span = last.saturating_sub(from) + 1;
+    let min_resolution = span.div_ceil(MAX_HISTORY_POINTS);
+    let resolution = resolution.unwrap_or(1).max(min_resolution);
+
+    // The history is oldest first, so each period's points are together
+    let mut periods: Vec<(u64, Vec<&TpsPoint>)> = vec![];
+    for point in history
+        .iter()
+        .filter(|point| (from..=to).contains(&point.timestamp))
+    {
+        let timestamp = from + (point.timestamp - from) / resolution * resolution;
+        match periods.last_mut() {
+            Some((period, period_points)) if *period == timestamp => period_points.push(point),
+            _ => periods.push((timestamp, vec![point])),
+        }
+    }
+    let points = periods
+        .into_iter()
+        .map(|(timestamp, period_points)| mean_point(timestamp, &period_points))
+        .collect();
+
+    Ok(Json(TpsHistory {
+        chain,
+        resolution,
+        points,
+    }))
+}
+
+/// `GET /v1/updates`: live updates as server-sent events, named `tps` when a
+/// chain's TPS is recalculated and `status` when it stalls or recovers, each
+/// with the update as JSON.
+#[get("/updates")]
+fn updates(live_updates: &State<LiveUpdates>, mut shutdown: Shutdown) -> EventStream![] {
+    let mut receiver = live_updates.subscribe();
+    EventStream! {
+        loop {
+            let update = tokio::select! {
+                update = receiver.recv() => match update {
+                    Ok(update) => update,
+                    // Too slow to keep up, so some updates are missed, but
+                    // later ones are still sent
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!("event stream client missed {} live updates", missed);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                _ = &mut shutdown => break,
+            };
+            yield Event::json(&update).event(update.kind());
+        }
+    }
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![chains, tps, chain_tps, chain_tps_history, updates]
+}
+
+/// Each window's TPS averaged over the points it was calculated in.
+fn mean_point(timestamp: u64, points: &[&TpsPoint]) -> HistoryPoint {
+    let mut sums: BTreeMap<TpsWindow, (f64, u32)> = BTreeMap::new();
+    for point in points {
+        for (window, tps) in &point.tps {
+            let (sum, count) = sums.entry(*window).or_default();
+            *sum += tps;
+            *count += 1;
+        }
+    }
+    HistoryPoint {
+        timestamp,
+        tps: sums
+            .into_iter()
+            .map(|(window, (sum, count))| (window, sum / f64::from(count)))
+            .collect(),
+        num_points: points.len(),
+    }
+}
+
+fn tracked_chains() -> Vec<Chain> {
+    Chain::all_chains()
+        .into_iter()
+        .chain(Chain::testnet_chains())
+        .collect()
+}
+
+/// A chain's published TPS, `None` if it has none.
+async fn load_chain_tps(
+    chain: Chain,
+    db: &Arc<dyn Db>,
+    history: bool,
+) -> Result<Option<ChainTps>, Status> {
+    let internal_error = |_| Status::InternalServerError;
+    let mut tps = BTreeMap::new();
+    let mut gas_per_second = BTreeMap::new();
+    for window in TpsWindow::all_windows() {
+        if let Some(window_tps) = db.load_tps(chain, window).await.map_err(internal_error)? {
+            tps.insert(window, window_tps);
+        }
+        if let Some(window_gas) = db
+            .load_gas_per_second(chain, window)
+            .await
+            .map_err(internal_error)?
+        {
+            gas_per_second.insert(window, window_gas);
+        }
+    }
+    if tps.is_empty() {
+        return Ok(None);
+    }
+
+    let calculation_log = db
+        .load_calculation_log(chain)
+        .await
+        .map_err(internal_error)?;
+    let history = match history {
+        true => Some(db.load_tps_history(chain).await.map_err(internal_error)?),
+        false => None,
+    };
+    Ok(Some(ChainTps {
+        chain,
+        tps,
+        gas_per_second,
+        calculated_at: calculation_log.as_ref().map(|log| log.calculating_end),
+        stale: calculation_log.is_some_and(|log| log.stale),
+        history,
+    }))
+}
+
+#[cfg(test)]
+mod test_api {
+    use super::MAX_HISTORY_POINTS;
+    use crate::live::{LiveUpdate, LiveUpdates};
+    use crate::test_helpers::{
+        calculated_at, client, connect_websocket, next_message, store_tps, TIMEOUT,
+    };
+    use realtps_common::{
+        chain::Chain,
+        db::{AggregateTpsPoint, Db, TpsPoint, TpsWindow},
+        memory_db::MemoryDb,
+    };
+    use rocket::http::{ContentType, Status};
+    use rocket::serde::json::Value;
+    use rocket::tokio::io::AsyncReadExt;
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+
+    #[rocket::async_test]
+    async fn list_chains_with_tps() {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let client = client(db.clone()).await;
+
+        let response = client.get("/v1/chains").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            response.into_json::<Value>().await,
+            Some(Value::Array(vec![]))
+        );
+
+        store_tps(&db, Chain::Ethereum, 15.0).await;
+        store_tps(&db, Chain::EthereumSepolia, 5.0).await;
+        let response = client.get("/v1/chains").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let chains = response.into_json::<Value>().await.expect("json");
+        let chains = chains.as_array().expect("array");
+        assert_eq!(chains.len(), 2);
+        assert_eq!(chains[0]["chain"], "ethereum");
+        assert_eq!(chains[0]["name"], "Ethereum");
+        assert_eq!(chains[0]["mainnet"], Value::Null);
+        assert_eq!(chains[0]["stale"], false);
+        assert_eq!(chains[1]["chain"], "ethereum-sepolia");
+        assert_eq!(chains[1]["mainnet"], "ethereum");
+    }
+
+    #[rocket::async_test]
+    async fn all_tps_with_the_aggregate() {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        store_tps(&db, Chain::Ethereum, 15.0).await;
+        let aggregate = AggregateTpsPoint {
+            timestamp: 1_700_000_000,
+            num_chains: 1,
+            tps: BTreeMap::from([(TpsWindow::Week, 15.0)]),
+            categories: BTreeMap::new(),
+        };
+        db.append_aggregate_tps(&aggregate)
+            .await
+            .expect("aggregate");
+        let client = client(db).await;
+
+        let response = client.get("/v1/tps").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let tps = response.into_json::<Value>().await.expect("json");
+        assert_eq!(tps["aggregate"]["tps"]["week"], 15.0);
+        let chains = tps["chains"].as_array().expect("array");
+        assert_eq!(chains.len(), 1);
+        assert_eq!(chains[0]["chain"], "ethereum");
+        assert_eq!(chains[0]["tps"]["hour"], 15.0);
+    }
+
+    #[rocket::async_test]
+    async fn one_chains_tps() {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        store_tps(&db, Chain::Ethereum, 15.0).await;
+        let point = TpsPoint {
+            timestamp: 1_700_000_000,
+            tps: BTreeMap::from([(TpsWindow::Week, 15.0)]),
+        };
+        db.append_tps_history(Chain::Ethereum, &point)
+            .await
+            .expect("history");
+        let client = client(db).await;
+
+        let response = client.get("/v1/chains/ethereum/tps").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let tps = response.into_json::<Value>().await.expect("json");
+        assert_eq!(tps["chain"], "ethereum");
+        assert_eq!(tps["tps"]["week"], 15.0);
+        assert_eq!(tps["calculated_at"], "2023-11-14T22:13:20Z");
+        assert!(tps.get("history").is_none());
+
+        let response = client
+            .get("/v1/chains/ethereum/tps?history=true")
+            .dispatch()
+            .await;
+        let tps = response.into_json::<Value>().await.expect("json");
+        assert_eq!(tps["history"][0]["timestamp"], 1_700_000_000);
+    }
+
+    #[rocket::async_test]
+    async fn unknown_chains_and_chains_without_tps_are_not_found() {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let client = client(db).await;
+        for uri in [
+            "/v1/chains/nochain/tps",
+            "/v1/chains/ethereum/tps",
+            "/v1/chains/nochain/tps/history",
+        ] {
+            let response = client.get(uri).dispatch().await;
+            assert_eq!(response.status(), Status::NotFound, "{}", uri);
+        }
+    }
+
+    /// Stores an hourly TPS at each timestamp.
+    async fn store_history(db: &Arc<dyn Db>, points: &[(u64, f64)]) {
+        for (timestamp, tps) in points {
+            let point = TpsPoint {
+                timestamp: *timestamp,
+                tps: BTreeMap::from([(TpsWindow::Hour, *tps)]),
+            };
+            db.append_tps_history(Chain::Ethereum, &point)
+                .await
+                .expect("history");
+        }
+    }
+
+    #[rocket::async_test]
+    async fn history_averaged_over_each_period() {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        store_history(&db, &[(1000, 1.0), (1010, 3.0), (1020, 5.0), (1030, 7.0)]).await;
+        let client = client(db).await;
+
+        let response = client
+            .get("/v1/chains/ethereum/tps/history?resolution=20")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let history = response.into_json::<Value>().await.expect("json");
+        assert_eq!(history["chain"], "ethereum");
+        assert_eq!(history["resolution"], 20);
+        let points = history["points"].as_array().expect("points");
+        assert_eq!(points.len(), 2);
+        assert_eq!(points[0]["timestamp"], 1000);
+        assert_eq!(points[0]["tps"]["hour"], 2.0);
+        assert_eq!(points[0]["num_points"], 2);
+        assert_eq!(points[1]["timestamp"], 1020);
+        assert_eq!(points[1]["tps"]["hour"], 6.0);
+
+        // Periods start at `from`, and points after `to` are left out
+        let response = client
+            .get("/v1/chains/ethereum/tps/history?from=1010&to=1025&resolution=20")
+            .dispatch()
+            .await;
+        let history = response.into_json::<Value>().await.expect("json");
+        let points = history["points"].as_array().expect("points");
+        assert_eq!(points.len(), 1);
+        assert_eq!(points[0]["timestamp"], 1010);
+        assert_eq!(points[0]["tps"]["hour"], 4.0);
+
+        // By default, every point
+        let response = client
+            .get("/v1/chains/ethereum/tps/history")
+            .dispatch()
+            .await;
+        let history = response.into_json::<Value>().await.expect("json");
+        assert_eq!(history["resolution"], 1);
+        assert_eq!(history["points"].as_array().expect("points").len(), 4);
+    }
+
+    #[rocket::async_test]
+    async fn wide_history_ranges_are_downsampled() {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let points: Vec<(u64, f64)> = (0..3000).map(|i| (i * 60, 1.0)).collect();
+        store_history(&db, &points).await;
+        let client = client(db).await;
+
+        // Too fine for the range, so raised
+        let response = client
+            .get("/v1/chains/ethereum/tps/history?from=0&to=1000000&resolution=1")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let history = response.into_json::<Value>().await.expect("json");
+        assert_eq!(history["resolution"], 180);
+        let points = history["points"].as_array().expect("points");
+        assert!(points.len() <= MAX_HISTORY_POINTS as usize);
+        let num_points: u64 = points
+            .iter()
+            .map(|point| point["num_points"].as_u64().expect("num_points"))
+            .sum();
+        assert_eq!(num_points, 3000);
+    }
+
+    #[rocket::async_test]
+    async fn bad_history_ranges_are_rejected() {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        store_history(&db, &[(1000, 1.0)]).await;
+        let client = client(db).await;
+
+        for uri in [
+            "/v1/chains/ethereum/tps/history?from=2000&to=1000",
+            "/v1/chains/ethereum/tps/history?resolution=0",
+        ] {
+            let response = client.get(uri).dispatch().await;
+            assert_eq!(response.status(), Status::BadRequest, "{}", uri);
+        }
+    }
+
+    #[rocket::async_test]
+    async fn updates_are_sent_as_events_and_to_websockets() {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let client = client(db).await;
+        let live_updates = client
+            .rocket()
+            .state::<LiveUpdates>()
+            .expect("live updates")
+            .clone();
+        let mut websocket = connect_websocket(live_updates.clone()).await;
+        let mut response = client.get("/v1/updates").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::EventStream));
+
+        live_updates.send(LiveUpdate::Tps {
+            chain: Chain::Ethereum,
+            tps: BTreeMap::from([(TpsWindow::Week, 15.0)]),
+            calculated_at: calculated_at(),
+        });
+
+        // Each event ends with a blank line
+        let mut events = vec![];
+        while !events.ends_with(b"\n\n") {
+            let mut buf = [0; 1024];
+            let read = tokio::time::timeout(TIMEOUT, response.read(&mut buf))
+                .await
+                .expect("event")
+                .expect("read");
+            assert_ne!(read, 0, "event stream ended");
+            events.extend_from_slice(&buf[..read]);
+        }
+        let events = String::from_utf8(events).expect("utf8");
+        // Skipping comments, which Rocket sends as heartbeats
+        let lines: Vec<&str> = events
+            .lines()
+            .filter(|line| !line.starts_with(':'))
+            .collect();
+        assert_eq!(lines.len(), 3, "{:?}", events);
+        assert_eq!(lines[0], "event:tps");
+        assert_eq!(lines[2], "");
+        let data = lines[1].strip_prefix("data:").expect("data");
+        let data: Value = rocket::serde::json::from_str(data).expect("json");
+        assert_eq!(data["type"], "tps");
+        assert_eq!(data["chain"], "ethereum");
+        assert_eq!(data["tps"]["week"], 15.0);
+
+        // The same update, from the same source
+        assert_eq!(next_message(&mut websocket).await, data);
+    }
+}
@@ -0,0 +1,714 @@
+//! A GraphQL API at `/graphql`, for queries the JSON API doesn't fit, like a
+//! chain's blocks, or a few fields of many chains at once.
+
+use async_graphql::connection::{query, Connection, Edge};
+use async_graphql::{
+    Context, EmptyMutation, EmptySubscription, Enum, Error, Object, OutputType, Result, Schema,
+    SimpleObject,
+};
+use chrono::{DateTime, Utc};
+use realtps_common::{
+    chain::Chain,
+    db::{self, Db, TpsWindow},
+    metadata::{self, ChainKind, ChainMetadata, MetadataOverride},
+};
+use rocket::serde::json::Json;
+use rocket::{Route, State};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// A page's size when a query doesn't give one.
+const DEFAULT_PAGE_SIZE: usize = 100;
+
+/// The most items returned in one page.
+const MAX_PAGE_SIZE: usize = 1000;
+
+/// How deeply queries may nest, to bound the work one query asks for.
+const MAX_QUERY_DEPTH: usize = 8;
+
+pub type RealtpsSchema = Schema<Query, EmptyMutation, EmptySubscription>;
+
+/// The schema, answering from `db` with the metadata `overrides`.
+pub fn schema(db: Arc<dyn Db>, overrides: HashMap<Chain, MetadataOverride>) -> RealtpsSchema {
+    Schema::build(Query, EmptyMutation, EmptySubscription)
+        .data(db)
+        .data(overrides)
+        .limit_depth(MAX_QUERY_DEPTH)
+        .finish()
+}
+
+/// `POST /graphql`: a GraphQL query, as JSON like `{"query": "{ ... }"}`.
+/// Errors in the query are answered in the response's `errors`.
+#[post("/graphql", data = "<request>")]
+async fn graphql(
+    schema: &State<RealtpsSchema>,
+    request: Json<async_graphql::Request>,
+) -> Json<async_graphql::Response> {
+    Json(schema.execute(request.into_inner()).await)
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![graphql]
+}
+
+#[derive(Enum, Copy, Clone, Eq, PartialEq)]
+#[graphql(name = "TpsWindow", remote = "TpsWindow")]
+enum GqlTpsWindow {
+    Hour,
+    Day,
+    Week,
+    Month,
+}
+
+#[derive(Enum, Copy, Clone, Eq, PartialEq)]
+#[graphql(name = "ChainKind", remote = "ChainKind")]
+enum GqlChainKind {
+    L1,
+    L2,
+    Sidechain,
+}
+
+pub struct Query;
+
+#[Object]
+impl Query {
+    /// The chains with published TPS, optionally only of one kind, and only
+    /// mainnets or testnets.
+    #[allow(clippy::too_many_arguments)]
+    async fn chains(
+        &self,
+        ctx: &Context<'_>,
+        kind: Option<GqlChainKind>,
+        testnet: Option<bool>,
+        after: Option<String>,
+        before: Option<String>,
+        first: Option<i32>,
+        last: Option<i32>,
+    ) -> Result<Connection<usize, GqlChain>> {
+        let db = ctx.data::<Arc<dyn Db>>()?;
+        let overrides = ctx.data::<HashMap<Chain, MetadataOverride>>()?;
+        let mut chains = vec![];
+        for chain in Chain::all_chains()
+            .into_iter()
+            .chain(Chain::testnet_chains())
+        {
+            if testnet.is_some_and(|testnet| testnet != chain.is_testnet()) {
+                continue;
+            }
+            let metadata = metadata::chain_metadata(chain, overrides);
+            if kind.is_some_and(|kind| ChainKind::from(kind) != metadata.kind) {
+                continue;
+            }
+            if db.load_tps(chain, TpsWindow::Week).await?.is_some() {
+                chains.push(GqlChain { chain, metadata });
+            }
+        }
+        paginate(chains, after, before, first, last).await
+    }
+
+    /// A chain by its id, like `ethereum`, whether or not it has TPS.
+    async fn chain(&self, ctx: &Context<'_>, id: String) -> Result<GqlChain> {
+        let overrides = ctx.data::<HashMap<Chain, MetadataOverride>>()?;
+        let chain = Chain::try_from(id.as_str())?;
+        Ok(GqlChain {
+            chain,
+            metadata: metadata::chain_metadata(chain, overrides),
+        })
+    }
+}
+
+#[derive(SimpleObject)]
+#[graphql(name = "WindowTps")]
+struct GqlWindowTps {
+    window: GqlTpsWindow,
+    tps: f64,
+    gas_per_second: Option<f64>,
+}
+
+/// A calculation result, in a chain's TPS history.
+#[derive(SimpleObject)]
+#[graphql(name = "TpsPoint")]
+struct GqlTpsPoint {
+    /// The newest block's timestamp when calculated
+    timestamp: u64,
+    tps: Vec<GqlWindowTps>,
+}
+
+/// A stored block. Values the chain's client doesn't report are null.
+#[derive(SimpleObject)]
+#[graphql(name = "Block")]
+struct GqlBlock {
+    number: u64,
+    timestamp: u64,
+    hash: String,
+    parent_hash: String,
+    num_txs: u64,
+    num_vote_txs: Option<u64>,
+    num_txs_failed: Option<u64>,
+    gas_used: Option<u64>,
+    gas_limit: Option<u64>,
+    fees: Option<u64>,
+    num_token_transfers: Option<u64>,
+    producer: Option<String>,
+}
+
+impl From<db::Block> for GqlBlock {
+    fn from(block: db::Block) -> GqlBlock {
+        GqlBlock {
+            number: block.block_number,
+            timestamp: block.timestamp,
+            hash: block.hash,
+            parent_hash: block.parent_hash,
+            num_txs: block.num_txs,
+            num_vote_txs: block.num_vote_txs,
+            num_txs_failed: block.num_txs_failed,
+            gas_used: block.gas_used,
+            gas_limit: block.gas_limit,
+            fees: block.fees,
+            num_token_transfers: block.num_token_transfers,
+            producer: block.producer,
+        }
+    }
+}
+
+/// Statistics of the blocks in a window, null where any block lacks the
+/// count.
+#[derive(SimpleObject)]
+#[graphql(name = "WindowStats")]
+struct GqlWindowStats {
+    window: GqlTpsWindow,
+    fee_per_tx: Option<f64>,
+    fees_per_second: Option<f64>,
+    token_transfers_per_second: Option<f64>,
+    empty_block_share: Option<f64>,
+    utilization: Option<f64>,
+    anomalous_timestamps: u64,
+    coverage: Option<f64>,
+}
+
+/// Statistics of the blocks in the shortest window.
+#[derive(SimpleObject)]
+#[graphql(name = "BlockStats")]
+struct GqlBlockStats {
+    mean_block_time: Option<f64>,
+    median_block_time: Option<f64>,
+    median_txs_per_block: Option<u64>,
+    p99_txs_per_block: Option<u64>,
+    max_txs_per_block: Option<u64>,
+    num_producers: Option<u64>,
+    nakamoto_coefficient: Option<u64>,
+}
+
+#[derive(SimpleObject)]
+#[graphql(name = "ChainStats")]
+struct GqlChainStats {
+    windows: Vec<GqlWindowStats>,
+    blocks: GqlBlockStats,
+}
+
+impl From<db::ChainStats> for GqlChainStats {
+    fn from(stats: db::ChainStats) -> GqlChainStats {
+        GqlChainStats {
+            windows: stats
+                .windows
+                .into_iter()
+                .map(|(window, window_stats)| GqlWindowStats {
+                    window: window.into(),
+                    fee_per_tx: window_stats.fee_per_tx,
+                    fees_per_second: window_stats.fees_per_second,
+                    token_transfers_per_second: window_stats.token_transfers_per_second,
+                    empty_block_share: window_stats.empty_block_share,
+                    utilization: window_stats.utilization,
+                    anomalous_timestamps: window_stats.anomalous_timestamps,
+                    coverage: window_stats.coverage,
+                })
+                .collect(),
+            blocks: GqlBlockStats {
+                mean_block_time: stats.block_times.as_ref().map(|times| times.mean),
+                median_block_time: stats.block_times.as_ref().map(|times| times.median),
+                median_txs_per_block: stats.txs_per_block.as_ref().map(|txs| txs.p50),
+                p99_txs_per_block: stats.txs_per_block.as_ref().map(|txs| txs.p99),
+                max_txs_per_block: stats.txs_per_block.as_ref().map(|txs| txs.max),
+                num_producers: stats.producers.as_ref().map(|p| p.num_producers),
+                nakamoto_coefficient: stats.producers.as_ref().map(|p| p.nakamoto_coefficient),
+            },
+        }
+    }
+}
+
+#[derive(Clone)]
+struct GqlChain {
+    chain: Chain,
+    metadata: ChainMetadata,
+}
+
+#[Object(name = "Chain")]
+impl GqlChain {
+    async fn id(&self) -> String {
+        self.chain.to_string()
+    }
+
+    async fn name(&self) -> &str {
+        &self.metadata.name
+    }
+
+    async fn ticker(&self) -> &str {
+        &self.metadata.ticker
+    }
+
+    async fn kind(&self) -> GqlChainKind {
+        self.metadata.kind.into()
+    }
+
+    async fn website(&self) -> Option<&str> {
+        self.metadata.website.as_deref()
+    }
+
+    async fn explorer_url(&self) -> Option<&str> {
+        self.metadata.explorer_url.as_deref()
+    }
+
+    /// For testnets, the id of the mainnet they test
+    async fn mainnet(&self) -> Option<String> {
+        self.chain.mainnet().map(|mainnet| mainnet.to_string())
+    }
+
+    /// The published TPS over each window, or only `window`
+    async fn tps(
+        &self,
+        ctx: &Context<'_>,
+        window: Option<GqlTpsWindow>,
+    ) -> Result<Vec<GqlWindowTps>> {
+        let db = ctx.data::<Arc<dyn Db>>()?;
+        let mut tps = vec![];
+        for tps_window in TpsWindow::all_windows() {
+            if window.is_some_and(|window| TpsWindow::from(window) != tps_window) {
+                continue;
+            }
+            if let Some(window_tps) = db.load_tps(self.chain, tps_window).await? {
+                tps.push(GqlWindowTps {
+                    window: tps_window.into(),
+                    tps: window_tps,
+                    gas_per_second: db.load_gas_per_second(self.chain, tps_window).await?,
+                });
+            }
+        }
+        Ok(tps)
+    }
+
+    async fn calculated_at(&self, ctx: &Context<'_>) -> Result<Option<DateTime<Utc>>> {
+        let db = ctx.data::<Arc<dyn Db>>()?;
+        let log = db.load_calculation_log(self.chain).await?;
+        Ok(log.map(|log| log.calculating_end))
+    }
+
+    /// The chain is disabled, so its TPS is no longer updated
+    async fn stale(&self, ctx: &Context<'_>) -> Result<bool> {
+        let db = ctx.data::<Arc<dyn Db>>()?;
+        let log = db.load_calculation_log(self.chain).await?;
+        Ok(log.is_some_and(|log| log.stale))
+    }
+
+    async fn stats(&self, ctx: &Context<'_>) -> Result<Option<GqlChainStats>> {
+        let db = ctx.data::<Arc<dyn Db>>()?;
+        let stats = db.load_chain_stats(self.chain).await?;
+        Ok(stats.map(GqlChainStats::from))
+    }
+
+    /// The TPS history, oldest first, from `from` to `to` in seconds since
+    /// the epoch.
+    #[allow(clippy::too_many_arguments)]
+    async fn history(
+        &self,
+        ctx: &Context<'_>,
+        from: Option<u64>,
+        to: Option<u64>,
+        after: Option<String>,
+        before: Option<String>,
+        first: Option<i32>,
+        last: Option<i32>,
+    ) -> Result<Connection<usize, GqlTpsPoint>> {
+        let db = ctx.data::<Arc<dyn Db>>()?;
+        let range = from.unwrap_or(0)..=to.unwrap_or(u64::MAX);
+        let points = db
+            .load_tps_history(self.chain)
+            .await?
+            .into_iter()
+            .filter(|point| range.contains(&point.timestamp))
+            .map(|point| GqlTpsPoint {
+                timestamp: point.timestamp,
+                tps: point
+                    .tps
+                    .into_iter()
+                    .map(|(window, tps)| GqlWindowTps {
+                        window: window.into(),
+                        tps,
+                        gas_per_second: None,
+                    })
+                    .collect(),
+            })
+            .collect();
+        paginate(points, after, before, first, last).await
+    }
+
+    /// The stored blocks, newest first, from `to`, by default the newest, back
+    /// to `from`. Each block's cursor is its number.
+    async fn blocks(
+        &self,
+        ctx: &Context<'_>,
+        from: Option<u64>,
+        to: Option<u64>,
+        after: Option<String>,
+        first: Option<i32>,
+    ) -> Result<Connection<usize, GqlBlock>> {
+        let db = ctx.data::<Arc<dyn Db>>()?;
+        let chain = self.chain;
+        let from = from.unwrap_or(0);
+        query(
+            after,
+            None,
+            first,
+            None,
+            |after: Option<usize>, _, first, _| async move {
+                let page_size = page_size(first);
+                let mut next = match after {
+                    Some(after) => match db.load_block(chain, after as u64).await? {
+                        Some(block) => block.prev_block_number,
+                        None => return Err(Error::new("unknown cursor")),
+                    },
+                    None => {
+                        let highest = db.load_highest_block_number(chain).await?;
+                        highest.map(|highest| to.map_or(highest, |to| to.min(highest)))
+                    }
+                };
+                let mut blocks = vec![];
+                while let Some(block_number) = next.filter(|number| *number >= from) {
+                    if blocks.len() == page_size {
+                        break;
+                    }
+                    // Older blocks may have been pruned
+                    let Some(block) = db.load_block(chain, block_number).await? else {
+                        next = None;
+                        break;
+                    };
+                    next = block.prev_block_number;
+                    blocks.push(block);
+                }
+
+                let has_next_page = next.is_some_and(|number| number >= from);
+                let mut connection = Connection::new(after.is_some(), has_next_page);
+                connection.edges.extend(
+                    blocks
+                        .into_iter()
+                        .map(|block| Edge::new(block.block_number as usize, GqlBlock::from(block))),
+                );
+                Ok::<_, Error>(connection)
+            },
+        )
+        .await
+    }
+}
+
+/// `first`, or the default, capped at the maximum.
+fn page_size(first: Option<usize>) -> usize {
+    first.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE)
+}
+
+/// A page of `items`, each's cursor its index, from `first`/`after` or
+/// `last`/`before`.
+async fn paginate<T: OutputType>(
+    items: Vec<T>,
+    after: Option<String>,
+    before: Option<String>,
+    first: Option<i32>,
+    last: Option<i32>,
+) -> Result<Connection<usize, T>> {
+    query(
+        after,
+        before,
+        first,
+        last,
+        |after: Option<usize>, before: Option<usize>, first, last| async move {
+            let mut start = after.map_or(0, |after| after + 1).min(items.len());
+            let mut end = before.unwrap_or(items.len()).clamp(start, items.len());
+            match last {
+                Some(last) if first.is_none() => {
+                    start = end.saturating_sub(last.min(MAX_PAGE_SIZE)).max(start);
+                }
+                _ => end = end.min(start + page_size(first)),
+            }
+
+            let mut connection = Connection::new(start > 0, end < items.len());
+            connection.edges.extend(
+                items
+                    .into_iter()
+                    .enumerate()
+                    .skip(start)
+                    .take(end - start)
+                    .map(|(index, item)| Edge::new(index, item)),
+            );
+            Ok::<_, Error>(connection)
+        },
+    )
+    .await
+}
+
+#[cfg(test)]
+mod test_graphql {
+    use super::*;
+    use async_graphql::Value;
+    use chrono::TimeZone;
+    use realtps_common::{
+        db::{CalculationLog, TpsPoint},
+        memory_db::MemoryDb,
+    };
+    use rocket::http::Status;
+    use rocket::local::asynchronous::Client;
+    use rocket::serde::json::serde_json;
+    use std::collections::BTreeMap;
+
+    async fn store_tps(db: &Arc<dyn Db>, chain: Chain, tps: f64) {
+        for window in TpsWindow::all_windows() {
+            db.store_tps(chain, window, tps).await.expect("tps");
+        }
+        let calculated_at = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let log = CalculationLog {
+            calculating_start: calculated_at,
+            calculating_end: calculated_at,
+            newest_block_timestamp: calculated_at,
+            oldest_block_timestamp: calculated_at,
+            stale: false,
+        };
+        db.store_calculation_log(chain, &log)
+            .await
+            .expect("calculation log");
+    }
+
+    async fn execute(db: Arc<dyn Db>, query: &str) -> serde_json::Value {
+        let response = schema(db, HashMap::new()).execute(query).await;
+        assert!(response.errors.is_empty(), "{:?}", response.errors);
+        response.data.into_json().expect("json")
+    }
+
+    async fn execute_error(db: Arc<dyn Db>, query: &str) -> String {
+        let response = schema(db, HashMap::new()).execute(query).await;
+        assert_eq!(response.data, Value::Null);
+        response.errors[0].message.clone()
+    }
+
+    #[rocket::async_test]
+    async fn chains_filtered_and_paginated() {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        store_tps(&db, Chain::Ethereum, 15.0).await;
+        store_tps(&db, Chain::Polygon, 50.0).await;
+        store_tps(&db, Chain::EthereumSepolia, 10.0).await;
+
+        let data = execute(db.clone(), "{ chains { edges { node { id } } } }").await;
+        let ids: Vec<&str> = data["chains"]["edges"]
+            .as_array()
+            .expect("edges")
+            .iter()
+            .map(|edge| edge["node"]["id"].as_str().expect("id"))
+            .collect();
+        assert_eq!(ids, ["ethereum", "polygon", "ethereum-sepolia"]);
+
+        let data = execute(
+            db.clone(),
+            "{ chains(testnet: true) { edges { node { id mainnet } } } }",
+        )
+        .await;
+        assert_eq!(
+            data["chains"]["edges"][0]["node"],
+            serde_json::json!({ "id": "ethereum-sepolia", "mainnet": "ethereum" })
+        );
+
+        let data = execute(
+            db.clone(),
+            "{ chains(testnet: false, first: 1) {
+                edges { cursor node { id } }
+                pageInfo { hasNextPage endCursor }
+            } }",
+        )
+        .await;
+        let chains = &data["chains"];
+        assert_eq!(chains["edges"].as_array().expect("edges").len(), 1);
+        assert_eq!(chains["edges"][0]["node"]["id"], "ethereum");
+        assert_eq!(chains["pageInfo"]["hasNextPage"], true);
+
+        let after = chains["pageInfo"]["endCursor"].as_str().expect("cursor");
+        let data = execute(
+            db,
+            &format!(
+                r#"{{ chains(testnet: false, first: 1, after: "{}") {{
+                    edges {{ node {{ id }} }}
+                    pageInfo {{ hasNextPage }}
+                }} }}"#,
+                after
+            ),
+        )
+        .await;
+        assert_eq!(data["chains"]["edges"][0]["node"]["id"], "polygon");
+        assert_eq!(data["chains"]["pageInfo"]["hasNextPage"], false);
+    }
+
+    #[rocket::async_test]
+    async fn a_chains_tps_and_metadata() {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        store_tps(&db, Chain::Ethereum, 15.0).await;
+
+        let data = execute(
+            db.clone(),
+            r#"{ chain(id: "ethereum") {
+                name kind calculatedAt stale
+                tps(window: WEEK) { window tps }
+            } }"#,
+        )
+        .await;
+        assert_eq!(
+            data["chain"],
+            serde_json::json!({
+                "name": "Ethereum",
+                "kind": "L1",
+                "calculatedAt": "2023-11-14T22:13:20+00:00",
+                "stale": false,
+                "tps": [{ "window": "WEEK", "tps": 15.0 }],
+            })
+        );
+
+        let message = execute_error(db, r#"{ chain(id: "nochain") { name } }"#).await;
+        assert!(message.contains("nochain"), "{}", message);
+    }
+
+    #[rocket::async_test]
+    async fn history_in_a_range() {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        for timestamp in [1000, 2000, 3000, 4000] {
+            let point = TpsPoint {
+                timestamp,
+                tps: BTreeMap::from([(TpsWindow::Hour, timestamp as f64 / 100.0)]),
+            };
+            db.append_tps_history(Chain::Ethereum, &point)
+                .await
+                .expect("history");
+        }
+
+        let data = execute(
+            db,
+            r#"{ chain(id: "ethereum") {
+                history(from: 2000, to: 4000, last: 2) {
+                    edges { node { timestamp tps { window tps } } }
+                    pageInfo { hasPreviousPage }
+                }
+            } }"#,
+        )
+        .await;
+        let history = &data["chain"]["history"];
+        let timestamps: Vec<u64> = history["edges"]
+            .as_array()
+            .expect("edges")
+            .iter()
+            .map(|edge| edge["node"]["timestamp"].as_u64().expect("timestamp"))
+            .collect();
+        assert_eq!(timestamps, [3000, 4000]);
+        assert_eq!(history["edges"][0]["node"]["tps"][0]["tps"], 30.0);
+        assert_eq!(history["pageInfo"]["hasPreviousPage"], true);
+    }
+
+    #[rocket::async_test]
+    async fn blocks_newest_first() {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        for block_number in 10..=20 {
+            let block: db::Block = serde_json::from_value(serde_json::json!({
+                "chain": "ethereum",
+                "block_number": block_number,
+                "prev_block_number": block_number - 1,
+                "timestamp": 1000 + block_number * 12,
+                "num_txs": block_number * 2,
+                "hash": format!("0x{}", block_number),
+                "parent_hash": format!("0x{}", block_number - 1),
+            }))
+            .expect("block");
+            db.store_block(block).await.expect("block");
+        }
+        db.store_highest_block_number(Chain::Ethereum, 20)
+            .await
+            .expect("highest");
+
+        let data = execute(
+            db.clone(),
+            r#"{ chain(id: "ethereum") {
+                blocks(to: 18, first: 3) {
+                    edges { node { number numTxs } }
+                    pageInfo { hasNextPage endCursor }
+                }
+            } }"#,
+        )
+        .await;
+        let blocks = &data["chain"]["blocks"];
+        let numbers: Vec<u64> = blocks["edges"]
+            .as_array()
+            .expect("edges")
+            .iter()
+            .map(|edge| edge["node"]["number"].as_u64().expect("number"))
+            .collect();
+        assert_eq!(numbers, [18, 17, 16]);
+        assert_eq!(blocks["edges"][0]["node"]["numTxs"], 36);
+        assert_eq!(blocks["pageInfo"]["hasNextPage"], true);
+
+        // Stops at the oldest stored block
+        let after = blocks["pageInfo"]["endCursor"].as_str().expect("cursor");
+        let data = execute(
+            db,
+            &format!(
+                r#"{{ chain(id: "ethereum") {{
+                    blocks(first: 100, after: "{}") {{
+                        edges {{ node {{ number }} }}
+                        pageInfo {{ hasNextPage }}
+                    }}
+                }} }}"#,
+                after
+            ),
+        )
+        .await;
+        let blocks = &data["chain"]["blocks"];
+        assert_eq!(blocks["edges"].as_array().expect("edges").len(), 6);
+        assert_eq!(blocks["edges"][5]["node"]["number"], 10);
+        assert_eq!(blocks["pageInfo"]["hasNextPage"], false);
+    }
+
+    #[rocket::async_test]
+    async fn post_a_query() {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        store_tps(&db, Chain::Ethereum, 15.0).await;
+        let rocket = rocket::build()
+            .manage(schema(db, HashMap::new()))
+            .mount("/", routes());
+        let client = Client::tracked(rocket).await.expect("rocket");
+
+        let response = client
+            .post("/graphql")
+            .json(&serde_json::json!({
+                "query": "query($id: String!) { chain(id: $id) { tps(window: HOUR) { tps } } }",
+                "variables": { "id": "ethereum" },
+            }))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let body = response
+            .into_json::<serde_json::Value>()
+            .await
+            .expect("json");
+        assert_eq!(body["data"]["chain"]["tps"][0]["tps"], 15.0);
+
+        let response = client
+            .post("/graphql")
+            .json(&serde_json::json!({ "query": "{ nofield }" }))
+            .dispatch()
+            .await;
+        let body = response
+            .into_json::<serde_json::Value>()
+            .await
+            .expect("json");
+        assert!(body["errors"][0]["message"].is_string());
+    }
+}
@@ -0,0 +1,333 @@
+//! Live updates, pushed to clients as the importer recalculates TPS and as
+//! chains stall or recover, so they don't have to poll.
+
+use chrono::{DateTime, Utc};
+use log::{debug, info, warn};
+use realtps_common::{
+    chain::Chain,
+    db::{Db, TpsWindow},
+};
+use rocket::futures::{SinkExt, StreamExt};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio_tungstenite::tungstenite::Message;
+
+/// How often the db is checked for changes. The importer calculates every
+/// few minutes.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Updates held for a slow client before it starts missing them.
+const CHANNEL_CAPACITY: usize = 256;
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LiveUpdate {
+    /// A chain's TPS was recalculated
+    Tps {
+        chain: Chain,
+        tps: BTreeMap<TpsWindow, f64>,
+        calculated_at: DateTime<Utc>,
+    },
+    /// A chain, or its import, stalled, or recovered
+    Status { chain: Chain, stalled: bool },
+}
+
+impl LiveUpdate {
+    /// The `type` of the update, as serialized
+    pub fn kind(&self) -> &'static str {
+        match self {
+            LiveUpdate::Tps { .. } => "tps",
+            LiveUpdate::Status { .. } => "status",
+        }
+    }
+}
+
+/// Sends each live update to every subscriber.
+#[derive(Clone)]
+pub struct LiveUpdates(broadcast::Sender<LiveUpdate>);
+
+impl LiveUpdates {
+    pub fn new() -> LiveUpdates {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        LiveUpdates(sender)
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<LiveUpdate> {
+        self.0.subscribe()
+    }
+
+    /// Sends `update` to every subscriber. No subscribers is not an error.
+    pub fn send(&self, update: LiveUpdate) {
+        let _ = self.0.send(update);
+    }
+}
+
+/// What was last seen of a chain, to tell when it changes.
+#[derive(Default)]
+struct ChainState {
+    tps: BTreeMap<TpsWindow, f64>,
+    calculated_at: Option<DateTime<Utc>>,
+    stalled: Option<bool>,
+}
+
+/// Polls the db every `poll_interval`, sending an update whenever a chain's
+/// TPS is recalculated or it stalls or recovers. Only changes after the first
+/// poll are sent. Runs while the website does, for the event stream and any
+/// websocket.
+pub async fn watch(db: Arc<dyn Db>, updates: LiveUpdates, poll_interval: Duration) {
+    let mut last_states: HashMap<Chain, ChainState> = HashMap::new();
+    let mut interval = tokio::time::interval(poll_interval);
+    loop {
+        interval.tick().await;
+        for chain in Chain::all_chains()
+            .into_iter()
+            .chain(Chain::testnet_chains())
+        {
+            let state = match load_chain_state(chain, &db).await {
+                Ok(state) => state,
+                Err(e) => {
+                    warn!("unable to load state of chain {}: {}", chain, e);
+                    continue;
+                }
+            };
+            if let Some(last_state) = last_states.get(&chain) {
+                for update in changes(chain, last_state, &state) {
+                    updates.send(update);
+                }
+            }
+            last_states.insert(chain, state);
+        }
+    }
+}
+
+async fn load_chain_state(chain: Chain, db: &Arc<dyn Db>) -> anyhow::Result<ChainState> {
+    let mut tps = BTreeMap::new();
+    for window in TpsWindow::all_windows() {
+        if let Some(window_tps) = db.load_tps(chain, window).await? {
+            tps.insert(window, window_tps);
+        }
+    }
+    let calculated_at = db
+        .load_calculation_log(chain)
+        .await?
+        .map(|log| log.calculating_end);
+    let now = u64::try_from(Utc::now().timestamp()).expect("timestamp");
+    let stalled = db
+        .load_chain_health(chain)
+        .await?
+        .map(|health| health.is_stalled_at(now));
+    Ok(ChainState {
+        tps,
+        calculated_at,
+        stalled,
+    })
+}
+
+fn changes(chain: Chain, last_state: &ChainState, state: &ChainState) -> Vec<LiveUpdate> {
+    let mut updates = vec![];
+    if let Some(calculated_at) = state.calculated_at {
+        // The importer logs a calculation before storing its TPS, so a poll
+        // between the two sees the new log with the old TPS
+        if last_state.calculated_at != Some(calculated_at) || last_state.tps != state.tps {
+            updates.push(LiveUpdate::Tps {
+                chain,
+                tps: state.tps.clone(),
+                calculated_at,
+            });
+        }
+    }
+    if let (Some(was_stalled), Some(stalled)) = (last_state.stalled, state.stalled) {
+        if was_stalled != stalled {
+            updates.push(LiveUpdate::Status { chain, stalled });
+        }
+    }
+    updates
+}
+
+/// Serves live updates to websocket clients at `address`, each as a JSON
+/// text message.
+pub async fn serve_websocket(address: SocketAddr, updates: LiveUpdates) {
+    match TcpListener::bind(address).await {
+        Ok(listener) => {
+            info!("serving live updates at ws://{}", address);
+            accept_websockets(listener, updates).await;
+        }
+        Err(e) => warn!("unable to listen for websockets on {}: {}", address, e),
+    }
+}
+
+/// Serves live updates to websocket clients connecting to `listener`.
+pub async fn accept_websockets(listener: TcpListener, updates: LiveUpdates) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(send_updates(stream, updates.subscribe()));
+            }
+            Err(e) => warn!("unable to accept websocket connection: {}", e),
+        }
+    }
+}
+
+async fn send_updates(stream: TcpStream, mut receiver: broadcast::Receiver<LiveUpdate>) {
+    let mut websocket = match tokio_tungstenite::accept_async(stream).await {
+        Ok(websocket) => websocket,
+        Err(e) => {
+            debug!("websocket handshake failed: {}", e);
+            return;
+        }
+    };
+    loop {
+        tokio::select! {
+            update = receiver.recv() => match update {
+                Ok(update) => {
+                    let json = rocket::serde::json::to_string(&update).expect("json");
+                    if websocket.send(Message::Text(json)).await.is_err() {
+                        return;
+                    }
+                }
+                // Too slow to keep up, so some updates are missed, but later
+                // ones are still sent
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("websocket client missed {} live updates", missed);
+                }
+                Err(RecvError::Closed) => return,
+            },
+            // Clients only listen, but reading answers their pings
+            message = websocket.next() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_live {
+    use super::*;
+    use crate::test_helpers::{calculated_at, connect_websocket, next_message, store_tps, TIMEOUT};
+    use realtps_common::{db::ChainHealth, memory_db::MemoryDb};
+
+    async fn next_update(receiver: &mut broadcast::Receiver<LiveUpdate>) -> LiveUpdate {
+        tokio::time::timeout(TIMEOUT, receiver.recv())
+            .await
+            .expect("update")
+            .expect("update")
+    }
+
+    fn health(stalled: bool) -> ChainHealth {
+        ChainHealth {
+            checked_at: u64::try_from(Utc::now().timestamp()).expect("timestamp"),
+            seconds_since_import: 0,
+            seconds_since_latest_block: 0,
+            stalled,
+        }
+    }
+
+    #[tokio::test]
+    async fn watch_sends_recalculations_and_stalls() {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        db.store_chain_health(Chain::Ethereum, &health(false))
+            .await
+            .expect("health");
+        let updates = LiveUpdates::new();
+        let mut receiver = updates.subscribe();
+        tokio::spawn(watch(
+            db.clone(),
+            updates.clone(),
+            Duration::from_millis(10),
+        ));
+        // Past the first poll, whose state isn't sent
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        store_tps(&db, Chain::Ethereum, 15.0).await;
+        match next_update(&mut receiver).await {
+            LiveUpdate::Tps {
+                chain,
+                tps,
+                calculated_at: at,
+            } => {
+                assert_eq!(chain, Chain::Ethereum);
+                assert_eq!(tps[&TpsWindow::Hour], 15.0);
+                assert_eq!(at, calculated_at());
+            }
+            update => panic!("unexpected update {:?}", update),
+        }
+
+        db.store_chain_health(Chain::Ethereum, &health(true))
+            .await
+            .expect("health");
+        match next_update(&mut receiver).await {
+            LiveUpdate::Status { chain, stalled } => {
+                assert_eq!(chain, Chain::Ethereum);
+                assert!(stalled);
+            }
+            update => panic!("unexpected update {:?}", update),
+        }
+    }
+
+    #[test]
+    fn tps_stored_after_its_calculation_log_is_sent() {
+        let state = |tps| ChainState {
+            tps: BTreeMap::from([(TpsWindow::Hour, tps)]),
+            calculated_at: Some(calculated_at()),
+            stalled: None,
+        };
+        // Polled between the calculation log and the TPS being stored
+        let calculated = state(10.0);
+        let stored = state(15.0);
+        assert!(changes(Chain::Ethereum, &calculated, &calculated).is_empty());
+        match changes(Chain::Ethereum, &calculated, &stored).as_slice() {
+            [LiveUpdate::Tps { tps, .. }] => assert_eq!(tps[&TpsWindow::Hour], 15.0),
+            updates => panic!("unexpected updates {:?}", updates),
+        }
+    }
+
+    #[tokio::test]
+    async fn websocket_clients_are_sent_updates() {
+        let updates = LiveUpdates::new();
+        let mut websocket = connect_websocket(updates.clone()).await;
+
+        updates.send(LiveUpdate::Status {
+            chain: Chain::Ethereum,
+            stalled: true,
+        });
+        let message = next_message(&mut websocket).await;
+        assert_eq!(message["type"], "status");
+        assert_eq!(message["chain"], "ethereum");
+        assert_eq!(message["stalled"], true);
+    }
+
+    #[tokio::test]
+    async fn lagging_websocket_clients_are_sent_later_updates() {
+        let updates = LiveUpdates::new();
+        let mut websocket = connect_websocket(updates.clone()).await;
+
+        // The test runtime is single threaded, so none are sent until it
+        // awaits, and the first are overwritten
+        for _ in 0..CHANNEL_CAPACITY {
+            updates.send(LiveUpdate::Status {
+                chain: Chain::Ethereum,
+                stalled: true,
+            });
+        }
+        updates.send(LiveUpdate::Status {
+            chain: Chain::Polygon,
+            stalled: false,
+        });
+
+        let mut received = 0;
+        loop {
+            let message = next_message(&mut websocket).await;
+            received += 1;
+            if message["chain"] == "polygon" {
+                break;
+            }
+        }
+        assert!(received <= CHANNEL_CAPACITY);
+    }
+}
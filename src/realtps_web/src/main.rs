@@ -1,14 +1,45 @@
 #[macro_use]
 extern crate rocket;
 
-use chrono::Duration;
+// Rocket re-exports a `uri!` macro for each route, which lints as unused
+// outside the crate root
+#[allow(unused_imports)]
+mod api;
+#[allow(unused_imports)]
+mod graphql;
+mod live;
+#[allow(unused_imports)]
+mod metrics;
+#[cfg(test)]
+mod test_helpers;
+
+use ::log::warn;
+use chrono::{Duration, Utc};
+use live::LiveUpdates;
 use realtps_common::{
     chain::Chain,
-    db::{CalculationLog, Db, JsonDb},
+    db::{CalculationLog, Db, TpsWindow},
+    db_config::{make_db, DbConfig},
+    metadata::{self, ChainKind, MetadataOverride},
 };
+use rocket::fairing::AdHoc;
+use rocket::figment::Figment;
 use rocket::fs::{relative, FileServer};
+use rocket::http::{Header, Status};
+use rocket::{Build, Rocket, State};
 use rocket_dyn_templates::Template;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// TPS over less of the week than this is flagged.
+const MIN_COVERAGE: f64 = 0.9;
+
+/// How long badges may be cached, by browsers and by image proxies like
+/// GitHub's, in seconds.
+const BADGE_MAX_AGE: u64 = 300;
 
 #[derive(Serialize, Deserialize, Debug)]
 struct EmptyContext {}
@@ -16,16 +47,36 @@ struct EmptyContext {}
 #[derive(Serialize, Deserialize, Debug)]
 struct Context {
     rows: Vec<Row>,
+    /// Shown apart, next to their mainnets
+    testnet_rows: Vec<Row>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 struct Row {
     chain_id: Chain,
     chain_name: String,
+    ticker: String,
+    kind: ChainKind,
+    website: Option<String>,
+    explorer_url: Option<String>,
     note: Option<String>,
     tps: f64,
     tps_str: String,
+    /// TPS over the last hour
+    recent_tps_str: Option<String>,
     is_data_too_old: bool,
+    /// The chain is disabled, so its TPS is the last calculated
+    is_stale: bool,
+    /// Blocks are missing from the week
+    is_data_incomplete: bool,
+    /// The chain, or our import of it, has stopped
+    is_stalled: bool,
+    /// The fewest block producers making over half the recent blocks
+    nakamoto_coefficient: Option<u64>,
+    /// For testnets, the name of the mainnet they test
+    mainnet_name: Option<String>,
+    /// For testnets, their mainnet's TPS, to compare against
+    mainnet_tps_str: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -40,60 +91,171 @@ struct Log {
     log_details: CalculationLog,
 }
 
-#[get("/")]
-fn index() -> Template {
-    let mut list = Vec::new();
-    let db = JsonDb;
+#[derive(Serialize, Deserialize, Debug)]
+struct BadgeContext {
+    label: String,
+    value: String,
+    color: &'static str,
+    width: usize,
+    label_width: usize,
+    value_width: usize,
+    /// The middle of the label
+    label_x: usize,
+    /// The middle of the value
+    value_x: usize,
+}
+
+impl BadgeContext {
+    fn new(label: String, value: String, color: &'static str) -> BadgeContext {
+        // Roughly, for 11px Verdana, with 5px either side
+        let text_width = |text: &str| text.chars().count() * 7 + 10;
+        let label_width = text_width(&label);
+        let value_width = text_width(&value);
+        BadgeContext {
+            label,
+            value,
+            color,
+            width: label_width + value_width,
+            label_width,
+            value_width,
+            label_x: label_width / 2,
+            value_x: label_width + value_width / 2,
+        }
+    }
+}
+
+#[derive(Responder)]
+struct Badge {
+    svg: Template,
+    cache_control: Header<'static>,
+}
+
+/// Chain metadata replacing the built-in, from `chain_metadata` in
+/// `Rocket.toml`, like `[default.chain_metadata.polygon]`.
+struct MetadataOverrides(HashMap<Chain, MetadataOverride>);
 
+#[get("/")]
+async fn index(
+    db: &State<Arc<dyn Db>>,
+    overrides: &State<MetadataOverrides>,
+) -> Result<Template, Status> {
+    let mut rows = Vec::new();
     for chain in Chain::all_chains() {
-        if let Some(tps) = db
-            .load_tps(chain)
-            .unwrap_or_else(|_| panic!("No tps data for chain {}", &chain))
+        rows.extend(
+            load_row(chain, db, overrides)
+                .await
+                .map_err(internal_error)?,
+        );
+    }
+
+    let mut testnet_rows = Vec::new();
+    for chain in Chain::testnet_chains() {
+        if let Some(mut row) = load_row(chain, db, overrides)
+            .await
+            .map_err(internal_error)?
         {
-            let mut is_data_too_old = false;
-            if let Some(log_details) = db
-                .load_calculation_log(chain)
-                .unwrap_or_else(|_| panic!("No calculation log for chain {}", &chain))
-            {
-                if log_details.calculating_start - log_details.newest_block_timestamp
-                    > Duration::days(1)
-                {
-                    is_data_too_old = true;
-                }
-            }
+            let mainnet = chain.mainnet().expect("testnet");
+            row.mainnet_name = Some(metadata::chain_metadata(mainnet, &overrides.0).name);
+            row.mainnet_tps_str = db
+                .load_tps(mainnet, TpsWindow::Week)
+                .await
+                .map_err(internal_error)?
+                .map(|tps| format!("{:.2}", tps));
+            testnet_rows.push(row);
+        }
+    }
 
-            let note = chain_note(chain).map(ToString::to_string);
-            let chain_id = chain;
-            let chain_name = chain.description().to_string();
-            let tps_str = format!("{:.2}", tps);
+    let context = Context { rows, testnet_rows };
+    Ok(Template::render("index", &context))
+}
 
-            list.push(Row {
-                chain_id,
-                chain_name,
-                note,
-                tps,
-                tps_str,
-                is_data_too_old,
-            });
+/// Logs an error loading a page, which is served as a 500.
+fn internal_error(e: anyhow::Error) -> Status {
+    warn!("unable to load from the db: {:#}", e);
+    Status::InternalServerError
+}
+
+/// A chain's row, if it has TPS.
+async fn load_row(
+    chain: Chain,
+    db: &Arc<dyn Db>,
+    overrides: &MetadataOverrides,
+) -> anyhow::Result<Option<Row>> {
+    let tps = match db.load_tps(chain, TpsWindow::Week).await? {
+        Some(tps) => tps,
+        None => return Ok(None),
+    };
+
+    let recent_tps = db.load_tps(chain, TpsWindow::Hour).await?;
+
+    let mut is_data_too_old = false;
+    let mut is_stale = false;
+    if let Some(log_details) = db.load_calculation_log(chain).await? {
+        if log_details.calculating_start - log_details.newest_block_timestamp > Duration::days(1) {
+            is_data_too_old = true;
         }
+        is_stale = log_details.stale;
     }
 
-    let context = Context { rows: list };
-    Template::render("index", &context)
+    let stats = db.load_chain_stats(chain).await?;
+    let coverage = stats
+        .as_ref()
+        .and_then(|stats| stats.windows.get(&TpsWindow::Week)?.coverage);
+    let is_data_incomplete = matches!(coverage, Some(coverage) if coverage < MIN_COVERAGE);
+
+    let chain_health = db.load_chain_health(chain).await?;
+    let now = u64::try_from(Utc::now().timestamp()).expect("timestamp");
+    let is_stalled = matches!(chain_health, Some(health) if health.is_stalled_at(now));
+
+    let nakamoto_coefficient = stats
+        .and_then(|stats| stats.producers)
+        .map(|producers| producers.nakamoto_coefficient);
+
+    let note = chain_note(chain).map(ToString::to_string);
+    let chain_id = chain;
+    let metadata = metadata::chain_metadata(chain, &overrides.0);
+    let tps_str = format!("{:.2}", tps);
+    let recent_tps_str = recent_tps.map(|tps| format!("{:.2}", tps));
+
+    Ok(Some(Row {
+        chain_id,
+        chain_name: metadata.name,
+        ticker: metadata.ticker,
+        kind: metadata.kind,
+        website: metadata.website,
+        explorer_url: metadata.explorer_url,
+        note,
+        tps,
+        tps_str,
+        recent_tps_str,
+        is_data_too_old,
+        is_stale,
+        is_data_incomplete,
+        is_stalled,
+        nakamoto_coefficient,
+        mainnet_name: None,
+        mainnet_tps_str: None,
+    }))
 }
 
 #[get("/log")]
-fn log() -> Template {
+async fn log(
+    db: &State<Arc<dyn Db>>,
+    overrides: &State<MetadataOverrides>,
+) -> Result<Template, Status> {
     let mut list = Vec::new();
-    let db = JsonDb;
 
-    for chain in Chain::all_chains() {
+    for chain in Chain::all_chains()
+        .into_iter()
+        .chain(Chain::testnet_chains())
+    {
         if let Some(log_details) = db
             .load_calculation_log(chain)
-            .unwrap_or_else(|_| panic!("No calculation log for chain {}", &chain))
+            .await
+            .map_err(internal_error)?
         {
             let chain_id = chain;
-            let chain_name = chain.description().to_string();
+            let chain_name = metadata::chain_metadata(chain, &overrides.0).name;
 
             list.push(Log {
                 chain_id,
@@ -104,7 +266,42 @@ fn log() -> Template {
     }
 
     let context = LogContext { log_list: list };
-    Template::render("log", &context)
+    Ok(Template::render("log", &context))
+}
+
+/// A shields.io-style badge of a chain's weekly TPS, like
+/// `/badge/ethereum.svg`, grey when the TPS isn't current, or can't be
+/// loaded, since a broken image is no use in a README.
+#[get("/badge/<file>")]
+async fn badge(
+    file: &str,
+    db: &State<Arc<dyn Db>>,
+    overrides: &State<MetadataOverrides>,
+) -> Option<Badge> {
+    let chain = Chain::try_from(file.strip_suffix(".svg")?).ok()?;
+    let row = load_row(chain, db, overrides).await.unwrap_or_else(|e| {
+        warn!("unable to load the badge of chain {}: {:#}", chain, e);
+        None
+    });
+    let context = match row {
+        Some(row) => {
+            let is_current = !(row.is_stale || row.is_stalled || row.is_data_too_old);
+            let color = if is_current { "#4c1" } else { "#9f9f9f" };
+            BadgeContext::new(format!("{} TPS", row.chain_name), row.tps_str, color)
+        }
+        None => {
+            let chain_name = metadata::chain_metadata(chain, &overrides.0).name;
+            BadgeContext::new(
+                format!("{} TPS", chain_name),
+                "no data".to_string(),
+                "#9f9f9f",
+            )
+        }
+    };
+    Some(Badge {
+        svg: Template::render("badge", &context),
+        cache_control: Header::new("Cache-Control", format!("max-age={}", BADGE_MAX_AGE)),
+    })
 }
 
 #[get("/about")]
@@ -114,10 +311,71 @@ fn about() -> Template {
 
 #[launch]
 fn rocket() -> _ {
-    rocket::build()
-        .mount("/", routes![index, about, log])
+    let rocket = rocket::build();
+    let db = make_db(&db_config(rocket.figment()))
+        .unwrap_or_else(|e| panic!("unable to open the db: {}", e));
+    website(rocket, db)
+}
+
+/// The db the importer writes, from `db_backend`, `db_path` and
+/// `object_store` in `Rocket.toml`, or `ROCKET_DB_BACKEND` and so on, like
+/// `--db-backend`, `--db-path` and `--object-store` for the importer.
+fn db_config(figment: &Figment) -> DbConfig {
+    fn extract<T: DeserializeOwned>(figment: &Figment, key: &str) -> Option<T> {
+        match figment.extract_inner(key) {
+            Ok(value) => Some(value),
+            Err(e) if e.missing() => None,
+            Err(e) => panic!("invalid {}: {}", key, e),
+        }
+    }
+    DbConfig {
+        backend: extract(figment, "db_backend"),
+        path: extract(figment, "db_path"),
+        object_store: extract(figment, "object_store"),
+    }
+}
+
+/// The website and API, serving what's in `db`.
+fn website(rocket: Rocket<Build>, db: Arc<dyn Db>) -> Rocket<Build> {
+    let metadata_overrides = match rocket.figment().extract_inner("chain_metadata") {
+        Ok(overrides) => overrides,
+        Err(e) if e.missing() => HashMap::new(),
+        Err(e) => panic!("invalid chain_metadata: {}", e),
+    };
+    let schema = graphql::schema(db.clone(), metadata_overrides.clone());
+    // `ws_port` in `Rocket.toml`, or `ROCKET_WS_PORT`, to serve live updates
+    // over websockets on that port
+    let ws_port: Option<u16> = rocket.figment().extract_inner("ws_port").ok();
+    let live_updates = LiveUpdates::new();
+    let serve_live_updates = AdHoc::on_liftoff("Live updates", {
+        let live_db = db.clone();
+        let live_updates = live_updates.clone();
+        move |rocket| {
+            Box::pin(async move {
+                tokio::spawn(live::watch(
+                    live_db,
+                    live_updates.clone(),
+                    live::POLL_INTERVAL,
+                ));
+                if let Some(ws_port) = ws_port {
+                    let address = SocketAddr::new(rocket.config().address, ws_port);
+                    tokio::spawn(live::serve_websocket(address, live_updates));
+                }
+            })
+        }
+    });
+    rocket
+        .manage(db)
+        .manage(MetadataOverrides(metadata_overrides))
+        .manage(schema)
+        .manage(live_updates)
+        .mount("/", routes![index, about, log, badge])
+        .mount("/", metrics::routes())
+        .mount("/", graphql::routes())
+        .mount("/v1", api::routes())
         .mount("/static", FileServer::from(relative!("static")))
         .attach(Template::fairing())
+        .attach(serve_live_updates)
 }
 
 fn chain_note(chain: Chain) -> Option<&'static str> {
@@ -126,3 +384,138 @@ fn chain_note(chain: Chain) -> Option<&'static str> {
         _ => None,
     }
 }
+
+#[cfg(test)]
+mod test_db_config {
+    use super::db_config;
+    use realtps_common::{
+        chain::Chain,
+        db::{Db, TpsWindow},
+        db_config::make_db,
+        object_store_db::ObjectStoreDb,
+    };
+    use rocket::Config;
+    use tempdir::TempDir;
+
+    #[tokio::test]
+    async fn open_the_importers_object_store() -> Result<(), anyhow::Error> {
+        let dir = TempDir::new("realtps_web")?;
+        let path = dir.path().to_str().expect("path");
+        let importer_db = ObjectStoreDb::new(&format!("file://{}", path))?;
+        importer_db
+            .store_tps(Chain::Ethereum, TpsWindow::Week, 15.0)
+            .await?;
+
+        let figment = Config::figment()
+            .merge(("db_backend", "object-store"))
+            .merge(("db_path", path));
+        let db = make_db(&db_config(&figment))?;
+        assert_eq!(
+            db.load_tps(Chain::Ethereum, TpsWindow::Week).await?,
+            Some(15.0)
+        );
+
+        let figment = Config::figment()
+            .merge(("db_backend", "json"))
+            .merge(("object_store", "s3://bucket"));
+        assert!(make_db(&db_config(&figment)).is_err());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_badge {
+    use crate::test_helpers::{client, store_tps};
+    use realtps_common::{
+        chain::Chain,
+        db::{tps_file, ChainHealth, Db, TpsWindow},
+        memory_db::MemoryDb,
+    };
+    use rocket::http::{ContentType, Status};
+    use std::sync::Arc;
+
+    #[rocket::async_test]
+    async fn badge_of_the_weekly_tps() {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        store_tps(&db, Chain::Ethereum, 15.0).await;
+        let client = client(db).await;
+
+        let response = client.get("/badge/ethereum.svg").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::SVG));
+        assert_eq!(
+            response.headers().get_one("Cache-Control"),
+            Some("max-age=300")
+        );
+        let svg = response.into_string().await.expect("svg");
+        assert!(svg.contains("Ethereum TPS: 15.00"));
+        assert!(svg.contains("#4c1"));
+    }
+
+    #[rocket::async_test]
+    async fn grey_badge_of_a_stalled_chain() {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        store_tps(&db, Chain::Ethereum, 15.0).await;
+        let health = ChainHealth {
+            checked_at: 0,
+            seconds_since_import: 0,
+            seconds_since_latest_block: 0,
+            stalled: true,
+        };
+        db.store_chain_health(Chain::Ethereum, &health)
+            .await
+            .expect("health");
+        let client = client(db).await;
+
+        let response = client.get("/badge/ethereum.svg").dispatch().await;
+        let svg = response.into_string().await.expect("svg");
+        assert!(svg.contains("Ethereum TPS: 15.00"));
+        assert!(svg.contains("#9f9f9f"));
+    }
+
+    #[rocket::async_test]
+    async fn badge_of_a_chain_without_tps() {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let client = client(db).await;
+
+        let response = client.get("/badge/ethereum.svg").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::SVG));
+        let svg = response.into_string().await.expect("svg");
+        assert!(svg.contains("Ethereum TPS: no data"));
+    }
+
+    #[rocket::async_test]
+    async fn unknown_chains_have_no_badge() {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        let client = client(db).await;
+
+        for uri in ["/badge/nochain.svg", "/badge/ethereum.png"] {
+            let response = client.get(uri).dispatch().await;
+            assert_eq!(response.status(), Status::NotFound, "{}", uri);
+        }
+    }
+
+    #[rocket::async_test]
+    async fn unreadable_tps_is_no_data() {
+        let db: Arc<dyn Db> = Arc::new(MemoryDb::new());
+        db.store_record_json(
+            Chain::Ethereum,
+            &tps_file(TpsWindow::Week),
+            b"not json".to_vec(),
+        )
+        .await
+        .expect("record");
+        let client = client(db).await;
+
+        let response = client.get("/badge/ethereum.svg").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let svg = response.into_string().await.expect("svg");
+        assert!(svg.contains("Ethereum TPS: no data"));
+        assert!(svg.contains("#9f9f9f"));
+
+        // The pages can't leave the chain out, so fail instead
+        let response = client.get("/").dispatch().await;
+        assert_eq!(response.status(), Status::InternalServerError);
+    }
+}
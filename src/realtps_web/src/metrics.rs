@@ -0,0 +1,183 @@
+//! Prometheus metrics at `/metrics`, for putting realtps data on other
+//! dashboards.
+
+use chrono::Utc;
+use realtps_common::{
+    chain::Chain,
+    db::{Db, LeaseKind, TpsWindow},
+};
+use rocket::http::{ContentType, Status};
+use rocket::{Route, State};
+use std::fmt::Write;
+use std::sync::Arc;
+
+/// A gauge and its samples, written in the Prometheus text format.
+struct Gauge {
+    name: &'static str,
+    help: &'static str,
+    samples: Vec<(String, f64)>,
+}
+
+impl Gauge {
+    fn new(name: &'static str, help: &'static str) -> Gauge {
+        Gauge {
+            name,
+            help,
+            samples: vec![],
+        }
+    }
+
+    fn set(&mut self, labels: &[(&str, &str)], value: f64) {
+        let labels = labels
+            .iter()
+            .map(|(name, value)| format!("{}=\"{}\"", name, value))
+            .collect::<Vec<_>>()
+            .join(",");
+        self.samples.push((labels, value));
+    }
+
+    fn write(&self, out: &mut String) {
+        writeln!(out, "# HELP {} {}", self.name, self.help).expect("write");
+        writeln!(out, "# TYPE {} gauge", self.name).expect("write");
+        for (labels, value) in &self.samples {
+            match labels.is_empty() {
+                true => writeln!(out, "{} {}", self.name, value),
+                false => writeln!(out, "{}{{{}}} {}", self.name, labels, value),
+            }
+            .expect("write");
+        }
+    }
+}
+
+/// `GET /metrics`: each chain's published TPS, how far behind its import is
+/// and when it was last updated, as Prometheus gauges.
+#[get("/metrics")]
+async fn metrics(db: &State<Arc<dyn Db>>) -> Result<(ContentType, String), Status> {
+    let internal_error = |_| Status::InternalServerError;
+    let now = u64::try_from(Utc::now().timestamp()).expect("timestamp");
+
+    let mut tps = Gauge::new(
+        "realtps_tps",
+        "Published transactions per second over the window",
+    );
+    let mut aggregate_tps = Gauge::new(
+        "realtps_aggregate_tps",
+        "Summed transactions per second of the mainnets over the window",
+    );
+    let mut import_lag = Gauge::new(
+        "realtps_import_lag_seconds",
+        "Age of the newest imported block",
+    );
+    let mut blocks_behind = Gauge::new(
+        "realtps_import_blocks_behind",
+        "Blocks left to import when the import last reported catching up",
+    );
+    let mut last_import = Gauge::new(
+        "realtps_last_import_timestamp_seconds",
+        "When the import last succeeded",
+    );
+    let mut last_calculation = Gauge::new(
+        "realtps_last_calculation_timestamp_seconds",
+        "When the TPS was last calculated",
+    );
+    let mut stale = Gauge::new(
+        "realtps_stale",
+        "1 if the chain is disabled and its TPS no longer updated",
+    );
+    let mut stalled = Gauge::new(
+        "realtps_stalled",
+        "1 if the chain, or its import, has stopped making blocks",
+    );
+
+    for chain in Chain::all_chains()
+        .into_iter()
+        .chain(Chain::testnet_chains())
+    {
+        let chain_name = chain.to_string();
+        let chain_label = [("chain", chain_name.as_str())];
+
+        for window in TpsWindow::all_windows() {
+            if let Some(window_tps) = db.load_tps(chain, window).await.map_err(internal_error)? {
+                let window = window.to_string();
+                tps.set(&[("chain", &chain_name), ("window", &window)], window_tps);
+            }
+        }
+
+        if let Some(newest_block) = newest_block_timestamp(chain, db).await? {
+            import_lag.set(&chain_label, now.saturating_sub(newest_block) as f64);
+        }
+        if let Some(progress) = db
+            .load_import_progress(chain)
+            .await
+            .map_err(internal_error)?
+        {
+            blocks_behind.set(&chain_label, progress.blocks_behind as f64);
+        }
+        if let Some(last_success) = db
+            .load_job_status(chain, LeaseKind::Import)
+            .await
+            .map_err(internal_error)?
+            .and_then(|status| status.last_success)
+        {
+            last_import.set(&chain_label, last_success.timestamp() as f64);
+        }
+        if let Some(log) = db
+            .load_calculation_log(chain)
+            .await
+            .map_err(internal_error)?
+        {
+            last_calculation.set(&chain_label, log.calculating_end.timestamp() as f64);
+            stale.set(&chain_label, f64::from(u8::from(log.stale)));
+        }
+        if let Some(health) = db.load_chain_health(chain).await.map_err(internal_error)? {
+            stalled.set(&chain_label, f64::from(u8::from(health.is_stalled_at(now))));
+        }
+    }
+
+    if let Some(point) = db
+        .load_aggregate_tps_history()
+        .await
+        .map_err(internal_error)?
+        .pop()
+    {
+        for (window, window_tps) in point.tps {
+            aggregate_tps.set(&[("window", &window.to_string())], window_tps);
+        }
+    }
+
+    let mut out = String::new();
+    for gauge in [
+        tps,
+        aggregate_tps,
+        import_lag,
+        blocks_behind,
+        last_import,
+        last_calculation,
+        stale,
+        stalled,
+    ] {
+        gauge.write(&mut out);
+    }
+    Ok((ContentType::Plain, out))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![metrics]
+}
+
+async fn newest_block_timestamp(chain: Chain, db: &Arc<dyn Db>) -> Result<Option<u64>, Status> {
+    let internal_error = |_| Status::InternalServerError;
+    let highest_block_number = match db
+        .load_highest_block_number(chain)
+        .await
+        .map_err(internal_error)?
+    {
+        Some(block_number) => block_number,
+        None => return Ok(None),
+    };
+    let block = db
+        .load_block(chain, highest_block_number)
+        .await
+        .map_err(internal_error)?;
+    Ok(block.map(|block| block.timestamp))
+}
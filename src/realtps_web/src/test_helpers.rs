@@ -0,0 +1,75 @@
+use crate::live::{self, LiveUpdates};
+use crate::website;
+use chrono::{DateTime, TimeZone, Utc};
+use realtps_common::{
+    chain::Chain,
+    db::{CalculationLog, Db, TpsWindow},
+};
+use rocket::fs::relative;
+use rocket::futures::StreamExt;
+use rocket::local::asynchronous::Client;
+use rocket::serde::json::{self, Value};
+use rocket::Config;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::{client_async, WebSocketStream};
+
+/// How long to wait for a live update.
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// When `store_tps` calculates.
+pub fn calculated_at() -> DateTime<Utc> {
+    Utc.timestamp_opt(1_700_000_000, 0).unwrap()
+}
+
+/// A client of the website serving `db`.
+pub async fn client(db: Arc<dyn Db>) -> Client {
+    let figment = Config::figment()
+        .merge(("template_dir", relative!("templates")))
+        .merge(("log_level", "off"));
+    Client::tracked(website(rocket::custom(figment), db))
+        .await
+        .expect("rocket")
+}
+
+/// Stores the same TPS for every window, as calculated from blocks up to a
+/// minute before `calculated_at`.
+pub async fn store_tps(db: &Arc<dyn Db>, chain: Chain, tps: f64) {
+    for window in TpsWindow::all_windows() {
+        db.store_tps(chain, window, tps).await.expect("tps");
+    }
+    let calculated_at = calculated_at();
+    let log = CalculationLog {
+        calculating_start: calculated_at,
+        calculating_end: calculated_at,
+        newest_block_timestamp: calculated_at - chrono::Duration::minutes(1),
+        oldest_block_timestamp: calculated_at - chrono::Duration::weeks(1),
+        stale: false,
+    };
+    db.store_calculation_log(chain, &log)
+        .await
+        .expect("calculation log");
+}
+
+/// A websocket client of live updates from `updates`.
+pub async fn connect_websocket(updates: LiveUpdates) -> WebSocketStream<TcpStream> {
+    let listener = TcpListener::bind("127.0.0.1:0").await.expect("listen");
+    let address = listener.local_addr().expect("address");
+    tokio::spawn(live::accept_websockets(listener, updates));
+    let stream = TcpStream::connect(address).await.expect("connect");
+    let (websocket, _) = client_async(format!("ws://{}", address), stream)
+        .await
+        .expect("handshake");
+    websocket
+}
+
+/// The next live update sent to a websocket client.
+pub async fn next_message(websocket: &mut WebSocketStream<TcpStream>) -> Value {
+    let message = tokio::time::timeout(TIMEOUT, websocket.next())
+        .await
+        .expect("message")
+        .expect("open")
+        .expect("message");
+    json::from_str(message.to_text().expect("text")).expect("json")
+}